@@ -1,4 +1,5 @@
 #include <stdint.h>
+#include <unistd.h>
 
 #if defined(__GNUC__)
   #define EXPORT __attribute__((visibility("default")))
@@ -6,6 +7,8 @@
   #define EXPORT
 #endif
 
+EXPORT const uint32_t concorde_domain_abi = 1;
+
 struct Sum {
     uint16_t n;
     uint16_t m;
@@ -20,3 +23,16 @@ EXPORT struct Sum add(uint16_t a, uint16_t b){
 EXPORT uint64_t max(uint64_t a, uint64_t b) {
     return a > b ? a : b;
 }
+
+EXPORT uint64_t crash(void) {
+    return *(volatile uint64_t *) 0;
+}
+
+EXPORT int32_t negate(int32_t a) {
+    return -a;
+}
+
+EXPORT uint64_t nap(uint64_t ms) {
+    usleep(ms * 1000);
+    return ms;
+}
@@ -0,0 +1,88 @@
+//! A macro for embedding Concorde programs in Rust.
+//!
+//! `concorde_asm!` takes a list of instructions, each followed by `;`, and evaluates to a
+//! `Vec<Instruction>`. Any line can be given a label with `name:`, and Jump, JumpIfTrue,
+//! JumpIfFalse, CreateCoroutine, SpawnActor, and the list loops can target a label with `@name` in
+//! place of an index:
+//!
+//! ```ignore
+//! let instructions = concorde_asm! {
+//!     MemExtend(100);
+//!     WriteIntToSymbol(0, 3);
+//!     top:
+//!     SubtractImmediate(0, 1, 0);
+//!     CompareGreaterImmediate(0, 0, 8);
+//!     JumpIfTrue(@top, 8);
+//!     Return(0, 8);
+//! };
+//! ```
+//!
+//! Every label becomes an item in the enclosing block, so jumping to an undefined label or
+//! defining one twice is a compile error in the host. Labels can't be Rust keywords. The macro
+//! recurses once per line, so very long programs may need a higher `recursion_limit`.
+
+#[doc(hidden)]
+pub mod __private {
+    pub use concordeisa::instructions::Instruction;
+}
+
+#[macro_export]
+macro_rules! concorde_asm {
+    ($($body:tt)*) => {{
+        $crate::__concorde_asm!(@labels $($body)*);
+        $crate::__concorde_asm!(@build $crate::ProgramBuilder::new(); $($body)*)
+    }};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __concorde_asm {
+    // Declare an item for every label.
+    (@labels) => {};
+    (@labels $label:ident : $($rest:tt)*) => {
+        #[allow(dead_code, non_camel_case_types)]
+        struct $label;
+        $crate::__concorde_asm!(@labels $($rest)*);
+    };
+    (@labels $other:tt $($rest:tt)*) => {
+        $crate::__concorde_asm!(@labels $($rest)*);
+    };
+
+    // Add each line to the builder, checking that each label used exists by naming its item.
+    (@build $builder:expr;) => {
+        $builder.build().expect("labels are checked when the host compiles")
+    };
+    (@build $builder:expr; $label:ident : $($rest:tt)*) => {
+        $crate::__concorde_asm!(@build $builder.label(stringify!($label)); $($rest)*)
+    };
+    (@build $builder:expr; Jump(@ $target:ident); $($rest:tt)*) => {
+        $crate::__concorde_asm!(@build { let _ = $target; $builder }.jump(stringify!($target)); $($rest)*)
+    };
+    (@build $builder:expr; JumpIfTrue(@ $target:ident, $condition:expr); $($rest:tt)*) => {
+        $crate::__concorde_asm!(@build { let _ = $target; $builder }.jump_if(stringify!($target), $condition); $($rest)*)
+    };
+    (@build $builder:expr; JumpIfFalse(@ $target:ident, $condition:expr); $($rest:tt)*) => {
+        $crate::__concorde_asm!(@build { let _ = $target; $builder }.jump_if_not(stringify!($target), $condition); $($rest)*)
+    };
+    (@build $builder:expr; CreateCoroutine(@ $target:ident, $arg_addr:expr, $n_arg_bytes:expr, $write_fut_id:expr); $($rest:tt)*) => {
+        $crate::__concorde_asm!(@build { let _ = $target; $builder }.call(stringify!($target), $arg_addr, $n_arg_bytes, $write_fut_id); $($rest)*)
+    };
+    (@build $builder:expr; SpawnActor(@ $target:ident, $message_size:expr, $write_actor_id:expr); $($rest:tt)*) => {
+        $crate::__concorde_asm!(@build { let _ = $target; $builder }.spawn_actor(stringify!($target), $message_size, $write_actor_id); $($rest)*)
+    };
+    (@build $builder:expr; ForEach($list:expr, $item:expr, @ $target:ident); $($rest:tt)*) => {
+        $crate::__concorde_asm!(@build { let _ = $target; $builder }.for_each($list, $item, stringify!($target)); $($rest)*)
+    };
+    (@build $builder:expr; MapList($list:expr, $item:expr, @ $target:ident, $dest:expr); $($rest:tt)*) => {
+        $crate::__concorde_asm!(@build { let _ = $target; $builder }.map_list($list, $item, stringify!($target), $dest); $($rest)*)
+    };
+    (@build $builder:expr; FilterList($list:expr, $item:expr, @ $target:ident, $dest:expr); $($rest:tt)*) => {
+        $crate::__concorde_asm!(@build { let _ = $target; $builder }.filter_list($list, $item, stringify!($target), $dest); $($rest)*)
+    };
+    (@build $builder:expr; ReduceList($list:expr, $item:expr, @ $target:ident, $accumulator:expr); $($rest:tt)*) => {
+        $crate::__concorde_asm!(@build { let _ = $target; $builder }.reduce_list($list, $item, stringify!($target), $accumulator); $($rest)*)
+    };
+    (@build $builder:expr; $name:ident ( $($arg:expr),* $(,)? ); $($rest:tt)*) => {
+        $crate::__concorde_asm!(@build $builder.instruction($crate::asm::__private::Instruction::$name($($arg),*)); $($rest)*)
+    };
+}
@@ -0,0 +1,343 @@
+//! ConcordeVM's arbitrary-precision integers.
+//!
+//! Implements the values behind the Big* instructions, for programs whose integers outgrow an
+//! i64. In memory, a big integer is an i64 byte count followed by that many bytes of the number
+//! in little-endian two's complement, so its size can be read like any other length-prefixed
+//! value.
+
+use crate::log_and_return_err;
+
+use log::error;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// An integer of any size, stored as a sign and 32 bit limbs, least significant first. The limbs
+/// never end in a zero, and zero is never negative.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct BigInt {
+    negative: bool,
+    limbs: Vec<u32>,
+}
+
+impl BigInt {
+    fn new(negative: bool, mut limbs: Vec<u32>) -> BigInt {
+        trim(&mut limbs);
+        let negative = negative && !limbs.is_empty();
+        return BigInt { negative, limbs };
+    }
+
+    pub(crate) fn from_i64(value: i64) -> BigInt {
+        let magnitude = value.unsigned_abs();
+        return BigInt::new(value < 0, vec![magnitude as u32, (magnitude >> 32) as u32]);
+    }
+
+    /// The value as an i64, if it fits.
+    pub(crate) fn to_i64(&self) -> Option<i64> {
+        if self.limbs.len() > 2 {
+            return None;
+        }
+        let magnitude = self.limbs.iter().rev().fold(0u64, |value, limb| (value << 32) | *limb as u64);
+        return if self.negative {
+            0i64.checked_sub_unsigned(magnitude)
+        } else {
+            i64::try_from(magnitude).ok()
+        };
+    }
+
+    pub(crate) fn is_zero(&self) -> bool {
+        return self.limbs.is_empty();
+    }
+
+    /// Read a number from little-endian two's complement bytes. No bytes reads as zero.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> BigInt {
+        let negative = bytes.last().is_some_and(|byte| byte & 0x80 != 0);
+        let mut limbs: Vec<u32> = bytes.chunks(4).map(|chunk| {
+            let mut word = if negative { [0xff; 4] } else { [0; 4] };
+            word[..chunk.len()].copy_from_slice(chunk);
+            u32::from_le_bytes(word)
+        }).collect();
+        if negative {
+            negate_limbs(&mut limbs);
+        }
+        return BigInt::new(negative, limbs);
+    }
+
+    /// The shortest little-endian two's complement bytes for this number.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut limbs = self.limbs.clone();
+        limbs.push(0);
+        if self.negative {
+            negate_limbs(&mut limbs);
+        }
+        let mut bytes: Vec<u8> = limbs.iter().flat_map(|limb| limb.to_le_bytes()).collect();
+        // Drop bytes that only repeat the sign of the one before.
+        let fill = if self.negative { 0xff } else { 0 };
+        while bytes.len() > 1 && bytes[bytes.len() - 1] == fill && (bytes[bytes.len() - 2] & 0x80 != 0) == self.negative {
+            bytes.pop();
+        }
+        return bytes;
+    }
+
+    /// Parse a decimal number, with an optional leading `-`.
+    pub(crate) fn parse(text: &str) -> Result<BigInt, String> {
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text),
+        };
+        if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+            log_and_return_err!("{:?} is not a decimal integer", text);
+        }
+        let mut limbs = Vec::new();
+        for byte in digits.bytes() {
+            multiply_add_small(&mut limbs, 10, (byte - b'0') as u32);
+        }
+        return Ok(BigInt::new(negative, limbs));
+    }
+
+    pub(crate) fn add(&self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, add_magnitudes(&self.limbs, &other.limbs));
+        }
+        return match compare_magnitudes(&self.limbs, &other.limbs) {
+            Ordering::Less => BigInt::new(other.negative, subtract_magnitudes(&other.limbs, &self.limbs)),
+            _ => BigInt::new(self.negative, subtract_magnitudes(&self.limbs, &other.limbs)),
+        };
+    }
+
+    pub(crate) fn subtract(&self, other: &BigInt) -> BigInt {
+        return self.add(&BigInt::new(!other.negative, other.limbs.clone()));
+    }
+
+    pub(crate) fn multiply(&self, other: &BigInt) -> BigInt {
+        return BigInt::new(self.negative != other.negative, multiply_magnitudes(&self.limbs, &other.limbs));
+    }
+
+    /// The quotient, rounded towards zero, and the remainder, which takes the sign of `self`, like
+    /// i64 division. Returns None when dividing by zero.
+    pub(crate) fn divide(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (quotient, remainder) = divide_magnitudes(&self.limbs, &other.limbs);
+        return Some((BigInt::new(self.negative != other.negative, quotient), BigInt::new(self.negative, remainder)));
+    }
+
+    /// `self` to the power of `exponent`, modulo `modulus`, as a number from 0 to `modulus - 1`.
+    pub(crate) fn pow_mod(&self, exponent: &BigInt, modulus: &BigInt) -> Result<BigInt, String> {
+        if exponent.negative {
+            log_and_return_err!("Can't raise to the negative power {}", exponent);
+        }
+        if modulus.negative || modulus.is_zero() {
+            log_and_return_err!("Can't take a power modulo {}, which isn't positive", modulus);
+        }
+        let reduce = |value: &BigInt| {
+            let (_, remainder) = value.divide(modulus).unwrap();
+            if remainder.negative { remainder.add(modulus) } else { remainder }
+        };
+        let mut result = reduce(&BigInt::from_i64(1));
+        let mut base = reduce(self);
+        for limb in &exponent.limbs {
+            for bit in 0..32 {
+                if (limb >> bit) & 1 == 1 {
+                    result = reduce(&result.multiply(&base));
+                }
+                base = reduce(&base.multiply(&base));
+            }
+        }
+        return Ok(result);
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        return match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare_magnitudes(&self.limbs, &other.limbs),
+            (true, true) => compare_magnitudes(&other.limbs, &self.limbs),
+        };
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        // Peel off nine decimal digits at a time, least significant first.
+        let mut limbs = self.limbs.clone();
+        let mut chunks = Vec::new();
+        while !limbs.is_empty() {
+            chunks.push(divide_small(&mut limbs, 1_000_000_000));
+        }
+        let mut text = if self.negative { "-".to_string() } else { String::new() };
+        text.push_str(&chunks.pop().unwrap().to_string());
+        for chunk in chunks.iter().rev() {
+            text.push_str(&format!("{:09}", chunk));
+        }
+        return write!(f, "{}", text);
+    }
+}
+
+fn trim(limbs: &mut Vec<u32>) {
+    while limbs.last() == Some(&0) {
+        limbs.pop();
+    }
+}
+
+// Negate a fixed-width two's complement number in place.
+fn negate_limbs(limbs: &mut [u32]) {
+    let mut carry = true;
+    for limb in limbs.iter_mut() {
+        (*limb, carry) = (!*limb).overflowing_add(carry as u32);
+    }
+}
+
+fn compare_magnitudes(a: &[u32], b: &[u32]) -> Ordering {
+    return a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()));
+}
+
+fn add_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut sum = Vec::with_capacity(long.len() + 1);
+    let mut carry = 0u64;
+    for (i, limb) in long.iter().enumerate() {
+        let total = *limb as u64 + *short.get(i).unwrap_or(&0) as u64 + carry;
+        sum.push(total as u32);
+        carry = total >> 32;
+    }
+    sum.push(carry as u32);
+    return sum;
+}
+
+// a - b, where a is at least b.
+fn subtract_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut difference = Vec::with_capacity(a.len());
+    let mut borrow = false;
+    for (i, limb) in a.iter().enumerate() {
+        let (value, first) = limb.overflowing_sub(*b.get(i).unwrap_or(&0));
+        let (value, second) = value.overflowing_sub(borrow as u32);
+        difference.push(value);
+        borrow = first || second;
+    }
+    return difference;
+}
+
+fn multiply_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut product = vec![0u32; a.len() + b.len()];
+    for (i, x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, y) in b.iter().enumerate() {
+            let total = *x as u64 * *y as u64 + product[i + j] as u64 + carry;
+            product[i + j] = total as u32;
+            carry = total >> 32;
+        }
+        product[i + b.len()] = carry as u32;
+    }
+    return product;
+}
+
+// limbs = limbs * factor + addend.
+fn multiply_add_small(limbs: &mut Vec<u32>, factor: u32, addend: u32) {
+    let mut carry = addend as u64;
+    for limb in limbs.iter_mut() {
+        let total = *limb as u64 * factor as u64 + carry;
+        *limb = total as u32;
+        carry = total >> 32;
+    }
+    if carry != 0 {
+        limbs.push(carry as u32);
+    }
+}
+
+// Divide in place by a single limb, returning the remainder.
+fn divide_small(limbs: &mut Vec<u32>, divisor: u32) -> u32 {
+    let mut remainder = 0u64;
+    for limb in limbs.iter_mut().rev() {
+        let value = (remainder << 32) | *limb as u64;
+        *limb = (value / divisor as u64) as u32;
+        remainder = value % divisor as u64;
+    }
+    trim(limbs);
+    return remainder as u32;
+}
+
+// Long division of magnitudes, with Knuth's algorithm D. `v` must not be zero.
+fn divide_magnitudes(u: &[u32], v: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if compare_magnitudes(u, v) == Ordering::Less {
+        return (Vec::new(), u.to_vec());
+    }
+    if v.len() == 1 {
+        let mut quotient = u.to_vec();
+        let remainder = divide_small(&mut quotient, v[0]);
+        return (quotient, vec![remainder]);
+    }
+
+    // Shift both so the divisor's top limb has its high bit set, which keeps the estimates close.
+    let n = v.len();
+    let m = u.len();
+    let shift = v[n - 1].leading_zeros();
+    let shifted = |limbs: &[u32], extra: bool| {
+        let mut out: Vec<u32> = (0..limbs.len()).map(|i| {
+            let low = if shift > 0 && i > 0 { limbs[i - 1] >> (32 - shift) } else { 0 };
+            (limbs[i] << shift) | low
+        }).collect();
+        if extra {
+            out.push(if shift > 0 { limbs[limbs.len() - 1] >> (32 - shift) } else { 0 });
+        }
+        out
+    };
+    let vn = shifted(v, false);
+    let mut un = shifted(u, true);
+
+    let mut quotient = vec![0u32; m - n + 1];
+    for j in (0..=m - n).rev() {
+        // Estimate this limb of the quotient from the top two limbs, then correct it.
+        let top = ((un[j + n] as u64) << 32) | un[j + n - 1] as u64;
+        let mut estimate = top / vn[n - 1] as u64;
+        let mut rest = top % vn[n - 1] as u64;
+        while estimate >> 32 != 0 || estimate * vn[n - 2] as u64 > ((rest << 32) | un[j + n - 2] as u64) {
+            estimate -= 1;
+            rest += vn[n - 1] as u64;
+            if rest >> 32 != 0 {
+                break;
+            }
+        }
+
+        // Subtract estimate * vn from the running remainder.
+        let mut borrow: i64 = 0;
+        for i in 0..n {
+            let product = estimate * vn[i] as u64;
+            let value = un[i + j] as i64 - borrow - (product & 0xffff_ffff) as i64;
+            un[i + j] = value as u32;
+            borrow = (product >> 32) as i64 - (value >> 32);
+        }
+        let value = un[j + n] as i64 - borrow;
+        un[j + n] = value as u32;
+
+        // The estimate can still be one too big, in which case add the divisor back.
+        quotient[j] = estimate as u32;
+        if value < 0 {
+            quotient[j] = quotient[j].wrapping_sub(1);
+            let mut carry = 0u64;
+            for i in 0..n {
+                let total = un[i + j] as u64 + vn[i] as u64 + carry;
+                un[i + j] = total as u32;
+                carry = total >> 32;
+            }
+            un[j + n] = un[j + n].wrapping_add(carry as u32);
+        }
+    }
+
+    let remainder = (0..n).map(|i| {
+        let high = if shift > 0 { un[i + 1] << (32 - shift) } else { 0 };
+        (un[i] >> shift) | high
+    }).collect();
+    return (quotient, remainder);
+}
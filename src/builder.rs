@@ -0,0 +1,191 @@
+//! ConcordeVM's program builder.
+//!
+//! Provides a fluent way of writing programs from Rust, with named labels in place of the raw
+//! instruction indices jumps otherwise need.
+
+use crate::linker::{targets_mut, Block};
+use crate::log_and_return_err;
+
+use concordeisa::instructions::Instruction;
+use log::error;
+use std::collections::HashMap;
+
+/// Builds a list of instructions. Methods are named after the instructions they add, and any
+/// instruction without a method can be added with `instruction`.
+///
+/// Jump targets are given as label names, which are resolved by `build`.
+#[derive(Default)]
+pub struct ProgramBuilder {
+    instructions: Vec<Instruction>,
+    labels: HashMap<String, usize>,
+    // Targets still to be resolved: instruction index, target slot, and label.
+    fixups: Vec<(usize, usize, String)>,
+    duplicate_labels: Vec<String>,
+}
+
+impl ProgramBuilder {
+    pub fn new() -> ProgramBuilder {
+        ProgramBuilder::default()
+    }
+
+    /// Name the position of the next instruction, so it can be jumped to.
+    pub fn label(mut self, name: &str) -> ProgramBuilder {
+        if self.labels.insert(name.to_string(), self.instructions.len()).is_some() {
+            self.duplicate_labels.push(name.to_string());
+        }
+        self
+    }
+
+    /// Add any instruction. Its jump targets are used as they are.
+    pub fn instruction(mut self, instruction: Instruction) -> ProgramBuilder {
+        self.instructions.push(instruction);
+        self
+    }
+
+    // Add an instruction whose jump targets are the given labels, in slot order.
+    fn with_labels(mut self, instruction: Instruction, labels: &[&str]) -> ProgramBuilder {
+        let index = self.instructions.len();
+        for (slot, label) in labels.iter().enumerate() {
+            self.fixups.push((index, slot, label.to_string()));
+        }
+        self.instruction(instruction)
+    }
+
+    pub fn mem_extend(self, n: usize) -> ProgramBuilder {
+        self.instruction(Instruction::MemExtend(n))
+    }
+
+    pub fn write_int(self, dest: usize, value: i64) -> ProgramBuilder {
+        self.instruction(Instruction::WriteIntToSymbol(dest, value))
+    }
+
+    pub fn write_bool(self, dest: usize, value: bool) -> ProgramBuilder {
+        self.instruction(Instruction::WriteBoolToSymbol(dest, value))
+    }
+
+    pub fn write_string(self, dest: usize, value: &str) -> ProgramBuilder {
+        self.instruction(Instruction::WriteStringToSymbol(dest, value.to_string()))
+    }
+
+    pub fn write_bytes(self, dest: usize, value: Vec<u8>) -> ProgramBuilder {
+        self.instruction(Instruction::WriteBytesToSymbol(dest, value))
+    }
+
+    pub fn copy(self, source: usize, dest: usize, n: usize) -> ProgramBuilder {
+        self.instruction(Instruction::MemCpy(source, dest, n))
+    }
+
+    pub fn add(self, a: usize, b: usize, dest: usize) -> ProgramBuilder {
+        self.instruction(Instruction::AddSymbols(a, b, dest))
+    }
+
+    pub fn subtract(self, a: usize, b: usize, dest: usize) -> ProgramBuilder {
+        self.instruction(Instruction::SubtractSymbols(a, b, dest))
+    }
+
+    pub fn multiply(self, a: usize, b: usize, dest: usize) -> ProgramBuilder {
+        self.instruction(Instruction::MultiplySymbols(a, b, dest))
+    }
+
+    pub fn divide(self, a: usize, b: usize, dest: usize) -> ProgramBuilder {
+        self.instruction(Instruction::DivideSymbols(a, b, dest))
+    }
+
+    pub fn add_immediate(self, a: usize, literal: i64, dest: usize) -> ProgramBuilder {
+        self.instruction(Instruction::AddImmediate(a, literal, dest))
+    }
+
+    pub fn subtract_immediate(self, a: usize, literal: i64, dest: usize) -> ProgramBuilder {
+        self.instruction(Instruction::SubtractImmediate(a, literal, dest))
+    }
+
+    pub fn compare_equal(self, a: usize, b: usize, dest: usize) -> ProgramBuilder {
+        self.instruction(Instruction::CompareEqual(a, b, dest))
+    }
+
+    pub fn compare_greater(self, a: usize, b: usize, dest: usize) -> ProgramBuilder {
+        self.instruction(Instruction::CompareGreater(a, b, dest))
+    }
+
+    pub fn compare_lesser(self, a: usize, b: usize, dest: usize) -> ProgramBuilder {
+        self.instruction(Instruction::CompareLesser(a, b, dest))
+    }
+
+    pub fn jump(self, label: &str) -> ProgramBuilder {
+        self.with_labels(Instruction::Jump(0), &[label])
+    }
+
+    pub fn jump_if(self, label: &str, condition: usize) -> ProgramBuilder {
+        self.with_labels(Instruction::JumpIfTrue(0, condition), &[label])
+    }
+
+    pub fn jump_if_not(self, label: &str, condition: usize) -> ProgramBuilder {
+        self.with_labels(Instruction::JumpIfFalse(0, condition), &[label])
+    }
+
+    /// Jump to the label paired with the i64 at `value`, or to `default` if none match.
+    pub fn switch(self, value: usize, cases: &[(i64, &str)], default: &str) -> ProgramBuilder {
+        let instruction = Instruction::Switch(value, cases.iter().map(|(case, _)| (*case, 0)).collect(), 0);
+        let mut labels: Vec<&str> = cases.iter().map(|(_, label)| *label).collect();
+        labels.push(default);
+        self.with_labels(instruction, &labels)
+    }
+
+    /// Start a coroutine at the label, and write the id of its future to `write_fut_id`.
+    pub fn call(self, label: &str, arg_addr: usize, n_arg_bytes: usize, write_fut_id: usize) -> ProgramBuilder {
+        self.with_labels(Instruction::CreateCoroutine(0, arg_addr, n_arg_bytes, write_fut_id), &[label])
+    }
+
+    /// Start an actor that handles each `message_size` byte message sent to it with the block at
+    /// the label, and write its id to `write_actor_id`.
+    pub fn spawn_actor(self, label: &str, message_size: usize, write_actor_id: usize) -> ProgramBuilder {
+        self.with_labels(Instruction::SpawnActor(0, message_size, write_actor_id), &[label])
+    }
+
+    pub fn await_future(self, fut_id_location: usize, dest: usize) -> ProgramBuilder {
+        self.instruction(Instruction::Await(fut_id_location, dest))
+    }
+
+    pub fn ret(self, address: usize, n: usize) -> ProgramBuilder {
+        self.instruction(Instruction::Return(address, n))
+    }
+
+    /// Run the block at the label once for each item of the list at `list`, with the item in `item`.
+    pub fn for_each(self, list: usize, item: usize, label: &str) -> ProgramBuilder {
+        self.with_labels(Instruction::ForEach(list, item, 0), &[label])
+    }
+
+    /// Like `for_each`, but put what the block returns for each item in the list at `dest`.
+    pub fn map_list(self, list: usize, item: usize, label: &str, dest: usize) -> ProgramBuilder {
+        self.with_labels(Instruction::MapList(list, item, 0, dest), &[label])
+    }
+
+    /// Like `for_each`, but put the items the block returns true for in the list at `dest`.
+    pub fn filter_list(self, list: usize, item: usize, label: &str, dest: usize) -> ProgramBuilder {
+        self.with_labels(Instruction::FilterList(list, item, 0, dest), &[label])
+    }
+
+    /// Like `for_each`, but replace the value at `accumulator` with what the block returns.
+    pub fn reduce_list(self, list: usize, item: usize, label: &str, accumulator: usize) -> ProgramBuilder {
+        self.with_labels(Instruction::ReduceList(list, item, 0, accumulator), &[label])
+    }
+
+    /// Resolve every label and return the instructions.
+    pub fn build(mut self) -> Result<Vec<Instruction>, String> {
+        if let Some(label) = self.duplicate_labels.first() {
+            log_and_return_err!("Label {} is defined more than once", label);
+        }
+        for (index, slot, label) in &self.fixups {
+            let Some(target) = self.labels.get(label) else {
+                log_and_return_err!("Instruction {} jumps to undefined label {}", index, label);
+            };
+            *targets_mut(&mut self.instructions[*index])[*slot] = *target;
+        }
+        return Ok(self.instructions);
+    }
+
+    /// Resolve every label and return the instructions as a block for linking.
+    pub fn build_block(self, name: &str) -> Result<Block, String> {
+        return Ok(Block::new(name, self.build()?));
+    }
+}
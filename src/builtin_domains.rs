@@ -0,0 +1,139 @@
+//! ConcordeVM's built-in domains.
+//!
+//! Native functions compiled into the VM, so common ones work without shipping a library with
+//! the program. A built-in domain is loaded with LoadSO like any other, under a reserved name in
+//! place of the path, and its functions are added with AddFFIFn and called with CallFFIFn as
+//! usual. Adding a function checks the declared signature against the real one.
+//!
+//! - `builtin:math`: `sqrt`, `exp`, `ln`, `sin`, `cos`, `tan`, `floor` and `ceil` take and return
+//!   an f64, `pow` takes two f64s, and `gcd` takes two u64s and returns one.
+//! - `builtin:string`: `to_upper` and `to_lower` take a u32 character code and return one, and
+//!   `is_alphabetic`, `is_numeric` and `is_whitespace` take one and return a u8 bool.
+//! - `builtin:os`: `pid` returns the u32 process id, `cpu_count` the u64 number of CPUs, and
+//!   `unix_time` the u64 seconds since the Unix epoch.
+//!
+//! Calls that get arguments they can't use, such as a character code that isn't one, return 0.
+
+use crate::domain::{type_code, DomainValue, FFIFunctionSignature, FFIType};
+use crate::log_and_return_err;
+
+use libffi::raw::{FFI_TYPE_DOUBLE, FFI_TYPE_UINT8, FFI_TYPE_UINT32, FFI_TYPE_UINT64};
+use log::error;
+use std::collections::HashMap;
+
+// Names under this prefix load built-in domains instead of libraries.
+pub(crate) const BUILTIN_DOMAIN_PREFIX: &str = "builtin:";
+
+struct Builtin {
+    name: &'static str,
+    // Argument and return types, as libffi type codes.
+    args: &'static [u32],
+    ret: u32,
+    // Takes the arguments packed as for a native call, and returns the value's bytes.
+    call: fn(&[u8]) -> Vec<u8>,
+}
+
+// Every argument here is 8 bytes, or the only one, so the nth starts at n * 8.
+fn f64_arg(args: &[u8], n: usize) -> f64 {
+    return f64::from_ne_bytes(args[n * 8..n * 8 + 8].try_into().unwrap());
+}
+
+fn u64_arg(args: &[u8], n: usize) -> u64 {
+    return u64::from_ne_bytes(args[n * 8..n * 8 + 8].try_into().unwrap());
+}
+
+fn char_arg(args: &[u8]) -> Option<char> {
+    return char::from_u32(u32::from_ne_bytes(args[..4].try_into().unwrap()));
+}
+
+fn char_result(c: Option<char>) -> Vec<u8> {
+    return c.map_or(0, |c| c as u32).to_ne_bytes().to_vec();
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    return a;
+}
+
+const MATH: &[Builtin] = &[
+    Builtin { name: "sqrt", args: &[FFI_TYPE_DOUBLE], ret: FFI_TYPE_DOUBLE, call: |args| f64_arg(args, 0).sqrt().to_ne_bytes().to_vec() },
+    Builtin { name: "exp", args: &[FFI_TYPE_DOUBLE], ret: FFI_TYPE_DOUBLE, call: |args| f64_arg(args, 0).exp().to_ne_bytes().to_vec() },
+    Builtin { name: "ln", args: &[FFI_TYPE_DOUBLE], ret: FFI_TYPE_DOUBLE, call: |args| f64_arg(args, 0).ln().to_ne_bytes().to_vec() },
+    Builtin { name: "sin", args: &[FFI_TYPE_DOUBLE], ret: FFI_TYPE_DOUBLE, call: |args| f64_arg(args, 0).sin().to_ne_bytes().to_vec() },
+    Builtin { name: "cos", args: &[FFI_TYPE_DOUBLE], ret: FFI_TYPE_DOUBLE, call: |args| f64_arg(args, 0).cos().to_ne_bytes().to_vec() },
+    Builtin { name: "tan", args: &[FFI_TYPE_DOUBLE], ret: FFI_TYPE_DOUBLE, call: |args| f64_arg(args, 0).tan().to_ne_bytes().to_vec() },
+    Builtin { name: "floor", args: &[FFI_TYPE_DOUBLE], ret: FFI_TYPE_DOUBLE, call: |args| f64_arg(args, 0).floor().to_ne_bytes().to_vec() },
+    Builtin { name: "ceil", args: &[FFI_TYPE_DOUBLE], ret: FFI_TYPE_DOUBLE, call: |args| f64_arg(args, 0).ceil().to_ne_bytes().to_vec() },
+    Builtin { name: "pow", args: &[FFI_TYPE_DOUBLE, FFI_TYPE_DOUBLE], ret: FFI_TYPE_DOUBLE, call: |args| f64_arg(args, 0).powf(f64_arg(args, 1)).to_ne_bytes().to_vec() },
+    Builtin { name: "gcd", args: &[FFI_TYPE_UINT64, FFI_TYPE_UINT64], ret: FFI_TYPE_UINT64, call: |args| gcd(u64_arg(args, 0), u64_arg(args, 1)).to_ne_bytes().to_vec() },
+];
+
+const STRING: &[Builtin] = &[
+    Builtin { name: "to_upper", args: &[FFI_TYPE_UINT32], ret: FFI_TYPE_UINT32, call: |args| char_result(char_arg(args).and_then(|c| c.to_uppercase().next())) },
+    Builtin { name: "to_lower", args: &[FFI_TYPE_UINT32], ret: FFI_TYPE_UINT32, call: |args| char_result(char_arg(args).and_then(|c| c.to_lowercase().next())) },
+    Builtin { name: "is_alphabetic", args: &[FFI_TYPE_UINT32], ret: FFI_TYPE_UINT8, call: |args| vec![char_arg(args).is_some_and(char::is_alphabetic) as u8] },
+    Builtin { name: "is_numeric", args: &[FFI_TYPE_UINT32], ret: FFI_TYPE_UINT8, call: |args| vec![char_arg(args).is_some_and(char::is_numeric) as u8] },
+    Builtin { name: "is_whitespace", args: &[FFI_TYPE_UINT32], ret: FFI_TYPE_UINT8, call: |args| vec![char_arg(args).is_some_and(char::is_whitespace) as u8] },
+];
+
+const OS: &[Builtin] = &[
+    Builtin { name: "pid", args: &[], ret: FFI_TYPE_UINT32, call: |_| std::process::id().to_ne_bytes().to_vec() },
+    Builtin {
+        name: "cpu_count",
+        args: &[],
+        ret: FFI_TYPE_UINT64,
+        call: |_| (std::thread::available_parallelism().map_or(0, |n| n.get()) as u64).to_ne_bytes().to_vec(),
+    },
+    Builtin {
+        name: "unix_time",
+        args: &[],
+        ret: FFI_TYPE_UINT64,
+        call: |_| std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |time| time.as_secs()).to_ne_bytes().to_vec(),
+    },
+];
+
+// A built-in domain, loaded under some domain id.
+pub(crate) struct BuiltinDomain {
+    name: &'static str,
+    builtins: &'static [Builtin],
+    // With the type and size of what each returns.
+    functions: HashMap<usize, (&'static Builtin, FFIType, usize)>,
+}
+
+impl BuiltinDomain {
+    // Find the built-in domain with the reserved name `path`.
+    pub(crate) fn find(path: &str) -> Result<BuiltinDomain, String> {
+        let (name, builtins) = match path.strip_prefix(BUILTIN_DOMAIN_PREFIX) {
+            Some("math") => ("math", MATH),
+            Some("string") => ("string", STRING),
+            Some("os") => ("os", OS),
+            _ => log_and_return_err!("There is no built-in domain named {}", path),
+        };
+        return Ok(BuiltinDomain { name, builtins, functions: HashMap::new() });
+    }
+
+    pub(crate) fn add_function(&mut self, fn_id: usize, signature: &FFIFunctionSignature) -> Result<(), String> {
+        let Some(builtin) = self.builtins.iter().find(|builtin| builtin.name == signature.name) else {
+            log_and_return_err!("Built-in domain {} has no function named {}", self.name, signature.name);
+        };
+        let arg_codes: Vec<u32> = signature.arg_types.iter().map(|t| type_code(&t.0)).collect();
+        if arg_codes != builtin.args || type_code(&signature.ret_type.0) != builtin.ret {
+            log_and_return_err!("Function {} of built-in domain {} was added with the wrong signature", signature.name, self.name);
+        }
+        self.functions.insert(fn_id, (builtin, signature.ret_type.clone(), signature.ret_size));
+        return Ok(());
+    }
+
+    pub(crate) fn n_ret_bytes(&self, fn_id: usize) -> Option<usize> {
+        return self.functions.get(&fn_id).map(|(_, _, ret_size)| *ret_size);
+    }
+
+    pub(crate) fn call(&self, fn_id: usize, args: &[u8]) -> Result<DomainValue, String> {
+        let Some((builtin, ret_type, _)) = self.functions.get(&fn_id) else {
+            log_and_return_err!("Function {} of built-in domain {} was never added", fn_id, self.name);
+        };
+        return DomainValue::from_bytes(&ret_type.0, &(builtin.call)(args));
+    }
+}
@@ -0,0 +1,835 @@
+//! ConcordeVM's binary program format.
+//!
+//! Provides a versioned, little-endian encoding for instructions, along with the `Encoder` and
+//! `Decoder` primitives that other binary formats in the VM (eg. snapshots) are built from.
+//!
+//! A program file is the magic bytes `CVBC`, a u16 format version, and then a length-prefixed
+//! list of instructions. Each instruction is a u8 opcode followed by its operands in order.
+//! From version 2, the instructions are followed by a bool, and then the program's `DebugInfo`
+//! if it is true.
+
+use crate::debug_info::DebugInfo;
+use crate::log_and_return_err;
+
+use concordeisa::instructions::Instruction;
+use libffi::middle::Type;
+use libffi::raw;
+use log::error;
+
+const MAGIC: &[u8; 4] = b"CVBC";
+const VERSION: u16 = 2;
+
+/// The number of opcodes in the instruction set. Opcodes run from 0 to `OPCODE_COUNT - 1`.
+pub const OPCODE_COUNT: usize = 162;
+
+/// The opcode identifying an instruction, both in program files and in dispatch tables.
+pub fn opcode(instruction: &Instruction) -> u8 {
+    match instruction {
+        Instruction::WriteStringToSymbol(..) => 0,
+        Instruction::WriteIntToSymbol(..) => 1,
+        Instruction::WriteBoolToSymbol(..) => 2,
+        Instruction::WriteBytesToSymbol(..) => 3,
+        Instruction::MemCpy(..) => 4,
+        Instruction::MemExtend(..) => 5,
+        Instruction::MemExtendTo(..) => 6,
+        Instruction::Ind(..) => 7,
+        Instruction::AddSymbols(..) => 8,
+        Instruction::SubtractSymbols(..) => 9,
+        Instruction::MultiplySymbols(..) => 10,
+        Instruction::DivideSymbols(..) => 11,
+        Instruction::ModuloSymbols(..) => 12,
+        Instruction::MinSymbols(..) => 13,
+        Instruction::MaxSymbols(..) => 14,
+        Instruction::FmaSymbols(..) => 15,
+        Instruction::SinSymbol(..) => 16,
+        Instruction::CosSymbol(..) => 17,
+        Instruction::TanSymbol(..) => 18,
+        Instruction::ArcsinSymbol(..) => 19,
+        Instruction::ArccosSymbol(..) => 20,
+        Instruction::ArctanSymbol(..) => 21,
+        Instruction::CompareEqual(..) => 22,
+        Instruction::CompareGreater(..) => 23,
+        Instruction::CompareLesser(..) => 24,
+        Instruction::OpenStream(..) => 25,
+        Instruction::CloseStream(..) => 26,
+        Instruction::FlushStream(..) => 27,
+        Instruction::ReadStream(..) => 28,
+        Instruction::WriteStream(..) => 29,
+        Instruction::ReadLine(..) => 30,
+        Instruction::ReadFileToSymbol(..) => 31,
+        Instruction::WriteSymbolToFile(..) => 32,
+        Instruction::RenameFile(..) => 33,
+        Instruction::DeleteFile(..) => 34,
+        Instruction::CopyFile(..) => 35,
+        Instruction::GetEnv(..) => 36,
+        Instruction::SetEnv(..) => 37,
+        Instruction::GetArgs(..) => 38,
+        Instruction::SpawnProcess(..) => 39,
+        Instruction::WaitProcess(..) => 40,
+        Instruction::Jump(..) => 41,
+        Instruction::JumpIfTrue(..) => 42,
+        Instruction::Await(..) => 43,
+        Instruction::CreateCoroutine(..) => 44,
+        Instruction::Return(..) => 45,
+        Instruction::DeleteFuture(..) => 46,
+        Instruction::LoadSO(..) => 47,
+        Instruction::AddFFIFn(..) => 48,
+        Instruction::CallFFIFn(..) => 49,
+        Instruction::NoOp() => 50,
+        Instruction::AddImmediate(..) => 51,
+        Instruction::SubtractImmediate(..) => 52,
+        Instruction::MultiplyImmediate(..) => 53,
+        Instruction::DivideImmediate(..) => 54,
+        Instruction::ModuloImmediate(..) => 55,
+        Instruction::CompareEqualImmediate(..) => 56,
+        Instruction::CompareGreaterImmediate(..) => 57,
+        Instruction::CompareLesserImmediate(..) => 58,
+        Instruction::Switch(..) => 59,
+        Instruction::JumpIfFalse(..) => 60,
+        Instruction::Import(..) => 61,
+        Instruction::CreateCoroutineIndirect(..) => 62,
+        Instruction::BeginTransaction() => 63,
+        Instruction::CommitTransaction() => 64,
+        Instruction::RollbackTransaction() => 65,
+        Instruction::MakeConst(..) => 66,
+        Instruction::ConcatBytes(..) => 67,
+        Instruction::FillBytes(..) => 68,
+        Instruction::CopyBytesRange(..) => 69,
+        Instruction::FindBytes(..) => 70,
+        Instruction::EncodeIntLE(..) => 71,
+        Instruction::EncodeIntBE(..) => 72,
+        Instruction::DecodeIntLE(..) => 73,
+        Instruction::DecodeIntBE(..) => 74,
+        Instruction::PackStruct(..) => 75,
+        Instruction::UnpackStruct(..) => 76,
+        Instruction::Hash(..) => 77,
+        Instruction::AeadEncrypt(..) => 78,
+        Instruction::AeadDecrypt(..) => 79,
+        Instruction::RandomBytes(..) => 80,
+        Instruction::RandomInt(..) => 81,
+        Instruction::NowUnixMillis(..) => 82,
+        Instruction::MonotonicNanos(..) => 83,
+        Instruction::FormatTimestamp(..) => 84,
+        Instruction::ParseTimestamp(..) => 85,
+        Instruction::CheckedAdd(..) => 86,
+        Instruction::CheckedSubtract(..) => 87,
+        Instruction::CheckedMultiply(..) => 88,
+        Instruction::WrappingAdd(..) => 89,
+        Instruction::WrappingSubtract(..) => 90,
+        Instruction::WrappingMultiply(..) => 91,
+        Instruction::BigFromInt(..) => 92,
+        Instruction::BigToInt(..) => 93,
+        Instruction::BigFromString(..) => 94,
+        Instruction::BigToString(..) => 95,
+        Instruction::BigAdd(..) => 96,
+        Instruction::BigSubtract(..) => 97,
+        Instruction::BigMultiply(..) => 98,
+        Instruction::BigDivide(..) => 99,
+        Instruction::BigModulo(..) => 100,
+        Instruction::BigPowMod(..) => 101,
+        Instruction::BigCompareEqual(..) => 102,
+        Instruction::BigCompareGreater(..) => 103,
+        Instruction::BigCompareLesser(..) => 104,
+        Instruction::SqrtSymbol(..) => 105,
+        Instruction::PowSymbols(..) => 106,
+        Instruction::AbsSymbol(..) => 107,
+        Instruction::FloorSymbol(..) => 108,
+        Instruction::CeilSymbol(..) => 109,
+        Instruction::FloatMinSymbols(..) => 110,
+        Instruction::FloatMaxSymbols(..) => 111,
+        Instruction::ExpSymbol(..) => 112,
+        Instruction::LnSymbol(..) => 113,
+        Instruction::Arctan2Symbols(..) => 114,
+        Instruction::CompareStringsEqual(..) => 115,
+        Instruction::CompareStringsGreater(..) => 116,
+        Instruction::CompareStringsLesser(..) => 117,
+        Instruction::CompareBoolsEqual(..) => 118,
+        Instruction::CompareFloatsEqual(..) => 119,
+        Instruction::CompareFloatsGreater(..) => 120,
+        Instruction::CompareFloatsLesser(..) => 121,
+        Instruction::CompareBytesEqual(..) => 122,
+        Instruction::CompareBytesGreater(..) => 123,
+        Instruction::CompareBytesLesser(..) => 124,
+        Instruction::ForEach(..) => 125,
+        Instruction::SortList(..) => 126,
+        Instruction::BinarySearch(..) => 127,
+        Instruction::MapList(..) => 128,
+        Instruction::FilterList(..) => 129,
+        Instruction::ReduceList(..) => 130,
+        Instruction::PrintSymbol(..) => 131,
+        Instruction::FormatString(..) => 132,
+        Instruction::CreateSharedRegion(..) => 133,
+        Instruction::AtomicAdd(..) => 134,
+        Instruction::CompareAndSwap(..) => 135,
+        Instruction::Lock(..) => 136,
+        Instruction::Unlock(..) => 137,
+        Instruction::ReadShared(..) => 138,
+        Instruction::WriteShared(..) => 139,
+        Instruction::SpawnActor(..) => 140,
+        Instruction::SendMessage(..) => 141,
+        Instruction::LoadCode(..) => 142,
+        Instruction::ReturnFromInterrupt() => 143,
+        Instruction::AwaitEvent(..) => 144,
+        Instruction::Assert(..) => 145,
+        Instruction::AssertType(..) => 146,
+        Instruction::GetStackDepth(..) => 147,
+        Instruction::GetCallerSymbol(..) => 148,
+        Instruction::ListCodeBlocks(..) => 149,
+        Instruction::BlockLength(..) => 150,
+        Instruction::BeginScope() => 151,
+        Instruction::EndScope(..) => 152,
+        Instruction::Select(..) => 153,
+        Instruction::MutexCreate(..) => 154,
+        Instruction::MutexLock(..) => 155,
+        Instruction::MutexUnlock(..) => 156,
+        Instruction::SemaphoreCreate(..) => 157,
+        Instruction::SemaphoreAcquire(..) => 158,
+        Instruction::SemaphoreRelease(..) => 159,
+        Instruction::UnloadDomain(..) => 160,
+        Instruction::InvokeHostValue(..) => 161,
+    }
+}
+
+/// Appends little-endian values to a byte buffer.
+#[derive(Default)]
+pub struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder { bytes: Vec::new() }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    pub fn i64(&mut self, value: i64) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    /// usizes are always stored as 8 bytes, so files are portable between platforms.
+    pub fn usize(&mut self, value: usize) {
+        self.bytes.extend((value as u64).to_le_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.usize(value.len());
+        self.bytes.extend(value);
+    }
+
+    pub fn string(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+    }
+
+    /// Write a magic number and format version, for the start of a file.
+    pub fn header(&mut self, magic: &[u8; 4], version: u16) {
+        self.bytes.extend(magic);
+        self.u16(version);
+    }
+
+    pub fn ffi_type(&mut self, value: &Type) {
+        self.raw_ffi_type(value.as_raw_ptr());
+    }
+
+    fn raw_ffi_type(&mut self, raw_type: *const raw::ffi_type) {
+        // Safety: `Type` always wraps a valid ffi_type, and struct types own a null-terminated
+        // list of valid element types.
+        unsafe {
+            self.u16((*raw_type).type_);
+            if u32::from((*raw_type).type_) == raw::FFI_TYPE_STRUCT {
+                let mut n = 0;
+                while !(*(*raw_type).elements.add(n)).is_null() {
+                    n += 1;
+                }
+                self.usize(n);
+                for i in 0..n {
+                    self.raw_ffi_type(*(*raw_type).elements.add(i));
+                }
+            }
+        }
+    }
+
+    pub fn instructions(&mut self, instructions: &[Instruction]) {
+        self.usize(instructions.len());
+        for instruction in instructions {
+            self.instruction(instruction);
+        }
+    }
+
+    pub fn instruction(&mut self, instruction: &Instruction) {
+        self.u8(opcode(instruction));
+        match instruction {
+            Instruction::WriteStringToSymbol(a, value)
+            | Instruction::AssertType(a, value) => { self.usize(*a); self.string(value); },
+            Instruction::WriteIntToSymbol(a, value) => { self.usize(*a); self.i64(*value); },
+            Instruction::WriteBoolToSymbol(a, value) => { self.usize(*a); self.bool(*value); },
+            Instruction::WriteBytesToSymbol(a, value) => { self.usize(*a); self.bytes(value); },
+
+            Instruction::MemCpy(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+            Instruction::MemExtend(a) => { self.usize(*a); },
+            Instruction::MemExtendTo(a) => { self.usize(*a); },
+            Instruction::Ind(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+
+            Instruction::AddSymbols(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+            Instruction::SubtractSymbols(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+            Instruction::MultiplySymbols(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+            Instruction::DivideSymbols(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+            Instruction::ModuloSymbols(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+            Instruction::MinSymbols(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+            Instruction::MaxSymbols(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+            Instruction::FmaSymbols(a, b, c, d) => { self.usizes(&[*a, *b, *c, *d]); },
+
+            Instruction::SinSymbol(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::CosSymbol(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::TanSymbol(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::ArcsinSymbol(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::ArccosSymbol(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::ArctanSymbol(a, b) => { self.usizes(&[*a, *b]); },
+
+            Instruction::CompareEqual(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+            Instruction::CompareGreater(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+            Instruction::CompareLesser(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+
+            Instruction::OpenStream(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+            Instruction::CloseStream(a) => { self.usize(*a); },
+            Instruction::FlushStream(a) => { self.usize(*a); },
+            Instruction::ReadStream(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+            Instruction::WriteStream(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+            Instruction::ReadLine(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::ReadFileToSymbol(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::WriteSymbolToFile(a, b, atomic) => { self.usizes(&[*a, *b]); self.bool(*atomic); },
+            Instruction::SortList(a, b, ascending) => { self.usizes(&[*a, *b]); self.bool(*ascending); },
+            Instruction::RenameFile(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::DeleteFile(a) => { self.usize(*a); },
+            Instruction::CopyFile(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::GetEnv(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::SetEnv(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::GetArgs(a) => { self.usize(*a); },
+            Instruction::SpawnProcess(a, b, c, d, e, f) => { self.usizes(&[*a, *b, *c, *d, *e, *f]); },
+            Instruction::WaitProcess(a, b) => { self.usizes(&[*a, *b]); },
+
+            Instruction::Jump(a) => { self.usize(*a); },
+            Instruction::JumpIfTrue(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::Await(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::CreateCoroutine(a, b, c, d) => { self.usizes(&[*a, *b, *c, *d]); },
+            Instruction::Return(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::DeleteFuture(a) => { self.usize(*a); },
+            Instruction::GetStackDepth(a) => { self.usize(*a); },
+            Instruction::GetCallerSymbol(a) => { self.usize(*a); },
+            Instruction::ListCodeBlocks(a) => { self.usize(*a); },
+            Instruction::MutexCreate(a)
+            | Instruction::MutexLock(a)
+            | Instruction::MutexUnlock(a)
+            | Instruction::SemaphoreAcquire(a)
+            | Instruction::SemaphoreRelease(a) => { self.usize(*a); },
+            Instruction::BlockLength(a, b) => { self.usizes(&[*a, *b]); },
+
+            Instruction::LoadSO(a, path) => { self.usize(*a); self.string(path); },
+            Instruction::UnloadDomain(a) => { self.usize(*a); },
+            Instruction::InvokeHostValue(a, b, c, d) => { self.usizes(&[*a, *b, *c, *d]); },
+            Instruction::AddFFIFn(a, b, name, arg_types, ret_type) => {
+                self.usizes(&[*a, *b]);
+                self.string(name);
+                self.usize(arg_types.len());
+                for arg_type in arg_types {
+                    self.ffi_type(arg_type);
+                }
+                self.ffi_type(ret_type);
+            },
+            Instruction::CallFFIFn(a, b, c, d, e) => { self.usizes(&[*a, *b, *c, *d, *e]); },
+
+            Instruction::NoOp() | Instruction::ReturnFromInterrupt() | Instruction::BeginScope() => {},
+            Instruction::EndScope(cancel) => { self.bool(*cancel); },
+
+            Instruction::AddImmediate(a, literal, c) => { self.usize(*a); self.i64(*literal); self.usize(*c); },
+            Instruction::SubtractImmediate(a, literal, c) => { self.usize(*a); self.i64(*literal); self.usize(*c); },
+            Instruction::MultiplyImmediate(a, literal, c) => { self.usize(*a); self.i64(*literal); self.usize(*c); },
+            Instruction::DivideImmediate(a, literal, c) => { self.usize(*a); self.i64(*literal); self.usize(*c); },
+            Instruction::ModuloImmediate(a, literal, c) => { self.usize(*a); self.i64(*literal); self.usize(*c); },
+            Instruction::CompareEqualImmediate(a, literal, c) => { self.usize(*a); self.i64(*literal); self.usize(*c); },
+            Instruction::CompareGreaterImmediate(a, literal, c) => { self.usize(*a); self.i64(*literal); self.usize(*c); },
+            Instruction::CompareLesserImmediate(a, literal, c) => { self.usize(*a); self.i64(*literal); self.usize(*c); },
+
+            Instruction::Switch(value, cases, default) => {
+                self.usize(*value);
+                self.usize(cases.len());
+                for (case, target) in cases {
+                    self.i64(*case);
+                    self.usize(*target);
+                }
+                self.usize(*default);
+            },
+            Instruction::JumpIfFalse(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::Import(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::CreateCoroutineIndirect(a, b, c, d) => { self.usizes(&[*a, *b, *c, *d]); },
+            Instruction::BeginTransaction() | Instruction::CommitTransaction() | Instruction::RollbackTransaction() => {},
+            Instruction::MakeConst(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::ConcatBytes(a, b, c, d, e) => { self.usizes(&[*a, *b, *c, *d, *e]); },
+            Instruction::FillBytes(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+            Instruction::CopyBytesRange(a, b, c, d, e) => { self.usizes(&[*a, *b, *c, *d, *e]); },
+            Instruction::FindBytes(a, b, c, d, e) => { self.usizes(&[*a, *b, *c, *d, *e]); },
+            Instruction::EncodeIntLE(a, b, c, d)
+            | Instruction::EncodeIntBE(a, b, c, d)
+            | Instruction::DecodeIntLE(a, b, c, d)
+            | Instruction::DecodeIntBE(a, b, c, d)
+            | Instruction::Hash(a, b, c, d)
+            | Instruction::BinarySearch(a, b, c, d)
+            | Instruction::MapList(a, b, c, d)
+            | Instruction::FilterList(a, b, c, d)
+            | Instruction::ReduceList(a, b, c, d)
+            | Instruction::AtomicAdd(a, b, c, d)
+            | Instruction::ReadShared(a, b, c, d)
+            | Instruction::WriteShared(a, b, c, d)
+            | Instruction::BigPowMod(a, b, c, d) => { self.usizes(&[*a, *b, *c, *d]); },
+            Instruction::PackStruct(a, b, c)
+            | Instruction::UnpackStruct(a, b, c)
+            | Instruction::RandomInt(a, b, c)
+            | Instruction::FormatTimestamp(a, b, c)
+            | Instruction::ParseTimestamp(a, b, c)
+            | Instruction::CheckedAdd(a, b, c)
+            | Instruction::CheckedSubtract(a, b, c)
+            | Instruction::CheckedMultiply(a, b, c)
+            | Instruction::WrappingAdd(a, b, c)
+            | Instruction::WrappingSubtract(a, b, c)
+            | Instruction::WrappingMultiply(a, b, c)
+            | Instruction::SpawnActor(a, b, c)
+            | Instruction::LoadCode(a, b, c)
+            | Instruction::BigAdd(a, b, c)
+            | Instruction::BigSubtract(a, b, c)
+            | Instruction::BigMultiply(a, b, c)
+            | Instruction::BigDivide(a, b, c)
+            | Instruction::BigModulo(a, b, c)
+            | Instruction::BigCompareEqual(a, b, c)
+            | Instruction::BigCompareGreater(a, b, c)
+            | Instruction::BigCompareLesser(a, b, c)
+            | Instruction::PowSymbols(a, b, c)
+            | Instruction::FloatMinSymbols(a, b, c)
+            | Instruction::FloatMaxSymbols(a, b, c)
+            | Instruction::Arctan2Symbols(a, b, c)
+            | Instruction::CompareStringsEqual(a, b, c)
+            | Instruction::CompareStringsGreater(a, b, c)
+            | Instruction::CompareStringsLesser(a, b, c)
+            | Instruction::CompareBoolsEqual(a, b, c)
+            | Instruction::CompareFloatsEqual(a, b, c)
+            | Instruction::CompareFloatsGreater(a, b, c)
+            | Instruction::CompareFloatsLesser(a, b, c)
+            | Instruction::ForEach(a, b, c)
+            | Instruction::FormatString(a, b, c) => { self.usizes(&[*a, *b, *c]); },
+            Instruction::AeadEncrypt(a, b, c, d, e)
+            | Instruction::AeadDecrypt(a, b, c, d, e)
+            | Instruction::CompareBytesEqual(a, b, c, d, e)
+            | Instruction::CompareBytesGreater(a, b, c, d, e)
+            | Instruction::CompareBytesLesser(a, b, c, d, e)
+            | Instruction::CompareAndSwap(a, b, c, d, e) => { self.usizes(&[*a, *b, *c, *d, *e]); },
+            Instruction::RandomBytes(a, b)
+            | Instruction::BigFromInt(a, b)
+            | Instruction::BigToInt(a, b)
+            | Instruction::BigFromString(a, b)
+            | Instruction::BigToString(a, b)
+            | Instruction::SqrtSymbol(a, b)
+            | Instruction::AbsSymbol(a, b)
+            | Instruction::FloorSymbol(a, b)
+            | Instruction::CeilSymbol(a, b)
+            | Instruction::ExpSymbol(a, b)
+            | Instruction::LnSymbol(a, b)
+            | Instruction::CreateSharedRegion(a, b)
+            | Instruction::SendMessage(a, b)
+            | Instruction::AwaitEvent(a, b)
+            | Instruction::Assert(a, b)
+            | Instruction::Select(a, b)
+            | Instruction::SemaphoreCreate(a, b) => { self.usizes(&[*a, *b]); },
+            Instruction::NowUnixMillis(a)
+            | Instruction::MonotonicNanos(a)
+            | Instruction::PrintSymbol(a)
+            | Instruction::Lock(a)
+            | Instruction::Unlock(a) => { self.usize(*a); },
+        }
+    }
+
+    fn usizes(&mut self, values: &[usize]) {
+        for value in values {
+            self.usize(*value);
+        }
+    }
+}
+
+/// Reads little-endian values written by an `Encoder`.
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Decoder<'a> {
+        Decoder { bytes, position: 0 }
+    }
+
+    /// Whether every byte has been read.
+    pub fn is_finished(&self) -> bool {
+        self.position == self.bytes.len()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.position + n > self.bytes.len() {
+            log_and_return_err!("Unexpected end of data at byte {}", self.position);
+        }
+        let taken = &self.bytes[self.position..self.position + n];
+        self.position += n;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, String> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn i64(&mut self) -> Result<i64, String> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, String> {
+        let value = u64::from_le_bytes(self.take(8)?.try_into().unwrap());
+        match usize::try_from(value) {
+            Ok(value) => Ok(value),
+            Err(_) => log_and_return_err!("Value {} at byte {} doesn't fit in a usize", value, self.position - 8),
+        }
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.usize()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        match String::from_utf8(self.bytes()?) {
+            Ok(string) => Ok(string),
+            Err(e) => log_and_return_err!("Invalid string before byte {}: {}", self.position, e),
+        }
+    }
+
+    /// Check the magic number, and return the format version.
+    /// Versions newer than `max_version` are rejected.
+    pub fn header(&mut self, magic: &[u8; 4], max_version: u16) -> Result<u16, String> {
+        if self.take(4)? != magic {
+            log_and_return_err!("Data does not start with {:?}", String::from_utf8_lossy(magic));
+        }
+        let version = self.u16()?;
+        if version > max_version {
+            log_and_return_err!("Unsupported format version {} (newest supported is {})", version, max_version);
+        }
+        Ok(version)
+    }
+
+    pub fn ffi_type(&mut self) -> Result<Type, String> {
+        let code = self.u16()?;
+        let ffi_type = match u32::from(code) {
+            raw::FFI_TYPE_VOID => Type::void(),
+            raw::FFI_TYPE_INT => Type::c_int(),
+            raw::FFI_TYPE_FLOAT => Type::f32(),
+            raw::FFI_TYPE_DOUBLE => Type::f64(),
+            raw::FFI_TYPE_LONGDOUBLE => Type::longdouble(),
+            raw::FFI_TYPE_UINT8 => Type::u8(),
+            raw::FFI_TYPE_SINT8 => Type::i8(),
+            raw::FFI_TYPE_UINT16 => Type::u16(),
+            raw::FFI_TYPE_SINT16 => Type::i16(),
+            raw::FFI_TYPE_UINT32 => Type::u32(),
+            raw::FFI_TYPE_SINT32 => Type::i32(),
+            raw::FFI_TYPE_UINT64 => Type::u64(),
+            raw::FFI_TYPE_SINT64 => Type::i64(),
+            raw::FFI_TYPE_POINTER => Type::pointer(),
+            raw::FFI_TYPE_STRUCT => {
+                let n = self.usize()?;
+                let mut elements = Vec::with_capacity(n);
+                for _ in 0..n {
+                    elements.push(self.ffi_type()?);
+                }
+                Type::structure(elements)
+            },
+            _ => log_and_return_err!("Unsupported FFI type code {}", code),
+        };
+        Ok(ffi_type)
+    }
+
+    pub fn instructions(&mut self) -> Result<Vec<Instruction>, String> {
+        let n = self.usize()?;
+        let mut instructions = Vec::with_capacity(n.min(self.bytes.len()));
+        for _ in 0..n {
+            instructions.push(self.instruction()?);
+        }
+        Ok(instructions)
+    }
+
+    pub fn instruction(&mut self) -> Result<Instruction, String> {
+        let opcode = self.u8()?;
+        let instruction = match opcode {
+            0 => Instruction::WriteStringToSymbol(self.usize()?, self.string()?),
+            1 => Instruction::WriteIntToSymbol(self.usize()?, self.i64()?),
+            2 => Instruction::WriteBoolToSymbol(self.usize()?, self.bool()?),
+            3 => Instruction::WriteBytesToSymbol(self.usize()?, self.bytes()?),
+
+            4 => Instruction::MemCpy(self.usize()?, self.usize()?, self.usize()?),
+            5 => Instruction::MemExtend(self.usize()?),
+            6 => Instruction::MemExtendTo(self.usize()?),
+            7 => Instruction::Ind(self.usize()?, self.usize()?, self.usize()?),
+
+            8 => Instruction::AddSymbols(self.usize()?, self.usize()?, self.usize()?),
+            9 => Instruction::SubtractSymbols(self.usize()?, self.usize()?, self.usize()?),
+            10 => Instruction::MultiplySymbols(self.usize()?, self.usize()?, self.usize()?),
+            11 => Instruction::DivideSymbols(self.usize()?, self.usize()?, self.usize()?),
+            12 => Instruction::ModuloSymbols(self.usize()?, self.usize()?, self.usize()?),
+            13 => Instruction::MinSymbols(self.usize()?, self.usize()?, self.usize()?),
+            14 => Instruction::MaxSymbols(self.usize()?, self.usize()?, self.usize()?),
+            15 => Instruction::FmaSymbols(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+
+            16 => Instruction::SinSymbol(self.usize()?, self.usize()?),
+            17 => Instruction::CosSymbol(self.usize()?, self.usize()?),
+            18 => Instruction::TanSymbol(self.usize()?, self.usize()?),
+            19 => Instruction::ArcsinSymbol(self.usize()?, self.usize()?),
+            20 => Instruction::ArccosSymbol(self.usize()?, self.usize()?),
+            21 => Instruction::ArctanSymbol(self.usize()?, self.usize()?),
+
+            22 => Instruction::CompareEqual(self.usize()?, self.usize()?, self.usize()?),
+            23 => Instruction::CompareGreater(self.usize()?, self.usize()?, self.usize()?),
+            24 => Instruction::CompareLesser(self.usize()?, self.usize()?, self.usize()?),
+
+            25 => Instruction::OpenStream(self.usize()?, self.usize()?, self.usize()?),
+            26 => Instruction::CloseStream(self.usize()?),
+            27 => Instruction::FlushStream(self.usize()?),
+            28 => Instruction::ReadStream(self.usize()?, self.usize()?, self.usize()?),
+            29 => Instruction::WriteStream(self.usize()?, self.usize()?, self.usize()?),
+            30 => Instruction::ReadLine(self.usize()?, self.usize()?),
+            31 => Instruction::ReadFileToSymbol(self.usize()?, self.usize()?),
+            32 => Instruction::WriteSymbolToFile(self.usize()?, self.usize()?, self.bool()?),
+            33 => Instruction::RenameFile(self.usize()?, self.usize()?),
+            34 => Instruction::DeleteFile(self.usize()?),
+            35 => Instruction::CopyFile(self.usize()?, self.usize()?),
+            36 => Instruction::GetEnv(self.usize()?, self.usize()?),
+            37 => Instruction::SetEnv(self.usize()?, self.usize()?),
+            38 => Instruction::GetArgs(self.usize()?),
+            39 => Instruction::SpawnProcess(self.usize()?, self.usize()?, self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            40 => Instruction::WaitProcess(self.usize()?, self.usize()?),
+
+            41 => Instruction::Jump(self.usize()?),
+            42 => Instruction::JumpIfTrue(self.usize()?, self.usize()?),
+            43 => Instruction::Await(self.usize()?, self.usize()?),
+            44 => Instruction::CreateCoroutine(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            45 => Instruction::Return(self.usize()?, self.usize()?),
+            46 => Instruction::DeleteFuture(self.usize()?),
+
+            47 => Instruction::LoadSO(self.usize()?, self.string()?),
+            48 => {
+                let (domain_id, function_id, name) = (self.usize()?, self.usize()?, self.string()?);
+                let n_args = self.usize()?;
+                let mut arg_types = Vec::new();
+                for _ in 0..n_args {
+                    arg_types.push(self.ffi_type()?);
+                }
+                Instruction::AddFFIFn(domain_id, function_id, name, arg_types, self.ffi_type()?)
+            },
+            49 => Instruction::CallFFIFn(self.usize()?, self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+
+            50 => Instruction::NoOp(),
+
+            51 => Instruction::AddImmediate(self.usize()?, self.i64()?, self.usize()?),
+            52 => Instruction::SubtractImmediate(self.usize()?, self.i64()?, self.usize()?),
+            53 => Instruction::MultiplyImmediate(self.usize()?, self.i64()?, self.usize()?),
+            54 => Instruction::DivideImmediate(self.usize()?, self.i64()?, self.usize()?),
+            55 => Instruction::ModuloImmediate(self.usize()?, self.i64()?, self.usize()?),
+            56 => Instruction::CompareEqualImmediate(self.usize()?, self.i64()?, self.usize()?),
+            57 => Instruction::CompareGreaterImmediate(self.usize()?, self.i64()?, self.usize()?),
+            58 => Instruction::CompareLesserImmediate(self.usize()?, self.i64()?, self.usize()?),
+
+            59 => {
+                let value = self.usize()?;
+                let mut cases = Vec::new();
+                for _ in 0..self.usize()? {
+                    cases.push((self.i64()?, self.usize()?));
+                }
+                Instruction::Switch(value, cases, self.usize()?)
+            },
+            60 => Instruction::JumpIfFalse(self.usize()?, self.usize()?),
+            61 => Instruction::Import(self.usize()?, self.usize()?),
+            62 => Instruction::CreateCoroutineIndirect(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            63 => Instruction::BeginTransaction(),
+            64 => Instruction::CommitTransaction(),
+            65 => Instruction::RollbackTransaction(),
+            66 => Instruction::MakeConst(self.usize()?, self.usize()?),
+            67 => Instruction::ConcatBytes(self.usize()?, self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            68 => Instruction::FillBytes(self.usize()?, self.usize()?, self.usize()?),
+            69 => Instruction::CopyBytesRange(self.usize()?, self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            70 => Instruction::FindBytes(self.usize()?, self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            71 => Instruction::EncodeIntLE(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            72 => Instruction::EncodeIntBE(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            73 => Instruction::DecodeIntLE(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            74 => Instruction::DecodeIntBE(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            75 => Instruction::PackStruct(self.usize()?, self.usize()?, self.usize()?),
+            76 => Instruction::UnpackStruct(self.usize()?, self.usize()?, self.usize()?),
+            77 => Instruction::Hash(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            78 => Instruction::AeadEncrypt(self.usize()?, self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            79 => Instruction::AeadDecrypt(self.usize()?, self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            80 => Instruction::RandomBytes(self.usize()?, self.usize()?),
+            81 => Instruction::RandomInt(self.usize()?, self.usize()?, self.usize()?),
+            82 => Instruction::NowUnixMillis(self.usize()?),
+            83 => Instruction::MonotonicNanos(self.usize()?),
+            84 => Instruction::FormatTimestamp(self.usize()?, self.usize()?, self.usize()?),
+            85 => Instruction::ParseTimestamp(self.usize()?, self.usize()?, self.usize()?),
+            86 => Instruction::CheckedAdd(self.usize()?, self.usize()?, self.usize()?),
+            87 => Instruction::CheckedSubtract(self.usize()?, self.usize()?, self.usize()?),
+            88 => Instruction::CheckedMultiply(self.usize()?, self.usize()?, self.usize()?),
+            89 => Instruction::WrappingAdd(self.usize()?, self.usize()?, self.usize()?),
+            90 => Instruction::WrappingSubtract(self.usize()?, self.usize()?, self.usize()?),
+            91 => Instruction::WrappingMultiply(self.usize()?, self.usize()?, self.usize()?),
+            92 => Instruction::BigFromInt(self.usize()?, self.usize()?),
+            93 => Instruction::BigToInt(self.usize()?, self.usize()?),
+            94 => Instruction::BigFromString(self.usize()?, self.usize()?),
+            95 => Instruction::BigToString(self.usize()?, self.usize()?),
+            96 => Instruction::BigAdd(self.usize()?, self.usize()?, self.usize()?),
+            97 => Instruction::BigSubtract(self.usize()?, self.usize()?, self.usize()?),
+            98 => Instruction::BigMultiply(self.usize()?, self.usize()?, self.usize()?),
+            99 => Instruction::BigDivide(self.usize()?, self.usize()?, self.usize()?),
+            100 => Instruction::BigModulo(self.usize()?, self.usize()?, self.usize()?),
+            101 => Instruction::BigPowMod(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            102 => Instruction::BigCompareEqual(self.usize()?, self.usize()?, self.usize()?),
+            103 => Instruction::BigCompareGreater(self.usize()?, self.usize()?, self.usize()?),
+            104 => Instruction::BigCompareLesser(self.usize()?, self.usize()?, self.usize()?),
+            105 => Instruction::SqrtSymbol(self.usize()?, self.usize()?),
+            106 => Instruction::PowSymbols(self.usize()?, self.usize()?, self.usize()?),
+            107 => Instruction::AbsSymbol(self.usize()?, self.usize()?),
+            108 => Instruction::FloorSymbol(self.usize()?, self.usize()?),
+            109 => Instruction::CeilSymbol(self.usize()?, self.usize()?),
+            110 => Instruction::FloatMinSymbols(self.usize()?, self.usize()?, self.usize()?),
+            111 => Instruction::FloatMaxSymbols(self.usize()?, self.usize()?, self.usize()?),
+            112 => Instruction::ExpSymbol(self.usize()?, self.usize()?),
+            113 => Instruction::LnSymbol(self.usize()?, self.usize()?),
+            114 => Instruction::Arctan2Symbols(self.usize()?, self.usize()?, self.usize()?),
+            115 => Instruction::CompareStringsEqual(self.usize()?, self.usize()?, self.usize()?),
+            116 => Instruction::CompareStringsGreater(self.usize()?, self.usize()?, self.usize()?),
+            117 => Instruction::CompareStringsLesser(self.usize()?, self.usize()?, self.usize()?),
+            118 => Instruction::CompareBoolsEqual(self.usize()?, self.usize()?, self.usize()?),
+            119 => Instruction::CompareFloatsEqual(self.usize()?, self.usize()?, self.usize()?),
+            120 => Instruction::CompareFloatsGreater(self.usize()?, self.usize()?, self.usize()?),
+            121 => Instruction::CompareFloatsLesser(self.usize()?, self.usize()?, self.usize()?),
+            122 => Instruction::CompareBytesEqual(self.usize()?, self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            123 => Instruction::CompareBytesGreater(self.usize()?, self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            124 => Instruction::CompareBytesLesser(self.usize()?, self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            125 => Instruction::ForEach(self.usize()?, self.usize()?, self.usize()?),
+            126 => Instruction::SortList(self.usize()?, self.usize()?, self.bool()?),
+            127 => Instruction::BinarySearch(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            128 => Instruction::MapList(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            129 => Instruction::FilterList(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            130 => Instruction::ReduceList(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            131 => Instruction::PrintSymbol(self.usize()?),
+            132 => Instruction::FormatString(self.usize()?, self.usize()?, self.usize()?),
+            133 => Instruction::CreateSharedRegion(self.usize()?, self.usize()?),
+            134 => Instruction::AtomicAdd(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            135 => Instruction::CompareAndSwap(self.usize()?, self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            136 => Instruction::Lock(self.usize()?),
+            137 => Instruction::Unlock(self.usize()?),
+            138 => Instruction::ReadShared(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            139 => Instruction::WriteShared(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            140 => Instruction::SpawnActor(self.usize()?, self.usize()?, self.usize()?),
+            141 => Instruction::SendMessage(self.usize()?, self.usize()?),
+            142 => Instruction::LoadCode(self.usize()?, self.usize()?, self.usize()?),
+            143 => Instruction::ReturnFromInterrupt(),
+            144 => Instruction::AwaitEvent(self.usize()?, self.usize()?),
+            145 => Instruction::Assert(self.usize()?, self.usize()?),
+            146 => Instruction::AssertType(self.usize()?, self.string()?),
+            147 => Instruction::GetStackDepth(self.usize()?),
+            148 => Instruction::GetCallerSymbol(self.usize()?),
+            149 => Instruction::ListCodeBlocks(self.usize()?),
+            150 => Instruction::BlockLength(self.usize()?, self.usize()?),
+            151 => Instruction::BeginScope(),
+            152 => Instruction::EndScope(self.bool()?),
+            153 => Instruction::Select(self.usize()?, self.usize()?),
+            154 => Instruction::MutexCreate(self.usize()?),
+            155 => Instruction::MutexLock(self.usize()?),
+            156 => Instruction::MutexUnlock(self.usize()?),
+            157 => Instruction::SemaphoreCreate(self.usize()?, self.usize()?),
+            158 => Instruction::SemaphoreAcquire(self.usize()?),
+            159 => Instruction::SemaphoreRelease(self.usize()?),
+            160 => Instruction::UnloadDomain(self.usize()?),
+            161 => Instruction::InvokeHostValue(self.usize()?, self.usize()?, self.usize()?, self.usize()?),
+            _ => log_and_return_err!("Unknown opcode {} at byte {}", opcode, self.position - 1),
+        };
+        Ok(instruction)
+    }
+}
+
+/// Encode a list of instructions as a program file.
+pub fn encode_program(instructions: &[Instruction]) -> Vec<u8> {
+    encode(instructions, None)
+}
+
+/// Encode a list of instructions as a program file, along with where they came from.
+pub fn encode_program_with_debug_info(instructions: &[Instruction], debug_info: &DebugInfo) -> Vec<u8> {
+    encode(instructions, Some(debug_info))
+}
+
+fn encode(instructions: &[Instruction], debug_info: Option<&DebugInfo>) -> Vec<u8> {
+    let mut encoder = Encoder::new();
+    encoder.header(MAGIC, VERSION);
+    encoder.instructions(instructions);
+    encoder.bool(debug_info.is_some());
+    if let Some(debug_info) = debug_info {
+        debug_info.encode(&mut encoder);
+    }
+    encoder.finish()
+}
+
+/// Decode a program file back into a list of instructions.
+pub fn decode_program(bytes: &[u8]) -> Result<Vec<Instruction>, String> {
+    Ok(decode_program_with_debug_info(bytes)?.0)
+}
+
+/// Decode a program file back into a list of instructions, and its debug info if it has any.
+pub fn decode_program_with_debug_info(bytes: &[u8]) -> Result<(Vec<Instruction>, Option<DebugInfo>), String> {
+    let mut decoder = Decoder::new(bytes);
+    let version = decoder.header(MAGIC, VERSION)?;
+    let instructions = decoder.instructions()?;
+    let debug_info = if version >= 2 && decoder.bool()? {
+        Some(DebugInfo::decode(&mut decoder)?)
+    } else {
+        None
+    };
+    if !decoder.is_finished() {
+        log_and_return_err!("Trailing data after program");
+    }
+    Ok((instructions, debug_info))
+}
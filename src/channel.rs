@@ -0,0 +1,105 @@
+//! ConcordeVM's channels between VMs.
+//!
+//! A channel connects two CPUs in the same process, so a supervisor and its workers can talk
+//! without going through the OS. The host makes a pair of ends and connects one to each CPU under
+//! a name, and the guest opens it as the stream "channel://name". Either end can also be kept by
+//! the host, which reads and writes it like any other `Read + Write`.
+//!
+//! Every write to an end arrives at the other as one message, in order, and a read never spans
+//! two messages. Reads wait until the other end writes something, so ends being read on the same
+//! thread must already hold a message. Once the other end is closed or dropped, reads see the end
+//! of the stream and writes fail. Ends are `Send`, so CPUs on different threads, like those of a
+//! `SharedCpu` or a `VmPool`, can be connected.
+
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// One end of a channel.
+pub struct ChannelEnd {
+    reader: ChannelReader,
+    writer: ChannelWriter,
+}
+
+impl ChannelEnd {
+    /// Make a channel, returning its two ends.
+    pub fn pair() -> (ChannelEnd, ChannelEnd) {
+        let (to_second, from_first) = channel();
+        let (to_first, from_second) = channel();
+        let first = ChannelEnd {
+            reader: ChannelReader { messages: from_second, pending: Vec::new(), read: 0 },
+            writer: ChannelWriter { messages: to_second },
+        };
+        let second = ChannelEnd {
+            reader: ChannelReader { messages: from_first, pending: Vec::new(), read: 0 },
+            writer: ChannelWriter { messages: to_first },
+        };
+        return (first, second);
+    }
+
+    // Split into halves for the stream that opens this end.
+    pub(crate) fn split(self) -> (ChannelReader, ChannelWriter) {
+        return (self.reader, self.writer);
+    }
+}
+
+impl Read for ChannelEnd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        return self.reader.read(buf);
+    }
+}
+
+impl Write for ChannelEnd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return self.writer.write(buf);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.writer.flush();
+    }
+}
+
+// The half of an end that receives messages, keeping what's left of a partly read one.
+pub(crate) struct ChannelReader {
+    messages: Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+    read: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read == self.pending.len() {
+            match self.messages.recv() {
+                Ok(message) => self.pending = message,
+                // The other end is gone, so this is the end of the stream.
+                Err(_) => return Ok(0),
+            }
+            self.read = 0;
+        }
+        let n = buf.len().min(self.pending.len() - self.read);
+        buf[..n].copy_from_slice(&self.pending[self.read..self.read + n]);
+        self.read += n;
+        return Ok(n);
+    }
+}
+
+// The half of an end that sends messages.
+pub(crate) struct ChannelWriter {
+    messages: Sender<Vec<u8>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty message would read as the end of the stream.
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.messages.send(buf.to_vec()).is_err() {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "the other end of the channel is closed"));
+        }
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
@@ -0,0 +1,75 @@
+//! ConcordeVM's clocks.
+//!
+//! Guest programs read the time through a `Clock` chosen by the embedder, so timestamps and
+//! timeouts can come from the host, or from a `VirtualClock` that only moves when it's told to,
+//! for runs that must behave the same every time.
+
+use std::cell::Cell;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// A source of the time, for the NowUnixMillis and MonotonicNanos instructions.
+pub trait Clock {
+    /// Milliseconds since the Unix epoch.
+    fn unix_millis(&self) -> i64;
+    /// Nanoseconds since some fixed point, which never go backwards.
+    fn monotonic_nanos(&self) -> i64;
+}
+
+/// The host's clocks. Monotonic time counts from when the clock was made.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn unix_millis(&self) -> i64 {
+        // A host clock set before the epoch reads as negative.
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_millis() as i64,
+            Err(e) => -(e.duration().as_millis() as i64),
+        }
+    }
+
+    fn monotonic_nanos(&self) -> i64 {
+        return self.start.elapsed().as_nanos() as i64;
+    }
+}
+
+/// A clock that stands still until it's advanced, starting at a given Unix time.
+pub struct VirtualClock {
+    start_millis: i64,
+    elapsed_nanos: Cell<i64>,
+}
+
+impl VirtualClock {
+    /// Make a clock reading `unix_millis`, with monotonic time at 0.
+    pub fn new(unix_millis: i64) -> VirtualClock {
+        VirtualClock { start_millis: unix_millis, elapsed_nanos: Cell::new(0) }
+    }
+
+    /// Move both of the clock's times forward by `nanos`.
+    pub fn advance(&self, nanos: u64) {
+        self.elapsed_nanos.set(self.elapsed_nanos.get().saturating_add(i64::try_from(nanos).unwrap_or(i64::MAX)));
+    }
+}
+
+impl Clock for VirtualClock {
+    fn unix_millis(&self) -> i64 {
+        return self.start_millis + self.elapsed_nanos.get() / 1_000_000;
+    }
+
+    fn monotonic_nanos(&self) -> i64 {
+        return self.elapsed_nanos.get();
+    }
+}
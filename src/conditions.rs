@@ -0,0 +1,241 @@
+//! ConcordeVM's breakpoint conditions.
+//!
+//! A tiny expression language over memory, so a breakpoint can wait for a rare state instead of
+//! stopping every time it's reached. A condition like `counter > 100 && flag == true` compares
+//! values with `==`, `!=`, `<`, `<=`, `>` and `>=`, and combines them with `&&`, `||`, `!` and
+//! parentheses. Values are integer literals, `true` and `false`, symbols named with
+//! `Memory::bind`, and `@address` for the i64 at an address. Named symbols of one byte are bools,
+//! and of eight bytes are i64s.
+//!
+//! Conditions are parsed once, when the breakpoint is added, and evaluated against memory each
+//! time it's reached. Comparing values of different types, or reading a symbol that doesn't
+//! exist, is an error when evaluated.
+
+use crate::log_and_return_err;
+use crate::memory::Memory;
+
+use log::error;
+
+/// A parsed condition.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    expression: Expression,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expression {
+    Int(i64),
+    Bool(bool),
+    Symbol(String),
+    Address(usize),
+    Not(Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Compare(Box<Expression>, Comparison, Box<Expression>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Value {
+    Int(i64),
+    Bool(bool),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Int(i64),
+    Name(String),
+    Address(usize),
+    Operator(&'static str),
+}
+
+const OPERATORS: [&str; 11] = ["&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")"];
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Condition, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens: &tokens, position: 0 };
+        let expression = parser.or()?;
+        if let Some(token) = tokens.get(parser.position) {
+            log_and_return_err!("Unexpected {:?} in condition {}", token, source);
+        }
+        return Ok(Condition { expression });
+    }
+
+    /// Whether the condition holds for `memory`.
+    pub fn evaluate(&self, memory: &Memory) -> Result<bool, String> {
+        match evaluate(&self.expression, memory)? {
+            Value::Bool(value) => return Ok(value),
+            Value::Int(_) => log_and_return_err!("Condition is an integer, not a bool"),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while !rest.is_empty() {
+        if let Some(operator) = OPERATORS.iter().find(|operator| rest.starts_with(**operator)) {
+            tokens.push(Token::Operator(operator));
+            rest = &rest[operator.len()..];
+        } else {
+            let end = rest.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':' || c == '@' || c == '-')).unwrap_or(rest.len());
+            if end == 0 {
+                log_and_return_err!("Unexpected character {:?} in condition {}", rest.chars().next().unwrap(), source);
+            }
+            let word = &rest[..end];
+            let token = if let Some(address) = word.strip_prefix('@') {
+                match address.parse() {
+                    Ok(address) => Token::Address(address),
+                    Err(_) => log_and_return_err!("Invalid address {} in condition {}", word, source),
+                }
+            } else if word.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+                match word.parse() {
+                    Ok(value) => Token::Int(value),
+                    Err(_) => log_and_return_err!("Invalid integer {} in condition {}", word, source),
+                }
+            } else {
+                Token::Name(word.to_string())
+            };
+            tokens.push(token);
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    return Ok(tokens);
+}
+
+// A recursive descent parser, from the loosest binding operator to the tightest.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn eat(&mut self, operator: &str) -> bool {
+        if matches!(self.tokens.get(self.position), Some(Token::Operator(found)) if *found == operator) {
+            self.position += 1;
+            return true;
+        }
+        return false;
+    }
+
+    fn or(&mut self) -> Result<Expression, String> {
+        let mut left = self.and()?;
+        while self.eat("||") {
+            left = Expression::Or(Box::new(left), Box::new(self.and()?));
+        }
+        return Ok(left);
+    }
+
+    fn and(&mut self) -> Result<Expression, String> {
+        let mut left = self.compare()?;
+        while self.eat("&&") {
+            left = Expression::And(Box::new(left), Box::new(self.compare()?));
+        }
+        return Ok(left);
+    }
+
+    fn compare(&mut self) -> Result<Expression, String> {
+        let left = self.operand()?;
+        let comparisons = [
+            ("==", Comparison::Equal),
+            ("!=", Comparison::NotEqual),
+            ("<=", Comparison::LessOrEqual),
+            (">=", Comparison::GreaterOrEqual),
+            ("<", Comparison::Less),
+            (">", Comparison::Greater),
+        ];
+        for (operator, comparison) in comparisons {
+            if self.eat(operator) {
+                return Ok(Expression::Compare(Box::new(left), comparison, Box::new(self.operand()?)));
+            }
+        }
+        return Ok(left);
+    }
+
+    fn operand(&mut self) -> Result<Expression, String> {
+        if self.eat("!") {
+            return Ok(Expression::Not(Box::new(self.operand()?)));
+        }
+        if self.eat("(") {
+            let inner = self.or()?;
+            if !self.eat(")") {
+                log_and_return_err!("Missing ) in condition");
+            }
+            return Ok(inner);
+        }
+        let expression = match self.tokens.get(self.position) {
+            Some(Token::Int(value)) => Expression::Int(*value),
+            Some(Token::Address(address)) => Expression::Address(*address),
+            Some(Token::Name(name)) if name == "true" => Expression::Bool(true),
+            Some(Token::Name(name)) if name == "false" => Expression::Bool(false),
+            Some(Token::Name(name)) => Expression::Symbol(name.clone()),
+            Some(token) => log_and_return_err!("Expected a value in condition, found {:?}", token),
+            None => log_and_return_err!("Condition ended where a value was expected"),
+        };
+        self.position += 1;
+        return Ok(expression);
+    }
+}
+
+fn evaluate(expression: &Expression, memory: &Memory) -> Result<Value, String> {
+    let value = match expression {
+        Expression::Int(value) => Value::Int(*value),
+        Expression::Bool(value) => Value::Bool(*value),
+        Expression::Symbol(name) => {
+            let Some((address, n)) = memory.lookup(name) else {
+                log_and_return_err!("No symbol named {} in condition", name);
+            };
+            if !matches!(address.checked_add(n), Some(end) if end <= memory.len()) {
+                log_and_return_err!("Symbol {} is outside memory", name);
+            }
+            match n {
+                1 => Value::Bool(memory.read_typed::<bool>(address)),
+                8 => Value::Int(memory.read_typed::<i64>(address)),
+                _ => log_and_return_err!("Symbol {} is {} bytes, so it's neither a bool nor an i64", name, n),
+            }
+        },
+        Expression::Address(address) => {
+            if !matches!(address.checked_add(8), Some(end) if end <= memory.len()) {
+                log_and_return_err!("Address {} in condition is outside memory", address);
+            }
+            Value::Int(memory.read_typed::<i64>(*address))
+        },
+        Expression::Not(inner) => Value::Bool(!boolean(inner, memory)?),
+        Expression::And(left, right) => Value::Bool(boolean(left, memory)? && boolean(right, memory)?),
+        Expression::Or(left, right) => Value::Bool(boolean(left, memory)? || boolean(right, memory)?),
+        Expression::Compare(left, comparison, right) => {
+            let ordering = match (evaluate(left, memory)?, evaluate(right, memory)?) {
+                (Value::Int(a), Value::Int(b)) => a.cmp(&b),
+                (Value::Bool(a), Value::Bool(b)) => a.cmp(&b),
+                (a, b) => log_and_return_err!("Can't compare {:?} with {:?} in condition", a, b),
+            };
+            Value::Bool(match comparison {
+                Comparison::Equal => ordering.is_eq(),
+                Comparison::NotEqual => ordering.is_ne(),
+                Comparison::Less => ordering.is_lt(),
+                Comparison::LessOrEqual => ordering.is_le(),
+                Comparison::Greater => ordering.is_gt(),
+                Comparison::GreaterOrEqual => ordering.is_ge(),
+            })
+        },
+    };
+    return Ok(value);
+}
+
+fn boolean(expression: &Expression, memory: &Memory) -> Result<bool, String> {
+    match evaluate(expression, memory)? {
+        Value::Bool(value) => return Ok(value),
+        Value::Int(_) => log_and_return_err!("Expected a bool in condition, found an integer"),
+    }
+}
@@ -0,0 +1,96 @@
+//! ConcordeVM's coverage tracking.
+//!
+//! Counts how many times each instruction of a program runs, so the authors of Concorde programs,
+//! and of the compilers that generate them, can see which code their tests reach. Share one
+//! `Coverage` between every CPU running the program, and read it once they're done, either as a
+//! text summary or as an lcov tracefile for the usual coverage tools.
+//!
+//! Instructions fused into a superinstruction are each counted.
+
+use crate::cpu::Program;
+
+use std::collections::BTreeMap;
+
+/// How many times each instruction has run, by index.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Coverage {
+    hits: Vec<u64>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    pub(crate) fn record(&mut self, index: usize) {
+        if index >= self.hits.len() {
+            self.hits.resize(index + 1, 0);
+        }
+        self.hits[index] += 1;
+    }
+
+    /// How many times the instruction at `index` has run.
+    pub fn hits(&self, index: usize) -> u64 {
+        return self.hits.get(index).copied().unwrap_or(0);
+    }
+
+    /// How many of the first `n` instructions have run at least once.
+    pub fn covered(&self, n: usize) -> usize {
+        return self.hits.iter().take(n).filter(|hits| **hits > 0).count();
+    }
+
+    /// Summarize coverage of `program`: the share of instructions covered, then the same for each
+    /// of its blocks, if it was linked, then every instruction that never ran.
+    pub fn report(&self, program: &Program) -> String {
+        let n = program.instructions.len();
+        let mut lines = vec![format!("Covered {} of {} instructions ({})", self.covered(n), n, percentage(self.covered(n), n))];
+        let mut blocks: Vec<(&String, _)> = program.blocks.iter().collect();
+        blocks.sort_by_key(|(_, range)| range.start);
+        for (name, range) in blocks {
+            let covered = range.clone().filter(|index| self.hits(*index) > 0).count();
+            lines.push(format!("  {}: {} of {} ({})", name, covered, range.len(), percentage(covered, range.len())));
+        }
+        let missed: Vec<usize> = (0..n).filter(|index| self.hits(*index) == 0).collect();
+        if !missed.is_empty() {
+            lines.push(String::from("Not covered:"));
+            lines.extend(missed.into_iter().map(|index| format!("  {}", program.describe_location(index))));
+        }
+        return lines.join("\n") + "\n";
+    }
+
+    /// Write coverage of `program` as an lcov tracefile. Instructions are placed at their source
+    /// lines if the program has debug info, and a line counts as run as many times as its
+    /// least-run instruction. Instructions without a location are put in a file named `program`,
+    /// one per line.
+    pub fn to_lcov(&self, program: &Program) -> String {
+        let mut files: BTreeMap<&str, BTreeMap<u32, u64>> = BTreeMap::new();
+        for index in 0..program.instructions.len() {
+            let location = program.debug_info.as_ref().and_then(|debug_info| debug_info.location(index));
+            let (file, line) = match location {
+                Some(location) => (location.file.as_str(), location.line),
+                None => ("program", index as u32 + 1),
+            };
+            let hits = files.entry(file).or_default().entry(line).or_insert(u64::MAX);
+            *hits = (*hits).min(self.hits(index));
+        }
+
+        let mut lcov = String::new();
+        for (file, lines) in files {
+            lcov.push_str(&format!("SF:{}\n", file));
+            for (line, hits) in &lines {
+                lcov.push_str(&format!("DA:{},{}\n", line, hits));
+            }
+            lcov.push_str(&format!("LF:{}\n", lines.len()));
+            lcov.push_str(&format!("LH:{}\n", lines.values().filter(|hits| **hits > 0).count()));
+            lcov.push_str("end_of_record\n");
+        }
+        return lcov;
+    }
+}
+
+fn percentage(covered: usize, n: usize) -> String {
+    if n == 0 {
+        return String::from("100.0%");
+    }
+    return format!("{:.1}%", covered as f64 * 100.0 / n as f64);
+}
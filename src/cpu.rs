@@ -5,19 +5,68 @@
 //!
 //! Instructions are stored as `Vec<Instruction>`s along with a PC
 
-use crate::{instructions::execute_instruction, instructions::Interrupt, io::ConcordeIO};
+use crate::{instructions::execute_instruction, instructions::{ArithmeticMode, DispatchTable, Interrupt}, io::{CapturedOutput, ConcordeIO, Environment}};
+use std::cell::RefCell;
 use std::rc::Rc;
 use crate::memory::*;
+use crate::channel::ChannelEnd;
+use crate::clock::Clock;
+use crate::conditions::Condition;
+use crate::coverage::Coverage;
+use crate::events::{Event, EventBus};
+use crate::execution_log::{self, ExecutionLog, TraceStep};
+use crate::interrupt_controller::{InterruptController, InterruptLine};
+use crate::metrics::Metrics;
+use crate::recording::IoRecorder;
+use crate::sandbox::{PermissionDenied, SandboxPolicy};
+use crate::snapshot::{CoreDump, CpuSnapshot};
+use crate::validation;
+use crate::debug_info::DebugInfo;
+use crate::verifier;
+use crate::fusion::{self, Fused};
+use crate::optimizer;
+use crate::linker::{self, Block, Module};
+use crate::stdlib::stdlib;
+use crate::log_and_return_err;
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::fmt;
+use std::time::{Duration, Instant};
 
 use concordeisa::instructions::{self, Instruction};
 
-use log::info;
+use log::{error, info};
 use std::vec::Vec;
 
+/// What `CPU::replace_block` does with execution already inside the block it replaces.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReloadPolicy {
+    /// Let it finish in the old block. Only jumps made after the reload run the new one.
+    FinishOld,
+    /// Start the new block over, abandoning any list loops started in the old one.
+    Restart,
+}
+
 #[derive(Clone)]
 pub struct Program{
     pub instructions: Rc<Vec<Instruction>>,
-    pub pc: usize
+    pub pc: usize,
+    /// Where each instruction came from in its source, if known.
+    pub debug_info: Option<Rc<DebugInfo>>,
+    // Superinstructions to run in place of the instruction pairs starting at each index, if fused.
+    pub(crate) fused: Option<Rc<Vec<Option<Fused>>>>,
+    /// How integer arithmetic handles overflow. Forks of this program use the same mode.
+    pub arithmetic: ArithmeticMode,
+    /// Whether Assert and AssertType are checked, rather than skipped. On by default; turn it off
+    /// for release runs. Forks of this program do the same.
+    pub assertions: bool,
+    // Loops part way through their lists, innermost last.
+    pub(crate) loops: Vec<ListLoop>,
+    // The instructions each named block occupies, if the program was linked.
+    pub(crate) blocks: Rc<HashMap<String, Range<usize>>>,
+    // Where to carry on once the running interrupt handler returns, if one is running.
+    pub(crate) interrupted: Option<usize>,
 }
 
 impl Default for Program {
@@ -29,11 +78,85 @@ impl Default for Program {
 impl Program {
     /// Create a new empty `ExecutionStack`.
     pub fn new(instructions: Vec<Instruction>) -> Program {
-        return Program { instructions: Rc::new(instructions), pc: 0 };
+        return Program {
+            instructions: Rc::new(instructions),
+            pc: 0,
+            debug_info: None,
+            fused: None,
+            arithmetic: ArithmeticMode::default(),
+            assertions: true,
+            loops: Vec::new(),
+            blocks: Rc::new(HashMap::new()),
+            interrupted: None,
+        };
     }
 
     pub fn fork_to_pc(&self, pc: usize) -> Program {
-        return Program { instructions: Rc::clone(&self.instructions), pc: pc }
+        return Program {
+            instructions: Rc::clone(&self.instructions),
+            pc: pc,
+            debug_info: self.debug_info.clone(),
+            fused: self.fused.clone(),
+            arithmetic: self.arithmetic,
+            assertions: self.assertions,
+            loops: Vec::new(),
+            blocks: Rc::clone(&self.blocks),
+            interrupted: None,
+        }
+    }
+
+    /// Fuse common pairs of instructions into superinstructions, which run faster but have the
+    /// same effect. Coroutines forked from this program share the fused instructions.
+    ///
+    /// Fusion is not kept in snapshots, and instructions added by Import are not fused.
+    pub fn fuse_superinstructions(&mut self) {
+        self.fused = Some(Rc::new(fusion::fuse(&self.instructions)));
+    }
+
+    /// Optimize the instructions for a program entered at the current pc, without changing what
+    /// it does. Instructions are replaced with `NoOp`s rather than removed, so indices stay valid.
+    pub fn optimize(&mut self) {
+        optimizer::optimize(Rc::make_mut(&mut self.instructions).as_mut_slice(), self.pc);
+        if self.fused.is_some() {
+            self.fuse_superinstructions();
+        }
+    }
+
+    /// The instructions the block named `name` occupies, if the program was linked with it.
+    pub fn block(&self, name: &str) -> Option<Range<usize>> {
+        return self.blocks.get(name).cloned();
+    }
+
+    /// The names of the blocks the program was linked with, in the order they were laid out.
+    pub fn block_names(&self) -> Vec<String> {
+        let mut blocks: Vec<(&String, &Range<usize>)> = self.blocks.iter().collect();
+        blocks.sort_by_key(|(_, range)| range.start);
+        return blocks.into_iter().map(|(name, _)| name.clone()).collect();
+    }
+
+    // Name the blocks of `module`, just linked to start at `starts`.
+    pub(crate) fn record_blocks(&mut self, module: &Module, starts: &[usize]) {
+        let blocks = Rc::make_mut(&mut self.blocks);
+        for (block, start) in module.blocks.iter().zip(starts) {
+            blocks.insert(block.name.clone(), *start..*start + block.instructions.len());
+        }
+    }
+
+    /// Describe an instruction by its index, and where it came from in the source if known.
+    pub fn describe_location(&self, index: usize) -> String {
+        match self.debug_info.as_ref().and_then(|debug_info| debug_info.describe(index)) {
+            Some(source) => format!("instruction {} ({})", index, source),
+            None => format!("instruction {}", index),
+        }
+    }
+
+    /// The name of the code an instruction belongs to: its name in the debug info if it has one,
+    /// or else the linked block it's in.
+    pub fn symbol_at(&self, index: usize) -> Option<String> {
+        if let Some(name) = self.debug_info.as_ref().and_then(|debug_info| debug_info.name(index)) {
+            return Some(name.to_string());
+        }
+        return self.blocks.iter().find(|(_, range)| range.contains(&index)).map(|(name, _)| name.clone());
     }
 
     pub fn get_instruction(&self) -> &Instruction{
@@ -48,6 +171,8 @@ impl Program {
     /// Jump execution to a given symbol. Will not error, even if the symbol is undefined.
     pub fn jump(&mut self, target: usize) {
         info!("Jumped to {}!", target);
+        #[cfg(feature = "tracing")]
+        tracing::trace!(to = target, "jump");
         self.pc = target;
     }
 
@@ -56,6 +181,71 @@ impl Program {
     }
 }
 
+/// What a loop does with the value its body returns for each item.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LoopKind {
+    /// ForEach: nothing.
+    Each,
+    /// MapList: puts it in the list at the address.
+    Map(usize),
+    /// FilterList: keeps the item in the list at the address if it's true.
+    Filter(usize),
+    /// ReduceList: replaces the accumulator at the address with it.
+    Reduce(usize),
+}
+
+/// A loop over a list, part way through. While it runs, a Return ends the current iteration
+/// rather than the coroutine.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ListLoop {
+    pub(crate) kind: LoopKind,
+    pub(crate) list: usize,
+    pub(crate) item: usize,
+    pub(crate) body: usize,
+    /// Index of the next item to run the body for.
+    pub(crate) next: usize,
+    /// How many items the list had when the loop started.
+    pub(crate) count: usize,
+    /// How many items a FilterList has kept so far.
+    pub(crate) kept: usize,
+    /// Where to carry on once every item is done.
+    pub(crate) resume: usize,
+}
+
+/// An error raised by an instruction. The CPU keeps it, with the pc still at the instruction,
+/// until the embedder resumes execution.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fault {
+    pub pc: usize,
+    pub message: String,
+    /// What the sandbox refused, if that's why the instruction failed.
+    pub denied: Option<PermissionDenied>,
+}
+
+/// Error returned when a call to `CPU::run` goes on for longer than its watchdog allows.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timeout {
+    /// How many instructions the run had executed.
+    pub instructions: u64,
+    /// How long the run had taken.
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Timeout: the watchdog stopped the program after {} instructions and {:?}", self.instructions, self.elapsed)
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+// The most time and instructions a single call to `CPU::run` may take.
+#[derive(Clone, Copy, Default)]
+struct Watchdog {
+    max_time: Option<Duration>,
+    max_instructions: Option<u64>,
+}
+
 /// The `CPU` is where instruction reading and execution is handled.
 ///
 /// Contains `Memory`, as well as an `Program`. These are used to read and execute
@@ -65,15 +255,48 @@ pub struct CPU {
     pub memory: Memory,
     io: ConcordeIO,
     pub program: Program,
+    dispatch: Rc<DispatchTable>,
+    fault: Option<Fault>,
+    timed_out: Option<Timeout>,
+    execution_log: Option<Rc<RefCell<ExecutionLog>>>,
+    coverage: Option<Rc<RefCell<Coverage>>>,
+    // Only the instruction, cycle, and error counts are kept up to date.
+    counters: Metrics,
+    events: Rc<RefCell<EventBus>>,
+    core_dumps: Option<CoreDumps>,
+    watchdog: Watchdog,
+    interrupts: InterruptController,
+    // Breakpoints by pc, with the condition each waits for, if any.
+    breakpoints: HashMap<usize, Option<Condition>>,
+}
+
+// Where to write a core dump if an instruction fails, and the pcs of the latest cycles to put in
+// it.
+struct CoreDumps {
+    path: PathBuf,
+    trace: VecDeque<usize>,
+    trace_length: usize,
 }
 
 impl CPU {
-    /// Create a new `CPU`. Initializes both the memory and stack to be empty.
+    /// Create a new `CPU`. Initializes both the memory and stack to be empty. Use a `CpuBuilder`
+    /// to configure one while creating it.
     pub fn new(memory_size: usize) -> CPU {
         CPU {
             memory: Memory::new(memory_size),
             io: ConcordeIO::new(),
             program: Program::default(),
+            dispatch: DispatchTable::standard(),
+            fault: None,
+            timed_out: None,
+            execution_log: None,
+            coverage: None,
+            counters: Metrics::default(),
+            events: Rc::new(RefCell::new(EventBus::default())),
+            core_dumps: None,
+            watchdog: Watchdog::default(),
+            interrupts: InterruptController::default(),
+            breakpoints: HashMap::new(),
         }
     }
 
@@ -85,42 +308,489 @@ impl CPU {
         CPU {
             memory: Memory::new(memory_size),
             io: ConcordeIO::new(),
-            program: program
+            program: program,
+            dispatch: DispatchTable::standard(),
+            fault: None,
+            timed_out: None,
+            execution_log: None,
+            coverage: None,
+            counters: Metrics::default(),
+            events: Rc::new(RefCell::new(EventBus::default())),
+            core_dumps: None,
+            watchdog: Watchdog::default(),
+            interrupts: InterruptController::default(),
+            breakpoints: HashMap::new(),
         }
     }
 
-    pub fn get_memory_mut(&mut self) -> &mut Memory {
+    /// Restrict the IO this CPU's program can perform.
+    pub fn set_sandbox_policy(&mut self, policy: Rc<SandboxPolicy>) {
+        self.io.set_policy(policy);
+    }
+
+    /// Share an environment with this CPU's program.
+    pub fn set_environment(&mut self, environment: Rc<RefCell<Environment>>) {
+        self.io.set_environment(environment);
+    }
+
+    /// Share an IO recorder with this CPU's program.
+    pub fn set_io_recorder(&mut self, recorder: Rc<RefCell<IoRecorder>>) {
+        self.io.set_recorder(recorder);
+    }
+
+    /// Give this CPU's program the time from `clock`.
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.io.set_clock(clock);
+    }
+
+    /// Keep what this CPU's program writes to stdout and stderr in memory instead of passing it
+    /// to the host's. Returns a handle the host can read the output from after running.
+    pub fn capture_output(&mut self) -> CapturedOutput {
+        let capture = CapturedOutput::new();
+        self.set_output_capture(capture.clone());
+        return capture;
+    }
+
+    /// Write this CPU's program's stdout and stderr into a capture shared with others.
+    pub fn set_output_capture(&mut self, capture: CapturedOutput) {
+        self.io.set_capture(capture);
+    }
+
+    /// Connect `end` to this CPU, so its program can open it as the stream "channel://`name`".
+    pub fn connect_channel(&mut self, name: &str, end: ChannelEnd) {
+        self.io.connect_channel(name, end);
+    }
+
+    /// Let this CPU's program open the channel ends connected to others.
+    pub fn set_channels(&mut self, channels: Rc<RefCell<HashMap<String, ChannelEnd>>>) {
+        self.io.set_channels(channels);
+    }
+
+    /// Make the random numbers this CPU's program gets the same every run. CPUs given one seed
+    /// but different streams get independent numbers.
+    pub fn seed_rng(&mut self, seed: u64, stream: u64) {
+        self.io.seed_rng(seed, stream);
+    }
+
+    /// Log every instruction this CPU executes to `log`, which may be shared with others.
+    pub fn set_execution_log(&mut self, log: Rc<RefCell<ExecutionLog>>) {
+        self.execution_log = Some(log);
+    }
+
+    /// Count every instruction this CPU executes in `coverage`, which may be shared with others.
+    pub fn set_coverage(&mut self, coverage: Rc<RefCell<Coverage>>) {
+        self.coverage = Some(coverage);
+    }
+
+    /// Call `listener` with every event from this CPU's program from now on.
+    pub fn subscribe(&mut self, listener: impl FnMut(&Event) + 'static) {
+        self.events.borrow_mut().subscribe(Box::new(listener));
+    }
+
+    // Send events to listeners shared with other CPUs, in place of this CPU's own.
+    pub(crate) fn set_event_bus(&mut self, events: Rc<RefCell<EventBus>>) {
+        self.events = events;
+    }
+
+    fn publish(&self, event: impl FnOnce() -> Event) {
+        if self.events.borrow().has_listeners() {
+            let event = event();
+            self.events.borrow_mut().publish(&event);
+        }
+    }
+
+    // Tell listeners about the instruction at `pc`, which has just run without error.
+    fn publish_instruction_events(&self, instructions: &[Instruction], pc: usize, interrupt: &Interrupt) {
+        if !self.events.borrow().has_listeners() {
+            return;
+        }
+        // A fused pair is one cycle, but only its second instruction can jump.
+        let fused = self.program.fused.as_ref().is_some_and(|fused| matches!(fused.get(pc), Some(Some(_))));
+        let at = if fused { pc + 1 } else { pc };
+        let event = match (&instructions[at], interrupt) {
+            (Instruction::Jump(_) | Instruction::JumpIfTrue(..) | Instruction::JumpIfFalse(..) | Instruction::Switch(..), _)
+                if self.program.pc != at + 1 => Event::Jump { from: at, to: self.program.pc },
+            (_, Interrupt::Ret(address, n)) => Event::Return { pc, address: *address, n: *n },
+            (Instruction::OpenStream(path, symbol, _), _) => Event::StreamOpened { symbol: *symbol, name: self.memory.read_string(*path) },
+            _ => return,
+        };
+        self.events.borrow_mut().publish(&event);
+    }
+
+    /// Write a `CoreDump` to `path` whenever an instruction fails, holding the pcs of the last
+    /// `trace_length` cycles.
+    pub fn enable_core_dumps(&mut self, path: impl Into<PathBuf>, trace_length: usize) {
+        self.core_dumps = Some(CoreDumps { path: path.into(), trace: VecDeque::new(), trace_length });
+    }
+
+    // Write a core dump for the error `e`, if they're enabled. Failing to write one is logged
+    // rather than returned, so it doesn't hide the error itself.
+    fn dump_core(&self, e: &str) {
+        let Some(core_dumps) = &self.core_dumps else {
+            return;
+        };
+        let dump = CoreDump { snapshot: self.snapshot(), trace: core_dumps.trace.iter().copied().collect(), error: e.to_string() };
+        if let Err(write_error) = std::fs::write(&core_dumps.path, dump.to_bytes()) {
+            error!("Could not write core dump {}: {}", core_dumps.path.display(), write_error);
+        }
+    }
+
+    /// Run instructions with the handlers in `dispatch`.
+    pub fn set_dispatch_table(&mut self, dispatch: Rc<DispatchTable>) {
+        self.dispatch = dispatch;
+    }
+
+    /// Borrow the memory.
+    pub fn memory(&self) -> &Memory {
+        return &self.memory;
+    }
+
+    /// Mutably borrow the memory.
+    pub fn memory_mut(&mut self) -> &mut Memory {
         return &mut self.memory;
     }
 
+    /// Write `value` to the named symbol `name`, so the program can read it. A name that isn't
+    /// bound yet is bound to new memory at the end, sized for the value; one that is must be the
+    /// same size as the value.
+    pub fn set_global<T: ByteSerialisable>(&mut self, name: &str, value: &T) -> Result<(), String> {
+        let address = match self.memory.lookup(name) {
+            Some((address, n)) if n == value.get_size() => address,
+            Some((_, n)) => log_and_return_err!("Global {} holds {} bytes, but the value takes {}", name, n, value.get_size()),
+            None => {
+                let address = self.memory.len();
+                self.memory.extend_memory(value.get_size())?;
+                self.memory.bind(name, address, value.get_size());
+                address
+            },
+        };
+        self.memory.write(address, value);
+        return Ok(());
+    }
+
+    /// Read the named symbol `name` as a `T`.
+    pub fn get_global<T: ByteParseable>(&self, name: &str) -> Result<T, String> {
+        let Some((address, n)) = self.memory.lookup(name) else {
+            log_and_return_err!("No global named {}", name);
+        };
+        return parse_sized(self.memory.get_slice(address, n), &format!("Global {} holds", name));
+    }
+
+    /// Run the block named `entry` with `args` written one after another from address 0, where a
+    /// coroutine's arguments would be, and return what it returns as a `T`. The block runs on a
+    /// fork of memory, which is thrown away once it returns, and the pc is put back, so the loaded
+    /// program carries on as it was. Blocks that need a scheduler, like ones that await a future,
+    /// can't be called.
+    pub fn call<T: ByteParseable>(&mut self, entry: &str, args: &[&dyn ByteSerialisable]) -> Result<T, String> {
+        let Some(block) = self.program.block(entry) else {
+            log_and_return_err!("No block named {} is loaded", entry);
+        };
+        let fork = self.memory.fork();
+        let memory = std::mem::replace(&mut self.memory, fork);
+        let pc = self.program.pc;
+        let result = self.call_block(entry, block.start, args);
+        self.memory = memory;
+        self.program.jump(pc);
+        return result;
+    }
+
+    // Write `args` from address 0 and run the block at `start` until it returns.
+    fn call_block<T: ByteParseable>(&mut self, entry: &str, start: usize, args: &[&dyn ByteSerialisable]) -> Result<T, String> {
+        let mut address = 0;
+        for arg in args {
+            self.memory.extend_memory_to(address + arg.get_size())?;
+            self.memory.write(address, *arg);
+            address += arg.get_size();
+        }
+        self.program.jump(start);
+        return match self.run()? {
+            Interrupt::Ret(address, n) => parse_sized(self.memory.get_slice(address, n), &format!("{} returned", entry)),
+            Interrupt::Ok | Interrupt::EOF => log_and_return_err!("{} finished without returning", entry),
+            _ => log_and_return_err!("{} ran an instruction that needs a scheduler", entry),
+        };
+    }
+
     pub fn load_program(&mut self, program: Program) {
+        self.fault = None;
         self.program = program;
+        self.publish(|| Event::ProgramLoaded { instructions: self.program.instructions.len(), entrypoint: self.program.pc });
     }
 
-    // Runs until an interrupt is triggered
+    // Runs until an interrupt is triggered.
+    // Open streams are flushed if the program finishes, returns, or errors.
     pub fn run(&mut self) -> Result<Interrupt, String> {
+        return self.run_with_fuel(None).map(|interrupt| interrupt.unwrap());
+    }
+
+    /// Run like `run`, but stop once `fuel` instructions have been executed, returning None if
+    /// the program hadn't finished or been interrupted by then. Running again carries on from
+    /// where it stopped.
+    pub fn run_for(&mut self, fuel: u64) -> Result<Option<Interrupt>, String> {
+        return self.run_with_fuel(Some(fuel));
+    }
+
+    /// Run the program through every step of `trace`, read from an execution log, checking that
+    /// each runs the same instruction at the same pc as it did, and fails with the same error, if
+    /// any. Fails at the first step that diverges, describing it. Replay IO from a recording too,
+    /// with `set_io_recorder`, if the program did any.
+    pub fn replay(&mut self, trace: &[TraceStep]) -> Result<(), String> {
+        for (index, expected) in trace.iter().enumerate() {
+            let pc = self.program.pc;
+            let Some(instruction) = self.program.instructions.get(pc) else {
+                log_and_return_err!("Replay diverged at step {}: the trace ran {}({}) at {}, but the program had ended", index + 1, expected.instruction, expected.operands, expected.pc);
+            };
+            let (name, operands) = execution_log::describe(instruction);
+            if pc != expected.pc || name != expected.instruction || operands != expected.operands {
+                log_and_return_err!("Replay diverged at step {}: the trace ran {}({}) at {}, but the program ran {}({}) at {}", index + 1, expected.instruction, expected.operands, expected.pc, name, operands, pc);
+            }
+            let error = self.cycle().err();
+            if error != expected.error {
+                let describe = |error: &Option<String>| error.as_ref().map_or(String::from("succeeded"), |e| format!("failed with {:?}", e));
+                log_and_return_err!("Replay diverged at step {}: {}({}) at {} {} in the trace, but {} in the replay", index + 1, name, operands, pc, describe(&expected.error), describe(&error));
+            }
+        }
+        return Ok(());
+    }
+
+    /// Stop `continue_to_breakpoint`, `step_over`, and `step_out` before running the instruction
+    /// at `pc`.
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc, None);
+    }
+
+    /// Like `add_breakpoint`, but only stop when `condition` holds, as described in
+    /// `Condition`. Fails if the condition doesn't parse.
+    pub fn add_conditional_breakpoint(&mut self, pc: usize, condition: &str) -> Result<(), String> {
+        self.breakpoints.insert(pc, Some(Condition::parse(condition)?));
+        return Ok(());
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.remove(&pc);
+    }
+
+    /// How many frames execution is inside: the bodies of list loops part way through their
+    /// lists, and the interrupt handler, if one is running.
+    pub fn frame_depth(&self) -> usize {
+        return self.program.loops.len() + self.program.interrupted.is_some() as usize;
+    }
+
+    /// Run until the pc reaches a breakpoint, returning None, or the program finishes or is
+    /// interrupted, returning the interrupt.
+    pub fn continue_to_breakpoint(&mut self) -> Result<Option<Interrupt>, String> {
+        return self.run_while(|_| true);
+    }
+
+    /// Run the next instruction, and if it enters a frame, like the body of a ForEach, the rest
+    /// of that frame too. Returns like `continue_to_breakpoint`, stopping early at breakpoints.
+    pub fn step_over(&mut self) -> Result<Option<Interrupt>, String> {
+        let depth = self.frame_depth();
+        return self.run_while(|cpu| cpu.frame_depth() > depth);
+    }
+
+    /// Run until the current frame is left. Outside any frame, this runs until the program
+    /// finishes. Returns like `continue_to_breakpoint`, stopping early at breakpoints.
+    pub fn step_out(&mut self) -> Result<Option<Interrupt>, String> {
+        let depth = self.frame_depth();
+        return self.run_while(|cpu| depth == 0 || cpu.frame_depth() >= depth);
+    }
+
+    // Run at least one instruction, then more until `keep_going` is false or a breakpoint is
+    // reached. Returns None if stopped for either, or the interrupt that ended the run.
+    fn run_while(&mut self, keep_going: impl Fn(&CPU) -> bool) -> Result<Option<Interrupt>, String> {
+        loop {
+            if self.program.pc >= self.program.instructions.len() {
+                return Ok(Some(Interrupt::Ok));
+            }
+            match self.cycle()? {
+                Interrupt::Ok if self.program.pc >= self.program.instructions.len() => return Ok(Some(Interrupt::Ok)),
+                Interrupt::Ok => {},
+                interrupt => return Ok(Some(interrupt)),
+            }
+            if self.at_breakpoint()? || !keep_going(self) {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn at_breakpoint(&self) -> Result<bool, String> {
+        return match self.breakpoints.get(&self.program.pc) {
+            None => Ok(false),
+            Some(None) => Ok(true),
+            Some(Some(condition)) => condition.evaluate(&self.memory)
+                .map_err(|e| format!("Breakpoint at {} failed: {}", self.program.describe_location(self.program.pc), e)),
+        };
+    }
+
+    fn run_with_fuel(&mut self, fuel: Option<u64>) -> Result<Option<Interrupt>, String> {
+        let result = self.run_until_interrupt(fuel);
+        match result {
+            Err(_) => {
+                let _ = self.io.flush_all();
+                let _ = self.flush_execution_log();
+            },
+            Ok(Some(Interrupt::Ok)) | Ok(Some(Interrupt::EOF)) | Ok(Some(Interrupt::Ret(_, _))) => {
+                self.io.flush_all()?;
+                self.flush_execution_log()?;
+            },
+            Ok(_) => {},
+        };
+        match &result {
+            Ok(Some(Interrupt::Ok)) | Ok(Some(Interrupt::EOF)) => self.publish(|| Event::Halted { pc: self.program.pc, error: None }),
+            Err(e) => self.publish(|| Event::Halted { pc: self.program.pc, error: Some(e.clone()) }),
+            Ok(_) => {},
+        }
+        return result;
+    }
+
+    fn flush_execution_log(&self) -> Result<(), String> {
+        match &self.execution_log {
+            Some(log) => log.borrow_mut().flush(),
+            None => Ok(()),
+        }
+    }
+
+    // Returns None if the fuel runs out first.
+    fn run_until_interrupt(&mut self, fuel: Option<u64>) -> Result<Option<Interrupt>, String> {
+        let start = Instant::now();
+        let start_instructions = self.counters.instructions;
+        self.timed_out = None;
         while self.program.pc < self.program.instructions.len() {
+            let instructions = self.counters.instructions - start_instructions;
+            if fuel.is_some_and(|fuel| instructions >= fuel) {
+                return Ok(None);
+            }
+            let out_of_instructions = self.watchdog.max_instructions.is_some_and(|max| instructions >= max);
+            let out_of_time = self.watchdog.max_time.is_some_and(|max| start.elapsed() >= max);
+            if out_of_instructions || out_of_time {
+                let timeout = Timeout { instructions, elapsed: start.elapsed() };
+                self.timed_out = Some(timeout.clone());
+                log_and_return_err!("{}", timeout);
+            }
             match self.cycle()? {
                 Interrupt::Ok => {},
-                Interrupt::EOF => {return Ok(Interrupt::EOF);},
-                interrupt @ _ => {return Ok(interrupt)}
+                Interrupt::EOF => {return Ok(Some(Interrupt::EOF));},
+                interrupt @ _ => {return Ok(Some(interrupt))}
             };
         };
-        return Ok(Interrupt::Ok);
+        return Ok(Some(Interrupt::Ok));
     }
 
     // Run a single FDE cycle
+    // If the instruction fails, the pc is left on it and the fault is kept, so the embedder can
+    // fix things up and resume. Running again retries the instruction.
     pub fn cycle(&mut self) -> Result<Interrupt, String> {
+        if self.program.interrupted.is_none() && let Some(handler) = self.interrupts.take() {
+            self.program.interrupted = Some(self.program.pc);
+            self.program.jump(handler);
+        }
         if self.program.pc < self.program.instructions.len() {
-            return execute_instruction(&mut self.memory, &mut self.io, &mut self.program);
+            let pc = self.program.pc;
+            #[cfg(feature = "tracing")]
+            let _span = tracing::trace_span!("cycle", pc).entered();
+            self.memory.set_audit_pc(Some(pc));
+            // The instruction may replace the program, so keep hold of the one it's in.
+            let instructions = Rc::clone(&self.program.instructions);
+            if let Some(core_dumps) = &mut self.core_dumps {
+                core_dumps.trace.push_back(pc);
+                if core_dumps.trace.len() > core_dumps.trace_length {
+                    core_dumps.trace.pop_front();
+                }
+            }
+            let start = Instant::now();
+            let result = self.step(pc);
+            self.memory.set_audit_pc(None);
+            self.counters.cycles += 1;
+            if result.is_err() {
+                self.counters.errors += 1;
+            }
+            if let (Some(coverage), Ok(_)) = (&self.coverage, &result) {
+                let mut coverage = coverage.borrow_mut();
+                coverage.record(pc);
+                if self.program.fused.as_ref().is_some_and(|fused| matches!(fused.get(pc), Some(Some(_)))) {
+                    coverage.record(pc + 1);
+                }
+            }
+            if let Some(log) = &self.execution_log {
+                let error = result.as_ref().err().map(String::as_str);
+                log.borrow_mut().record(pc, &instructions[pc], start.elapsed(), error)?;
+            }
+            match &result {
+                Ok(interrupt) => self.publish_instruction_events(&instructions, pc, interrupt),
+                Err(e) => self.dump_core(e),
+            }
+            return result;
         }
         info!("Reached end of program!");
         Ok(Interrupt::Ok)
     }
 
-    /// Get a clone of the memory for debugging.
-    pub fn get_memory(&self) -> Memory {
-        self.memory.clone()
+    // Run the instruction at `pc`.
+    fn step(&mut self, pc: usize) -> Result<Interrupt, String> {
+        if let Some(fused) = self.program.fused.clone() {
+            if let Some(Some(op)) = fused.get(pc) {
+                let result = fusion::execute_fused(&mut self.memory, &mut self.program, op);
+                if result.is_ok() {
+                    // Every superinstruction stands for a pair of instructions.
+                    self.counters.instructions += 2;
+                }
+                return result;
+            }
+        }
+        let result = execute_instruction(&mut self.memory, &mut self.io, &mut self.program, &self.dispatch);
+        let denied = self.io.take_denied();
+        return match result {
+            Ok(interrupt) => {
+                self.counters.instructions += 1;
+                self.fault = None;
+                Ok(interrupt)
+            },
+            Err(e) => {
+                self.program.pc = pc;
+                #[cfg(feature = "tracing")]
+                tracing::error!(pc, error = %e, "instruction failed");
+                self.fault = Some(Fault { pc, message: e.clone(), denied });
+                Err(format!("{}\n  at {}: {:?}", e, self.program.describe_location(pc), self.program.instructions[pc]))
+            },
+        };
+    }
+
+    /// What this CPU has executed so far, and the memory and streams it's using.
+    pub fn metrics(&self) -> Metrics {
+        return Metrics {
+            memory_entries: self.memory.symbol_count(),
+            memory_bytes: self.memory.len(),
+            open_streams: self.io.open_streams(),
+            ..self.counters
+        };
+    }
+
+    /// The error the last instruction raised, if execution hasn't moved on from it yet.
+    pub fn fault(&self) -> Option<&Fault> {
+        return self.fault.as_ref();
+    }
+
+    /// The `Timeout` the watchdog stopped the last call to `run` or `run_for` with, if it did.
+    pub fn timed_out(&self) -> Option<&Timeout> {
+        return self.timed_out.as_ref();
+    }
+
+    /// Carry on after a fault by skipping the instruction that raised it.
+    pub fn skip_fault(&mut self) {
+        if let Some(fault) = self.fault.take() {
+            self.program.pc = fault.pc + 1;
+        }
+    }
+
+    /// Carry on after a fault from `target`, eg. the start of a handler block.
+    pub fn resume_at(&mut self, target: usize) {
+        self.fault = None;
+        self.program.jump(target);
+    }
+
+    /// Get read-only access to the memory, for inspecting it without a clone.
+    pub fn memory_view(&self) -> &Memory {
+        return &self.memory;
     }
 
     /// Get a clone of the stack for debugging.
@@ -128,9 +798,158 @@ impl CPU {
         self.program.clone()
     }
 
-    pub fn extend_memory(&mut self, n: usize){
-        self.memory.extend_memory(n);
+    pub fn extend_memory(&mut self, n: usize) -> Result<(), OutOfMemory> {
+        return self.memory.extend_memory(n);
+    }
+
+    /// Limit how many bytes of memory this CPU's program can use.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory.set_limit(limit);
+    }
+
+    /// Stop each call to `run` with a `Timeout` error once it has taken `max_time`, or executed
+    /// `max_instructions`, if given, which `timed_out` then returns. The pc is left on the next instruction, so running again
+    /// carries on with a fresh allowance.
+    pub fn set_watchdog(&mut self, max_time: Option<Duration>, max_instructions: Option<u64>) {
+        self.watchdog = Watchdog { max_time, max_instructions };
+    }
+
+    /// Run the block at `handler` whenever the interrupt `name` is raised, replacing any handler
+    /// it had. Returns the line for raising it.
+    pub fn register_interrupt(&mut self, name: &str, handler: usize) -> InterruptLine {
+        return self.interrupts.register(name, handler);
+    }
+
+    /// The line for raising the interrupt `name`, if it has a handler.
+    pub fn interrupt_line(&self, name: &str) -> Option<InterruptLine> {
+        return self.interrupts.line(name);
+    }
+
+    /// Raise the interrupt `name`, so its handler runs before the next instruction.
+    pub fn raise_interrupt(&self, name: &str) -> Result<(), String> {
+        let Some(line) = self.interrupts.line(name) else {
+            log_and_return_err!("No handler is registered for interrupt {}", name);
+        };
+        line.raise();
+        return Ok(());
+    }
+
+    /// Append the standard library to the loaded program.
+    /// Returns the index each routine starts at, by name.
+    pub fn load_stdlib(&mut self) -> HashMap<String, usize> {
+        let module = stdlib();
+        let (instructions, starts) = linker::link_at(std::slice::from_ref(&module), self.program.instructions.len())
+            .expect("the standard library always links");
+        self.program.record_blocks(&module, &starts);
+        Rc::make_mut(&mut self.program.instructions).extend(instructions);
+        return module.blocks.into_iter().map(|block| block.name).zip(starts).collect();
+    }
+
+    /// Replace the block named `block.name` with `block`, while the program is loaded.
+    ///
+    /// The new block is appended to the program, and every jump target pointing at the start of
+    /// the old block is pointed at the start of the new one. Targets in `block` are linked as they
+    /// would be by `link`, with references resolved against the program's other blocks. The old
+    /// instructions are left in place, so indices into them stay valid, and `policy` decides what
+    /// happens to execution already inside them.
+    ///
+    /// Only this CPU's program is changed: coroutines forked from it keep the code they had, and
+    /// targets held in memory, like tables for CreateCoroutineIndirect, are not rewritten.
+    pub fn replace_block(&mut self, block: Block, policy: ReloadPolicy) -> Result<(), String> {
+        let Some(old) = self.program.block(&block.name) else {
+            log_and_return_err!("No block named {} to replace", block.name);
+        };
+        let start = self.program.instructions.len();
+        let mut offsets: HashMap<&str, usize> = self.program.blocks.iter().map(|(name, range)| (name.as_str(), range.start)).collect();
+        offsets.insert(block.name.as_str(), start);
+        let relocated = linker::relocate(&block, &offsets)?;
+
+        let instructions = Rc::make_mut(&mut self.program.instructions);
+        for instruction in instructions.iter_mut() {
+            for target in linker::targets_mut(instruction) {
+                if *target == old.start {
+                    *target = start;
+                }
+            }
+        }
+        instructions.extend(relocated);
+        for each in &mut self.program.loops {
+            if each.body == old.start {
+                each.body = start;
+            }
+        }
+
+        if policy == ReloadPolicy::Restart {
+            // Loops started in the old block are abandoned along with everything inside them.
+            let started = self.program.loops.iter().position(|each| old.contains(&(each.resume - 1)));
+            if let Some(index) = started {
+                self.program.loops.truncate(index);
+            }
+            if started.is_some() || old.contains(&self.program.pc) {
+                self.program.pc = start;
+                self.fault = None;
+            }
+        }
+
+        Rc::make_mut(&mut self.program.blocks).insert(block.name.clone(), start..start + block.instructions.len());
+        if self.program.fused.is_some() {
+            self.program.fuse_superinstructions();
+        }
+        return Ok(());
+    }
+
+    /// Check the loaded program for problems before running it, starting from `entrypoint`.
+    /// Returns a description of each problem found, so an empty list means the program is valid.
+    pub fn validate(&self, entrypoint: usize) -> Vec<String> {
+        return validation::validate(&self.program.instructions, entrypoint);
+    }
+
+    /// Check the types of symbols the loaded program reads, starting from `entrypoint`.
+    /// Returns a description of each read of a symbol holding the wrong type.
+    pub fn verify(&self, entrypoint: usize) -> Vec<String> {
+        return verifier::verify(&self.program.instructions, entrypoint);
+    }
+
+    /// Capture the memory and program of this CPU. Open streams and host values are not included.
+    pub fn snapshot(&self) -> CpuSnapshot {
+        CpuSnapshot {
+            memory: self.memory.dump(),
+            frozen: self.memory.frozen_ranges().to_vec(),
+            names: self.memory.symbols(),
+            program: self.program.clone(),
+        }
+    }
+
+    /// Replace the memory and program of this CPU with those from a snapshot. The CPU keeps its
+    /// memory limit, shared regions, and host values.
+    pub fn restore(&mut self, snapshot: &CpuSnapshot) {
+        self.fault = None;
+        let limit = self.memory.limit();
+        let shared = self.memory.shared().clone();
+        let host_values = self.memory.host_values().to_vec();
+        self.memory = Memory::from_dump(snapshot.memory.clone());
+        for &(address, n) in &snapshot.frozen {
+            self.memory.freeze(address, n);
+        }
+        for (name, address, n) in &snapshot.names {
+            self.memory.bind(name, *address, *n);
+        }
+        self.memory.set_limit(limit);
+        self.memory.set_shared(shared);
+        self.memory.set_host_values(host_values);
+        let (arithmetic, assertions) = (self.program.arithmetic, self.program.assertions);
+        self.program = snapshot.program.clone();
+        self.program.arithmetic = arithmetic;
+        self.program.assertions = assertions;
+    }
+}
+
+// Parse `bytes` as a `T`, failing with `what` if they're the wrong size for one.
+fn parse_sized<T: ByteParseable>(bytes: &[u8], what: &str) -> Result<T, String> {
+    if let Some(size) = T::fixed_size().filter(|size| *size != bytes.len()) {
+        log_and_return_err!("{} {} bytes, but a {} takes {}", what, bytes.len(), std::any::type_name::<T>(), size);
     }
+    return Ok(T::from_bytes(bytes));
 }
 
 impl Default for CPU {
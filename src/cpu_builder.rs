@@ -0,0 +1,260 @@
+//! ConcordeVM's CPU builder.
+//!
+//! Gathers the settings an embedder would otherwise make one setter at a time into a single
+//! profile, which can build either a lone CPU or a scheduler that gives every coroutine the same
+//! settings.
+
+use crate::clock::VirtualClock;
+use crate::coverage::Coverage;
+use crate::execution_log::ExecutionLog;
+use crate::instructions::ArithmeticMode;
+use crate::recording::IoRecorder;
+use crate::sandbox::SandboxPolicy;
+use crate::{Program, Scheduler, CPU};
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+use std::time::Duration;
+
+// Where IO goes.
+#[derive(Default)]
+enum IoBackend {
+    #[default]
+    Live,
+    Record(String),
+    Replay(String),
+}
+
+/// Builds a `CPU`, or a `Scheduler`, from a profile of settings. Anything left unset keeps the
+/// default of `CPU::new` or `Scheduler::new`.
+///
+/// The memory size, program, and standard library only apply to a CPU, and the coroutine limit,
+/// verification, optimization, memory inheritance, and domain call timeout only to a scheduler.
+#[derive(Default)]
+pub struct CpuBuilder {
+    memory_size: usize,
+    program: Option<Program>,
+    stdlib: bool,
+    memory_limit: Option<usize>,
+    fuel: Option<u64>,
+    time_limit: Option<Duration>,
+    sandbox: Option<SandboxPolicy>,
+    io: IoBackend,
+    seed: Option<u64>,
+    trace: Option<Box<dyn Write>>,
+    coverage: Option<Rc<RefCell<Coverage>>>,
+    arithmetic: Option<ArithmeticMode>,
+    assertions: Option<bool>,
+    max_coroutines: Option<usize>,
+    verify: Option<bool>,
+    optimize: Option<bool>,
+    inherit_memory: Option<bool>,
+    domain_call_timeout: Option<Duration>,
+}
+
+impl CpuBuilder {
+    pub fn new() -> CpuBuilder {
+        CpuBuilder::default()
+    }
+
+    /// Start with `n` bytes of memory.
+    pub fn memory_size(mut self, n: usize) -> CpuBuilder {
+        self.memory_size = n;
+        self
+    }
+
+    /// Load `program`.
+    pub fn program(mut self, program: Program) -> CpuBuilder {
+        self.program = Some(program);
+        self
+    }
+
+    /// Choose whether the standard library is linked after the program.
+    pub fn stdlib(mut self, enabled: bool) -> CpuBuilder {
+        self.stdlib = enabled;
+        self
+    }
+
+    /// Let memory grow to at most `limit` bytes.
+    pub fn memory_limit(mut self, limit: usize) -> CpuBuilder {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    /// Stop a run with a `Timeout` error once it has executed `instructions`.
+    pub fn fuel(mut self, instructions: u64) -> CpuBuilder {
+        self.fuel = Some(instructions);
+        self
+    }
+
+    /// Stop a run with a `Timeout` error once it has taken `max_time`.
+    pub fn time_limit(mut self, max_time: Duration) -> CpuBuilder {
+        self.time_limit = Some(max_time);
+        self
+    }
+
+    /// Restrict the IO programs can perform.
+    pub fn sandbox(mut self, policy: SandboxPolicy) -> CpuBuilder {
+        self.sandbox = Some(policy);
+        self
+    }
+
+    /// Record all IO to the file at `path`.
+    pub fn record_io(mut self, path: &str) -> CpuBuilder {
+        self.io = IoBackend::Record(path.to_string());
+        self
+    }
+
+    /// Serve all IO from the recording at `path`, instead of performing it for real.
+    pub fn replay_io(mut self, path: &str) -> CpuBuilder {
+        self.io = IoBackend::Replay(path.to_string());
+        self
+    }
+
+    /// Make runs repeatable: random numbers are drawn from `seed`, and the time is kept by a
+    /// virtual clock starting at the Unix epoch.
+    pub fn deterministic(mut self, seed: u64) -> CpuBuilder {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Log every instruction executed to `writer`, as a line of JSON each.
+    pub fn trace(mut self, writer: impl Write + 'static) -> CpuBuilder {
+        self.trace = Some(Box::new(writer));
+        self
+    }
+
+    /// Count every instruction executed in `coverage`.
+    pub fn coverage(mut self, coverage: Rc<RefCell<Coverage>>) -> CpuBuilder {
+        self.coverage = Some(coverage);
+        self
+    }
+
+    /// Handle integer overflow with `mode`.
+    pub fn arithmetic_mode(mut self, mode: ArithmeticMode) -> CpuBuilder {
+        self.arithmetic = Some(mode);
+        self
+    }
+
+    /// Choose whether Assert and AssertType are checked.
+    pub fn assertions(mut self, enabled: bool) -> CpuBuilder {
+        self.assertions = Some(enabled);
+        self
+    }
+
+    /// Limit how many coroutines a scheduler may have alive at once.
+    pub fn max_coroutines(mut self, max: usize) -> CpuBuilder {
+        self.max_coroutines = Some(max);
+        self
+    }
+
+    /// Choose whether a scheduler checks the symbol types of programs before running them.
+    pub fn verify(mut self, verify: bool) -> CpuBuilder {
+        self.verify = Some(verify);
+        self
+    }
+
+    /// Choose whether a scheduler optimizes programs before running them.
+    pub fn optimize(mut self, optimize: bool) -> CpuBuilder {
+        self.optimize = Some(optimize);
+        self
+    }
+
+    /// Choose whether coroutines created with CreateCoroutine start with a copy of their parent's
+    /// memory.
+    pub fn inherit_memory(mut self, enabled: bool) -> CpuBuilder {
+        self.inherit_memory = Some(enabled);
+        self
+    }
+
+    /// Fail calls into domains that haven't returned after `timeout`.
+    pub fn domain_call_timeout(mut self, timeout: Duration) -> CpuBuilder {
+        self.domain_call_timeout = Some(timeout);
+        self
+    }
+
+    /// Build a CPU with the profile. Fails if the IO recording can't be opened.
+    pub fn build(self) -> Result<CPU, String> {
+        let mut cpu = CPU::with_program(self.memory_size, self.program.unwrap_or_default());
+        if self.stdlib {
+            cpu.load_stdlib();
+        }
+        cpu.set_memory_limit(self.memory_limit);
+        cpu.set_watchdog(self.time_limit, self.fuel);
+        if let Some(policy) = self.sandbox {
+            cpu.set_sandbox_policy(Rc::new(policy));
+        }
+        let recorder = match &self.io {
+            IoBackend::Live => IoRecorder::live(),
+            IoBackend::Record(path) => IoRecorder::record_to(path)?,
+            IoBackend::Replay(path) => IoRecorder::replay_from(path)?,
+        };
+        cpu.set_io_recorder(Rc::new(RefCell::new(recorder)));
+        if let Some(seed) = self.seed {
+            cpu.seed_rng(seed, 0);
+            cpu.set_clock(Rc::new(VirtualClock::new(0)));
+        }
+        if let Some(writer) = self.trace {
+            cpu.set_execution_log(Rc::new(RefCell::new(ExecutionLog::new(writer))));
+        }
+        if let Some(coverage) = self.coverage {
+            cpu.set_coverage(coverage);
+        }
+        if let Some(mode) = self.arithmetic {
+            cpu.program.arithmetic = mode;
+        }
+        if let Some(enabled) = self.assertions {
+            cpu.program.assertions = enabled;
+        }
+        return Ok(cpu);
+    }
+
+    /// Build a scheduler with the profile, which every coroutine it spawns runs with. Fails if
+    /// the IO recording can't be opened.
+    pub fn build_scheduler(self) -> Result<Scheduler, String> {
+        let mut scheduler = Scheduler::new();
+        if let Some(limit) = self.memory_limit {
+            scheduler.set_memory_limit(limit);
+        }
+        scheduler.set_watchdog(self.time_limit, self.fuel);
+        if let Some(policy) = self.sandbox {
+            scheduler.set_sandbox_policy(policy);
+        }
+        match &self.io {
+            IoBackend::Live => {},
+            IoBackend::Record(path) => scheduler.record_io(path)?,
+            IoBackend::Replay(path) => scheduler.replay_io(path)?,
+        }
+        if let Some(seed) = self.seed {
+            scheduler.seed_rng(seed);
+            scheduler.set_clock(Rc::new(VirtualClock::new(0)));
+        }
+        if let Some(writer) = self.trace {
+            scheduler.log_execution(writer);
+        }
+        if let Some(coverage) = self.coverage {
+            scheduler.set_coverage(coverage);
+        }
+        if let Some(mode) = self.arithmetic {
+            scheduler.set_arithmetic_mode(mode);
+        }
+        if let Some(enabled) = self.assertions {
+            scheduler.set_assertions(enabled);
+        }
+        if let Some(max) = self.max_coroutines {
+            scheduler.set_max_coroutines(max);
+        }
+        if let Some(verify) = self.verify {
+            scheduler.set_verify(verify);
+        }
+        if let Some(optimize) = self.optimize {
+            scheduler.set_optimize(optimize);
+        }
+        if let Some(enabled) = self.inherit_memory {
+            scheduler.set_inherit_memory(enabled);
+        }
+        scheduler.set_domain_call_timeout(self.domain_call_timeout);
+        return Ok(scheduler);
+    }
+}
@@ -0,0 +1,123 @@
+//! ConcordeVM's cryptography.
+//!
+//! Provides ChaCha20-Poly1305 authenticated encryption (RFC 8439) for the AeadEncrypt and
+//! AeadDecrypt instructions, so guest programs can protect data at rest or over sockets without a
+//! native domain. Keys are 32 bytes, nonces are 12 bytes, and a 16 byte tag follows each
+//! ciphertext. A nonce must never be reused with the same key. Encryption needs the `aead`
+//! feature.
+//!
+//! A ChaCha20 keystream backs each CPU's random number generator, which is keyed from the
+//! operating system unless the embedder seeds it for a replayable run.
+
+use crate::log_and_return_err;
+
+use chacha20::ChaCha20;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+#[cfg(feature = "aead")]
+use chacha20poly1305::{ChaCha20Poly1305, aead::{Aead, KeyInit}};
+use log::error;
+
+pub(crate) const KEY_LEN: usize = 32;
+pub(crate) const NONCE_LEN: usize = 12;
+#[cfg(feature = "aead")]
+const TAG_LEN: usize = 16;
+
+/// Encrypt `plaintext`, returning the ciphertext followed by its tag.
+#[cfg(feature = "aead")]
+pub(crate) fn seal(key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    match ChaCha20Poly1305::new(key.into()).encrypt(nonce.into(), plaintext) {
+        Ok(sealed) => Ok(sealed),
+        Err(_) => log_and_return_err!("Failed to encrypt {} bytes", plaintext.len()),
+    }
+}
+
+/// Check the tag on the end of `sealed` and decrypt the ciphertext before it.
+#[cfg(feature = "aead")]
+pub(crate) fn open(key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN], sealed: &[u8]) -> Result<Vec<u8>, String> {
+    if sealed.len() < TAG_LEN {
+        log_and_return_err!("Can't decrypt {} bytes, which is too short to hold a tag", sealed.len());
+    }
+    match ChaCha20Poly1305::new(key.into()).decrypt(nonce.into(), sealed) {
+        Ok(plaintext) => Ok(plaintext),
+        Err(_) => log_and_return_err!("Failed to decrypt: the data or its tag has been tampered with, or the key or nonce is wrong"),
+    }
+}
+
+#[cfg(not(feature = "aead"))]
+pub(crate) fn seal(_key: &[u8; KEY_LEN], _nonce: &[u8; NONCE_LEN], _plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    log_and_return_err!("AeadEncrypt needs ConcordeVM to be built with the aead feature");
+}
+
+#[cfg(not(feature = "aead"))]
+pub(crate) fn open(_key: &[u8; KEY_LEN], _nonce: &[u8; NONCE_LEN], _sealed: &[u8]) -> Result<Vec<u8>, String> {
+    log_and_return_err!("AeadDecrypt needs ConcordeVM to be built with the aead feature");
+}
+
+/// A random number generator that reads out a ChaCha20 keystream. Seeded from the operating
+/// system it is secure; seeded by the embedder it gives the same numbers every run.
+pub(crate) struct Rng {
+    key: [u8; KEY_LEN],
+    nonce: [u8; NONCE_LEN],
+    cipher: ChaCha20,
+}
+
+impl Rng {
+    /// A generator keyed by the operating system.
+    pub(crate) fn from_os() -> Result<Rng, String> {
+        let mut key = [0; KEY_LEN];
+        if let Err(e) = getrandom::fill(&mut key) {
+            log_and_return_err!("Could not get random bytes from the operating system: {}", e);
+        }
+        return Ok(Rng::new(key, [0; NONCE_LEN]));
+    }
+
+    /// A generator that always produces the same numbers for the same `seed` and `stream`.
+    /// Different streams with one seed are independent.
+    pub(crate) fn from_seed(seed: u64, stream: u64) -> Rng {
+        let mut key = [0; KEY_LEN];
+        key[..8].copy_from_slice(&seed.to_le_bytes());
+        let mut nonce = [0; NONCE_LEN];
+        nonce[4..].copy_from_slice(&stream.to_le_bytes());
+        return Rng::new(key, nonce);
+    }
+
+    fn new(key: [u8; KEY_LEN], nonce: [u8; NONCE_LEN]) -> Rng {
+        return Rng { key, nonce, cipher: ChaCha20::new(&key.into(), &nonce.into()) };
+    }
+
+    /// The next `n` random bytes.
+    pub(crate) fn bytes(&mut self, n: usize) -> Vec<u8> {
+        let mut bytes = vec![0; n];
+        if self.cipher.try_apply_keystream(&mut bytes).is_err() {
+            // Move on to a new nonce rather than running off the end of the keystream.
+            let high = u32::from_le_bytes(self.nonce[..4].try_into().unwrap());
+            self.nonce[..4].copy_from_slice(&high.wrapping_add(1).to_le_bytes());
+            *self = Rng::new(self.key, self.nonce);
+            self.cipher.apply_keystream(&mut bytes);
+        }
+        return bytes;
+    }
+
+/// A uniformly random integer from `min` to `max` inclusive.
+    pub(crate) fn int_in(&mut self, min: i64, max: i64) -> i64 {
+        let span = max.wrapping_sub(min) as u64;
+        let value = match span.checked_add(1) {
+            None => self.next_u64(),
+            Some(range) => {
+                // Reject the values that would make the low end of the range more likely.
+                let zone = u64::MAX - (u64::MAX - range + 1) % range;
+                loop {
+                    let value = self.next_u64();
+                    if value <= zone {
+                        break value % range;
+                    }
+                }
+            },
+        };
+        return min.wrapping_add(value as i64);
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        return u64::from_le_bytes(self.bytes(8).try_into().unwrap());
+    }
+}
@@ -0,0 +1,109 @@
+//! ConcordeVM's debug info.
+//!
+//! Maps instructions back to the source they were compiled from, so compilers targeting
+//! ConcordeISA can give users errors and traces in terms of their own code. Each instruction can
+//! have a source location, and the name of the function or other construct it came from.
+
+use crate::bytecode::{Decoder, Encoder};
+use crate::log_and_return_err;
+
+use log::error;
+use std::collections::{BTreeMap, HashMap};
+
+/// A position in a source file. Lines and columns start at 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+/// Source locations and names for the instructions of a program, by instruction index.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DebugInfo {
+    locations: BTreeMap<usize, SourceLocation>,
+    names: BTreeMap<usize, String>,
+}
+
+impl DebugInfo {
+    pub fn new() -> DebugInfo {
+        DebugInfo::default()
+    }
+
+    pub fn set_location(&mut self, index: usize, file: &str, line: u32, column: u32) {
+        self.locations.insert(index, SourceLocation { file: file.to_string(), line, column });
+    }
+
+    pub fn set_name(&mut self, index: usize, name: &str) {
+        self.names.insert(index, name.to_string());
+    }
+
+    pub fn location(&self, index: usize) -> Option<&SourceLocation> {
+        return self.locations.get(&index);
+    }
+
+    pub fn name(&self, index: usize) -> Option<&str> {
+        return self.names.get(&index).map(|name| name.as_str());
+    }
+
+    /// Describe where an instruction came from, like `in main at main.src:3:5`.
+    pub fn describe(&self, index: usize) -> Option<String> {
+        let location = self.location(index).map(|location| format!("at {}:{}:{}", location.file, location.line, location.column));
+        let name = self.name(index).map(|name| format!("in {}", name));
+        return match (name, location) {
+            (Some(name), Some(location)) => Some(format!("{} {}", name, location)),
+            (name, location) => name.or(location),
+        };
+    }
+
+    // File names are stored once each, and locations refer to them by index.
+    pub(crate) fn encode(&self, encoder: &mut Encoder) {
+        let mut files: Vec<&str> = Vec::new();
+        let mut file_ids = HashMap::new();
+        for location in self.locations.values() {
+            file_ids.entry(location.file.as_str()).or_insert_with(|| {
+                files.push(&location.file);
+                files.len() - 1
+            });
+        }
+
+        encoder.usize(files.len());
+        for file in files {
+            encoder.string(file);
+        }
+        encoder.usize(self.locations.len());
+        for (index, location) in &self.locations {
+            encoder.usize(*index);
+            encoder.usize(file_ids[location.file.as_str()]);
+            encoder.u32(location.line);
+            encoder.u32(location.column);
+        }
+        encoder.usize(self.names.len());
+        for (index, name) in &self.names {
+            encoder.usize(*index);
+            encoder.string(name);
+        }
+    }
+
+    pub(crate) fn decode(decoder: &mut Decoder) -> Result<DebugInfo, String> {
+        let mut files = Vec::new();
+        for _ in 0..decoder.usize()? {
+            files.push(decoder.string()?);
+        }
+        let mut info = DebugInfo::new();
+        for _ in 0..decoder.usize()? {
+            let index = decoder.usize()?;
+            let file_id = decoder.usize()?;
+            let Some(file) = files.get(file_id) else {
+                log_and_return_err!("Instruction {} refers to source file {}, which is not in the debug info", index, file_id);
+            };
+            let (line, column) = (decoder.u32()?, decoder.u32()?);
+            info.set_location(index, file, line, column);
+        }
+        for _ in 0..decoder.usize()? {
+            let index = decoder.usize()?;
+            info.names.insert(index, decoder.string()?);
+        }
+        return Ok(info);
+    }
+}
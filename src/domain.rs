@@ -1,10 +1,24 @@
 use libffi::{
     middle::Type,
     raw::{ffi_call, ffi_cif, ffi_prep_cif, ffi_status_FFI_OK, ffi_type},
+    raw::{FFI_TYPE_VOID, FFI_TYPE_FLOAT, FFI_TYPE_DOUBLE, FFI_TYPE_UINT8, FFI_TYPE_SINT8, FFI_TYPE_UINT16, FFI_TYPE_SINT16},
+    raw::{FFI_TYPE_UINT32, FFI_TYPE_SINT32, FFI_TYPE_UINT64, FFI_TYPE_SINT64},
 };
 
+use crate::builtin_domains::{BuiltinDomain, BUILTIN_DOMAIN_PREFIX};
+use crate::isolated_domains::{DomainIsolation, IsolatedDomain};
+use crate::memory::ByteSerialisable;
+
 use libloading;
-use std::{collections::HashMap, fmt::UpperHex};
+use std::{any::Any, collections::HashMap, fmt::UpperHex, panic::AssertUnwindSafe};
+
+/// The version of the protocol between the VM and its domains. Every domain library must export
+/// it as a `uint32_t` named `concorde_domain_abi`, and libraries built for another version are
+/// rejected when loaded, since calling into them may not be safe.
+pub const DOMAIN_ABI_VERSION: u32 = 1;
+
+// The symbol a domain library exports its protocol version under.
+const ABI_SYMBOL: &[u8] = b"concorde_domain_abi";
 
 #[derive(Debug, Clone)]
 struct FnPtr(*const ());
@@ -13,7 +27,7 @@ unsafe impl Send for FnPtr {}
 unsafe impl Sync for FnPtr {}
 
 #[derive(Debug, Clone)]
-struct FFIType(Type);
+pub(crate) struct FFIType(pub(crate) Type);
 
 unsafe impl Send for FFIType {}
 unsafe impl Sync for FFIType {}
@@ -69,6 +83,100 @@ pub unsafe fn generic_ffi_call(
     };
 }
 
+// The libffi type code of `t`, like FFI_TYPE_SINT32.
+pub(crate) fn type_code(t: &Type) -> u32 {
+    return u32::from(unsafe { (*t.as_raw_ptr()).type_ });
+}
+
+/// A value returned by a domain function, typed by the return type it was added with. Calls
+/// complete their futures with these, so a result lands in memory as the type it was declared
+/// as, however the domain got it back. Structs and other types without a variant of their own
+/// are kept as raw bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DomainValue {
+    Void,
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    Bytes(Vec<u8>),
+}
+
+impl DomainValue {
+    /// Read a value of type `ret_type` from exactly the bytes that make it up.
+    pub fn from_bytes(ret_type: &Type, bytes: &[u8]) -> Result<DomainValue, String> {
+        let size = (unsafe { *ret_type.as_raw_ptr() }).size;
+        let code = type_code(ret_type);
+        if code != FFI_TYPE_VOID && bytes.len() != size {
+            return Err(format!("Got {} bytes for a return value that takes {}", bytes.len(), size));
+        }
+        return Ok(match code {
+            FFI_TYPE_VOID => DomainValue::Void,
+            FFI_TYPE_SINT8 => DomainValue::I8(i8::from_ne_bytes(bytes.try_into().unwrap())),
+            FFI_TYPE_SINT16 => DomainValue::I16(i16::from_ne_bytes(bytes.try_into().unwrap())),
+            FFI_TYPE_SINT32 => DomainValue::I32(i32::from_ne_bytes(bytes.try_into().unwrap())),
+            FFI_TYPE_SINT64 => DomainValue::I64(i64::from_ne_bytes(bytes.try_into().unwrap())),
+            FFI_TYPE_UINT8 => DomainValue::U8(bytes[0]),
+            FFI_TYPE_UINT16 => DomainValue::U16(u16::from_ne_bytes(bytes.try_into().unwrap())),
+            FFI_TYPE_UINT32 => DomainValue::U32(u32::from_ne_bytes(bytes.try_into().unwrap())),
+            FFI_TYPE_UINT64 => DomainValue::U64(u64::from_ne_bytes(bytes.try_into().unwrap())),
+            FFI_TYPE_FLOAT => DomainValue::F32(f32::from_ne_bytes(bytes.try_into().unwrap())),
+            FFI_TYPE_DOUBLE => DomainValue::F64(f64::from_ne_bytes(bytes.try_into().unwrap())),
+            _ => DomainValue::Bytes(bytes.to_vec()),
+        });
+    }
+
+    // Read a value of type `ret_type` from the buffer libffi returned it in, which holds integers
+    // narrower than a register widened to a whole one.
+    fn from_ffi_return(ret_type: &Type, buffer: &[u8]) -> DomainValue {
+        let widened = || u64::from_ne_bytes(buffer[..8].try_into().unwrap());
+        let bytes = match type_code(ret_type) {
+            FFI_TYPE_SINT8 | FFI_TYPE_UINT8 => (widened() as u8).to_ne_bytes().to_vec(),
+            FFI_TYPE_SINT16 | FFI_TYPE_UINT16 => (widened() as u16).to_ne_bytes().to_vec(),
+            FFI_TYPE_SINT32 | FFI_TYPE_UINT32 => (widened() as u32).to_ne_bytes().to_vec(),
+            _ => buffer[..(unsafe { *ret_type.as_raw_ptr() }).size].to_vec(),
+        };
+        return DomainValue::from_bytes(ret_type, &bytes).unwrap();
+    }
+}
+
+impl ByteSerialisable for DomainValue {
+    fn to_bytes(&self) -> Vec<u8> {
+        return match self {
+            DomainValue::Void => Vec::new(),
+            DomainValue::I8(value) => value.to_bytes(),
+            DomainValue::I16(value) => value.to_bytes(),
+            DomainValue::I32(value) => value.to_bytes(),
+            DomainValue::I64(value) => value.to_bytes(),
+            DomainValue::U8(value) => value.to_bytes(),
+            DomainValue::U16(value) => value.to_bytes(),
+            DomainValue::U32(value) => value.to_bytes(),
+            DomainValue::U64(value) => value.to_bytes(),
+            DomainValue::F32(value) => value.to_bytes(),
+            DomainValue::F64(value) => value.to_bytes(),
+            DomainValue::Bytes(bytes) => bytes.clone(),
+        };
+    }
+
+    fn write_bytes_to(&self, vec: &mut Vec<u8>, address: usize) {
+        self.to_bytes().write_bytes_to(vec, address);
+    }
+
+    fn append_bytes_to(&self, vec: &mut Vec<u8>) {
+        vec.extend(self.to_bytes());
+    }
+
+    fn get_size(&self) -> usize {
+        return self.to_bytes().len();
+    }
+}
+
 struct ByteVec(Vec<u8>);
 
 impl UpperHex for ByteVec {
@@ -99,10 +207,10 @@ impl FFIFunctionInfo {
 }
 
 pub struct FFIFunctionSignature {
-    name: String,
-    arg_types: Vec<FFIType>,
-    ret_type: FFIType,
-    ret_size: usize
+    pub(crate) name: String,
+    pub(crate) arg_types: Vec<FFIType>,
+    pub(crate) ret_type: FFIType,
+    pub(crate) ret_size: usize
 }
 
 impl FFIFunctionSignature {
@@ -124,7 +232,19 @@ pub struct FFIFunction {
     fn_ptr: FnPtr,
 }
 
+impl FFIFunction {
+    fn signature(&self) -> FFIFunctionSignature {
+        return FFIFunctionSignature {
+            name: self.name.clone(),
+            arg_types: self.arg_types.clone(),
+            ret_type: self.ret_type.clone(),
+            ret_size: self.n_ret_bytes,
+        };
+    }
+}
+
 pub struct Domain {
+    path: String,
     lib: libloading::Library,
     functions: HashMap<usize, FFIFunction>,
 }
@@ -132,12 +252,38 @@ pub struct Domain {
 impl Domain {
     pub unsafe fn new(lib_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let lib = unsafe { libloading::Library::new(lib_path) }?;
+        let Ok(abi) = (unsafe { lib.get::<*const u32>(ABI_SYMBOL) }) else {
+            return Err(format!("{} is not a domain library, since it doesn't export concorde_domain_abi", lib_path).into());
+        };
+        let abi = unsafe { **abi };
+        if abi != DOMAIN_ABI_VERSION {
+            return Err(format!("{} was built for domain ABI version {}, but this VM supports version {}", lib_path, abi, DOMAIN_ABI_VERSION).into());
+        }
         return Ok(Self {
+            path: lib_path.to_string(),
             lib,
             functions: HashMap::new(),
         });
     }
 
+    /// Load the library again from its path, and look up every function loaded from it again,
+    /// so a rebuilt plugin can be picked up without restarting the VM. The old library is closed
+    /// first, since otherwise the system hands back the copy it already has. Pointers to its
+    /// functions are no longer valid afterwards, so no calls into it may be in flight.
+    pub unsafe fn reload(self) -> Result<Domain, Box<dyn std::error::Error>> {
+        let signatures: Vec<(usize, FFIFunctionSignature)> = self.functions.iter()
+            .map(|(fn_id, ffi_fn)| (*fn_id, ffi_fn.signature()))
+            .collect();
+        let path = self.path.clone();
+        drop(self);
+
+        let mut domain = unsafe { Domain::new(&path)? };
+        for (fn_id, signature) in signatures {
+            unsafe { domain.load_fn(fn_id, &signature)? };
+        }
+        return Ok(domain);
+    }
+
     pub unsafe fn get_ffi_fn(
         &self,
         signature: &FFIFunctionSignature,
@@ -173,9 +319,10 @@ impl Domain {
         &self,
         fn_id: usize,
         args: &[u8],
-        return_buf: *mut u8,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<DomainValue, Box<dyn std::error::Error>> {
         let ffi_fn = self.functions.get(&fn_id).ok_or("Function not found")?;
+        // libffi needs room for at least a whole register, even for narrower return types.
+        let mut return_buf = vec![0u8; ffi_fn.n_ret_bytes.max(8)];
         let mut arg_types: Vec<*mut ffi_type> = ffi_fn
             .arg_types
             .iter()
@@ -188,38 +335,108 @@ impl Domain {
                 ffi_fn.fn_ptr.0,
                 &mut arg_types,
                 ret_type_ptr,
-                return_buf,
+                return_buf.as_mut_ptr(),
                 args,
             );
         }
-        return Ok(());
+        return Ok(DomainValue::from_ffi_return(&ffi_fn.ret_type.0, &return_buf));
     }
 }
 
 pub struct FFIFuncTable {
     domains: HashMap<usize, Domain>,
+    isolated: HashMap<usize, IsolatedDomain>,
+    builtin: HashMap<usize, BuiltinDomain>,
 }
 
 impl FFIFuncTable {
     pub fn new() -> FFIFuncTable {
         return FFIFuncTable {
             domains: HashMap::new(),
+            isolated: HashMap::new(),
+            builtin: HashMap::new(),
         };
     }
 
+    fn contains_domain(&self, domain_id: usize) -> bool {
+        return self.domains.contains_key(&domain_id) || self.isolated.contains_key(&domain_id) || self.builtin.contains_key(&domain_id);
+    }
+
+    // Load the built-in domain with the reserved name `path`, if it is one.
+    fn add_builtin_domain(&mut self, domain_id: usize, path: &str) -> Option<Result<(), Box<dyn std::error::Error>>> {
+        if !path.starts_with(BUILTIN_DOMAIN_PREFIX) {
+            return None;
+        }
+        return Some(BuiltinDomain::find(path).map(|domain| { self.builtin.insert(domain_id, domain); }).map_err(|e| e.into()));
+    }
+
+    /// Load the library at `so_path` in a helper process started as `isolation` says, rather
+    /// than in this one. Built-in domains are still loaded in this one, since they're part of it.
+    pub fn add_isolated_domain(
+        &mut self,
+        domain_id: usize,
+        so_path: String,
+        isolation: &DomainIsolation,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.contains_domain(domain_id) {
+            panic!("Reinserting domain with key {}", domain_id);
+        }
+        if let Some(result) = self.add_builtin_domain(domain_id, &so_path) {
+            return result;
+        }
+        self.isolated.insert(domain_id, IsolatedDomain::start(&so_path, isolation)?);
+        return Ok(());
+    }
+
+    /// Load the library at `so_path`, or the built-in domain it names, like `builtin:math`.
     pub unsafe fn add_domain(
         &mut self,
         domain_id: usize,
         so_path: String,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        if self.domains.contains_key(&domain_id) {
+        if self.contains_domain(domain_id) {
             panic!("Reinserting domain with key {}", domain_id);
         }
+        if let Some(result) = self.add_builtin_domain(domain_id, &so_path) {
+            return result;
+        }
         let domain = unsafe { Domain::new(&so_path)? };
         self.domains.insert(domain_id, domain);
         return Ok(());
     }
 
+    /// Close the library of a domain, forgetting its functions.
+    ///
+    /// # Safety
+    ///
+    /// No calls into the domain may be in flight, and nothing may still hold a pointer into its
+    /// library, since closing it unmaps the library's code and data.
+    pub unsafe fn remove_domain(&mut self, domain_id: usize) -> Result<(), Box<dyn std::error::Error>> {
+        if self.domains.remove(&domain_id).is_none() && self.isolated.remove(&domain_id).is_none() && self.builtin.remove(&domain_id).is_none() {
+            return Err(format!("Domain {} is not loaded", domain_id).into());
+        }
+        return Ok(());
+    }
+
+    /// Reload the library of a domain, as `Domain::reload` does. If the library can't be loaded
+    /// again, or no longer has one of the domain's functions, the domain is left unloaded.
+    /// Built-in domains have nothing to reload, and are left as they are.
+    pub unsafe fn reload_domain(&mut self, domain_id: usize) -> Result<(), Box<dyn std::error::Error>> {
+        if self.builtin.contains_key(&domain_id) {
+            return Ok(());
+        }
+        if let Some(domain) = self.isolated.remove(&domain_id) {
+            self.isolated.insert(domain_id, domain.restart()?);
+            return Ok(());
+        }
+        let Some(domain) = self.domains.remove(&domain_id) else {
+            return Err(format!("Domain {} is not loaded", domain_id).into());
+        };
+        let domain = unsafe { domain.reload()? };
+        self.domains.insert(domain_id, domain);
+        return Ok(());
+    }
+
     pub unsafe fn load_function_from_so(
         &mut self,
         domain_id: usize,
@@ -227,23 +444,46 @@ impl FFIFuncTable {
     ) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(domain) = self.domains.get_mut(&domain_id) {
             unsafe { domain.load_fn(func.key, &func.signature)? };
+        } else if let Some(domain) = self.isolated.get_mut(&domain_id) {
+            domain.add_function(func.key, func.signature)?;
+        } else if let Some(domain) = self.builtin.get_mut(&domain_id) {
+            domain.add_function(func.key, &func.signature)?;
         }
 
         return Ok(());
     }
 
+    /// Call a function of a domain, getting back its result as the type it was added with. A
+    /// panic in a function implemented in Rust, like those of built-in domains, fails the call
+    /// instead of unwinding into the VM.
     pub unsafe fn call_function(
         &self,
         domain_id: usize,
         fn_id: usize,
         args: &[u8],
-        return_buf: *mut u8,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<DomainValue, Box<dyn std::error::Error>> {
+        let called = std::panic::catch_unwind(AssertUnwindSafe(|| unsafe { self.dispatch_call(domain_id, fn_id, args) }));
+        return match called {
+            Ok(result) => result,
+            Err(payload) => Err(format!("Panicked: {}", panic_message(&*payload)).into()),
+        };
+    }
+
+    unsafe fn dispatch_call(
+        &self,
+        domain_id: usize,
+        fn_id: usize,
+        args: &[u8],
+    ) -> Result<DomainValue, Box<dyn std::error::Error>> {
         if let Some(domain) = self.domains.get(&domain_id) {
-            unsafe { domain.call_function(fn_id, args, return_buf)? };
+            return unsafe { domain.call_function(fn_id, args) };
+        } else if let Some(domain) = self.isolated.get(&domain_id) {
+            return Ok(domain.call(fn_id, args)?);
+        } else if let Some(domain) = self.builtin.get(&domain_id) {
+            return Ok(domain.call(fn_id, args)?);
         }
 
-        return Ok(());
+        return Err(format!("Domain {} is not loaded", domain_id).into());
     }
 
     pub fn get_ffi_fn(&self, domain_id: usize, fn_id: usize) -> Option<&FFIFunction> {
@@ -256,6 +496,12 @@ impl FFIFuncTable {
     }
 
     pub fn get_n_ret_bytes(&self, domain_id: usize, fn_id: usize) -> Option<usize> {
+        if let Some(domain) = self.isolated.get(&domain_id) {
+            return domain.n_ret_bytes(fn_id);
+        }
+        if let Some(domain) = self.builtin.get(&domain_id) {
+            return domain.n_ret_bytes(fn_id);
+        }
         if let Some(domain) = self.domains.get(&domain_id) {
             if let Some(ffi_fn) = domain.functions.get(&fn_id) {
                 return Some(ffi_fn.n_ret_bytes);
@@ -265,6 +511,17 @@ impl FFIFuncTable {
     }
 }
 
+// The message a panic was raised with, if it had one.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        return message.to_string();
+    }
+    if let Some(message) = payload.downcast_ref::<String>() {
+        return message.clone();
+    }
+    return "no message".to_string();
+}
+
 fn str_to_ffi_type(s: &str) -> Type {
     match s {
         "i8" => Type::i8(),
@@ -306,15 +563,11 @@ fn test() -> Result<(), Box<dyn std::error::Error>> {
         )?
     };
 
-    let mut ret_buffer = ByteVec(vec![0u8; 12]); // Buffer to hold the return struct (3 i16s = 6 bytes)
-
     let x = (0xFFFFu16).to_ne_bytes();
     let y = (0xFFFFu16).to_ne_bytes();
     let input_buffer = [x.as_slice(), y.as_slice()].concat();
 
-    unsafe {
-        d.call_function(1, 1, &input_buffer, ret_buffer.0.as_mut_ptr())?;
-    }
+    let ret_buffer = ByteVec(unsafe { d.call_function(1, 1, &input_buffer)? }.to_bytes());
 
     print!("{:02X}\n", ret_buffer);
 
@@ -0,0 +1,88 @@
+//! ConcordeVM's event sources.
+//!
+//! Lets guest programs sleep until something happens outside the VM, instead of polling for it. A
+//! coroutine runs AwaitEvent with the name of an event, and is suspended until the event fires,
+//! when its payload is written where the coroutine asked. The host fires events through an
+//! `EventSender`, which can be cloned and sent to any thread. The scheduler also comes with
+//! sources for timers and file changes, which fire events of their own.
+//!
+//! Every coroutine waiting for an event wakes when it fires. Events fired while nothing is
+//! waiting for them are kept, and wake the next coroutines to await them, so none are missed.
+//! Sources run on threads of their own, and stop once the scheduler is dropped. Timers keep real
+//! time, not the scheduler's clock.
+
+use crate::domain::DomainValue;
+use crate::log_and_return_err;
+
+use log::error;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// Something that wakes a scheduler waiting for work.
+pub(crate) enum Wakeup {
+    // An FFI call finished, completing the future with this id with its return value.
+    Ffi(usize, DomainValue),
+    // The FFI call that would complete the future with this id failed.
+    FfiFailed(usize, String),
+    // An event fired, with its name and payload.
+    Event(String, Vec<u8>),
+}
+
+/// A handle for firing events at a scheduler, from any thread.
+#[derive(Clone)]
+pub struct EventSender {
+    wakeups: Sender<Wakeup>,
+}
+
+impl EventSender {
+    pub(crate) fn new(wakeups: Sender<Wakeup>) -> EventSender {
+        return EventSender { wakeups };
+    }
+
+    /// Fire the event `name`, waking the coroutines waiting for it with `payload`. Fails once
+    /// the scheduler has been dropped.
+    pub fn fire(&self, name: &str, payload: Vec<u8>) -> Result<(), String> {
+        if self.wakeups.send(Wakeup::Event(name.to_string(), payload)).is_err() {
+            log_and_return_err!("Can't fire event {}, since the scheduler has been dropped", name);
+        }
+        return Ok(());
+    }
+}
+
+// Fire `name` every `interval`, with the number of times it has fired as an i64.
+pub(crate) fn start_timer(sender: EventSender, name: String, interval: Duration) {
+    thread::spawn(move || {
+        for ticks in 1i64.. {
+            thread::sleep(interval);
+            if sender.fire(&name, ticks.to_ne_bytes().to_vec()).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+// Fire `name` whenever the file at `path` is created, changed, or removed, checking every
+// `poll_interval`, with the number of changes seen as an i64.
+pub(crate) fn watch_file(sender: EventSender, name: String, path: PathBuf, poll_interval: Duration) {
+    let modified = |path: &PathBuf| -> Option<SystemTime> {
+        return std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    };
+    thread::spawn(move || {
+        let mut last = modified(&path);
+        let mut changes = 0i64;
+        loop {
+            thread::sleep(poll_interval);
+            let now = modified(&path);
+            if now == last {
+                continue;
+            }
+            last = now;
+            changes += 1;
+            if sender.fire(&name, changes.to_ne_bytes().to_vec()).is_err() {
+                return;
+            }
+        }
+    });
+}
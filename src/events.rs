@@ -0,0 +1,49 @@
+//! ConcordeVM's lifecycle events.
+//!
+//! Hosts can subscribe to a CPU or a scheduler to be told what the guest is doing as it happens,
+//! instead of polling. Listeners are called in the order they subscribed, and must not subscribe
+//! more listeners from inside a call.
+
+/// Something the guest did.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A program was loaded, with this many instructions, to start at `entrypoint`.
+    ProgramLoaded { instructions: usize, entrypoint: usize },
+    /// A jump instruction at `from` moved execution to `to`.
+    Jump { from: usize, to: usize },
+    /// The Return at `pc` returned the `n` bytes at `address` from the coroutine.
+    Return { pc: usize, address: usize, n: usize },
+    /// The stream named `name` was opened under the symbol `symbol`.
+    StreamOpened { symbol: usize, name: String },
+    /// A coroutine was spawned, starting at `pc`. Only schedulers know coroutine ids, so only
+    /// their subscribers get this.
+    CoroutineSpawned { id: usize, pc: usize },
+    /// The program stopped at `pc`, by running off its end, reaching EOF, or raising `error`.
+    Halted { pc: usize, error: Option<String> },
+}
+
+/// A host function that's told about events.
+pub type Listener = Box<dyn FnMut(&Event)>;
+
+/// The listeners subscribed to a CPU, or to every CPU of a scheduler.
+#[derive(Default)]
+pub(crate) struct EventBus {
+    listeners: Vec<Listener>,
+}
+
+impl EventBus {
+    pub(crate) fn subscribe(&mut self, listener: Listener) {
+        self.listeners.push(listener);
+    }
+
+    /// Whether anyone is listening, so events don't have to be built for nobody.
+    pub(crate) fn has_listeners(&self) -> bool {
+        return !self.listeners.is_empty();
+    }
+
+    pub(crate) fn publish(&mut self, event: &Event) {
+        for listener in self.listeners.iter_mut() {
+            listener(event);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! ConcordeVM's execution log.
+//!
+//! Writes a line of JSON for every instruction a CPU executes, for tools that analyze runs. Each
+//! line is an object with these fields:
+//!
+//! - `pc`: the index of the instruction
+//! - `opcode`: its opcode, as in `bytecode::opcode`
+//! - `instruction`: the name of the instruction, like `AddSymbols`
+//! - `operands`: its operands, written as they are in the instruction's `Debug` output
+//! - `duration_ns`: how long it took to execute, in nanoseconds
+//! - `error`: the error it raised, or `null`
+//!
+//! Runs of instructions fused into a superinstruction are logged as one line for the instruction
+//! they start at.
+//!
+//! Logs can be read back with `read_trace`, and replayed with `CPU::replay`, which checks that a
+//! program does exactly what the log says it did. Together with an IO recording, this turns a log
+//! from a failed run elsewhere into a session that can be debugged locally.
+
+use crate::bytecode::opcode;
+use crate::log_and_return_err;
+
+use concordeisa::instructions::Instruction;
+use log::error;
+use serde_json::{json, Value};
+use std::io::{BufRead, Write};
+use std::time::Duration;
+
+/// Where CPUs write their execution logs.
+pub struct ExecutionLog {
+    writer: Box<dyn Write>,
+}
+
+impl ExecutionLog {
+    /// Write the log to `writer`.
+    pub fn new(writer: impl Write + 'static) -> ExecutionLog {
+        ExecutionLog { writer: Box::new(writer) }
+    }
+
+    /// Add a line for the instruction at `pc`, which took `duration` and raised `error`, if any.
+    pub fn record(&mut self, pc: usize, instruction: &Instruction, duration: Duration, error: Option<&str>) -> Result<(), String> {
+        let (name, operands) = describe(instruction);
+        let line = json!({
+            "pc": pc,
+            "opcode": opcode(instruction),
+            "instruction": name,
+            "operands": operands,
+            "duration_ns": duration.as_nanos() as u64,
+            "error": error,
+        });
+        if let Err(e) = writeln!(self.writer, "{}", line) {
+            log_and_return_err!("Failed to write execution log: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Write out anything the writer has buffered.
+    pub fn flush(&mut self) -> Result<(), String> {
+        if let Err(e) = self.writer.flush() {
+            log_and_return_err!("Failed to flush execution log: {}", e);
+        }
+        Ok(())
+    }
+}
+
+/// A line of an execution log, read back for replay.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceStep {
+    pub pc: usize,
+    pub instruction: String,
+    pub operands: String,
+    pub error: Option<String>,
+}
+
+/// Read every line of an execution log.
+pub fn read_trace(reader: impl BufRead) -> Result<Vec<TraceStep>, String> {
+    let mut steps = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => log_and_return_err!("Failed to read execution log: {}", e),
+        };
+        let value: Value = match serde_json::from_str(&line) {
+            Ok(value) => value,
+            Err(e) => log_and_return_err!("Line {} of the execution log is not JSON: {}", index + 1, e),
+        };
+        let step = (|| Some(TraceStep {
+            pc: value.get("pc")?.as_u64()? as usize,
+            instruction: value.get("instruction")?.as_str()?.to_string(),
+            operands: value.get("operands")?.as_str()?.to_string(),
+            error: match value.get("error")? {
+                Value::Null => None,
+                error => Some(error.as_str()?.to_string()),
+            },
+        }))();
+        let Some(step) = step else {
+            log_and_return_err!("Line {} of the execution log is missing a field", index + 1);
+        };
+        steps.push(step);
+    }
+    return Ok(steps);
+}
+
+// The name of an instruction, and its operands as written in its `Debug` output.
+pub(crate) fn describe(instruction: &Instruction) -> (String, String) {
+    let debug = format!("{:?}", instruction);
+    return match debug.split_once('(') {
+        Some((name, operands)) => (name.to_string(), operands.strip_suffix(')').unwrap_or(operands).to_string()),
+        None => (debug, String::new()),
+    };
+}
@@ -0,0 +1,73 @@
+//! ConcordeVM's string formatting.
+//!
+//! Fills in templates for the FormatString instruction. Each placeholder takes the next item of
+//! a list of 8 byte items, laid out like the lists ForEach loops over:
+//!
+//! - `{}`: the item as a decimal i64
+//! - `{x}`: the item as a hexadecimal i64, in two's complement if it's negative
+//! - `{f}`: an f32 in the first 4 bytes of the item
+//! - `{b}`: a bool in the first byte of the item, as `true` or `false`
+//! - `{s}`: the NUL-terminated string at the address in the item
+//!
+//! `{{` and `}}` stand for literal braces. A template must use every item it's given.
+
+use crate::log_and_return_err;
+
+use log::error;
+
+/// Fill in `template` with `items`, reading the strings `{s}` refers to with `string`.
+pub(crate) fn format(template: &str, items: &[i64], string: impl Fn(usize) -> Result<String, String>) -> Result<String, String> {
+    let mut output = String::new();
+    let mut items = items.iter();
+    let mut used = 0;
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c == '}' {
+            if chars.next() != Some('}') {
+                log_and_return_err!("Unmatched }} in template {:?}", template);
+            }
+            output.push('}');
+            continue;
+        }
+        if c != '{' {
+            output.push(c);
+            continue;
+        }
+
+        let mut code = String::new();
+        loop {
+            match chars.next() {
+                Some('{') if code.is_empty() => {
+                    output.push('{');
+                    break;
+                },
+                Some('}') => {
+                    let Some(&item) = items.next() else {
+                        log_and_return_err!("Template {:?} has more placeholders than the {} items given", template, used);
+                    };
+                    used += 1;
+                    let bytes = item.to_ne_bytes();
+                    let field = match code.as_str() {
+                        "" => item.to_string(),
+                        "x" => format!("{:x}", item),
+                        "f" => f32::from_ne_bytes(bytes[..4].try_into().unwrap()).to_string(),
+                        "b" => (bytes[0] == 1).to_string(),
+                        "s" => match usize::try_from(item) {
+                            Ok(address) => string(address)?,
+                            Err(_) => log_and_return_err!("Item {} for {{s}} in template {:?} isn't an address", item, template),
+                        },
+                        _ => log_and_return_err!("Unknown placeholder {{{}}} in template {:?}", code, template),
+                    };
+                    output.push_str(&field);
+                    break;
+                },
+                Some(c) => code.push(c),
+                None => log_and_return_err!("Unmatched {{ in template {:?}", template),
+            }
+        }
+    }
+    if items.len() > 0 {
+        log_and_return_err!("Template {:?} only uses {} of the {} items given", template, used, used + items.len());
+    }
+    return Ok(output);
+}
@@ -0,0 +1,115 @@
+//! ConcordeVM's superinstruction fusion.
+//!
+//! An optional pass that finds common pairs of instructions and replaces them with a single fused
+//! operation, saving a dispatch and a round trip through memory each time the pair runs.
+//!
+//! Fused operations are kept in a table alongside the program rather than in place of its
+//! instructions, so jump targets keep their meaning. A jump to the second instruction of a fused
+//! pair just runs that instruction on its own.
+
+use crate::cpu::Program;
+use crate::instructions::Interrupt;
+use crate::memory::Memory;
+
+use concordeisa::instructions::Instruction;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Comparison {
+    Equal,
+    Greater,
+    Lesser,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Operand {
+    Symbol(usize),
+    Literal(i64),
+}
+
+/// An operation standing in for a pair of instructions.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Fused {
+    /// A comparison writing to `dest`, followed by a jump conditional on `dest`. The jump is
+    /// taken if the comparison result equals `jump_when`.
+    CompareJump { comparison: Comparison, a: usize, b: Operand, dest: usize, target: usize, jump_when: bool },
+    /// A write of an integer literal, followed by an addition using it.
+    WriteIntAdd { symbol: usize, value: i64, a: usize, b: usize, dest: usize },
+}
+
+fn comparison(instruction: &Instruction) -> Option<(Comparison, usize, Operand, usize)> {
+    match *instruction {
+        Instruction::CompareEqual(a, b, dest) => Some((Comparison::Equal, a, Operand::Symbol(b), dest)),
+        Instruction::CompareGreater(a, b, dest) => Some((Comparison::Greater, a, Operand::Symbol(b), dest)),
+        Instruction::CompareLesser(a, b, dest) => Some((Comparison::Lesser, a, Operand::Symbol(b), dest)),
+        Instruction::CompareEqualImmediate(a, b, dest) => Some((Comparison::Equal, a, Operand::Literal(b), dest)),
+        Instruction::CompareGreaterImmediate(a, b, dest) => Some((Comparison::Greater, a, Operand::Literal(b), dest)),
+        Instruction::CompareLesserImmediate(a, b, dest) => Some((Comparison::Lesser, a, Operand::Literal(b), dest)),
+        _ => None,
+    }
+}
+
+fn fuse_pair(first: &Instruction, second: &Instruction) -> Option<Fused> {
+    if let Some((comparison, a, b, dest)) = comparison(first) {
+        return match *second {
+            Instruction::JumpIfTrue(target, condition) if condition == dest =>
+                Some(Fused::CompareJump { comparison, a, b, dest, target, jump_when: true }),
+            Instruction::JumpIfFalse(target, condition) if condition == dest =>
+                Some(Fused::CompareJump { comparison, a, b, dest, target, jump_when: false }),
+            _ => None,
+        };
+    }
+    if let (Instruction::WriteIntToSymbol(symbol, value), Instruction::AddSymbols(a, b, dest)) = (first, second)
+        && (*symbol == *a || *symbol == *b) {
+        return Some(Fused::WriteIntAdd { symbol: *symbol, value: *value, a: *a, b: *b, dest: *dest });
+    }
+    None
+}
+
+/// Find every fusable pair, returning the fused operation to run at each index, if any.
+/// Pairs don't overlap, so the second instruction of a pair never starts another.
+pub(crate) fn fuse(instructions: &[Instruction]) -> Vec<Option<Fused>> {
+    let mut table = vec![None; instructions.len()];
+    let mut index = 0;
+    while index + 1 < instructions.len() {
+        if let Some(fused) = fuse_pair(&instructions[index], &instructions[index + 1]) {
+            table[index] = Some(fused);
+            index += 2;
+        } else {
+            index += 1;
+        }
+    }
+    return table;
+}
+
+/// Run a fused operation with the same effect on memory and the pc as its pair of instructions.
+pub(crate) fn execute_fused(memory: &mut Memory, program: &mut Program, fused: &Fused) -> Result<Interrupt, String> {
+    match *fused {
+        Fused::CompareJump { comparison, a, b, dest, target, jump_when } => {
+            let a_data = memory.read_typed::<i64>(a);
+            let b_data = match b {
+                Operand::Symbol(b) => memory.read_typed::<i64>(b),
+                Operand::Literal(b) => b,
+            };
+            let result = match comparison {
+                Comparison::Equal => a_data == b_data,
+                Comparison::Greater => a_data > b_data,
+                Comparison::Lesser => a_data < b_data,
+            };
+            memory.store(dest, &result)?;
+            if result == jump_when {
+                program.jump(target);
+            } else {
+                program.pc += 2;
+            }
+        },
+        Fused::WriteIntAdd { symbol, value, a, b, dest } => {
+            memory.store(symbol, &value)?;
+            let a_data = if a == symbol { value } else { memory.read_typed::<i64>(a) };
+            let b_data = if b == symbol { value } else { memory.read_typed::<i64>(b) };
+            let result = program.arithmetic.fit(a_data as i128 + b_data as i128, || format!("{} + {}", a_data, b_data))?;
+            memory.store(dest, &result)?;
+            program.pc += 2;
+        },
+    }
+    return Ok(Interrupt::Ok);
+}
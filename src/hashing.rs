@@ -0,0 +1,57 @@
+//! ConcordeVM's hash functions.
+//!
+//! Provides the digests behind the Hash instruction, so programs can checksum data or key
+//! tables by it without loading a native domain.
+
+use crate::log_and_return_err;
+
+use log::error;
+use sha2::{Digest, Sha256};
+
+/// The hash functions guest programs can use.
+///
+/// Algorithms are encoded as integers in the ISA, in the order they are declared here.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashAlgorithm {
+    /// CRC-32 (IEEE), written as 4 big-endian bytes.
+    Crc32,
+    /// SHA-256, 32 bytes.
+    Sha256,
+    /// BLAKE3, 32 bytes.
+    Blake3,
+}
+
+impl TryFrom<usize> for HashAlgorithm {
+    type Error = String;
+
+    fn try_from(code: usize) -> Result<Self, Self::Error> {
+        match code {
+            0 => Ok(HashAlgorithm::Crc32),
+            1 => Ok(HashAlgorithm::Sha256),
+            2 => Ok(HashAlgorithm::Blake3),
+            _ => log_and_return_err!("Unknown hash algorithm {}", code),
+        }
+    }
+}
+
+impl HashAlgorithm {
+    /// Hash `data`, returning the digest.
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Crc32 => crc32(data).to_be_bytes().to_vec(),
+            HashAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+            HashAlgorithm::Blake3 => blake3::hash(data).as_bytes().to_vec(),
+        }
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    return !crc;
+}
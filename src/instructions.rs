@@ -2,95 +2,346 @@
 //!
 //! Provides a function to execute arbitrary instructions as defined by the ConcordeISA.
 
-use crate::cpu::Program;
-use crate::io::ConcordeIO;
+use crate::bigint::BigInt;
+use crate::bytecode::{opcode, OPCODE_COUNT};
+use crate::cpu::{ListLoop, LoopKind, Program};
+use crate::crypto::{self, KEY_LEN, NONCE_LEN};
+use crate::formatting;
+use crate::hashing::HashAlgorithm;
+use crate::io::{ConcordeIO, OpenMode};
+use crate::linker::{self, Module};
+use crate::lists::{Items, ListKind};
+use crate::log_and_return_err;
 use crate::memory::{ByteParseable, ByteSerialisable, Memory};
+use crate::packing;
+use crate::timestamps;
+use crate::verifier;
 use libffi::middle::Type;
+use std::cmp::Ordering;
+use std::rc::Rc;
 
 use concordeisa::{instructions::Instruction};
 
-use log::info;
+use log::{error, info};
 
-/// Execute the given instruction and increment the execution pointer.
-/// Return an error if something goes wrong. (eg. division by zero, or accessing invalid memory)
-//
-/// Currently, each instruction from the enum maps to a function of the same name in a `match` statement. There
-/// may be a better way to do this that's more extensible. We also handle incrementing the stack
-/// only when we need to in the same way, so there's room for improvement.
+/// A function that runs one kind of instruction. It's given the instruction itself, and should
+/// not move the pc, which `execute_instruction` does afterwards for everything except jumps.
+pub type Handler = fn(&mut Memory, &mut ConcordeIO, &mut Program, &Instruction) -> Result<Interrupt, String>;
+
+/// Maps each opcode to the `Handler` that runs it, so dispatch costs the same however many
+/// instructions there are.
+#[derive(Clone)]
+pub struct DispatchTable {
+    handlers: Vec<Handler>,
+}
+
+thread_local! {
+    static STANDARD_TABLE: Rc<DispatchTable> = Rc::new(DispatchTable { handlers: standard_handlers() });
+}
+
+impl DispatchTable {
+    /// The table for the standard instruction set. It's built once per thread and shared.
+    pub fn standard() -> Rc<DispatchTable> {
+        return STANDARD_TABLE.with(Rc::clone);
+    }
+
+    /// Run `handler` in place of the current handler for `opcode`.
+    pub fn register(&mut self, opcode: u8, handler: Handler) -> Result<(), String> {
+        let Some(slot) = self.handlers.get_mut(opcode as usize) else {
+            log_and_return_err!("Opcode {} is not in the instruction set", opcode);
+        };
+        *slot = handler;
+        return Ok(());
+    }
+}
+
+/// Execute the given instruction with its handler from `dispatch`, and increment the execution
+/// pointer. Return an error if something goes wrong. (eg. division by zero, or accessing invalid memory)
 pub fn execute_instruction(
     memory: &mut Memory,
     io: &mut ConcordeIO,
     program: &mut Program,
+    dispatch: &DispatchTable,
 ) -> Result<Interrupt, String> {
-    let instruction = program.get_instruction().clone();
+    // Holding our own handle to the block lets us borrow the instruction instead of cloning it,
+    // while still passing the program mutably to jumps.
+    let instructions = Rc::clone(&program.instructions);
+    let instruction = &instructions[program.pc];
     info!("Executing instruction {:?}", instruction);
 
-    let result = match instruction {
-        // Immediate writes
-        Instruction::WriteStringToSymbol(symbol, ref value) => write_to_symbol::<String>(memory, symbol, &value),
-        Instruction::WriteIntToSymbol(symbol, value) => write_to_symbol::<i64>(memory, symbol, &value),
-        Instruction::WriteBoolToSymbol(symbol, value) => write_to_symbol::<bool>(memory, symbol, &value),
-        Instruction::WriteBytesToSymbol(symbol, ref value) => write_to_symbol::<Vec<u8>>(memory, symbol, value),
-
-        // Memory management
-        Instruction::MemCpy(source, dest, n) => copy_symbol(memory, source, dest, n),
-        Instruction::MemExtend(n_bytes) => extend_memory(memory, n_bytes),
-        Instruction::MemExtendTo(n_bytes)=> extend_memory_to(memory, n_bytes),
-        Instruction::Ind(addr_location, dest, n) => ind(memory, addr_location, dest, n),
-
-        // Arithmetic (force integral ops to i64)
-        Instruction::AddSymbols(a, b, dest) => add_symbols::<i64>(memory, a, b, dest),
-        Instruction::SubtractSymbols(a, b, dest) => subtract_symbols::<i64>(memory, a, b, dest),
-        Instruction::MultiplySymbols(a, b, dest) => multiply_symbols::<i64>(memory, a, b, dest),
-        Instruction::DivideSymbols(a, b, dest) => divide_symbols::<i64>(memory, a, b, dest),
-        Instruction::ModuloSymbols(a, b, dest) => modulo_symbols::<i64>(memory, a, b, dest),
-        Instruction::MinSymbols(a, b, dest) => min_symbols::<i64>(memory, a, b, dest),
-        Instruction::MaxSymbols(a, b, dest) => max_symbols::<i64>(memory, a, b, dest),
-        Instruction::FmaSymbols(a, b, c, dest) => fma_symbols::<i64>(memory, a, b, c, dest),
-
-        // Trig (force to f32)
-        Instruction::SinSymbol(a, dest) => sin_symbol::<f32>(memory, a, dest),
-        Instruction::CosSymbol(a, dest) => cos_symbol::<f32>(memory, a, dest),
-        Instruction::TanSymbol(a, dest) => tan_symbol::<f32>(memory, a, dest),
-        Instruction::ArcsinSymbol(a, dest) => arcsin_symbol::<f32>(memory, a, dest),
-        Instruction::ArccosSymbol(a, dest) => arccos_symbol::<f32>(memory, a, dest),
-        Instruction::ArctanSymbol(a, dest) => arctan_symbol::<f32>(memory, a, dest),
-
-        // Comparisons (also integral -> i64)
-        Instruction::CompareEqual(a, b, dest) => compare_equal::<i64>(memory, a, b, dest),
-        Instruction::CompareGreater(a, b, dest) => compare_greater::<i64>(memory, a, b, dest),
-        Instruction::CompareLesser(a, b, dest) => compare_lesser::<i64>(memory, a, b, dest),
-
-        // Flow control
-        Instruction::Jump(target) => jump(program, target),
-        Instruction::JumpIfTrue(target, condition) => jump_if_true(memory, program, target, condition),
-        Instruction::Await(fut_id_location, return_write_addr) => Ok(Interrupt::Await(memory.read_typed::<usize>(fut_id_location), return_write_addr)),
-        Instruction::CreateCoroutine(dest, arg_addr, n_arg_bytes, write_coro_id_addr) => Ok(Interrupt::CreateCoroutine(dest, arg_addr, n_arg_bytes, write_coro_id_addr)),
-        Instruction::Return(address, n) => ret(address, n),
-        Instruction::DeleteFuture(future_id) => delete_future(future_id),
-
-        
-        Instruction::LoadSO(domain_id, ref lib_path) => Ok(Interrupt::LoadSO(domain_id, lib_path.clone())),
-        Instruction::AddFFIFn(domain_id, function_id, ref function_name, ref arg_types, ref ret_type) => Ok(Interrupt::AddFFIFn(domain_id, function_id, function_name.clone(), arg_types.clone(), ret_type.clone())),
-        Instruction::CallFFIFn(domain_id, function_id, arg_addr, n_arg_bytes, ret_addr) => Ok(Interrupt::CallFFIFn(domain_id, function_id, arg_addr, n_arg_bytes, ret_addr)),
-
-        // Misc.
-        Instruction::NoOp() => Ok(Interrupt::Ok),
-
-        #[allow(unreachable_patterns)]
-        _ => Err("Unimplemented operation!".to_string()),
-    };
+    let handler = dispatch.handlers[opcode(instruction) as usize];
+    let result = handler(memory, io, program, instruction);
 
     // We don't want to increment the stack after jumping, since it'll start execution from the
     // second instruction as a result.
-    match instruction {
-        Instruction::Jump(_) | Instruction::JumpIfTrue(_, _) => {}
+    match *instruction {
+        Instruction::Jump(_) | Instruction::JumpIfTrue(_, _) | Instruction::JumpIfFalse(_, _) | Instruction::Switch(_, _, _) => {}
+        // These move the pc themselves, since they may jump into or out of a loop body.
+        Instruction::ForEach(..) | Instruction::MapList(..) | Instruction::FilterList(..) | Instruction::ReduceList(..) | Instruction::Return(..) | Instruction::ReturnFromInterrupt() => {}
+        // An instruction that yields runs again when the coroutine is resumed.
+        _ if matches!(result, Ok(Interrupt::Yield)) => {}
         _ => program.increment(),
     };
 
     result
 }
 
+// Builds the standard handlers from a list of `opcode: Variant(fields) => body` entries. Each body
+// can use the instruction's fields, along with the names given for the memory, IO, and program.
+macro_rules! standard_handlers {
+    ($memory:ident, $io:ident, $program:ident; $($opcode:literal: $variant:ident($($field:pat),*) => $body:expr),* $(,)?) => {
+        #[allow(unused_variables)]
+        fn standard_handlers() -> Vec<Handler> {
+            let unimplemented: Handler = |_, _, _, _| Err("Unimplemented operation!".to_string());
+            let mut handlers = vec![unimplemented; OPCODE_COUNT];
+            $(
+                let handler: Handler = |$memory, $io, $program, instruction| {
+                    let Instruction::$variant($($field),*) = *instruction else {
+                        unreachable!("instructions are dispatched by opcode");
+                    };
+                    $body
+                };
+                handlers[$opcode] = handler;
+            )*
+            return handlers;
+        }
+    };
+}
+
+// Opcodes are the ones from `bytecode::opcode`.
+standard_handlers! { memory, io, program;
+    // Immediate writes
+    0: WriteStringToSymbol(symbol, ref value) => write_to_symbol::<String>(memory, symbol, &value),
+    1: WriteIntToSymbol(symbol, value) => write_to_symbol::<i64>(memory, symbol, &value),
+    2: WriteBoolToSymbol(symbol, value) => write_to_symbol::<bool>(memory, symbol, &value),
+    3: WriteBytesToSymbol(symbol, ref value) => write_to_symbol::<Vec<u8>>(memory, symbol, value),
+
+    // Memory management
+    4: MemCpy(source, dest, n) => copy_symbol(memory, source, dest, n),
+    5: MemExtend(n_bytes) => extend_memory(memory, n_bytes),
+    6: MemExtendTo(n_bytes) => extend_memory_to(memory, n_bytes),
+    7: Ind(addr_location, dest, n) => ind(memory, addr_location, dest, n),
+    66: MakeConst(symbol, n) => make_const(memory, symbol, n),
+
+    // Bytes
+    67: ConcatBytes(a, a_len, b, b_len, dest) => concat_bytes(memory, a, a_len, b, b_len, dest),
+    68: FillBytes(dest, value, n) => fill_bytes(memory, dest, value, n),
+    69: CopyBytesRange(src, src_offset, dest, dest_offset, n) => copy_bytes_range(memory, src, src_offset, dest, dest_offset, n),
+    70: FindBytes(haystack, haystack_len, needle, needle_len, dest) => find_bytes(memory, haystack, haystack_len, needle, needle_len, dest),
+    71: EncodeIntLE(bytes, offset, width, value) => encode_int(memory, bytes, offset, width, value, Endianness::Little),
+    72: EncodeIntBE(bytes, offset, width, value) => encode_int(memory, bytes, offset, width, value, Endianness::Big),
+    73: DecodeIntLE(bytes, offset, width, dest) => decode_int(memory, bytes, offset, width, dest, Endianness::Little),
+    74: DecodeIntBE(bytes, offset, width, dest) => decode_int(memory, bytes, offset, width, dest, Endianness::Big),
+    75: PackStruct(format, values, dest) => pack_struct(memory, format, values, dest),
+    76: UnpackStruct(format, bytes, dest) => unpack_struct(memory, format, bytes, dest),
+    77: Hash(algorithm, bytes, n, dest) => hash(memory, algorithm, bytes, n, dest),
+
+    // Cryptography
+    78: AeadEncrypt(key, nonce, src, n, dest) => aead_encrypt(memory, key, nonce, src, n, dest),
+    79: AeadDecrypt(key, nonce, src, n, dest) => aead_decrypt(memory, key, nonce, src, n, dest),
+    80: RandomBytes(n, dest) => random_bytes(memory, io, n, dest),
+    81: RandomInt(min, max, dest) => random_int(memory, io, min, max, dest),
+
+    // Time
+    82: NowUnixMillis(dest) => write_time(memory, dest, io.clock().unix_millis()),
+    83: MonotonicNanos(dest) => write_time(memory, dest, io.clock().monotonic_nanos()),
+    84: FormatTimestamp(millis, format, dest) => format_timestamp(memory, millis, format, dest),
+    132: FormatString(template, args, dest) => format_string(memory, template, args, dest),
+    85: ParseTimestamp(string, format, dest) => parse_timestamp(memory, string, format, dest),
+
+    // Arithmetic (force integral ops to i64), with overflow handled by the program's mode
+    8: AddSymbols(a, b, dest) => int_arithmetic(memory, a, b, dest, program.arithmetic, "+", |a, b| a + b),
+    9: SubtractSymbols(a, b, dest) => int_arithmetic(memory, a, b, dest, program.arithmetic, "-", |a, b| a - b),
+    10: MultiplySymbols(a, b, dest) => int_arithmetic(memory, a, b, dest, program.arithmetic, "*", |a, b| a * b),
+    11: DivideSymbols(a, b, dest) => divide_symbols(memory, a, b, dest, program.arithmetic),
+    12: ModuloSymbols(a, b, dest) => modulo_symbols(memory, a, b, dest),
+    13: MinSymbols(a, b, dest) => min_symbols::<i64>(memory, a, b, dest),
+    14: MaxSymbols(a, b, dest) => max_symbols::<i64>(memory, a, b, dest),
+    15: FmaSymbols(a, b, c, dest) => fma_symbols(memory, a, b, c, dest, program.arithmetic),
+
+    // Arithmetic with its own overflow handling, whatever the program's mode
+    86: CheckedAdd(a, b, dest) => int_arithmetic(memory, a, b, dest, ArithmeticMode::Trap, "+", |a, b| a + b),
+    87: CheckedSubtract(a, b, dest) => int_arithmetic(memory, a, b, dest, ArithmeticMode::Trap, "-", |a, b| a - b),
+    88: CheckedMultiply(a, b, dest) => int_arithmetic(memory, a, b, dest, ArithmeticMode::Trap, "*", |a, b| a * b),
+    89: WrappingAdd(a, b, dest) => int_arithmetic(memory, a, b, dest, ArithmeticMode::Wrapping, "+", |a, b| a + b),
+    90: WrappingSubtract(a, b, dest) => int_arithmetic(memory, a, b, dest, ArithmeticMode::Wrapping, "-", |a, b| a - b),
+    91: WrappingMultiply(a, b, dest) => int_arithmetic(memory, a, b, dest, ArithmeticMode::Wrapping, "*", |a, b| a * b),
+
+    // Big integers
+    92: BigFromInt(a, dest) => write_bigint(memory, dest, &BigInt::from_i64(memory.read_typed::<i64>(a))),
+    93: BigToInt(a, dest) => big_to_int(memory, a, dest),
+    94: BigFromString(string, dest) => write_bigint(memory, dest, &BigInt::parse(&memory.read_string(string))?),
+    95: BigToString(a, dest) => big_to_string(memory, a, dest),
+    96: BigAdd(a, b, dest) => big_arithmetic(memory, a, b, dest, |a, b| Ok(a.add(b))),
+    97: BigSubtract(a, b, dest) => big_arithmetic(memory, a, b, dest, |a, b| Ok(a.subtract(b))),
+    98: BigMultiply(a, b, dest) => big_arithmetic(memory, a, b, dest, |a, b| Ok(a.multiply(b))),
+    99: BigDivide(a, b, dest) => big_arithmetic(memory, a, b, dest, |a, b| Ok(big_divide(a, b)?.0)),
+    100: BigModulo(a, b, dest) => big_arithmetic(memory, a, b, dest, |a, b| Ok(big_divide(a, b)?.1)),
+    101: BigPowMod(base, exponent, modulus, dest) => big_pow_mod(memory, base, exponent, modulus, dest),
+    102: BigCompareEqual(a, b, dest) => big_compare(memory, a, b, dest, |ordering| ordering.is_eq()),
+    103: BigCompareGreater(a, b, dest) => big_compare(memory, a, b, dest, |ordering| ordering.is_gt()),
+    104: BigCompareLesser(a, b, dest) => big_compare(memory, a, b, dest, |ordering| ordering.is_lt()),
+
+    // Arithmetic with a literal operand
+    51: AddImmediate(a, literal, dest) => apply_immediate(memory, a, literal, dest, |a, b| program.arithmetic.fit(a as i128 + b as i128, || format!("{} + {}", a, b))),
+    52: SubtractImmediate(a, literal, dest) => apply_immediate(memory, a, literal, dest, |a, b| program.arithmetic.fit(a as i128 - b as i128, || format!("{} - {}", a, b))),
+    53: MultiplyImmediate(a, literal, dest) => apply_immediate(memory, a, literal, dest, |a, b| program.arithmetic.fit(a as i128 * b as i128, || format!("{} * {}", a, b))),
+    54: DivideImmediate(a, literal, dest) => apply_immediate(memory, a, literal, dest, |a, b| divide(a, b, program.arithmetic)),
+    55: ModuloImmediate(a, literal, dest) => apply_immediate(memory, a, literal, dest, |a, b| a.checked_rem(b).ok_or_else(|| format!("Tried to take {} modulo {}", a, b))),
+
+    // Trig (force to f32)
+    16: SinSymbol(a, dest) => sin_symbol::<f32>(memory, a, dest),
+    17: CosSymbol(a, dest) => cos_symbol::<f32>(memory, a, dest),
+    18: TanSymbol(a, dest) => tan_symbol::<f32>(memory, a, dest),
+    19: ArcsinSymbol(a, dest) => arcsin_symbol::<f32>(memory, a, dest),
+    20: ArccosSymbol(a, dest) => arccos_symbol::<f32>(memory, a, dest),
+    21: ArctanSymbol(a, dest) => arctan_symbol::<f32>(memory, a, dest),
+
+    // Float math (also f32)
+    105: SqrtSymbol(a, dest) => float_unary(memory, a, dest, f32::sqrt),
+    106: PowSymbols(a, b, dest) => float_binary(memory, a, b, dest, f32::powf),
+    107: AbsSymbol(a, dest) => float_unary(memory, a, dest, f32::abs),
+    108: FloorSymbol(a, dest) => float_unary(memory, a, dest, f32::floor),
+    109: CeilSymbol(a, dest) => float_unary(memory, a, dest, f32::ceil),
+    110: FloatMinSymbols(a, b, dest) => float_binary(memory, a, b, dest, f32::min),
+    111: FloatMaxSymbols(a, b, dest) => float_binary(memory, a, b, dest, f32::max),
+    112: ExpSymbol(a, dest) => float_unary(memory, a, dest, f32::exp),
+    113: LnSymbol(a, dest) => float_unary(memory, a, dest, f32::ln),
+    114: Arctan2Symbols(y, x, dest) => float_binary(memory, y, x, dest, f32::atan2),
+
+    // Comparisons (also integral -> i64)
+    22: CompareEqual(a, b, dest) => compare_equal::<i64>(memory, a, b, dest),
+    23: CompareGreater(a, b, dest) => compare_greater::<i64>(memory, a, b, dest),
+    24: CompareLesser(a, b, dest) => compare_lesser::<i64>(memory, a, b, dest),
+    115: CompareStringsEqual(a, b, dest) => compare_strings(memory, a, b, dest, Ordering::is_eq),
+    116: CompareStringsGreater(a, b, dest) => compare_strings(memory, a, b, dest, Ordering::is_gt),
+    117: CompareStringsLesser(a, b, dest) => compare_strings(memory, a, b, dest, Ordering::is_lt),
+    118: CompareBoolsEqual(a, b, dest) => compare_equal::<bool>(memory, a, b, dest),
+    119: CompareFloatsEqual(a, b, dest) => compare_equal::<f32>(memory, a, b, dest),
+    120: CompareFloatsGreater(a, b, dest) => compare_greater::<f32>(memory, a, b, dest),
+    121: CompareFloatsLesser(a, b, dest) => compare_lesser::<f32>(memory, a, b, dest),
+    122: CompareBytesEqual(a, a_len, b, b_len, dest) => compare_bytes(memory, a, a_len, b, b_len, dest, Ordering::is_eq),
+    123: CompareBytesGreater(a, a_len, b, b_len, dest) => compare_bytes(memory, a, a_len, b, b_len, dest, Ordering::is_gt),
+    124: CompareBytesLesser(a, a_len, b, b_len, dest) => compare_bytes(memory, a, a_len, b, b_len, dest, Ordering::is_lt),
+    56: CompareEqualImmediate(a, literal, dest) => apply_immediate(memory, a, literal, dest, |a, b| Ok(a == b)),
+    57: CompareGreaterImmediate(a, literal, dest) => apply_immediate(memory, a, literal, dest, |a, b| Ok(a > b)),
+    58: CompareLesserImmediate(a, literal, dest) => apply_immediate(memory, a, literal, dest, |a, b| Ok(a < b)),
+
+    // I/O
+    25: OpenStream(name, stream, mode) => open_stream(memory, io, name, stream, mode),
+    26: CloseStream(stream) => close_stream(io, stream),
+    27: FlushStream(stream) => flush_stream(io, stream),
+    28: ReadStream(stream, n, dest) => read_stream(memory, io, stream, n, dest),
+    29: WriteStream(stream, n, src) => write_stream(memory, io, stream, n, src),
+    30: ReadLine(stream, dest) => read_line(memory, io, stream, dest),
+    131: PrintSymbol(symbol) => print_symbol(memory, io, symbol),
+    31: ReadFileToSymbol(path, dest) => read_file_to_symbol(memory, io, path, dest),
+    32: WriteSymbolToFile(path, src, atomic) => write_symbol_to_file(memory, io, path, src, atomic),
+    33: RenameFile(from, to) => rename_file(memory, io, from, to),
+    34: DeleteFile(path) => delete_file(memory, io, path),
+    35: CopyFile(from, to) => copy_file(memory, io, from, to),
+    36: GetEnv(name, dest) => get_env(memory, io, name, dest),
+    37: SetEnv(name, value) => set_env(memory, io, name, value),
+    38: GetArgs(dest) => get_args(memory, io, dest),
+    39: SpawnProcess(cmd, args, stdin, stdout, stderr, dest_pid) => spawn_process(memory, io, cmd, args, stdin, stdout, stderr, dest_pid),
+    40: WaitProcess(pid, dest_status) => wait_process(memory, io, pid, dest_status),
+
+    // Flow control
+    41: Jump(target) => jump(program, target),
+    42: JumpIfTrue(target, condition) => jump_if_true(memory, program, target, condition),
+    60: JumpIfFalse(target, condition) => jump_if_false(memory, program, target, condition),
+    59: Switch(value, ref cases, default) => switch(memory, program, value, cases, default),
+    43: Await(fut_id_location, return_write_addr) => Ok(Interrupt::Await(memory.read_typed::<usize>(fut_id_location), return_write_addr)),
+    144: AwaitEvent(name, dest) => Ok(Interrupt::AwaitEvent(memory.read_string(name), dest)),
+    153: Select(futures, dest) => select(memory, futures, dest),
+    44: CreateCoroutine(dest, arg_addr, n_arg_bytes, write_coro_id_addr) => Ok(Interrupt::CreateCoroutine(dest, arg_addr, n_arg_bytes, write_coro_id_addr)),
+    151: BeginScope() => Ok(Interrupt::BeginScope),
+    152: EndScope(cancel) => Ok(Interrupt::EndScope(cancel)),
+    62: CreateCoroutineIndirect(dest_location, arg_addr, n_arg_bytes, write_coro_id_addr) => Ok(Interrupt::CreateCoroutine(memory.read_typed::<usize>(dest_location), arg_addr, n_arg_bytes, write_coro_id_addr)),
+    61: Import(name, dest) => import(memory, io, program, name, dest),
+    142: LoadCode(bytes, n, dest) => load_code(memory, program, bytes, n, dest),
+    147: GetStackDepth(dest) => Ok(Interrupt::GetStackDepth(dest)),
+    148: GetCallerSymbol(dest) => Ok(Interrupt::GetCallerSymbol(dest)),
+    149: ListCodeBlocks(dest) => list_code_blocks(memory, program, dest),
+    150: BlockLength(name, dest) => block_length(memory, program, name, dest),
+    161: InvokeHostValue(value, args, n_args, dest) => invoke_host_value(memory, value, args, n_args, dest),
+    45: Return(address, n) => ret(memory, program, address, n),
+    125: ForEach(list, item, body) => for_each(memory, program, list, item, body),
+    126: SortList(list, kind, ascending) => sort_list(memory, list, kind, ascending),
+    127: BinarySearch(list, kind, value, dest) => binary_search(memory, list, kind, value, dest),
+    128: MapList(list, item, block, dest) => map_list(memory, program, list, item, block, dest),
+    129: FilterList(list, item, block, dest) => filter_list(memory, program, list, item, block, dest),
+    130: ReduceList(list, item, block, accumulator) => reduce_list(memory, program, list, item, block, accumulator),
+    46: DeleteFuture(future_id) => delete_future(future_id),
+
+    47: LoadSO(domain_id, ref lib_path) => Ok(Interrupt::LoadSO(domain_id, lib_path.clone())),
+    48: AddFFIFn(domain_id, function_id, ref function_name, ref arg_types, ref ret_type) => Ok(Interrupt::AddFFIFn(domain_id, function_id, function_name.clone(), arg_types.clone(), ret_type.clone())),
+    49: CallFFIFn(domain_id, function_id, arg_addr, n_arg_bytes, ret_addr) => Ok(Interrupt::CallFFIFn(domain_id, function_id, arg_addr, n_arg_bytes, ret_addr)),
+    160: UnloadDomain(domain_id) => Ok(Interrupt::UnloadDomain(domain_id)),
+
+    // Transactions
+    63: BeginTransaction() => begin_transaction(memory),
+    64: CommitTransaction() => commit_transaction(memory),
+    65: RollbackTransaction() => rollback_transaction(memory),
+
+    // Shared regions
+    133: CreateSharedRegion(region, n) => create_shared_region(memory, region, n),
+    134: AtomicAdd(region, offset, value, dest) => atomic_add(memory, region, offset, value, dest),
+    135: CompareAndSwap(region, offset, expected, new, dest) => compare_and_swap(memory, region, offset, expected, new, dest),
+    136: Lock(region) => lock(memory, region),
+    137: Unlock(region) => unlock(memory, region),
+    138: ReadShared(region, offset, n, dest) => read_shared(memory, region, offset, n, dest),
+    139: WriteShared(region, offset, n, src) => write_shared(memory, region, offset, n, src),
+
+    // Actors
+    140: SpawnActor(handler, message_size, write_actor_id) => Ok(Interrupt::SpawnActor(handler, message_size, write_actor_id)),
+    141: SendMessage(actor_location, message) => Ok(Interrupt::SendMessage(memory.read_typed::<usize>(actor_location), message)),
+
+    // Interrupts
+    143: ReturnFromInterrupt() => return_from_interrupt(program),
+
+    // Mutexes and semaphores
+    154: MutexCreate(dest) => Ok(Interrupt::MutexCreate(dest)),
+    155: MutexLock(mutex) => Ok(Interrupt::MutexLock(memory.read_typed::<usize>(mutex))),
+    156: MutexUnlock(mutex) => Ok(Interrupt::MutexUnlock(memory.read_typed::<usize>(mutex))),
+    157: SemaphoreCreate(permits, dest) => Ok(Interrupt::SemaphoreCreate(read_count(memory, permits)?, dest)),
+    158: SemaphoreAcquire(semaphore) => Ok(Interrupt::SemaphoreAcquire(memory.read_typed::<usize>(semaphore))),
+    159: SemaphoreRelease(semaphore) => Ok(Interrupt::SemaphoreRelease(memory.read_typed::<usize>(semaphore))),
+
+    // Assertions
+    145: Assert(condition, message) => assert(memory, program, condition, message),
+    146: AssertType(symbol, ref type_name) => assert_type(memory, program, symbol, type_name),
+
+    // Misc.
+    50: NoOp() => Ok(Interrupt::Ok),
+}
+
+/// How integer arithmetic handles results that don't fit in an i64.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ArithmeticMode {
+    /// Fail with an overflow error.
+    Trap,
+    /// Wrap around in two's complement.
+    #[default]
+    Wrapping,
+    /// Clamp to `i64::MIN` or `i64::MAX`.
+    Saturating,
+}
+
+impl ArithmeticMode {
+    /// Fit the exact result of an operation into an i64. `describe` gives the operation, for the
+    /// error when trapping.
+    pub(crate) fn fit(self, exact: i128, describe: impl FnOnce() -> String) -> Result<i64, String> {
+        if let Ok(result) = i64::try_from(exact) {
+            return Ok(result);
+        }
+        match self {
+            ArithmeticMode::Trap => log_and_return_err!("Integer overflow: {} doesn't fit in an i64", describe()),
+            ArithmeticMode::Wrapping => Ok(exact as i64),
+            ArithmeticMode::Saturating => Ok(if exact < 0 { i64::MIN } else { i64::MAX }),
+        }
+    }
+}
 
 pub enum Interrupt {
     //    fut id, return write addr
@@ -105,18 +356,48 @@ pub enum Interrupt {
     LoadSO(usize, String),
     AddFFIFn(usize, usize, String, Vec<Type>, Type),
     CallFFIFn(usize, usize, usize, usize, usize),
+    UnloadDomain(usize),
+
+    // handler, message size, write actor id addr
+    SpawnActor(usize, usize, usize),
+    //        actor id, message addr
+    SendMessage(usize, usize),
+    //         event name, payload write addr
+    AwaitEvent(String, usize),
+    //    future ids, write index addr
+    Select(Vec<usize>, usize),
+    //          write id addr
+    MutexCreate(usize),
+    //        mutex id
+    MutexLock(usize),
+    MutexUnlock(usize),
+    //              permits, write id addr
+    SemaphoreCreate(usize, usize),
+    //               semaphore id
+    SemaphoreAcquire(usize),
+    SemaphoreRelease(usize),
+    //            write addr
+    GetStackDepth(usize),
+    GetCallerSymbol(usize),
+
+    BeginScope,
+    //     cancel
+    EndScope(bool),
+
+    // Give way to other coroutines, and retry the instruction once this one is resumed.
+    Yield,
 
     Ok,
     EOF
 }
 
 fn extend_memory(memory: &mut Memory, n_bytes: usize) -> Result<Interrupt, String> {
-    memory.extend_memory(n_bytes);
+    memory.extend_memory(n_bytes)?;
     return Ok(Interrupt::Ok)
 }
 
 fn extend_memory_to(memory: &mut Memory, n_bytes: usize) -> Result<Interrupt, String> {
-    memory.extend_memory_to(n_bytes);
+    memory.extend_memory_to(n_bytes)?;
     return Ok(Interrupt::Ok)
 }
 
@@ -124,9 +405,30 @@ fn delete_future(future_id: usize) -> Result<Interrupt, String> {
     return Ok(Interrupt::DeleteFuture(future_id));
 }
 
+fn begin_transaction(memory: &mut Memory) -> Result<Interrupt, String> {
+    memory.begin_transaction();
+    return Ok(Interrupt::Ok);
+}
+
+fn commit_transaction(memory: &mut Memory) -> Result<Interrupt, String> {
+    memory.commit_transaction()?;
+    return Ok(Interrupt::Ok);
+}
+
+fn rollback_transaction(memory: &mut Memory) -> Result<Interrupt, String> {
+    memory.rollback_transaction()?;
+    return Ok(Interrupt::Ok);
+}
+
+
+/// Freeze the `n` bytes at `symbol`, so any later instruction that writes them fails.
+fn make_const(memory: &mut Memory, symbol: usize, n: usize) -> Result<Interrupt, String> {
+    memory.freeze(symbol, n);
+    return Ok(Interrupt::Ok);
+}
 
 fn write_to_symbol<T: ByteSerialisable>(memory: &mut Memory, symbol: usize, value: &T) -> Result<Interrupt, String> {
-    memory.write(symbol, value);
+    memory.store(symbol, value)?;
     return Ok(Interrupt::Ok);
 }
 
@@ -166,107 +468,417 @@ impl FloatTrig for f64 {
 }
 
 
+/// Apply `op` to the float in `a`, and put the result in `dest`.
+fn float_unary(memory: &mut Memory, a: usize, dest: usize, op: fn(f32) -> f32) -> Result<Interrupt, String> {
+    let result = op(memory.read_typed::<f32>(a));
+    memory.store(dest, &result)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Combine the floats in `a` and `b` with `op`, and put the result in `dest`.
+fn float_binary(memory: &mut Memory, a: usize, b: usize, dest: usize, op: fn(f32, f32) -> f32) -> Result<Interrupt, String> {
+    let result = op(memory.read_typed::<f32>(a), memory.read_typed::<f32>(b));
+    memory.store(dest, &result)?;
+    return Ok(Interrupt::Ok);
+}
+
 /// Copy `n` bytes from actual memory address in `[ptr_index]` to dest
 /// This is different from memcpy which uses offsets from the stack base pointer
 fn ind(memory: &mut Memory, ptr_index: usize, dest: usize, n: usize) -> Result<Interrupt, String>{
     let source = memory.read_typed::<usize>(ptr_index);
-    copy_symbol(memory, source, dest, n);
+    copy_symbol(memory, source, dest, n)?;
     return Ok(Interrupt::Ok);
 }
 
+// Read a length or offset stored as an i64 at `symbol`.
+fn read_count(memory: &Memory, symbol: usize) -> Result<usize, String> {
+    let value = memory.read_typed::<i64>(symbol);
+    return match usize::try_from(value) {
+        Ok(count) => Ok(count),
+        Err(_) => log_and_return_err!("Expected a length or offset at symbol {}, but found {}", symbol, value),
+    };
+}
 
-/// Add the integers in `a` and `b` together, and put the result in `dest`.
-/// Returns an error if either `a` or `b` is undefined, or does not contain an integer.
-fn add_symbols<
-    T: ByteParseable + ByteSerialisable + std::ops::Add<T, Output = T> + 'static,
->(
-    memory: &mut Memory,
-    a: usize,
-    b: usize,
-    dest: usize,
-) -> Result<Interrupt, String> {
-    let a_data = memory.read_typed::<T>(a);
-    let b_data = memory.read_typed::<T>(b);
-    let result = a_data + b_data;
-    memory.write(dest, &result);
-    Ok(Interrupt::Ok)
+// Read `n` bytes at `address`, failing if they run past the end of memory.
+fn read_bytes(memory: &Memory, address: usize, n: usize) -> Result<Vec<u8>, String> {
+    if address.saturating_add(n) > memory.len() {
+        log_and_return_err!("Tried to read {} bytes at {}, but memory is only {} bytes", n, address, memory.len());
+    }
+    return Ok(memory.read(address, n));
 }
 
-/// Subtract the integer in `b` from `a`, and put the result in `dest`.
-fn subtract_symbols<
-    T: ByteParseable + ByteSerialisable + std::ops::Sub<T, Output = T> + 'static,
->(
-    memory: &mut Memory,
-    a: usize,
-    b: usize,
-    dest: usize,
-) -> Result<Interrupt, String> {
-    let a_data = memory.read_typed::<T>(a);
-    let b_data = memory.read_typed::<T>(b);
-    let result = a_data - b_data;
-    memory.write(dest, &result);
-    Ok(Interrupt::Ok)
+/// Put the bytes at `a` followed by the bytes at `b` in `dest`, with their lengths given by the
+/// i64s at `a_len` and `b_len`. Memory is extended if the result doesn't fit.
+fn concat_bytes(memory: &mut Memory, a: usize, a_len: usize, b: usize, b_len: usize, dest: usize) -> Result<Interrupt, String> {
+    let mut data = read_bytes(memory, a, read_count(memory, a_len)?)?;
+    data.extend(read_bytes(memory, b, read_count(memory, b_len)?)?);
+    memory.extend_memory_to(dest + data.len())?;
+    memory.store(dest, &data)?;
+    return Ok(Interrupt::Ok);
 }
 
-/// Multiply the integers in `a` and `b`, and put the result in `dest`.
-fn multiply_symbols<
-    T: ByteParseable + ByteSerialisable + std::ops::Mul<T, Output = T> + 'static,
->(
+/// Set the number of bytes given by the i64 at `n`, starting at `dest`, to the byte at `value`.
+/// Memory is extended if they don't fit.
+fn fill_bytes(memory: &mut Memory, dest: usize, value: usize, n: usize) -> Result<Interrupt, String> {
+    let byte = memory.read_typed::<u8>(value);
+    let n = read_count(memory, n)?;
+    memory.extend_memory_to(dest + n)?;
+    memory.store(dest, &vec![byte; n])?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Copy bytes from `src` to `dest`, each offset by the i64s at `src_offset` and `dest_offset`,
+/// with the number of bytes given by the i64 at `n`. Memory is extended if they don't fit.
+fn copy_bytes_range(memory: &mut Memory, src: usize, src_offset: usize, dest: usize, dest_offset: usize, n: usize) -> Result<Interrupt, String> {
+    let data = read_bytes(memory, src + read_count(memory, src_offset)?, read_count(memory, n)?)?;
+    let dest = dest + read_count(memory, dest_offset)?;
+    memory.extend_memory_to(dest + data.len())?;
+    memory.store(dest, &data)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Find the first occurrence of the bytes at `needle` in the bytes at `haystack`, with their
+/// lengths given by the i64s at `needle_len` and `haystack_len`. The offset it was found at is
+/// written to `dest` as an i64, or -1 if it wasn't found.
+fn find_bytes(memory: &mut Memory, haystack: usize, haystack_len: usize, needle: usize, needle_len: usize, dest: usize) -> Result<Interrupt, String> {
+    let haystack = read_bytes(memory, haystack, read_count(memory, haystack_len)?)?;
+    let needle = read_bytes(memory, needle, read_count(memory, needle_len)?)?;
+    let offset = if needle.is_empty() {
+        Some(0)
+    } else {
+        haystack.windows(needle.len()).position(|window| window == needle.as_slice())
+    };
+    memory.store(dest, &offset.map_or(-1, |offset| offset as i64))?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Compare the strings in `a` and `b` byte by byte, and put whether `test` accepts the ordering in
+/// `dest` as a bool.
+fn compare_strings(memory: &mut Memory, a: usize, b: usize, dest: usize, test: fn(Ordering) -> bool) -> Result<Interrupt, String> {
+    let result = test(memory.read_string(a).cmp(&memory.read_string(b)));
+    memory.store(dest, &result)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Compare the bytes at `a` and `b`, with lengths in `[a_len]` and `[b_len]`, lexicographically,
+/// and put whether `test` accepts the ordering in `dest` as a bool. A prefix sorts first.
+fn compare_bytes(memory: &mut Memory, a: usize, a_len: usize, b: usize, b_len: usize, dest: usize, test: fn(Ordering) -> bool) -> Result<Interrupt, String> {
+    let a = read_bytes(memory, a, read_count(memory, a_len)?)?;
+    let b = read_bytes(memory, b, read_count(memory, b_len)?)?;
+    memory.store(dest, &test(a.cmp(&b)))?;
+    return Ok(Interrupt::Ok);
+}
+
+#[derive(Clone, Copy)]
+enum Endianness {
+    Little,
+    Big,
+}
+
+// Check that an integer is being encoded as 1, 2, 4 or 8 bytes.
+fn check_width(width: usize) -> Result<(), String> {
+    if !matches!(width, 1 | 2 | 4 | 8) {
+        log_and_return_err!("Integers can be encoded as 1, 2, 4 or 8 bytes, not {}", width);
+    }
+    return Ok(());
+}
+
+/// Write the low `width` bytes of the i64 at `value` into the bytes at `bytes`, at the offset given
+/// by the i64 at `offset`, in the given byte order. Memory is extended if they don't fit.
+fn encode_int(memory: &mut Memory, bytes: usize, offset: usize, width: usize, value: usize, endianness: Endianness) -> Result<Interrupt, String> {
+    check_width(width)?;
+    let dest = bytes + read_count(memory, offset)?;
+    let value = memory.read_typed::<i64>(value);
+    let data = match endianness {
+        Endianness::Little => value.to_le_bytes()[..width].to_vec(),
+        Endianness::Big => value.to_be_bytes()[8 - width..].to_vec(),
+    };
+    memory.extend_memory_to(dest + width)?;
+    memory.store(dest, &data)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Read a `width` byte unsigned integer in the given byte order from the bytes at `bytes`, at the
+/// offset given by the i64 at `offset`, and write it to `dest` as an i64.
+fn decode_int(memory: &mut Memory, bytes: usize, offset: usize, width: usize, dest: usize, endianness: Endianness) -> Result<Interrupt, String> {
+    check_width(width)?;
+    let data = read_bytes(memory, bytes + read_count(memory, offset)?, width)?;
+    let mut padded = [0u8; 8];
+    let value = match endianness {
+        Endianness::Little => {
+            padded[..width].copy_from_slice(&data);
+            i64::from_le_bytes(padded)
+        },
+        Endianness::Big => {
+            padded[8 - width..].copy_from_slice(&data);
+            i64::from_be_bytes(padded)
+        },
+    };
+    memory.store(dest, &value)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Pack the i64s starting at `values` into a record laid out by the struct format string in
+/// `format`, and put it in `dest`. See `packing` for the format. Memory is extended if the record
+/// doesn't fit.
+fn pack_struct(memory: &mut Memory, format: usize, values: usize, dest: usize) -> Result<Interrupt, String> {
+    let format = memory.read_string(format);
+    let (n_values, _) = packing::shape(&format)?;
+    let values: Vec<i64> = read_bytes(memory, values, n_values * 8)?
+        .chunks(8)
+        .map(i64::from_bytes)
+        .collect();
+    let data = packing::pack(&format, &values)?;
+    memory.extend_memory_to(dest + data.len())?;
+    memory.store(dest, &data)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Unpack the record at `bytes`, laid out by the struct format string in `format`, into one i64
+/// per field starting at `dest`. Memory is extended if they don't fit.
+fn unpack_struct(memory: &mut Memory, format: usize, bytes: usize, dest: usize) -> Result<Interrupt, String> {
+    let format = memory.read_string(format);
+    let (_, size) = packing::shape(&format)?;
+    let values = packing::unpack(&format, &read_bytes(memory, bytes, size)?)?;
+    let data: Vec<u8> = values.iter().flat_map(|value| value.to_bytes()).collect();
+    memory.extend_memory_to(dest + data.len())?;
+    memory.store(dest, &data)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Hash the bytes at `bytes`, with their length given by the i64 at `n`, using the algorithm
+/// encoded by `algorithm`, and put the digest in `dest`. Memory is extended if it doesn't fit.
+fn hash(memory: &mut Memory, algorithm: usize, bytes: usize, n: usize, dest: usize) -> Result<Interrupt, String> {
+    let algorithm = HashAlgorithm::try_from(algorithm)?;
+    let digest = algorithm.digest(&read_bytes(memory, bytes, read_count(memory, n)?)?);
+    memory.extend_memory_to(dest + digest.len())?;
+    memory.store(dest, &digest)?;
+    return Ok(Interrupt::Ok);
+}
+
+// Read the key and nonce for an AEAD instruction.
+fn read_key_and_nonce(memory: &Memory, key: usize, nonce: usize) -> Result<([u8; KEY_LEN], [u8; NONCE_LEN]), String> {
+    let key = read_bytes(memory, key, KEY_LEN)?.try_into().unwrap();
+    let nonce = read_bytes(memory, nonce, NONCE_LEN)?.try_into().unwrap();
+    return Ok((key, nonce));
+}
+
+/// Encrypt the bytes at `src`, with their length given by the i64 at `n`, using ChaCha20-Poly1305
+/// with the 32 byte key at `key` and the 12 byte nonce at `nonce`. The ciphertext, followed by a
+/// 16 byte tag, is put in `dest`. Fails unless ConcordeVM was built with the aead feature.
+fn aead_encrypt(memory: &mut Memory, key: usize, nonce: usize, src: usize, n: usize, dest: usize) -> Result<Interrupt, String> {
+    let (key, nonce) = read_key_and_nonce(memory, key, nonce)?;
+    let sealed = crypto::seal(&key, &nonce, &read_bytes(memory, src, read_count(memory, n)?)?)?;
+    memory.extend_memory_to(dest + sealed.len())?;
+    memory.store(dest, &sealed)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Decrypt the output of AeadEncrypt at `src`, with its length including the tag given by the i64
+/// at `n`, and put the plaintext in `dest`. Returns an error, and writes nothing, if the tag
+/// doesn't match.
+fn aead_decrypt(memory: &mut Memory, key: usize, nonce: usize, src: usize, n: usize, dest: usize) -> Result<Interrupt, String> {
+    let (key, nonce) = read_key_and_nonce(memory, key, nonce)?;
+    let plaintext = crypto::open(&key, &nonce, &read_bytes(memory, src, read_count(memory, n)?)?)?;
+    memory.extend_memory_to(dest + plaintext.len())?;
+    memory.store(dest, &plaintext)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Put as many random bytes as the i64 at `n` in `dest`, from the IO interface's generator.
+fn random_bytes(memory: &mut Memory, io: &mut ConcordeIO, n: usize, dest: usize) -> Result<Interrupt, String> {
+    let n = read_count(memory, n)?;
+    let bytes = io.rng()?.bytes(n);
+    memory.extend_memory_to(dest + bytes.len())?;
+    memory.store(dest, &bytes)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Put a random integer between the i64s at `min` and `max`, inclusive, in `dest`.
+/// Returns an error if `min` is greater than `max`.
+fn random_int(memory: &mut Memory, io: &mut ConcordeIO, min: usize, max: usize, dest: usize) -> Result<Interrupt, String> {
+    let min = memory.read_typed::<i64>(min);
+    let max = memory.read_typed::<i64>(max);
+    if min > max {
+        log_and_return_err!("Can't pick a random integer between {} and {}", min, max);
+    }
+    let value = io.rng()?.int_in(min, max);
+    memory.store(dest, &value)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Put a time read from the IO interface's clock in `dest`, as an i64.
+fn write_time(memory: &mut Memory, dest: usize, time: i64) -> Result<Interrupt, String> {
+    memory.store(dest, &time)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Format the timestamp in milliseconds in the i64 at `millis` with the format string in `format`,
+/// and put the result in `dest` as a NUL-terminated string. Memory is extended if it doesn't fit.
+fn format_timestamp(memory: &mut Memory, millis: usize, format: usize, dest: usize) -> Result<Interrupt, String> {
+    let mut text = timestamps::format(memory.read_typed::<i64>(millis), &memory.read_string(format))?.into_bytes();
+    text.push(0);
+    memory.extend_memory_to(dest + text.len())?;
+    memory.store(dest, &text)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Wait until the first of the futures in the int list at `futures` is complete, and write its
+/// index in the list to `dest` as an i64. Its value can then be had with Await, without waiting.
+fn select(memory: &Memory, futures: usize, dest: usize) -> Result<Interrupt, String> {
+    let Items::Int(items) = Items::read(memory, futures, ListKind::Int)? else { unreachable!() };
+    if items.is_empty() {
+        log_and_return_err!("Select needs at least one future");
+    }
+    return Ok(Interrupt::Select(items.into_iter().map(|id| id as usize).collect(), dest));
+}
+
+/// Fill in the template string in `template` with the items of the list at `args`, and put the
+/// result in `dest` as a NUL-terminated string. Memory is extended if the result doesn't fit.
+fn format_string(memory: &mut Memory, template: usize, args: usize, dest: usize) -> Result<Interrupt, String> {
+    let Items::Int(items) = Items::read(memory, args, ListKind::Int)? else { unreachable!() };
+    let string = |address: usize| {
+        if address >= memory.len() {
+            log_and_return_err!("Tried to format the string at {}, but memory is only {} bytes", address, memory.len());
+        }
+        return Ok(memory.read_string(address));
+    };
+    let mut text = formatting::format(&memory.read_string(template), &items, string)?.into_bytes();
+    text.push(0);
+    memory.extend_memory_to(dest + text.len())?;
+    memory.store(dest, &text)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Parse the string in `string` with the format string in `format`, and put the timestamp in
+/// milliseconds in `dest` as an i64. Returns an error if the string doesn't match the format.
+fn parse_timestamp(memory: &mut Memory, string: usize, format: usize, dest: usize) -> Result<Interrupt, String> {
+    let millis = timestamps::parse(&memory.read_string(string), &memory.read_string(format))?;
+    memory.store(dest, &millis)?;
+    return Ok(Interrupt::Ok);
+}
+
+
+/// Combine the i64s at `a` and `b`, and put the result in `dest`. `op` works out the exact
+/// result, which is fitted into an i64 by `mode`, so overflow is handled the way it says.
+fn int_arithmetic(
     memory: &mut Memory,
     a: usize,
     b: usize,
     dest: usize,
+    mode: ArithmeticMode,
+    symbol: &str,
+    op: fn(i128, i128) -> i128,
 ) -> Result<Interrupt, String> {
-    let a_data = memory.read_typed::<T>(a);
-    let b_data = memory.read_typed::<T>(b);
-    let result = a_data * b_data;
-    memory.write(dest, &result);
-    Ok(Interrupt::Ok)
+    let a_data = memory.read_typed::<i64>(a);
+    let b_data = memory.read_typed::<i64>(b);
+    let result = mode.fit(op(a_data as i128, b_data as i128), || format!("{} {} {}", a_data, symbol, b_data))?;
+    memory.store(dest, &result)?;
+    return Ok(Interrupt::Ok);
 }
 
 /// Divide the integer in `a` by `b`, and put the result in `dest`.
 /// Returns an error if `b` is zero.
-fn divide_symbols<
-    T: ByteParseable
-        + ByteSerialisable
-        + std::ops::Div<T, Output = T>
-        + PartialEq
-        + Copy
-        + 'static,
->(
-    memory: &mut Memory,
-    a: usize,
-    b: usize,
-    dest: usize,
-) -> Result<Interrupt, String> {
-    let a_data = memory.read_typed::<T>(a);
-    let b_data = memory.read_typed::<T>(b);
-    let result = a_data / b_data;
-    memory.write(dest, &result);
-    Ok(Interrupt::Ok)
+fn divide_symbols(memory: &mut Memory, a: usize, b: usize, dest: usize, mode: ArithmeticMode) -> Result<Interrupt, String> {
+    let a_data = memory.read_typed::<i64>(a);
+    let b_data = memory.read_typed::<i64>(b);
+    let result = divide(a_data, b_data, mode)?;
+    memory.store(dest, &result)?;
+    return Ok(Interrupt::Ok);
+}
+
+// Only i64::MIN / -1 overflows, so dividing exactly is enough.
+fn divide(a: i64, b: i64, mode: ArithmeticMode) -> Result<i64, String> {
+    if b == 0 {
+        return Err(format!("Tried to divide {} by {}", a, b));
+    }
+    return mode.fit(a as i128 / b as i128, || format!("{} / {}", a, b));
 }
 
 /// Modulo the integer in `a` by `b`, and put the result in `dest`.
 /// Returns an error if `b` is zero.
-fn modulo_symbols<
-    T: ByteParseable
-        + ByteSerialisable
-        + std::ops::Rem<T, Output = T>
-        + PartialEq
-        + Copy
-        + 'static,
->(
+fn modulo_symbols(memory: &mut Memory, a: usize, b: usize, dest: usize) -> Result<Interrupt, String> {
+    let a_data = memory.read_typed::<i64>(a);
+    let b_data = memory.read_typed::<i64>(b);
+    if b_data == 0 {
+        return Err(format!("Tried to take {} modulo {}", a_data, b_data));
+    }
+    // The remainder always fits, even for i64::MIN % -1.
+    memory.store(dest, &a_data.wrapping_rem(b_data))?;
+    return Ok(Interrupt::Ok);
+}
+
+// Read a big integer: an i64 byte count, then that many bytes of two's complement.
+fn read_bigint(memory: &Memory, address: usize) -> Result<BigInt, String> {
+    let n = read_count(memory, address)?;
+    return Ok(BigInt::from_bytes(&read_bytes(memory, address + 8, n)?));
+}
+
+/// Put the big integer `value` in `dest`, as an i64 byte count followed by the bytes. Memory is
+/// extended if it doesn't fit.
+fn write_bigint(memory: &mut Memory, dest: usize, value: &BigInt) -> Result<Interrupt, String> {
+    let bytes = value.to_bytes();
+    let mut data = (bytes.len() as i64).to_bytes();
+    data.extend(bytes);
+    memory.extend_memory_to(dest + data.len())?;
+    memory.store(dest, &data)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Put the big integer in `a` in `dest` as an i64.
+/// Returns an error if it doesn't fit.
+fn big_to_int(memory: &mut Memory, a: usize, dest: usize) -> Result<Interrupt, String> {
+    let value = read_bigint(memory, a)?;
+    let Some(result) = value.to_i64() else {
+        log_and_return_err!("Integer overflow: {} doesn't fit in an i64", value);
+    };
+    memory.store(dest, &result)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Put the big integer in `a` in `dest` as a NUL-terminated decimal string.
+fn big_to_string(memory: &mut Memory, a: usize, dest: usize) -> Result<Interrupt, String> {
+    let mut text = read_bigint(memory, a)?.to_string().into_bytes();
+    text.push(0);
+    memory.extend_memory_to(dest + text.len())?;
+    memory.store(dest, &text)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Combine the big integers in `a` and `b` with `op`, and put the result in `dest`.
+fn big_arithmetic(
     memory: &mut Memory,
     a: usize,
     b: usize,
     dest: usize,
+    op: impl FnOnce(&BigInt, &BigInt) -> Result<BigInt, String>,
 ) -> Result<Interrupt, String> {
-    let a_data = memory.read_typed::<T>(a);
-    let b_data = memory.read_typed::<T>(b);
-   
-    let result = a_data % b_data;   // Kinda hard to deal with div by zero so we can just throw a runtime error
-    memory.write(dest, &result);
-    Ok(Interrupt::Ok)
+    let result = op(&read_bigint(memory, a)?, &read_bigint(memory, b)?)?;
+    return write_bigint(memory, dest, &result);
+}
+
+// Divide big integers the way DivideSymbols and ModuloSymbols divide i64s.
+fn big_divide(a: &BigInt, b: &BigInt) -> Result<(BigInt, BigInt), String> {
+    match a.divide(b) {
+        Some(result) => Ok(result),
+        None => Err(format!("Tried to divide {} by {}", a, b)),
+    }
+}
+
+/// Raise the big integer in `base` to the power in `exponent`, modulo the one in `modulus`, and
+/// put the result in `dest`. Returns an error if the exponent is negative or the modulus isn't
+/// positive.
+fn big_pow_mod(memory: &mut Memory, base: usize, exponent: usize, modulus: usize, dest: usize) -> Result<Interrupt, String> {
+    let result = read_bigint(memory, base)?.pow_mod(&read_bigint(memory, exponent)?, &read_bigint(memory, modulus)?)?;
+    return write_bigint(memory, dest, &result);
+}
+
+/// Compare the big integers in `a` and `b`, and put whether `test` accepts the ordering in `dest`
+/// as a bool.
+fn big_compare(memory: &mut Memory, a: usize, b: usize, dest: usize, test: fn(Ordering) -> bool) -> Result<Interrupt, String> {
+    let result = test(read_bigint(memory, a)?.cmp(&read_bigint(memory, b)?));
+    memory.store(dest, &result)?;
+    return Ok(Interrupt::Ok);
 }
 
 /// Minimum of `a` and `b`, put result in `dest`.
@@ -281,7 +893,7 @@ fn min_symbols<
     let a_data = memory.read_typed::<T>(a);
     let b_data = memory.read_typed::<T>(b);
     let result = if a_data <= b_data { a_data } else { b_data };
-    memory.write(dest, &result);
+    memory.store(dest, &result)?;
     Ok(Interrupt::Ok)
 }
 
@@ -297,11 +909,25 @@ fn max_symbols<
     let a_data = memory.read_typed::<T>(a);
     let b_data = memory.read_typed::<T>(b);
     let result = if a_data >= b_data { a_data } else { b_data };
-    memory.write(dest, &result);
+    memory.store(dest, &result)?;
     Ok(Interrupt::Ok)
 }
 
 /// Check if the values in `a` and `b` are equal, and put the bool result in `dest`.
+/// Combine the i64 at `a` with a literal, and write the result to `dest`.
+fn apply_immediate<R: ByteSerialisable>(
+    memory: &mut Memory,
+    a: usize,
+    literal: i64,
+    dest: usize,
+    op: impl FnOnce(i64, i64) -> Result<R, String>,
+) -> Result<Interrupt, String> {
+    let a_data = memory.read_typed::<i64>(a);
+    let result = op(a_data, literal)?;
+    memory.store(dest, &result)?;
+    Ok(Interrupt::Ok)
+}
+
 fn compare_equal<
     T: ByteParseable + ByteSerialisable + PartialEq + 'static,
 >(
@@ -313,7 +939,7 @@ fn compare_equal<
     let a_data = memory.read_typed::<T>(a);
     let b_data = memory.read_typed::<T>(b);
     let result = a_data == b_data;
-    memory.write(dest, &result);
+    memory.store(dest, &result)?;
     Ok(Interrupt::Ok)
 }
 
@@ -329,7 +955,7 @@ fn compare_greater<
     let a_data = memory.read_typed::<T>(a);
     let b_data = memory.read_typed::<T>(b);
     let result = a_data > b_data;
-    memory.write(dest, &result);
+    memory.store(dest, &result)?;
     Ok(Interrupt::Ok)
 }
 
@@ -345,24 +971,19 @@ fn compare_lesser<
     let a_data = memory.read_typed::<T>(a);
     let b_data = memory.read_typed::<T>(b);
     let result = a_data < b_data;
-    memory.write(dest, &result);
+    memory.store(dest, &result)?;
     Ok(Interrupt::Ok)
 }
 
-/// Perform fused multiply-add on the integers in `a`, `b`, and `c` (a * b + c), and put the result in `dest`.
-/// Returns an error if any of `a`, `b`, or `c` is undefined, or does not contain an integer.
-fn fma_symbols<T: ByteParseable + ByteSerialisable + std::ops::Add<T, Output = T> + std::ops::Mul<T, Output = T> + 'static>(
-    memory: &mut Memory,
-    a: usize,
-    b: usize,
-    c: usize,
-    dest: usize,
-) -> Result<Interrupt, String> {
-    let a_data = memory.read_typed::<T>(a);
-    let b_data = memory.read_typed::<T>(b);
-    let c_data = memory.read_typed::<T>(c);
-    let result = a_data * b_data + c_data;
-    memory.write(dest, &result);
+/// Multiply the integers in `a` and `b`, add `c`, and put the result in `dest`. Only the final
+/// result is fitted into an i64 by `mode`.
+fn fma_symbols(memory: &mut Memory, a: usize, b: usize, c: usize, dest: usize, mode: ArithmeticMode) -> Result<Interrupt, String> {
+    let a_data = memory.read_typed::<i64>(a);
+    let b_data = memory.read_typed::<i64>(b);
+    let c_data = memory.read_typed::<i64>(c);
+    let exact = a_data as i128 * b_data as i128 + c_data as i128;
+    let result = mode.fit(exact, || format!("{} * {} + {}", a_data, b_data, c_data))?;
+    memory.store(dest, &result)?;
     return Ok(Interrupt::Ok);
 }
 
@@ -374,7 +995,7 @@ fn sin_symbol<T: ByteParseable + ByteSerialisable + FloatTrig + 'static>(
 ) -> Result<Interrupt, String> {
     let a_data = memory.read_typed::<T>(a);
     let result = a_data.sin();
-    memory.write(dest, &result);
+    memory.store(dest, &result)?;
     Ok(Interrupt::Ok)
 }
 
@@ -386,7 +1007,7 @@ fn cos_symbol<T: ByteParseable + ByteSerialisable + FloatTrig + 'static>(
 ) -> Result<Interrupt, String> {
     let a_data = memory.read_typed::<T>(a);
     let result = a_data.cos();
-    memory.write(dest, &result);
+    memory.store(dest, &result)?;
     Ok(Interrupt::Ok)
 }
 
@@ -398,7 +1019,7 @@ fn tan_symbol<T: ByteParseable + ByteSerialisable + FloatTrig + 'static>(
 ) -> Result<Interrupt, String> {
     let a_data = memory.read_typed::<T>(a);
     let result = a_data.tan();
-    memory.write(dest, &result);
+    memory.store(dest, &result)?;
     Ok(Interrupt::Ok)
 }
 
@@ -410,7 +1031,7 @@ fn arcsin_symbol<T: ByteParseable + ByteSerialisable + FloatTrig + 'static>(
 ) -> Result<Interrupt, String> {
     let a_data = memory.read_typed::<T>(a);
     let result = a_data.asin();
-    memory.write(dest, &result);
+    memory.store(dest, &result)?;
     Ok(Interrupt::Ok)
 }
 
@@ -422,7 +1043,7 @@ fn arccos_symbol<T: ByteParseable + ByteSerialisable + FloatTrig + 'static>(
 ) -> Result<Interrupt, String> {
     let a_data = memory.read_typed::<T>(a);
     let result = a_data.acos();
-    memory.write(dest, &result);
+    memory.store(dest, &result)?;
     Ok(Interrupt::Ok)
 }
 
@@ -434,7 +1055,7 @@ fn arctan_symbol<T: ByteParseable + ByteSerialisable + FloatTrig + 'static>(
 ) -> Result<Interrupt, String> {
     let a_data = memory.read_typed::<T>(a);
     let result = a_data.atan();
-    memory.write(dest, &result);
+    memory.store(dest, &result)?;
     Ok(Interrupt::Ok)
 }
 
@@ -460,20 +1081,369 @@ fn jump_if_true(
     return Ok(Interrupt::Ok);
 }
 
+/// Jump execution to the target if the condition is false. Will not error.
+fn jump_if_false(
+    memory: &mut Memory,
+    stack: &mut Program,
+    target: usize,
+    condition: usize,
+) -> Result<Interrupt, String> {
+    let c = memory.read_typed::<bool>(condition);
+    if c {
+        stack.increment();
+    } else {
+        stack.jump(target);
+    }
+    return Ok(Interrupt::Ok);
+}
+
+/// Jump execution to the target of the case matching the i64 at `value`, or to `default` if no
+/// case matches. Will not error.
+fn switch(
+    memory: &mut Memory,
+    stack: &mut Program,
+    value: usize,
+    cases: &[(i64, usize)],
+    default: usize,
+) -> Result<Interrupt, String> {
+    let v = memory.read_typed::<i64>(value);
+    let target = cases.iter()
+        .find(|(case, _)| *case == v)
+        .map_or(default, |(_, target)| *target);
+    stack.jump(target);
+    return Ok(Interrupt::Ok);
+}
+
+/// Load the module named by the string in `name` from the module search path, and append its
+/// blocks to the running program. The index each block starts at is written to `dest` as an i64
+/// count followed by one usize per block, ready for CreateCoroutineIndirect.
+///
+/// Coroutines already running keep the program they had; ones created afterwards see the import.
+fn import(memory: &mut Memory, io: &mut ConcordeIO, program: &mut Program, name: usize, dest: usize) -> Result<Interrupt, String> {
+    let name = memory.read_string(name);
+    let module = Module::from_bytes(&io.read_module(&name)?)?;
+    let (instructions, starts) = linker::link_at(std::slice::from_ref(&module), program.instructions.len())?;
+    Rc::make_mut(&mut program.instructions).extend(instructions);
+    program.record_blocks(&module, &starts);
+    return write_block_starts(memory, starts, dest);
+}
+
+/// Load the module encoded in the number of bytes given by the i64 at `n`, starting at `bytes`,
+/// appending its blocks to the program. Every block must pass the verifier, or nothing is loaded.
+/// Writes where each block starts to `dest` like Import does.
+fn load_code(memory: &mut Memory, program: &mut Program, bytes: usize, n: usize, dest: usize) -> Result<Interrupt, String> {
+    let module = Module::from_bytes(&read_bytes(memory, bytes, read_count(memory, n)?)?)?;
+    let (instructions, starts) = linker::link_at(std::slice::from_ref(&module), program.instructions.len())?;
+    let combined: Vec<Instruction> = program.instructions.iter().cloned().chain(instructions).collect();
+    let problems: Vec<String> = starts.iter().flat_map(|start| verifier::verify(&combined, *start)).collect();
+    if !problems.is_empty() {
+        log_and_return_err!("Loaded code failed verification: {}", problems.join("; "));
+    }
+    program.instructions = Rc::new(combined);
+    program.record_blocks(&module, &starts);
+    return write_block_starts(memory, starts, dest);
+}
+
+// Write how many blocks were loaded, then where each starts, to `dest`.
+fn write_block_starts(memory: &mut Memory, starts: Vec<usize>, dest: usize) -> Result<Interrupt, String> {
+    let mut data = (starts.len() as i64).to_bytes();
+    for start in starts {
+        data.extend(start.to_bytes());
+    }
+    memory.extend_memory_to(dest + data.len())?;
+    memory.store(dest, &data)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Write the names of the program's linked blocks to `dest` as a string list, in the order they
+/// were laid out.
+fn list_code_blocks(memory: &mut Memory, program: &Program, dest: usize) -> Result<Interrupt, String> {
+    let names = program.block_names();
+    // Same layout as Memory::read_string_list expects
+    let mut data = (names.len() as i64).to_bytes();
+    for name in names {
+        data.extend(name.into_bytes());
+        data.push(0);
+    }
+    memory.extend_memory_to(dest + data.len())?;
+    memory.store(dest, &data)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Write the number of instructions in the block named by the string in `name` to `dest` as an i64.
+fn block_length(memory: &mut Memory, program: &Program, name: usize, dest: usize) -> Result<Interrupt, String> {
+    let name = memory.read_string(name);
+    let Some(range) = program.block(&name) else {
+        log_and_return_err!("No block named {} is loaded", name);
+    };
+    memory.extend_memory_to(dest + 8)?;
+    memory.store(dest, &(range.len() as i64))?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Carry on from where the running interrupt handler interrupted the program.
+fn return_from_interrupt(program: &mut Program) -> Result<Interrupt, String> {
+    let Some(pc) = program.interrupted.take() else {
+        log_and_return_err!("ReturnFromInterrupt used outside an interrupt handler");
+    };
+    program.jump(pc);
+    return Ok(Interrupt::Ok);
+}
+
+/// Fail with the string in `message` unless the bool in `condition` is true. Does nothing if the
+/// program's assertions are disabled.
+fn assert(memory: &Memory, program: &Program, condition: usize, message: usize) -> Result<Interrupt, String> {
+    if program.assertions && !memory.read_typed::<bool>(condition) {
+        log_and_return_err!("Assertion failed: {}", memory.read_string(message));
+    }
+    return Ok(Interrupt::Ok);
+}
+
+/// Fail unless `symbol` could hold a value of the type named by `type_name`: an `int`, `float`,
+/// `bool`, or `string`. Memory isn't typed, so this checks that the value fits in memory, that a
+/// bool is 0 or 1, and that a string is terminated and valid UTF-8. Does nothing if the program's
+/// assertions are disabled.
+fn assert_type(memory: &Memory, program: &Program, symbol: usize, type_name: &str) -> Result<Interrupt, String> {
+    if !program.assertions {
+        return Ok(Interrupt::Ok);
+    }
+    let holds = match type_name {
+        "int" => symbol + 8 <= memory.len(),
+        "float" => symbol + 4 <= memory.len(),
+        "bool" => symbol < memory.len() && memory.read(symbol, 1)[0] <= 1,
+        "string" => {
+            let bytes = memory.read(symbol.min(memory.len()), memory.len().saturating_sub(symbol));
+            bytes.iter().position(|byte| *byte == 0).is_some_and(|end| std::str::from_utf8(&bytes[..end]).is_ok())
+        },
+        _ => log_and_return_err!("AssertType names unknown type {}", type_name),
+    };
+    if !holds {
+        log_and_return_err!("Assertion failed: symbol {} does not hold a {}", symbol, type_name);
+    }
+    return Ok(Interrupt::Ok);
+}
+
+/// Call the host value whose handle is at `value` with the `n_args` bytes at `args`, writing what
+/// it returns to `dest`.
+fn invoke_host_value(memory: &mut Memory, value: usize, args: usize, n_args: usize, dest: usize) -> Result<Interrupt, String> {
+    let Some(f) = memory.host_value(memory.read_typed::<usize>(value)) else {
+        log_and_return_err!("Symbol {} does not hold a host value", value);
+    };
+    let returned = f(&memory.read(args, n_args))?;
+    memory.extend_memory_to(dest + returned.len())?;
+    memory.store(dest, &returned)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Create shared region `region` with `n` zeroed bytes, unless it already exists with that size.
+fn create_shared_region(memory: &mut Memory, region: usize, n: usize) -> Result<Interrupt, String> {
+    memory.shared().create(region, n)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Add the i64 in `value` to the one at `offset` in shared region `region`, wrapping on overflow,
+/// and put the one it replaced in `dest`. Yields while another coroutine holds the region's lock.
+fn atomic_add(memory: &mut Memory, region: usize, offset: usize, value: usize, dest: usize) -> Result<Interrupt, String> {
+    let value = memory.read_typed::<i64>(value);
+    let old = memory.shared().access(region, offset, 8, |bytes| {
+        let old = i64::from_ne_bytes(bytes.try_into().unwrap());
+        bytes.copy_from_slice(&old.wrapping_add(value).to_ne_bytes());
+        old
+    })?;
+    let Some(old) = old else {
+        return Ok(Interrupt::Yield);
+    };
+    memory.store(dest, &old)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Replace the i64 at `offset` in shared region `region` with the one in `new`, if it equals the
+/// one in `expected`. Put whether it was replaced in `dest`. Yields while another coroutine holds
+/// the region's lock.
+fn compare_and_swap(memory: &mut Memory, region: usize, offset: usize, expected: usize, new: usize, dest: usize) -> Result<Interrupt, String> {
+    let expected = memory.read_typed::<i64>(expected);
+    let new = memory.read_typed::<i64>(new);
+    let swapped = memory.shared().access(region, offset, 8, |bytes| {
+        let swapped = i64::from_ne_bytes(bytes.try_into().unwrap()) == expected;
+        if swapped {
+            bytes.copy_from_slice(&new.to_ne_bytes());
+        }
+        swapped
+    })?;
+    let Some(swapped) = swapped else {
+        return Ok(Interrupt::Yield);
+    };
+    memory.store(dest, &swapped)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Take the lock of shared region `region`, yielding until it's free if another coroutine holds
+/// it. Until it's unlocked, only this coroutine can access the region.
+fn lock(memory: &mut Memory, region: usize) -> Result<Interrupt, String> {
+    if !memory.shared().lock(region)? {
+        return Ok(Interrupt::Yield);
+    }
+    return Ok(Interrupt::Ok);
+}
+
+/// Release the lock of shared region `region`.
+fn unlock(memory: &mut Memory, region: usize) -> Result<Interrupt, String> {
+    memory.shared().unlock(region)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Copy `n` bytes at `offset` in shared region `region` to `dest`. Yields while another
+/// coroutine holds the region's lock.
+fn read_shared(memory: &mut Memory, region: usize, offset: usize, n: usize, dest: usize) -> Result<Interrupt, String> {
+    let Some(data) = memory.shared().access(region, offset, n, |bytes| bytes.to_vec())? else {
+        return Ok(Interrupt::Yield);
+    };
+    memory.store(dest, &data)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Copy the `n` bytes at `src` to `offset` in shared region `region`. Yields while another
+/// coroutine holds the region's lock.
+fn write_shared(memory: &mut Memory, region: usize, offset: usize, n: usize, src: usize) -> Result<Interrupt, String> {
+    let data = read_bytes(memory, src, n)?;
+    let written = memory.shared().access(region, offset, n, |bytes| bytes.copy_from_slice(&data))?;
+    if written.is_none() {
+        return Ok(Interrupt::Yield);
+    }
+    return Ok(Interrupt::Ok);
+}
+
 /// Return execution to the last symbol. Will not error.
-fn ret(address: usize, n: usize) -> Result<Interrupt, String> {
-    return Ok(Interrupt::Ret(address, n));
+fn ret(memory: &mut Memory, program: &mut Program, address: usize, n: usize) -> Result<Interrupt, String> {
+    let Some(each) = program.loops.last_mut() else {
+        program.increment();
+        return Ok(Interrupt::Ret(address, n));
+    };
+    // Inside a loop body, returning hands the value to the loop, which moves on to the next item.
+    let index = each.next - 1;
+    match each.kind {
+        LoopKind::Each => {},
+        LoopKind::Map(dest) => {
+            let value = returned_value(memory, address, n, 8, "MapList")?;
+            memory.store(list_item(dest, index), &value)?;
+        },
+        LoopKind::Filter(dest) => {
+            if returned_value(memory, address, n, 1, "FilterList")?[0] == 1 {
+                let item = read_bytes(memory, list_item(each.list, index), 8)?;
+                memory.store(list_item(dest, each.kept), &item)?;
+                each.kept += 1;
+            }
+        },
+        LoopKind::Reduce(accumulator) => {
+            let value = returned_value(memory, address, n, 8, "ReduceList")?;
+            memory.store(accumulator, &value)?;
+        },
+    }
+    return next_iteration(memory, program);
 }
 
-/// Open a stream in the IO interface.
+// Read the `n` bytes a loop body returned at `address`, which must be `expected` bytes long.
+fn returned_value(memory: &Memory, address: usize, n: usize, expected: usize, name: &str) -> Result<Vec<u8>, String> {
+    if n != expected {
+        log_and_return_err!("A {} block must return {} bytes, but returned {}", name, expected, n);
+    }
+    return read_bytes(memory, address, n);
+}
+
+// The address of item `index` of the list at `list`.
+fn list_item(list: usize, index: usize) -> usize {
+    return list.saturating_add(8).saturating_add(index.saturating_mul(8));
+}
+
+/// Run the block at `body` once for each item of the list at `list`, with the item copied to
+/// `item`, then carry on after the ForEach. Lists are an i64 count followed by that many 8 byte
+/// items. The body ends each iteration with a Return. The count is only read once, so the loop
+/// always ends, even if the body changes the list.
+fn for_each(memory: &mut Memory, program: &mut Program, list: usize, item: usize, body: usize) -> Result<Interrupt, String> {
+    return start_loop(memory, program, LoopKind::Each, list, item, body);
+}
+
+/// Like ForEach, but put the 8 bytes `block` returns for each item in the list at `dest`, which
+/// is extended to hold as many items as `list`.
+fn map_list(memory: &mut Memory, program: &mut Program, list: usize, item: usize, block: usize, dest: usize) -> Result<Interrupt, String> {
+    return start_loop(memory, program, LoopKind::Map(dest), list, item, block);
+}
+
+/// Like ForEach, but put the items `block` returns true for in the list at `dest`, in order.
+/// `dest` may be `list`, to filter it in place.
+fn filter_list(memory: &mut Memory, program: &mut Program, list: usize, item: usize, block: usize, dest: usize) -> Result<Interrupt, String> {
+    return start_loop(memory, program, LoopKind::Filter(dest), list, item, block);
+}
+
+/// Like ForEach, but replace the 8 bytes at `accumulator` with what `block` returns for each
+/// item, so the block can combine the accumulator and the item. The accumulator starts with
+/// whatever value it holds.
+fn reduce_list(memory: &mut Memory, program: &mut Program, list: usize, item: usize, block: usize, accumulator: usize) -> Result<Interrupt, String> {
+    return start_loop(memory, program, LoopKind::Reduce(accumulator), list, item, block);
+}
+
+fn start_loop(memory: &mut Memory, program: &mut Program, kind: LoopKind, list: usize, item: usize, body: usize) -> Result<Interrupt, String> {
+    let count = read_count(memory, list)?;
+    if let LoopKind::Map(dest) | LoopKind::Filter(dest) = kind {
+        memory.extend_memory_to(list_item(dest, count))?;
+    }
+    program.loops.push(ListLoop { kind, list, item, body, next: 0, count, kept: 0, resume: program.pc + 1 });
+    let result = next_iteration(memory, program);
+    if result.is_err() {
+        program.loops.pop();
+    }
+    return result;
+}
+
+// Copy the innermost loop's next item into place and jump to its body, or finish the loop if
+// every item is done.
+fn next_iteration(memory: &mut Memory, program: &mut Program) -> Result<Interrupt, String> {
+    let each = program.loops.last_mut().unwrap();
+    if each.next == each.count {
+        match each.kind {
+            LoopKind::Map(dest) => memory.store(dest, &(each.count as i64))?,
+            LoopKind::Filter(dest) => memory.store(dest, &(each.kept as i64))?,
+            LoopKind::Each | LoopKind::Reduce(_) => {},
+        }
+        let resume = each.resume;
+        program.loops.pop();
+        program.jump(resume);
+        return Ok(Interrupt::Ok);
+    }
+    let item = read_bytes(memory, list_item(each.list, each.next), 8)?;
+    memory.store(each.item, &item)?;
+    each.next += 1;
+    let body = each.body;
+    program.jump(body);
+    return Ok(Interrupt::Ok);
+}
+
+/// Sort the list of `kind` items at `list` in place, smallest first if `ascending`.
+fn sort_list(memory: &mut Memory, list: usize, kind: usize, ascending: bool) -> Result<Interrupt, String> {
+    let mut items = Items::read(memory, list, ListKind::try_from(kind)?)?;
+    items.sort(ascending);
+    items.write(memory, list)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Find the value at `value` in the sorted list of `kind` items at `list`, and put the index of
+/// its first appearance in `dest`, or -1 if it isn't there.
+fn binary_search(memory: &mut Memory, list: usize, kind: usize, value: usize, dest: usize) -> Result<Interrupt, String> {
+    let index = Items::read(memory, list, ListKind::try_from(kind)?)?.search(memory, value)?;
+    memory.store(dest, &index.map_or(-1, |index| index as i64))?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Open a stream in the IO interface, using the mode encoded by `mode`.
 fn open_stream(
     memory: &mut Memory,
     io: &mut ConcordeIO,
     name: usize,
     stream: usize,
+    mode: usize,
 ) -> Result<Interrupt, String> {
-    let name_data: String = memory.read_typed::<String>(name);
-    io.open(&stream, name_data.clone())?;
+    let name_data: String = memory.read_string(name);
+    io.open(&stream, name_data, OpenMode::try_from(mode)?)?;
     return Ok(Interrupt::Ok);
 }
 
@@ -483,6 +1453,12 @@ fn close_stream(io: &mut ConcordeIO, stream: usize) -> Result<Interrupt, String>
     return Ok(Interrupt::Ok);
 }
 
+/// Write out anything buffered for a stream in the IO interface.
+fn flush_stream(io: &mut ConcordeIO, stream: usize) -> Result<Interrupt, String> {
+    io.flush(&stream)?;
+    return Ok(Interrupt::Ok);
+}
+
 /// Read `n` bytes from `stream` and put the result in `dest`.
 fn read_stream(
     memory: &mut Memory,
@@ -493,7 +1469,29 @@ fn read_stream(
 ) -> Result<Interrupt, String> {
     let n_data = memory.read_typed::<i64>(n);
     let (read_data, _read_n) = io.read(&stream, usize::try_from(n_data).unwrap())?;
-    memory.write(dest, &read_data);
+    memory.store(dest, &read_data)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Print the string in `symbol` to the default output stream, without adding a newline.
+fn print_symbol(memory: &mut Memory, io: &mut ConcordeIO, symbol: usize) -> Result<Interrupt, String> {
+    io.print(memory.read_string(symbol).as_bytes())?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Read a line from `stream` and put it in `dest` as a NUL-terminated string.
+/// The trailing newline is kept, so an empty string means the end of the stream was reached.
+/// Memory is extended if the line doesn't fit.
+fn read_line(
+    memory: &mut Memory,
+    io: &mut ConcordeIO,
+    stream: usize,
+    dest: usize,
+) -> Result<Interrupt, String> {
+    let mut line = io.read_line(&stream)?;
+    line.push(0);
+    memory.extend_memory_to(dest + line.len())?;
+    memory.store(dest, &line)?;
     return Ok(Interrupt::Ok);
 }
 
@@ -505,8 +1503,105 @@ fn write_stream(
     n: usize,
     src: usize,
 ) -> Result<Interrupt, String> {
-    let write_data = memory.read_typed::<Vec<u8>>(src);
     let n_data = memory.read_typed::<i64>(n);
-    io.write(&stream, &write_data[..usize::try_from(n_data).unwrap()])?;
+    let write_data = memory.read(src, usize::try_from(n_data).unwrap());
+    io.write(&stream, &write_data)?;
+    return Ok(Interrupt::Ok);
+}
+/// Read the whole file named by the string in `path` into `dest`.
+/// The length of the file is written to `dest` as an i64, followed by its contents. Memory is
+/// extended if the file doesn't fit.
+fn read_file_to_symbol(memory: &mut Memory, io: &mut ConcordeIO, path: usize, dest: usize) -> Result<Interrupt, String> {
+    let contents = io.read_file(&memory.read_string(path))?;
+    let len = contents.len() as i64;
+    memory.extend_memory_to(dest + len.get_size() + contents.len())?;
+    memory.store(dest, &len)?;
+    memory.store(dest + len.get_size(), &contents)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Write the bytes in `src` to the file named by the string in `path`, replacing its contents.
+/// `src` holds the number of bytes as an i64, followed by the bytes themselves, as written by
+/// `ReadFileToSymbol`.
+fn write_symbol_to_file(memory: &mut Memory, io: &mut ConcordeIO, path: usize, src: usize, atomic: bool) -> Result<Interrupt, String> {
+    let len = memory.read_typed::<i64>(src);
+    let contents = memory.read(src + len.get_size(), usize::try_from(len).unwrap());
+    io.write_file(&memory.read_string(path), &contents, atomic)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Rename the file named by the string in `from` to the name in `to`.
+fn rename_file(memory: &mut Memory, io: &mut ConcordeIO, from: usize, to: usize) -> Result<Interrupt, String> {
+    io.rename_file(&memory.read_string(from), &memory.read_string(to))?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Delete the file named by the string in `path`.
+fn delete_file(memory: &mut Memory, io: &mut ConcordeIO, path: usize) -> Result<Interrupt, String> {
+    io.delete_file(&memory.read_string(path))?;
     return Ok(Interrupt::Ok);
-}
\ No newline at end of file
+}
+
+/// Copy the file named by the string in `from` to the name in `to`.
+fn copy_file(memory: &mut Memory, io: &mut ConcordeIO, from: usize, to: usize) -> Result<Interrupt, String> {
+    io.copy_file(&memory.read_string(from), &memory.read_string(to))?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Write the value of the environment variable named by the string in `name` to `dest` as a
+/// NUL-terminated string. Unset variables are written as an empty string.
+fn get_env(memory: &mut Memory, io: &mut ConcordeIO, name: usize, dest: usize) -> Result<Interrupt, String> {
+    let mut value = io.get_env(&memory.read_string(name))?.unwrap_or_default().into_bytes();
+    value.push(0);
+    memory.extend_memory_to(dest + value.len())?;
+    memory.store(dest, &value)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Set the environment variable named by the string in `name` to the string in `value`.
+fn set_env(memory: &mut Memory, io: &mut ConcordeIO, name: usize, value: usize) -> Result<Interrupt, String> {
+    io.set_env(&memory.read_string(name), &memory.read_string(value))?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Write the program's arguments to `dest`.
+/// The number of arguments is written as an i64, followed by each argument as a NUL-terminated
+/// string. Memory is extended if the arguments don't fit.
+fn get_args(memory: &mut Memory, io: &mut ConcordeIO, dest: usize) -> Result<Interrupt, String> {
+    let args = io.get_args();
+    // Same layout as Memory::read_string_list expects
+    let mut data = (args.len() as i64).to_bytes();
+    for arg in args {
+        data.extend(arg.into_bytes());
+        data.push(0);
+    }
+    memory.extend_memory_to(dest + data.len())?;
+    memory.store(dest, &data)?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Run the command named by the string in `cmd` with the string list in `args`, and write its pid
+/// to `dest_pid` as an i64. The child's pipes are opened as the streams `stdin`, `stdout`, and `stderr`.
+#[allow(clippy::too_many_arguments)]
+fn spawn_process(
+    memory: &mut Memory,
+    io: &mut ConcordeIO,
+    cmd: usize,
+    args: usize,
+    stdin: usize,
+    stdout: usize,
+    stderr: usize,
+    dest_pid: usize,
+) -> Result<Interrupt, String> {
+    let pid = io.spawn_process(&memory.read_string(cmd), &memory.read_string_list(args), stdin, stdout, stderr)?;
+    memory.store(dest_pid, &i64::from(pid))?;
+    return Ok(Interrupt::Ok);
+}
+
+/// Wait for the process whose pid is in `pid` to exit, and write its exit code to `dest_status` as an i64.
+fn wait_process(memory: &mut Memory, io: &mut ConcordeIO, pid: usize, dest_status: usize) -> Result<Interrupt, String> {
+    let pid_data = memory.read_typed::<i64>(pid);
+    let status = io.wait_process(u32::try_from(pid_data).unwrap())?;
+    memory.store(dest_status, &status)?;
+    return Ok(Interrupt::Ok);
+}
@@ -0,0 +1,66 @@
+//! ConcordeVM's interrupt controller.
+//!
+//! Lets the host get a running program's attention. The host registers a handler block for a
+//! named interrupt and gets back an `InterruptLine`, which it can raise from any thread, such as
+//! one waiting for SIGINT. Raising a line only sets a flag, so it's safe to do from a signal
+//! handler too. Before each instruction, the CPU checks its lines, in the order they were
+//! registered, and jumps to the handler of the first one raised, remembering where it was. The
+//! handler ends with ReturnFromInterrupt, which carries on from there.
+//!
+//! Handlers don't nest: interrupts raised while one runs wait until it returns, and raising a
+//! line that's already raised does nothing. Only the CPU a line was registered on receives it, and
+//! handlers are not kept in snapshots, though one taken while a handler runs still carries on
+//! from the right place once it returns. A handler can run between any two instructions, so
+//! programs with handlers that write memory shouldn't be optimized.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A handle for raising one of a CPU's interrupts, from any thread.
+#[derive(Clone, Debug)]
+pub struct InterruptLine {
+    raised: Arc<AtomicBool>,
+}
+
+impl InterruptLine {
+    /// Ask the CPU to run the interrupt's handler at its next instruction.
+    pub fn raise(&self) {
+        self.raised.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the interrupt has been raised, and its handler hasn't started yet.
+    pub fn is_raised(&self) -> bool {
+        return self.raised.load(Ordering::SeqCst);
+    }
+}
+
+// A CPU's interrupts, in the order they were registered.
+#[derive(Default)]
+pub(crate) struct InterruptController {
+    interrupts: Vec<(String, usize, InterruptLine)>,
+}
+
+impl InterruptController {
+    // Handle the interrupt `name` with the block at `handler`, keeping its line if it already
+    // has one.
+    pub(crate) fn register(&mut self, name: &str, handler: usize) -> InterruptLine {
+        if let Some((_, existing, line)) = self.interrupts.iter_mut().find(|(existing, _, _)| existing == name) {
+            *existing = handler;
+            return line.clone();
+        }
+        let line = InterruptLine { raised: Arc::new(AtomicBool::new(false)) };
+        self.interrupts.push((name.to_string(), handler, line.clone()));
+        return line;
+    }
+
+    pub(crate) fn line(&self, name: &str) -> Option<InterruptLine> {
+        return self.interrupts.iter().find(|(existing, _, _)| existing == name).map(|(_, _, line)| line.clone());
+    }
+
+    // Lower the first raised line, and return where its handler starts.
+    pub(crate) fn take(&self) -> Option<usize> {
+        return self.interrupts.iter()
+            .find(|(_, _, line)| line.raised.swap(false, Ordering::SeqCst))
+            .map(|(_, handler, _)| *handler);
+    }
+}
@@ -1,74 +1,383 @@
 //! ConcordeVM's IO System.
 //!
-//! Currently only supports opening files.
+//! Supports opening files in a number of modes, as well as the standard streams, TCP
+//! connections, and channels to other CPUs.
 
+use crate::channel::ChannelEnd;
+use crate::clock::{Clock, SystemClock};
+use crate::crypto::Rng;
+use crate::hashing::HashAlgorithm;
 use crate::log_and_return_err;
+use crate::recording::{IoRecorder, Recordable};
+use crate::sandbox::{PermissionDenied, SandboxPolicy};
 
-use std::fs::{rename, File};
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::fs::{copy, remove_file, rename, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::rc::Rc;
 use log::error;
 use io_streams::*;
 
+/// The ways a stream can be opened.
+///
+/// Modes are encoded as integers in the ISA, in the order they are declared here.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpenMode {
+    /// Open an existing file for reading.
+    Read,
+    /// Open an existing file for writing, starting at the beginning without truncating it.
+    Write,
+    /// Open a file for writing at its end, creating it if it doesn't exist.
+    Append,
+    /// Create a new file for writing. Fails if the file already exists.
+    Create,
+    /// Open a file for writing, creating it if it doesn't exist and truncating it if it does.
+    Truncate,
+    /// Open an existing file for both reading and writing.
+    ReadWrite,
+    /// Write to [filename].tmp, and rename it over [filename] on close if anything was written.
+    AtomicWrite,
+}
+
+impl TryFrom<usize> for OpenMode {
+    type Error = String;
+
+    fn try_from(code: usize) -> Result<Self, Self::Error> {
+        match code {
+            0 => Ok(OpenMode::Read),
+            1 => Ok(OpenMode::Write),
+            2 => Ok(OpenMode::Append),
+            3 => Ok(OpenMode::Create),
+            4 => Ok(OpenMode::Truncate),
+            5 => Ok(OpenMode::ReadWrite),
+            6 => Ok(OpenMode::AtomicWrite),
+            _ => log_and_return_err!("Unknown stream open mode {}", code),
+        }
+    }
+}
+
+/// Whether `name` is reserved for one of the standard streams rather than a file.
+fn is_standard_stream(name: &str) -> bool {
+    matches!(name, "stdio" | "stdin" | "stdout" | "stderr")
+}
+
+/// Compression applied to a stream, chosen by prefixing its name, eg. "gzip:file.gz".
+///
+/// Writes are compressed and reads decompressed transparently. Needs the `compression` feature.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Wrap a reader so it decompresses what it reads.
+    #[cfg(feature = "compression")]
+    fn wrap_reader(self, reader: Box<dyn BufRead>) -> Result<Box<dyn BufRead>, String> {
+        match self {
+            Compression::Gzip => Ok(Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(reader)))),
+            Compression::Zstd => match zstd::stream::read::Decoder::with_buffer(reader) {
+                Ok(decoder) => Ok(Box::new(BufReader::new(decoder))),
+                Err(e) => log_and_return_err!("Could not start zstd decompression: {}", e),
+            },
+        }
+    }
+
+    /// Wrap a writer so it compresses what it writes. The compressed data is finished off when
+    /// the writer is dropped.
+    #[cfg(feature = "compression")]
+    fn wrap_writer(self, writer: Box<dyn Write>) -> Result<Box<dyn Write>, String> {
+        match self {
+            Compression::Gzip => Ok(Box::new(flate2::write::GzEncoder::new(writer, flate2::Compression::default()))),
+            Compression::Zstd => match zstd::stream::write::Encoder::new(writer, 0) {
+                Ok(encoder) => Ok(Box::new(encoder.auto_finish())),
+                Err(e) => log_and_return_err!("Could not start zstd compression: {}", e),
+            },
+        }
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn wrap_reader(self, _reader: Box<dyn BufRead>) -> Result<Box<dyn BufRead>, String> {
+        log_and_return_err!("{:?} streams need ConcordeVM to be built with the compression feature", self);
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn wrap_writer(self, _writer: Box<dyn Write>) -> Result<Box<dyn Write>, String> {
+        log_and_return_err!("{:?} streams need ConcordeVM to be built with the compression feature", self);
+    }
+}
+
+/// Split a stream name like "gzip:file.gz" into its compression and the name of the stream it
+/// wraps.
+fn split_compression(name: &str) -> (Option<Compression>, &str) {
+    if let Some(inner) = name.strip_prefix("gzip:") {
+        return (Some(Compression::Gzip), inner);
+    }
+    if let Some(inner) = name.strip_prefix("zstd:") {
+        return (Some(Compression::Zstd), inner);
+    }
+    return (None, name);
+}
+
+/// The name a stream name of the form "channel://name" connects to.
+fn channel_name(name: &str) -> Option<&str> {
+    return name.strip_prefix("channel://");
+}
+
+/// Split a stream name of the form "tcp://host:port" into its host and port.
+fn tcp_address(name: &str) -> Option<(String, u16)> {
+    let address = name.strip_prefix("tcp://")?;
+    let (host, port) = address.rsplit_once(':')?;
+    return Some((host.to_string(), port.parse().ok()?));
+}
+
+/// The SHA-256 digest of data being written, as hex, so recordings can check that replays write
+/// the same bytes without holding all of them.
+fn digest(data: &[u8]) -> String {
+    return HashAlgorithm::Sha256.digest(data).iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+/// Output written to stdout and stderr, kept in memory instead of being passed to the host's.
+///
+/// Clones share the same buffers, so the host can keep one and read what the program printed
+/// once it has run.
+#[derive(Debug, Clone, Default)]
+pub struct CapturedOutput {
+    stdout: Rc<RefCell<Vec<u8>>>,
+    stderr: Rc<RefCell<Vec<u8>>>,
+}
+
+impl CapturedOutput {
+    /// Make a new capture with nothing in it.
+    pub fn new() -> CapturedOutput {
+        CapturedOutput::default()
+    }
+
+    /// Everything written to stdout so far.
+    pub fn stdout(&self) -> Vec<u8> {
+        self.stdout.borrow().clone()
+    }
+
+    /// Everything written to stderr so far.
+    pub fn stderr(&self) -> Vec<u8> {
+        self.stderr.borrow().clone()
+    }
+
+    /// Empty both buffers.
+    pub fn clear(&self) {
+        self.stdout.borrow_mut().clear();
+        self.stderr.borrow_mut().clear();
+    }
+}
+
+// Writes into one of the buffers of a `CapturedOutput`.
+struct CaptureWriter(Rc<RefCell<Vec<u8>>>);
+
+impl Write for CaptureWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
 /// Stream object for Concorde to interface with system IO.
 pub struct ConcordeStream {
     name: String,
+    mode: OpenMode,
     // Replace with BufDuplexer
-    reader: BufReader<StreamReader>,
-    writer: BufWriter<StreamWriter>,
+    reader: Option<Box<dyn BufRead>>,
+    writer: Option<Box<dyn Write>>,
     has_written: bool,
 }
 
 impl ConcordeStream {
     /// Open a new stream
     ///
-    /// Using the name "stdio" will open the standard in/out stream.
-    /// Other names will be interpreted as files.
-    /// File IO is handled as such:
-    ///   - The file on disk with the given name is opened for reading.
+    /// The names "stdin", "stdout", and "stderr" open the corresponding standard stream, and
+    /// "stdio" opens stdin and stdout together as a single stream. Names of the form
+    /// "tcp://host:port" open a TCP connection. Neither of these look at `mode`.
+    /// Other names will be interpreted as files, and opened according to `mode`.
+    /// Files opened with `OpenMode::AtomicWrite` are handled as such:
     ///   - [filename].tmp is opened for writing.
     ///   - If anything is ever written to the file, a flag is set.
     ///   - When closing the file, if the above flag is set, [filename].tmp gets renamed to [filename].
-    pub fn open(name: &String) -> Result<ConcordeStream, String> {
-        if name == "stdio" {
+    ///
+    /// Any of these can be prefixed with "gzip:" or "zstd:" to compress the stream.
+    pub fn open(name: &String, mode: OpenMode) -> Result<ConcordeStream, String> {
+        ConcordeStream::open_captured(name, mode, None)
+    }
+
+    /// Open a new stream like `open`, but write stdout and stderr into `capture` if given.
+    pub fn open_captured(name: &String, mode: OpenMode, capture: Option<&CapturedOutput>) -> Result<ConcordeStream, String> {
+        let (compression, inner) = split_compression(name);
+        let Some(compression) = compression else {
+            return ConcordeStream::open_uncompressed(name, mode, capture);
+        };
+        let mut stream = ConcordeStream::open_uncompressed(&inner.to_string(), mode, capture)?;
+        if let Some(reader) = stream.reader.take() {
+            stream.reader = Some(compression.wrap_reader(reader)?);
+        }
+        if let Some(writer) = stream.writer.take() {
+            stream.writer = Some(compression.wrap_writer(writer)?);
+        }
+        Ok(stream)
+    }
+
+    fn open_uncompressed(name: &String, mode: OpenMode, capture: Option<&CapturedOutput>) -> Result<ConcordeStream, String> {
+        if is_standard_stream(name) {
+            let reader: Option<Box<dyn BufRead>> = match name.as_str() {
+                "stdio" | "stdin" => Some(Box::new(BufReader::new(StreamReader::stdin().unwrap()))),
+                _ => None,
+            };
+            let writer: Option<Box<dyn Write>> = match (name.as_str(), capture) {
+                ("stdio" | "stdout", Some(capture)) => Some(Box::new(CaptureWriter(Rc::clone(&capture.stdout)))),
+                ("stderr", Some(capture)) => Some(Box::new(CaptureWriter(Rc::clone(&capture.stderr)))),
+                ("stdio" | "stdout", None) => Some(Box::new(BufWriter::new(StreamWriter::stdout().unwrap()))),
+                ("stderr", None) => Some(Box::new(BufWriter::new(StreamWriter::stderr().unwrap()))),
+                _ => None,
+            };
+            return Ok(ConcordeStream {
+                name: name.clone(),
+                mode,
+                reader,
+                writer,
+                has_written: false,
+            })
+        }
+
+        if let Some((host, port)) = tcp_address(name) {
+            let stream = match TcpStream::connect((host.as_str(), port)) {
+                Ok(stream) => stream,
+                Err(e) => log_and_return_err!("Could not connect to {}: {}", &name, e),
+            };
+            let read_half = match stream.try_clone() {
+                Ok(read_half) => read_half,
+                Err(e) => log_and_return_err!("Could not connect to {}: {}", &name, e),
+            };
             return Ok(ConcordeStream {
                 name: name.clone(),
-                reader: BufReader::new(StreamReader::stdin().unwrap()),
-                writer: BufWriter::new(StreamWriter::stdout().unwrap()),
+                mode,
+                reader: Some(Box::new(BufReader::new(StreamReader::tcp_stream(read_half)))),
+                writer: Some(Box::new(BufWriter::new(StreamWriter::tcp_stream(stream)))),
                 has_written: false,
             })
         }
-        let file_read = File::options().read(true).write(false).open(name);
-        let mut out_name = name.clone();
-        out_name.push_str(".tmp");
-        let file_write = File::options().read(false).write(true).open(out_name);
-        match (file_read, file_write) {
-            (Ok(fr), Ok(fw)) => {
-                Ok(ConcordeStream {
-                    name: name.clone(),
-                    reader: BufReader::new(StreamReader::file(fr)),
-                    writer: BufWriter::new(StreamWriter::file(fw)),
-                    has_written: false,
-                })
+
+        let mut options = File::options();
+        let path = match mode {
+            OpenMode::Read => { options.read(true); name.clone() },
+            OpenMode::Write => { options.write(true); name.clone() },
+            OpenMode::Append => { options.append(true).create(true); name.clone() },
+            OpenMode::Create => { options.write(true).create_new(true); name.clone() },
+            OpenMode::Truncate => { options.write(true).create(true).truncate(true); name.clone() },
+            OpenMode::ReadWrite => { options.read(true).write(true); name.clone() },
+            OpenMode::AtomicWrite => { options.write(true).create(true).truncate(true); format!("{}.tmp", name) },
+        };
+
+        let file = match options.open(&path) {
+            Ok(file) => file,
+            Err(e) => log_and_return_err!("Could not open file {} in mode {:?}: {}", &name, mode, e),
+        };
+
+        let (reader, writer) = match mode {
+            OpenMode::Read => (Some(file), None),
+            OpenMode::ReadWrite => match file.try_clone() {
+                Ok(clone) => (Some(file), Some(clone)),
+                Err(e) => log_and_return_err!("Could not open file {} in mode {:?}: {}", &name, mode, e),
             },
-            _ => log_and_return_err!("Could not open file {}!", &name)
+            _ => (None, Some(file)),
+        };
+
+        // Both handles of a read-write file share its offset, so neither may read ahead or hold
+        // back writes, or a write after a read would land past where the program has read to.
+        // A reader holding a single byte never keeps one unread between calls.
+        if mode == OpenMode::ReadWrite {
+            return Ok(ConcordeStream {
+                name: name.clone(),
+                mode,
+                reader: reader.map(|f| Box::new(BufReader::with_capacity(1, StreamReader::file(f))) as Box<dyn BufRead>),
+                writer: writer.map(|f| Box::new(StreamWriter::file(f)) as Box<dyn Write>),
+                has_written: false,
+            });
+        }
+
+        Ok(ConcordeStream {
+            name: name.clone(),
+            mode,
+            reader: reader.map(|f| Box::new(BufReader::new(StreamReader::file(f))) as Box<dyn BufRead>),
+            writer: writer.map(|f| Box::new(BufWriter::new(StreamWriter::file(f))) as Box<dyn Write>),
+            has_written: false,
+        })
+    }
+
+    /// Wrap an already connected reader and/or writer, eg. the pipes of a subprocess.
+    pub fn from_parts(name: String, reader: Option<StreamReader>, writer: Option<StreamWriter>) -> ConcordeStream {
+        ConcordeStream {
+            name,
+            mode: OpenMode::ReadWrite,
+            reader: reader.map(|r| Box::new(BufReader::new(r)) as Box<dyn BufRead>),
+            writer: writer.map(|w| Box::new(BufWriter::new(w)) as Box<dyn Write>),
+            has_written: false,
+        }
+    }
+
+    /// Wrap one end of a channel to another CPU.
+    pub fn from_channel(name: String, end: ChannelEnd) -> ConcordeStream {
+        let (reader, writer) = end.split();
+        ConcordeStream {
+            name,
+            mode: OpenMode::ReadWrite,
+            reader: Some(Box::new(BufReader::new(reader))),
+            writer: Some(Box::new(writer)),
+            has_written: false,
         }
     }
 
     /// Attempt to read up to n bytes from the stream.
     /// Returns the read data, as well as the number of bytes read.
     pub fn read(&mut self, n: usize) -> Result<(Vec<u8>, usize), String> {
+        let reader = match self.reader.as_mut() {
+            Some(reader) => reader,
+            None => log_and_return_err!("Stream {} was not opened for reading", self.name),
+        };
         let mut buf: Vec<u8> = vec![0; n];
-        match self.reader.read(&mut buf[..]) {
+        match reader.read(&mut buf[..]) {
             Ok(n) => Ok((buf, n)),
             Err(e) => log_and_return_err!("Failed to read from {}: {}", self.name, e),
         }
     }
 
+    /// Read up to and including the next newline, or to the end of the stream.
+    /// Returns an empty buffer once the end of the stream has been reached.
+    pub fn read_line(&mut self) -> Result<Vec<u8>, String> {
+        let reader = match self.reader.as_mut() {
+            Some(reader) => reader,
+            None => log_and_return_err!("Stream {} was not opened for reading", self.name),
+        };
+        let mut line = Vec::new();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(_) => Ok(line),
+            Err(e) => log_and_return_err!("Failed to read from {}: {}", self.name, e),
+        }
+    }
+
     /// Attempt to write all the contents of buf to the stream.
     /// Returns the number of bytes written if successful.
     pub fn write(&mut self, buf: &[u8]) -> Result<usize, String>{
-        match self.writer.write(buf) {
+        let writer = match self.writer.as_mut() {
+            Some(writer) => writer,
+            None => log_and_return_err!("Stream {} was not opened for writing", self.name),
+        };
+        match writer.write(buf) {
             Ok(n) => {
                 self.has_written =  true;
                 Ok(n)
@@ -77,65 +386,595 @@ impl ConcordeStream {
         }
     }
 
+    /// Whether this stream is a file being written through [filename].tmp.
+    fn is_atomic_file(&self) -> bool {
+        self.mode == OpenMode::AtomicWrite && !is_standard_stream(&self.name) && tcp_address(&self.name).is_none()
+    }
+
+    /// Write out anything buffered for the stream.
+    pub fn flush(&mut self) -> Result<(), String> {
+        if let Some(writer) = self.writer.as_mut() && let Err(e) = writer.flush() {
+            log_and_return_err!("Failed to flush {}: {}", self.name, e);
+        }
+        Ok(())
+    }
+
+    /// Drop the stream without committing an atomic write.
+    /// Other streams are flushed as usual.
+    fn discard(mut self) -> Result<(), String> {
+        if self.is_atomic_file() {
+            drop(self.writer.take());
+            if let Err(e) = remove_file(format!("{}.tmp", self.name)) {
+                log_and_return_err!("Failed to discard {}: {}", self.name, e);
+            }
+            return Ok(());
+        }
+        self.flush()
+    }
+
     /// Close the stream.
-    /// Copies the temporary file to replace the existing one if anything was written to it.
-    pub fn close(self) -> Result<(), String> {
-        drop(self.reader);
-        drop(self.writer);
-        if self.name != "stdio" {
-            let mut out_name = self.name.clone();
-            out_name.push_str(".tmp");
-            match rename(out_name, self.name.clone()) {
-                Ok(()) => return Ok(()),
-                Err(e) => log_and_return_err!("Failed to close file {}: {}", self.name, e)
+    /// Flushes any buffered writes. For atomic writes, the temporary file replaces the existing
+    /// one if anything was written to it, and is discarded otherwise.
+    pub fn close(mut self) -> Result<(), String> {
+        drop(self.reader.take());
+        if let Some(mut writer) = self.writer.take() && let Err(e) = writer.flush() {
+            log_and_return_err!("Failed to flush {}: {}", self.name, e);
+        }
+        if self.is_atomic_file() {
+            let out_name = format!("{}.tmp", self.name);
+            let result = if self.has_written {
+                rename(out_name, self.name.clone())
+            } else {
+                remove_file(out_name)
+            };
+            if let Err(e) = result {
+                log_and_return_err!("Failed to close file {}: {}", self.name, e);
             }
         }
         Ok(())
     }
 }
 
+/// The environment variables and arguments visible to guest programs.
+///
+/// Variables set by the guest are kept here rather than in the host process's environment, and
+/// shadow the host's variables of the same name.
+#[derive(Debug, Clone, Default)]
+pub struct Environment {
+    args: Vec<String>,
+    vars: HashMap<String, String>,
+    module_paths: Vec<PathBuf>,
+}
+
+impl Environment {
+    /// Create an environment with the given arguments and no variables set by the guest.
+    pub fn new(args: Vec<String>) -> Environment {
+        Environment { args, vars: HashMap::new(), module_paths: Vec::new() }
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<String> {
+        match self.vars.get(name) {
+            Some(value) => Some(value.clone()),
+            None => std::env::var(name).ok(),
+        }
+    }
+
+    pub fn set_var(&mut self, name: &str, value: &str) {
+        self.vars.insert(name.to_string(), value.to_string());
+    }
+
+    /// Every variable the guest sees: the host's, shadowed by those the guest set. Host variables
+    /// that aren't valid unicode are left out, since the guest couldn't read them either.
+    pub(crate) fn all_vars(&self) -> HashMap<String, String> {
+        let mut vars: HashMap<String, String> = std::env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        vars.extend(self.vars.clone());
+        return vars;
+    }
+
+    pub fn get_args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    /// Add a directory to search for modules loaded by Import. Directories are searched in the
+    /// order they were added.
+    pub fn add_module_path(&mut self, path: impl Into<PathBuf>) {
+        self.module_paths.push(path.into());
+    }
+
+    pub fn module_paths(&self) -> &[PathBuf] {
+        &self.module_paths
+    }
+}
+
+/// Error returned by IO operations the sandbox checks.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IoError {
+    /// The sandbox policy doesn't allow the operation.
+    PermissionDenied(PermissionDenied),
+    /// The operation was allowed, but failed.
+    Failed(String),
+}
+
+impl fmt::Display for IoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoError::PermissionDenied(e) => write!(f, "{}", e),
+            IoError::Failed(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for IoError {}
+
+impl From<String> for IoError {
+    fn from(msg: String) -> Self {
+        IoError::Failed(msg)
+    }
+}
+
+impl From<IoError> for String {
+    fn from(e: IoError) -> Self {
+        e.to_string()
+    }
+}
+
 /// Concorde's IO interface. This is what the CPU uses to make IO calls.
-pub struct ConcordeIO(HashMap<usize, ConcordeStream>);
+///
+/// Every stream is opened subject to the interface's `SandboxPolicy`, and every operation goes
+/// through its `IoRecorder`, which may log it or serve its result from a recording instead.
+pub struct ConcordeIO {
+    streams: HashMap<usize, ConcordeStream>,
+    policy: Rc<SandboxPolicy>,
+    environment: Rc<RefCell<Environment>>,
+    processes: HashMap<u32, Child>,
+    recorder: Rc<RefCell<IoRecorder>>,
+    rng: Option<Rng>,
+    clock: Rc<dyn Clock>,
+    // Where PrintSymbol writes, opened the first time something is printed.
+    stdout: Option<ConcordeStream>,
+    capture: Option<CapturedOutput>,
+    // Channel ends connected by the host, until the guest opens them.
+    channels: Rc<RefCell<HashMap<String, ChannelEnd>>>,
+    // The last refusal of the sandbox, until the CPU takes it.
+    denied: Option<PermissionDenied>,
+}
 
 impl ConcordeIO {
-    // Make a new empty IO interface.
+    // Make a new empty IO interface with an unrestricted sandbox.
     pub fn new() -> ConcordeIO {
-        ConcordeIO(HashMap::new())
+        ConcordeIO::with_policy(Rc::new(SandboxPolicy::unrestricted()))
     }
 
-    /// Open `filename` under the symbol `name`.
-    pub fn open(&mut self, name: &usize, filename: String) -> Result<(), String> {
-        let stream = ConcordeStream::open(&filename);
-        if stream.is_err() {
-            log_and_return_err!("{}", stream.err().unwrap());
+    /// Make a new empty IO interface restricted by the given policy.
+    pub fn with_policy(policy: Rc<SandboxPolicy>) -> ConcordeIO {
+        ConcordeIO {
+            streams: HashMap::new(),
+            policy,
+            environment: Rc::new(RefCell::new(Environment::default())),
+            processes: HashMap::new(),
+            recorder: Rc::new(RefCell::new(IoRecorder::live())),
+            rng: None,
+            clock: Rc::new(SystemClock::new()),
+            stdout: None,
+            capture: None,
+            channels: Rc::new(RefCell::new(HashMap::new())),
+            denied: None,
+        }
+    }
+
+    /// Share the given environment with this interface.
+    pub fn set_environment(&mut self, environment: Rc<RefCell<Environment>>) {
+        self.environment = environment;
+    }
+
+    /// Share the given recorder with this interface.
+    pub fn set_recorder(&mut self, recorder: Rc<RefCell<IoRecorder>>) {
+        self.recorder = recorder;
+    }
+
+    /// Read the time from the given clock.
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// The clock guest programs read the time from.
+    pub(crate) fn clock(&self) -> &dyn Clock {
+        return self.clock.as_ref();
+    }
+
+    /// Write stdout and stderr into `capture` instead of the host's, for streams opened from now
+    /// on.
+    pub fn set_capture(&mut self, capture: CapturedOutput) {
+        self.capture = Some(capture);
+    }
+
+    /// Let the guest open `end` as the stream "channel://`name`". Each end can be opened once.
+    pub fn connect_channel(&mut self, name: &str, end: ChannelEnd) {
+        self.channels.borrow_mut().insert(name.to_string(), end);
+    }
+
+    /// Share the given channel ends with this interface.
+    pub fn set_channels(&mut self, channels: Rc<RefCell<HashMap<String, ChannelEnd>>>) {
+        self.channels = channels;
+    }
+
+    /// Make this interface's random numbers the same every run, for the given `seed` and `stream`.
+    pub fn seed_rng(&mut self, seed: u64, stream: u64) {
+        self.rng = Some(Rng::from_seed(seed, stream));
+    }
+
+    /// The random number generator, keyed by the operating system the first time it's needed
+    /// unless it has been seeded.
+    pub(crate) fn rng(&mut self) -> Result<&mut Rng, String> {
+        if self.rng.is_none() {
+            self.rng = Some(Rng::from_os()?);
+        }
+        return Ok(self.rng.as_mut().unwrap());
+    }
+
+    /// Perform an operation for real and record its result, or take its result from the
+    /// recording when replaying.
+    fn recorded<T: Recordable>(
+        &mut self,
+        operation: &str,
+        key: &str,
+        live: impl FnOnce(&mut ConcordeIO) -> Result<T, String>,
+    ) -> Result<T, String> {
+        self.checked(operation, key, |_| Ok(()), |io, ()| live(io)).map_err(String::from)
+    }
+
+    /// Like `recorded`, but first ask `check` whether the sandbox allows the operation, failing
+    /// with its PermissionDenied error if not. Whatever `check` returns is passed on to `live`.
+    fn checked<T: Recordable, C>(
+        &mut self,
+        operation: &str,
+        key: &str,
+        check: impl FnOnce(&ConcordeIO) -> Result<C, PermissionDenied>,
+        live: impl FnOnce(&mut ConcordeIO, C) -> Result<T, String>,
+    ) -> Result<T, IoError> {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(operation, key, "io");
+        let result = if self.recorder.borrow().is_replaying() {
+            self.recorder.borrow_mut().replay(operation, key)
+        } else {
+            let result = match check(self) {
+                Ok(checked) => live(self, checked).map_err(IoError::Failed),
+                Err(e) => {
+                    error!("{}", e);
+                    Err(IoError::PermissionDenied(e))
+                },
+            };
+            self.recorder.borrow_mut().record(operation, key, &result)?;
+            result
+        };
+        if let Err(IoError::PermissionDenied(e)) = &result {
+            self.denied = Some(e.clone());
+        }
+        result
+    }
+
+    /// Take the PermissionDenied error of the last operation the sandbox refused, if any, since
+    /// instructions pass their errors on as strings.
+    pub(crate) fn take_denied(&mut self) -> Option<PermissionDenied> {
+        self.denied.take()
+    }
+
+    /// Get the value of the environment variable `name`, or `None` if it isn't set.
+    pub fn get_env(&mut self, name: &str) -> Result<Option<String>, IoError> {
+        self.checked("getenv", name, |io| io.policy.check_env_read(name), |io, ()| {
+            Ok(io.environment.borrow().get_var(name))
+        })
+    }
+
+    /// Set the environment variable `name` for the guest.
+    pub fn set_env(&mut self, name: &str, value: &str) -> Result<(), IoError> {
+        self.checked("setenv", &format!("{}={}", name, value), |io| io.policy.check_env_write(name), |io, ()| {
+            io.environment.borrow_mut().set_var(name, value);
+            Ok(())
+        })
+    }
+
+    pub fn get_args(&self) -> Vec<String> {
+        self.environment.borrow().get_args().to_vec()
+    }
+
+    /// Replace the sandbox policy. Streams that are already open are unaffected.
+    pub fn set_policy(&mut self, policy: Rc<SandboxPolicy>) {
+        self.policy = policy;
+    }
+
+    /// The number of streams the guest has open.
+    pub fn open_streams(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Open `filename` under the symbol `name`, using the given `mode`.
+    /// Returns an error if the sandbox policy doesn't allow it.
+    pub fn open(&mut self, name: &usize, filename: String, mode: OpenMode) -> Result<(), IoError> {
+        self.checked("open", &format!("{} {} {:?}", name, filename, mode), |io| io.check_open(&filename, mode), |io, ()| {
+            if let Some(channel) = channel_name(&filename) {
+                let Some(end) = io.channels.borrow_mut().remove(channel) else {
+                    log_and_return_err!("No channel named {} is connected, or it's already open", channel);
+                };
+                io.streams.insert(*name, ConcordeStream::from_channel(filename.clone(), end));
+                return Ok(());
+            }
+            let stream = ConcordeStream::open_captured(&filename, mode, io.capture.as_ref());
+            if stream.is_err() {
+                log_and_return_err!("{}", stream.err().unwrap());
+            }
+            io.streams.insert(*name, stream.ok().unwrap());
+            Ok(())
+        })
+    }
+
+    fn check_open(&self, filename: &str, mode: OpenMode) -> Result<(), PermissionDenied> {
+        self.policy.check_open_streams(self.streams.len())?;
+        let (_, filename) = split_compression(filename);
+        if is_standard_stream(filename) {
+            return self.policy.check_standard_stream(filename);
+        }
+        // Channels were connected by the host, so it's already allowed them.
+        if channel_name(filename).is_some() {
+            return Ok(());
+        }
+        if let Some((host, port)) = tcp_address(filename) {
+            return self.policy.check_connect(&host, port);
+        }
+        match mode {
+            OpenMode::Read => self.policy.check_read(filename),
+            OpenMode::AtomicWrite => {
+                self.policy.check_write(filename)?;
+                self.policy.check_write(&format!("{}.tmp", filename))
+            },
+            _ => self.policy.check_write(filename),
         }
-        self.0.insert(name.clone(), stream.ok().unwrap());
-        Ok(())
     }
 
     /// Read `n` bytes from the stream at the given symbol.
     /// Returns the read data and the number of bytes read.
     pub fn read(&mut self, name: &usize, n: usize) -> Result<(Vec<u8>, usize), String> {
-        match self.0.get_mut(name) {
-            Some(stream) => stream.read(n),
-            None => log_and_return_err!("Tried to read from undefined stream {}", name),
-        }
+        // Only the bytes actually read are recorded.
+        let data: Vec<u8> = self.recorded("read", &format!("{} {}", name, n), |io| {
+            match io.streams.get_mut(name) {
+                Some(stream) => stream.read(n).map(|(buf, read)| buf[..read].to_vec()),
+                None => log_and_return_err!("Tried to read from undefined stream {}", name),
+            }
+        })?;
+        let read = data.len();
+        let mut buf = data;
+        buf.resize(n, 0);
+        Ok((buf, read))
+    }
+
+    /// Read a line from the stream at the given symbol, including its trailing newline.
+    pub fn read_line(&mut self, name: &usize) -> Result<Vec<u8>, String> {
+        self.recorded("readline", &name.to_string(), |io| {
+            match io.streams.get_mut(name) {
+                Some(stream) => stream.read_line(),
+                None => log_and_return_err!("Tried to read from undefined stream {}", name),
+            }
+        })
     }
 
     /// Write the contents of `buf` to the stream at the given symbol.
     /// Returns the number of bytes written.
     pub fn write(&mut self, name: &usize, buf: &[u8]) -> Result<usize, String> {
-        match self.0.get_mut(name) {
-            Some(stream) => stream.write(buf),
-            None => log_and_return_err!("Tried to write to undefined stream {}", name),
+        self.recorded("write", &format!("{} {} {}", name, buf.len(), digest(buf)), |io| {
+            match io.streams.get_mut(name) {
+                Some(stream) => stream.write(buf),
+                None => log_and_return_err!("Tried to write to undefined stream {}", name),
+            }
+        })
+    }
+
+    /// Write `text` to the default output stream, which is the host's stdout. Guest programs
+    /// don't need to open it first.
+    pub fn print(&mut self, text: &[u8]) -> Result<(), String> {
+        let check = |io: &ConcordeIO| io.policy.check_standard_stream("stdout");
+        self.checked("print", &format!("{} {}", text.len(), digest(text)), check, |io, ()| {
+            if io.stdout.is_none() {
+                io.stdout = Some(ConcordeStream::open_captured(&"stdout".to_string(), OpenMode::Write, io.capture.as_ref())?);
+            }
+            io.stdout.as_mut().unwrap().write(text)?;
+            Ok(())
+        }).map_err(String::from)
+    }
+
+    /// Read the whole file at `path`.
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, IoError> {
+        self.checked("readfile", path, |io| io.check_open(path, OpenMode::Read), |_, ()| {
+            let mut stream = ConcordeStream::open(&path.to_string(), OpenMode::Read)?;
+            let mut contents = Vec::new();
+            loop {
+                let (buf, n) = stream.read(4096)?;
+                if n == 0 {
+                    break;
+                }
+                contents.extend_from_slice(&buf[..n]);
+            }
+            stream.close()?;
+            Ok(contents)
+        })
+    }
+
+    /// Replace the contents of the file at `path` with `data`, creating it if needed.
+    /// If `atomic` is set, the file is written with `OpenMode::AtomicWrite`.
+    pub fn write_file(&mut self, path: &str, data: &[u8], atomic: bool) -> Result<(), IoError> {
+        let mode = if atomic { OpenMode::AtomicWrite } else { OpenMode::Truncate };
+        self.checked("writefile", &format!("{} {} {} {:?}", path, data.len(), digest(data), mode), |io| io.check_open(path, mode), |_, ()| {
+            let mut stream = ConcordeStream::open(&path.to_string(), mode)?;
+            let mut written = 0;
+            while written < data.len() {
+                match stream.write(&data[written..])? {
+                    0 => log_and_return_err!("Failed to write to {}: stream stopped accepting data", path),
+                    n => written += n,
+                }
+            }
+            stream.close()
+        })
+    }
+
+    /// Rename the file at `from` to `to`, replacing `to` if it exists.
+    pub fn rename_file(&mut self, from: &str, to: &str) -> Result<(), IoError> {
+        let check = |io: &ConcordeIO| io.policy.check_write(from).and_then(|_| io.policy.check_write(to));
+        self.checked("rename", &format!("{} {}", from, to), check, |_, ()| {
+            match rename(from, to) {
+                Ok(()) => Ok(()),
+                Err(e) => log_and_return_err!("Failed to rename {} to {}: {}", from, to, e),
+            }
+        })
+    }
+
+    /// Delete the file at `path`.
+    pub fn delete_file(&mut self, path: &str) -> Result<(), IoError> {
+        self.checked("delete", path, |io| io.policy.check_write(path), |_, ()| {
+            match remove_file(path) {
+                Ok(()) => Ok(()),
+                Err(e) => log_and_return_err!("Failed to delete {}: {}", path, e),
+            }
+        })
+    }
+
+    /// Read the module file `<name>.cvo` from the first directory in the module search path that
+    /// has one.
+    pub fn read_module(&mut self, name: &str) -> Result<Vec<u8>, IoError> {
+        // Only the module file that would be read is checked.
+        let check = |io: &ConcordeIO| {
+            let paths = io.environment.borrow().module_paths().to_vec();
+            let Some(path) = paths.iter().map(|dir| dir.join(format!("{}.cvo", name))).find(|path| path.is_file()) else {
+                return Ok(None);
+            };
+            io.policy.check_read(&path.to_string_lossy())?;
+            Ok(Some(path))
+        };
+        self.checked("module", name, check, |_, path| {
+            let Some(path) = path else {
+                log_and_return_err!("Module {} was not found on the module search path", name);
+            };
+            match std::fs::read(&path) {
+                Ok(contents) => Ok(contents),
+                Err(e) => log_and_return_err!("Failed to read module {}: {}", path.display(), e),
+            }
+        })
+    }
+
+    /// Copy the file at `from` to `to`, replacing `to` if it exists.
+    /// Returns the number of bytes copied.
+    pub fn copy_file(&mut self, from: &str, to: &str) -> Result<u64, IoError> {
+        let check = |io: &ConcordeIO| io.policy.check_read(from).and_then(|_| io.policy.check_write(to));
+        self.checked("copy", &format!("{} {}", from, to), check, |_, ()| {
+            match copy(from, to) {
+                Ok(n) => Ok(n),
+                Err(e) => log_and_return_err!("Failed to copy {} to {}: {}", from, to, e),
+            }
+        })
+    }
+
+    /// Write out anything buffered for the given stream.
+    pub fn flush(&mut self, name: &usize) -> Result<(), String> {
+        self.recorded("flush", &name.to_string(), |io| {
+            match io.streams.get_mut(name) {
+                Some(stream) => stream.flush(),
+                None => log_and_return_err!("Tried to flush undefined stream {}", name),
+            }
+        })
+    }
+
+    /// Flush every open stream. All streams are flushed even if some fail, and the first
+    /// error is returned.
+    pub fn flush_all(&mut self) -> Result<(), String> {
+        let mut result = Ok(());
+        for stream in self.streams.values_mut().chain(self.stdout.as_mut()) {
+            let flushed = stream.flush();
+            if result.is_ok() {
+                result = flushed;
+            }
         }
+        result
+    }
+
+    /// Run `command` with `args` as a subprocess, returning its pid.
+    ///
+    /// The child's stdin, stdout, and stderr are connected to new streams under the given
+    /// symbols. The child gets the guest's environment variables that the sandbox lets it read,
+    /// and no others. The stdin stream should be closed before waiting on a child that reads
+    /// until end of input.
+    pub fn spawn_process(
+        &mut self,
+        command: &str,
+        args: &[String],
+        stdin: usize,
+        stdout: usize,
+        stderr: usize,
+    ) -> Result<u32, IoError> {
+        let key = format!("{} {:?} {} {} {}", command, args, stdin, stdout, stderr);
+        let check = |io: &ConcordeIO| io.policy.check_spawn(command).and_then(|_| io.policy.check_open_streams(io.streams.len() + 2));
+        self.checked("spawn", &key, check, |io, ()| {
+            let vars = io.environment.borrow().all_vars().into_iter().filter(|(name, _)| io.policy.check_env_read(name).is_ok());
+            let spawned = Command::new(command)
+                .args(args)
+                .env_clear()
+                .envs(vars)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn();
+            let mut child = match spawned {
+                Ok(child) => child,
+                Err(e) => log_and_return_err!("Failed to spawn {}: {}", command, e),
+            };
+
+            let pid = child.id();
+            let child_stdin = child.stdin.take().map(StreamWriter::child_stdin);
+            let child_stdout = child.stdout.take().map(StreamReader::child_stdout);
+            let child_stderr = child.stderr.take().map(StreamReader::child_stderr);
+            io.streams.insert(stdin, ConcordeStream::from_parts(format!("{} stdin", command), None, child_stdin));
+            io.streams.insert(stdout, ConcordeStream::from_parts(format!("{} stdout", command), child_stdout, None));
+            io.streams.insert(stderr, ConcordeStream::from_parts(format!("{} stderr", command), child_stderr, None));
+            io.processes.insert(pid, child);
+            Ok(pid)
+        })
+    }
+
+    /// Wait for the subprocess with the given pid to exit, returning its exit code.
+    /// Processes killed by a signal report -1.
+    pub fn wait_process(&mut self, pid: u32) -> Result<i64, String> {
+        self.recorded("wait", &pid.to_string(), |io| {
+            let mut child = match io.processes.remove(&pid) {
+                Some(child) => child,
+                None => log_and_return_err!("Tried to wait on unknown process {}", pid),
+            };
+            match child.wait() {
+                Ok(status) => Ok(status.code().map(i64::from).unwrap_or(-1)),
+                Err(e) => log_and_return_err!("Failed to wait on process {}: {}", pid, e),
+            }
+        })
     }
 
     /// Close the given stream.
     pub fn close(&mut self, name: &usize) -> Result<(), String> {
-        match self.0.remove(name) {
-            Some(stream) => stream.close(),
-            None => log_and_return_err!("Tried to close undefined stream {}", name),
+        self.recorded("close", &name.to_string(), |io| {
+            match io.streams.remove(name) {
+                Some(stream) => stream.close(),
+                None => log_and_return_err!("Tried to close undefined stream {}", name),
+            }
+        })
+    }
+}
+
+impl Drop for ConcordeIO {
+    /// Streams the program never closed are closed when the interface goes away, so buffered
+    /// writes aren't lost. Unfinished atomic writes are discarded rather than committed.
+    fn drop(&mut self) {
+        for (_, stream) in self.streams.drain() {
+            let _ = stream.discard();
         }
     }
 }
@@ -0,0 +1,292 @@
+//! ConcordeVM's isolated domains.
+//!
+//! Runs the native library of a domain in a helper process, so a library that crashes or
+//! corrupts memory takes down the helper instead of the VM. The call fails, along with every
+//! later call into the domain, and the rest of the VM carries on. Domains are isolated by giving
+//! the scheduler a `DomainIsolation` before they're loaded.
+//!
+//! The helper is another run of the host binary, usually the same one, with
+//! `CONCORDEVM_DOMAIN_HOST` set in its environment. Binaries that isolate domains must call
+//! `serve_isolated_domain` first thing in main, which serves the VM and exits in a helper, and
+//! returns straight away otherwise. The VM and helper talk over the helper's stdin and stdout,
+//! using the frames of the remote protocol:
+//!
+//! 1. The helper writes `CVDH` to show it's ready. Anything printed before it is skipped.
+//! 2. The VM sends the path of the library, and the helper loads it like `Domain::new` does.
+//! 3. The VM sends each function added to the domain, by id, name and signature, and the helper
+//!    looks it up.
+//! 4. For each call, the VM sends the function id and packed arguments, and the helper calls it
+//!    and answers with the bytes it returned.
+//!
+//! Every request is answered with either the bytes or an error. Calls into one domain run one
+//! at a time. Since stdout carries the protocol, isolated libraries must not print to it; what
+//! they print to stderr is passed through.
+
+use crate::bytecode::{Decoder, Encoder};
+use crate::domain::{DomainValue, FFIFuncTable, FFIFunctionInfo, FFIFunctionSignature};
+use crate::memory::ByteSerialisable;
+use crate::log_and_return_err;
+use crate::remote::{read_frame, write_frame};
+
+use libffi::middle::Type;
+use log::error;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+
+const HELPER_VARIABLE: &str = "CONCORDEVM_DOMAIN_HOST";
+const HANDSHAKE: &[u8; 4] = b"CVDH";
+const REQUEST_MAGIC: &[u8; 4] = b"CVDQ";
+const RESPONSE_MAGIC: &[u8; 4] = b"CVDR";
+const VERSION: u16 = 1;
+
+// The id a helper keeps its one domain under.
+const DOMAIN: usize = 0;
+
+/// How to start the helper processes that isolated domains run in.
+#[derive(Clone, Debug)]
+pub struct DomainIsolation {
+    program: PathBuf,
+    args: Vec<String>,
+}
+
+impl DomainIsolation {
+    /// Run helpers as `program` with `args`. The program must call `serve_isolated_domain`.
+    pub fn new(program: impl Into<PathBuf>, args: Vec<String>) -> DomainIsolation {
+        return DomainIsolation { program: program.into(), args };
+    }
+
+    /// Run helpers as another copy of the running binary.
+    pub fn current_exe() -> Result<DomainIsolation, String> {
+        match std::env::current_exe() {
+            Ok(program) => return Ok(DomainIsolation::new(program, Vec::new())),
+            Err(e) => log_and_return_err!("Can't find the running binary to start domain helpers with: {}", e),
+        }
+    }
+}
+
+enum Request {
+    Load(String),
+    AddFunction(usize, String, Vec<Type>, Type),
+    Call(usize, Vec<u8>),
+}
+
+impl Request {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.header(REQUEST_MAGIC, VERSION);
+        match self {
+            Request::Load(path) => {
+                encoder.u8(0);
+                encoder.string(path);
+            },
+            Request::AddFunction(fn_id, name, arg_types, ret_type) => {
+                encoder.u8(1);
+                encoder.usize(*fn_id);
+                encoder.string(name);
+                encoder.usize(arg_types.len());
+                for arg_type in arg_types {
+                    encoder.ffi_type(arg_type);
+                }
+                encoder.ffi_type(ret_type);
+            },
+            Request::Call(fn_id, args) => {
+                encoder.u8(2);
+                encoder.usize(*fn_id);
+                encoder.bytes(args);
+            },
+        }
+        return encoder.finish();
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Request, String> {
+        let mut decoder = Decoder::new(bytes);
+        decoder.header(REQUEST_MAGIC, VERSION)?;
+        return Ok(match decoder.u8()? {
+            0 => Request::Load(decoder.string()?),
+            1 => {
+                let fn_id = decoder.usize()?;
+                let name = decoder.string()?;
+                let n_args = decoder.usize()?;
+                let arg_types = (0..n_args).map(|_| decoder.ffi_type()).collect::<Result<Vec<Type>, String>>()?;
+                Request::AddFunction(fn_id, name, arg_types, decoder.ffi_type()?)
+            },
+            2 => Request::Call(decoder.usize()?, decoder.bytes()?),
+            tag => log_and_return_err!("Unknown domain helper request {}", tag),
+        });
+    }
+}
+
+fn response_to_bytes(response: &Result<Vec<u8>, String>) -> Vec<u8> {
+    let mut encoder = Encoder::new();
+    encoder.header(RESPONSE_MAGIC, VERSION);
+    match response {
+        Ok(bytes) => {
+            encoder.bool(true);
+            encoder.bytes(bytes);
+        },
+        Err(e) => {
+            encoder.bool(false);
+            encoder.string(e);
+        },
+    }
+    return encoder.finish();
+}
+
+fn response_from_bytes(bytes: &[u8]) -> Result<Result<Vec<u8>, String>, String> {
+    let mut decoder = Decoder::new(bytes);
+    decoder.header(RESPONSE_MAGIC, VERSION)?;
+    return Ok(if decoder.bool()? { Ok(decoder.bytes()?) } else { Err(decoder.string()?) });
+}
+
+/// Serve the VM that started this process as a domain helper, then exit, if the process is one.
+/// Returns straight away otherwise. Binaries that isolate domains must call this first thing in
+/// main.
+pub fn serve_isolated_domain() {
+    if std::env::var_os(HELPER_VARIABLE).is_none() {
+        return;
+    }
+    let status = match serve(&mut std::io::stdin().lock(), &mut std::io::stdout().lock()) {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("Domain helper failed: {}", e);
+            1
+        },
+    };
+    std::process::exit(status);
+}
+
+fn serve(reader: &mut impl Read, writer: &mut impl Write) -> Result<(), String> {
+    if let Err(e) = writer.write_all(HANDSHAKE).and_then(|_| writer.flush()) {
+        log_and_return_err!("Failed to start domain helper: {}", e);
+    }
+    let mut table = FFIFuncTable::new();
+    while let Some(frame) = read_frame(reader)? {
+        let response = match Request::from_bytes(&frame)? {
+            Request::Load(path) => unsafe { table.add_domain(DOMAIN, path) }
+                .map(|_| Vec::new())
+                .map_err(|e| e.to_string()),
+            Request::AddFunction(fn_id, name, arg_types, ret_type) => unsafe { table.load_function_from_so(DOMAIN, FFIFunctionInfo::new(fn_id, name, arg_types, ret_type)) }
+                .map(|_| Vec::new())
+                .map_err(|e| e.to_string()),
+            Request::Call(fn_id, args) => match table.get_n_ret_bytes(DOMAIN, fn_id) {
+                Some(_) => unsafe { table.call_function(DOMAIN, fn_id, &args) }
+                    .map(|value| value.to_bytes())
+                    .map_err(|e| e.to_string()),
+                None => Err(format!("Function {} was never added", fn_id)),
+            },
+        };
+        write_frame(writer, &response_to_bytes(&response))?;
+    }
+    return Ok(());
+}
+
+// A running helper.
+struct Helper {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl Helper {
+    fn start(isolation: &DomainIsolation) -> Result<Helper, String> {
+        let spawned = Command::new(&isolation.program)
+            .args(&isolation.args)
+            .env(HELPER_VARIABLE, "1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => log_and_return_err!("Failed to start domain helper {}: {}", isolation.program.display(), e),
+        };
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let mut helper = Helper { child, stdin, stdout };
+
+        // Skip whatever the binary printed before it started serving.
+        let mut last = [0u8; 4];
+        while &last != HANDSHAKE {
+            let mut byte = [0u8];
+            if helper.stdout.read_exact(&mut byte).is_err() {
+                return Err(helper.exited());
+            }
+            last.rotate_left(1);
+            last[3] = byte[0];
+        }
+        return Ok(helper);
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Vec<u8>, String> {
+        if let Ok(Some(_)) = self.child.try_wait() {
+            return Err(self.exited());
+        }
+        write_frame(&mut self.stdin, &request.to_bytes()).map_err(|_| self.exited())?;
+        let Some(frame) = read_frame(&mut self.stdout).map_err(|_| self.exited())? else {
+            return Err(self.exited());
+        };
+        return response_from_bytes(&frame)?;
+    }
+
+    // Describe how the helper ended, once it's stopped answering.
+    fn exited(&mut self) -> String {
+        return match self.child.wait() {
+            Ok(status) => format!("The domain helper stopped, having exited with {}", status),
+            Err(e) => format!("The domain helper stopped: {}", e),
+        };
+    }
+}
+
+impl Drop for Helper {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// A domain whose library is loaded in a helper process.
+pub(crate) struct IsolatedDomain {
+    path: String,
+    isolation: DomainIsolation,
+    helper: Mutex<Helper>,
+    functions: HashMap<usize, FFIFunctionSignature>,
+}
+
+impl IsolatedDomain {
+    pub(crate) fn start(path: &str, isolation: &DomainIsolation) -> Result<IsolatedDomain, String> {
+        let mut helper = Helper::start(isolation)?;
+        helper.request(&Request::Load(path.to_string()))?;
+        return Ok(IsolatedDomain { path: path.to_string(), isolation: isolation.clone(), helper: Mutex::new(helper), functions: HashMap::new() });
+    }
+
+    pub(crate) fn add_function(&mut self, fn_id: usize, signature: FFIFunctionSignature) -> Result<(), String> {
+        let request = Request::AddFunction(fn_id, signature.name.clone(), signature.arg_types.iter().map(|t| t.0.clone()).collect(), signature.ret_type.0.clone());
+        self.helper.get_mut().unwrap().request(&request)?;
+        self.functions.insert(fn_id, signature);
+        return Ok(());
+    }
+
+    pub(crate) fn n_ret_bytes(&self, fn_id: usize) -> Option<usize> {
+        return self.functions.get(&fn_id).map(|signature| signature.ret_size);
+    }
+
+    pub(crate) fn call(&self, fn_id: usize, args: &[u8]) -> Result<DomainValue, String> {
+        let Some(signature) = self.functions.get(&fn_id) else {
+            return Err(format!("Function {} was never added", fn_id));
+        };
+        let returned = self.helper.lock().unwrap().request(&Request::Call(fn_id, args.to_vec()))?;
+        return DomainValue::from_bytes(&signature.ret_type.0, &returned);
+    }
+
+    // Start a new helper, and load the library and its functions in it again.
+    pub(crate) fn restart(self) -> Result<IsolatedDomain, String> {
+        let mut domain = IsolatedDomain::start(&self.path, &self.isolation)?;
+        for (fn_id, signature) in self.functions {
+            domain.add_function(fn_id, signature)?;
+        }
+        return Ok(domain);
+    }
+}
@@ -3,28 +3,226 @@
 mod cpu;
 pub use cpu::{
     CPU,
+    Fault,
     Program,
+    ReloadPolicy,
+    Timeout,
+};
+
+mod cpu_builder;
+pub use cpu_builder::{
+    CpuBuilder,
+};
+
+mod conditions;
+pub use conditions::{
+    Condition,
+};
+
+mod coverage;
+pub use coverage::{
+    Coverage,
+};
+
+mod interrupt_controller;
+pub use interrupt_controller::{
+    InterruptLine,
+};
+
+mod shared_cpu;
+pub use shared_cpu::{
+    SharedCpu,
+};
+
+mod event_sources;
+pub use event_sources::{
+    EventSender,
+};
+
+mod semaphores;
+
+mod isolated_domains;
+pub use isolated_domains::{
+    serve_isolated_domain,
+    DomainIsolation,
+};
+
+mod builtin_domains;
+
+mod pool;
+pub use pool::{
+    InstanceHandle,
+    InstanceLimits,
+    VmPool,
 };
 
 mod memory;
 pub use memory::{
+    ByteParseable,
+    ByteSerialisable,
+    HostValue,
     Memory,
+    OutOfMemory,
+    WriteProtected,
+    WriteRecord,
 };
 
 mod io;
+pub use io::{
+    CapturedOutput,
+    Compression,
+    Environment,
+    IoError,
+    OpenMode,
+};
+
+mod channel;
+pub use channel::{
+    ChannelEnd,
+};
+
+mod clock;
+pub use clock::{
+    Clock,
+    SystemClock,
+    VirtualClock,
+};
+
+mod recording;
+pub use recording::{
+    IoRecorder,
+};
+
+mod sandbox;
+pub use sandbox::{
+    Access,
+    PermissionDenied,
+    SandboxPolicy,
+};
+
+mod execution_log;
+pub use execution_log::{
+    read_trace,
+    TraceStep,
+    ExecutionLog,
+};
+
+mod events;
+pub use events::{
+    Event,
+    Listener,
+};
+
+mod metrics;
+pub use metrics::{
+    CoroutineCounts,
+    Metrics,
+};
+
+mod snapshot;
+pub use snapshot::{
+    CoreDump,
+    CpuSnapshot,
+    VmSnapshot,
+};
+
+mod remote;
+pub use remote::{
+    read_frame,
+    run_remote,
+    serve,
+    serve_connection,
+    write_frame,
+    RemoteRequest,
+    RemoteResponse,
+    RemoteResult,
+    MAX_CONNECTIONS,
+    MAX_FRAME_LENGTH,
+};
+
+mod bytecode;
+pub use bytecode::{
+    decode_program,
+    decode_program_with_debug_info,
+    encode_program,
+    encode_program_with_debug_info,
+    opcode,
+};
+
+mod debug_info;
+pub use debug_info::{
+    DebugInfo,
+    SourceLocation,
+};
+
+mod validation;
+
+mod verifier;
+
+mod fusion;
+
+mod optimizer;
+
+mod linker;
+pub use linker::{
+    link,
+    Block,
+    Module,
+    Reference,
+};
+
+#[doc(hidden)]
+pub mod asm;
+
+mod builder;
+pub use builder::{
+    ProgramBuilder,
+};
+
+mod stdlib;
+pub use stdlib::{
+    stdlib,
+};
+
+mod bigint;
+
+mod hashing;
+pub use hashing::{
+    HashAlgorithm,
+};
+
+mod lists;
+pub use lists::{
+    ListKind,
+};
+
+mod packing;
+
+mod timestamps;
+
+mod formatting;
+
+mod crypto;
+
 mod instructions;
 pub use instructions::{
-    Interrupt
+    ArithmeticMode,
+    DispatchTable,
+    Handler,
+    Interrupt,
 };
 
 mod scheduler;
 pub use scheduler::{
-    Scheduler
+    Scheduler,
+    StackOverflow,
 };
 
 mod domain;
 pub use domain::{
-    Domain
+    DOMAIN_ABI_VERSION,
+    Domain,
+    DomainValue
 };
 
 #[macro_use]
@@ -0,0 +1,189 @@
+//! ConcordeVM's static linker.
+//!
+//! Programs can be written as several named blocks, possibly spread over multiple modules. Jump
+//! targets in a block are offsets from the start of that block, unless a `Reference` says they
+//! point into another block. Linking lays the blocks out one after another, rewrites every target
+//! into an index in the combined program, and returns a `Program` ready to run.
+
+use crate::bytecode::{Decoder, Encoder};
+use crate::cpu::Program;
+use crate::log_and_return_err;
+
+use concordeisa::instructions::Instruction;
+use log::error;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+const MAGIC: &[u8; 4] = b"CVOB";
+const VERSION: u16 = 1;
+
+/// Marks a jump target in a block as an offset into another block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reference {
+    /// Index of the instruction in its block.
+    pub instruction: usize,
+    /// Which of the instruction's targets is meant. Only `Switch` has more than one, with its
+    /// cases first and its default last.
+    pub slot: usize,
+    /// Name of the block the target is in.
+    pub block: String,
+}
+
+/// A named block of instructions.
+#[derive(Clone, Debug)]
+pub struct Block {
+    pub name: String,
+    pub instructions: Vec<Instruction>,
+    pub references: Vec<Reference>,
+}
+
+impl Block {
+    pub fn new(name: &str, instructions: Vec<Instruction>) -> Block {
+        Block { name: name.to_string(), instructions, references: Vec::new() }
+    }
+
+    /// Make the first target of the given instruction point into another block.
+    pub fn with_reference(mut self, instruction: usize, block: &str) -> Block {
+        self.references.push(Reference { instruction, slot: 0, block: block.to_string() });
+        self
+    }
+}
+
+/// A group of blocks that can be saved as one file.
+#[derive(Clone, Debug, Default)]
+pub struct Module {
+    pub blocks: Vec<Block>,
+}
+
+impl Module {
+    pub fn new(blocks: Vec<Block>) -> Module {
+        Module { blocks }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.header(MAGIC, VERSION);
+        encoder.usize(self.blocks.len());
+        for block in &self.blocks {
+            encoder.string(&block.name);
+            encoder.instructions(&block.instructions);
+            encoder.usize(block.references.len());
+            for reference in &block.references {
+                encoder.usize(reference.instruction);
+                encoder.usize(reference.slot);
+                encoder.string(&reference.block);
+            }
+        }
+        return encoder.finish();
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Module, String> {
+        let mut decoder = Decoder::new(bytes);
+        decoder.header(MAGIC, VERSION)?;
+        let mut blocks = Vec::new();
+        for _ in 0..decoder.usize()? {
+            let name = decoder.string()?;
+            let instructions = decoder.instructions()?;
+            let mut references = Vec::new();
+            for _ in 0..decoder.usize()? {
+                references.push(Reference { instruction: decoder.usize()?, slot: decoder.usize()?, block: decoder.string()? });
+            }
+            blocks.push(Block { name, instructions, references });
+        }
+        if !decoder.is_finished() {
+            log_and_return_err!("Trailing data after module");
+        }
+        return Ok(Module { blocks });
+    }
+}
+
+/// Every jump target of an instruction, in slot order.
+pub(crate) fn targets_mut(instruction: &mut Instruction) -> Vec<&mut usize> {
+    match instruction {
+        Instruction::Jump(target)
+        | Instruction::JumpIfTrue(target, _)
+        | Instruction::JumpIfFalse(target, _)
+        | Instruction::CreateCoroutine(target, _, _, _)
+        | Instruction::SpawnActor(target, _, _)
+        | Instruction::ForEach(_, _, target)
+        | Instruction::MapList(_, _, target, _)
+        | Instruction::FilterList(_, _, target, _)
+        | Instruction::ReduceList(_, _, target, _) => vec![target],
+        Instruction::Switch(_, cases, default) => {
+            let mut targets: Vec<&mut usize> = cases.iter_mut().map(|(_, target)| target).collect();
+            targets.push(default);
+            targets
+        },
+        _ => Vec::new(),
+    }
+}
+
+/// Link the blocks of every module into one program, which starts at the block named `entrypoint`.
+pub fn link(modules: &[Module], entrypoint: &str) -> Result<Program, String> {
+    let (instructions, starts) = link_at(modules, 0)?;
+    let start = modules.iter()
+        .flat_map(|module| &module.blocks)
+        .zip(starts.iter().copied())
+        .find(|(block, _)| block.name == entrypoint)
+        .map(|(_, start)| start);
+    let Some(pc) = start else {
+        log_and_return_err!("Entrypoint block {} is not defined", entrypoint);
+    };
+    let blocks = modules.iter()
+        .flat_map(|module| &module.blocks)
+        .zip(starts)
+        .map(|(block, start)| (block.name.clone(), start..start + block.instructions.len()))
+        .collect();
+    return Ok(Program { instructions: Rc::new(instructions), pc, blocks: Rc::new(blocks), ..Program::default() });
+}
+
+/// Link the blocks of every module as if they were placed at index `base` of a program.
+/// Returns the linked instructions, and the index each block starts at, in order.
+pub(crate) fn link_at(modules: &[Module], base: usize) -> Result<(Vec<Instruction>, Vec<usize>), String> {
+    let mut offsets: HashMap<&str, usize> = HashMap::new();
+    let mut starts = Vec::new();
+    let mut len = base;
+    for block in modules.iter().flat_map(|module| &module.blocks) {
+        if offsets.insert(&block.name, len).is_some() {
+            log_and_return_err!("Block {} is defined more than once", block.name);
+        }
+        starts.push(len);
+        len += block.instructions.len();
+    }
+
+    let mut instructions = Vec::with_capacity(len - base);
+    for block in modules.iter().flat_map(|module| &module.blocks) {
+        instructions.extend(relocate(block, &offsets)?);
+    }
+    return Ok((instructions, starts));
+}
+
+/// Rewrite the targets of a block's instructions into indices in a program, given where each
+/// block, including this one, starts.
+pub(crate) fn relocate(block: &Block, offsets: &HashMap<&str, usize>) -> Result<Vec<Instruction>, String> {
+    let own_offset = offsets[block.name.as_str()];
+    let mut relocated = block.instructions.clone();
+
+    for reference in &block.references {
+        let Some(instruction) = relocated.get_mut(reference.instruction) else {
+            log_and_return_err!("Block {} has a reference on instruction {}, which does not exist", block.name, reference.instruction);
+        };
+        if targets_mut(instruction).len() <= reference.slot {
+            log_and_return_err!("Instruction {} in block {} has no jump target {} to reference", reference.instruction, block.name, reference.slot);
+        }
+        if !offsets.contains_key(reference.block.as_str()) {
+            log_and_return_err!("Block {} references undefined block {}", block.name, reference.block);
+        }
+    }
+
+    for (index, instruction) in relocated.iter_mut().enumerate() {
+        for (slot, target) in targets_mut(instruction).into_iter().enumerate() {
+            let base = match block.references.iter().find(|r| r.instruction == index && r.slot == slot) {
+                Some(reference) => offsets[reference.block.as_str()],
+                None => own_offset,
+            };
+            *target += base;
+        }
+    }
+    return Ok(relocated);
+}
@@ -0,0 +1,141 @@
+//! ConcordeVM's lists.
+//!
+//! A list is an i64 count followed by that many items, packed one after another: 8 byte i64s,
+//! 4 byte f32s, or NUL-terminated strings. Memory doesn't record types, so instructions that
+//! compare items are told which with a `ListKind`.
+
+use crate::log_and_return_err;
+use crate::memory::{Memory, WriteProtected};
+
+use log::error;
+use std::cmp::Ordering;
+use std::mem;
+
+/// The types of item a list can hold.
+///
+/// Kinds are encoded as integers in the ISA, in the order they are declared here.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListKind {
+    /// i64s, 8 bytes each.
+    Int,
+    /// f32s, 4 bytes each, ordered by `f32::total_cmp`.
+    Float,
+    /// NUL-terminated strings, ordered byte by byte.
+    String,
+}
+
+impl TryFrom<usize> for ListKind {
+    type Error = String;
+
+    fn try_from(code: usize) -> Result<Self, Self::Error> {
+        match code {
+            0 => Ok(ListKind::Int),
+            1 => Ok(ListKind::Float),
+            2 => Ok(ListKind::String),
+            _ => log_and_return_err!("Unknown list kind {}", code),
+        }
+    }
+}
+
+/// The items of a list, read out of memory.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Items {
+    Int(Vec<i64>),
+    Float(Vec<f32>),
+    String(Vec<String>),
+}
+
+// Fail if `n` bytes at `address` run past the end of memory.
+fn check_bounds(memory: &Memory, address: usize, n: usize) -> Result<(), String> {
+    if address.saturating_add(n) > memory.len() {
+        log_and_return_err!("Tried to read {} bytes at {}, but memory is only {} bytes", n, address, memory.len());
+    }
+    return Ok(());
+}
+
+fn sort_by<T>(items: &mut [T], compare: fn(&T, &T) -> Ordering, ascending: bool) {
+    if ascending {
+        items.sort_by(compare);
+    } else {
+        items.sort_by(|a, b| compare(b, a));
+    }
+}
+
+// The index of the first item in sorted `items` that isn't less than `value`, if it's equal.
+fn first_index<T>(items: &[T], value: &T, compare: fn(&T, &T) -> Ordering) -> Option<usize> {
+    let index = items.partition_point(|item| compare(item, value).is_lt());
+    return items.get(index).filter(|item| compare(item, value).is_eq()).map(|_| index);
+}
+
+impl Items {
+    /// Read the list of `kind` items at `list`.
+    pub(crate) fn read(memory: &Memory, list: usize, kind: ListKind) -> Result<Items, String> {
+        check_bounds(memory, list, 8)?;
+        let count = memory.read_typed::<i64>(list);
+        let Ok(count) = usize::try_from(count) else {
+            log_and_return_err!("The list at symbol {} has a negative count, {}", list, count);
+        };
+        let start = list + mem::size_of::<i64>();
+        return Ok(match kind {
+            ListKind::Int => {
+                check_bounds(memory, start, count.saturating_mul(8))?;
+                Items::Int((0..count).map(|i| memory.read_typed::<i64>(start + i * 8)).collect())
+            },
+            ListKind::Float => {
+                check_bounds(memory, start, count.saturating_mul(4))?;
+                Items::Float((0..count).map(|i| memory.read_typed::<f32>(start + i * 4)).collect())
+            },
+            ListKind::String => {
+                let mut strings = Vec::new();
+                let mut offset = start;
+                for _ in 0..count {
+                    check_bounds(memory, offset, 1)?;
+                    let string = memory.read_string(offset);
+                    // The NUL must be in memory too.
+                    check_bounds(memory, offset, string.len() + 1)?;
+                    offset += string.len() + 1;
+                    strings.push(string);
+                }
+                Items::String(strings)
+            },
+        });
+    }
+
+    /// Write the items back over the list at `list`, which must have the same layout.
+    pub(crate) fn write(&self, memory: &mut Memory, list: usize) -> Result<(), WriteProtected> {
+        let bytes: Vec<u8> = match self {
+            Items::Int(items) => items.iter().flat_map(|item| item.to_ne_bytes()).collect(),
+            Items::Float(items) => items.iter().flat_map(|item| item.to_ne_bytes()).collect(),
+            Items::String(items) => items.iter().flat_map(|item| item.bytes().chain([0])).collect(),
+        };
+        return memory.store(list + mem::size_of::<i64>(), &bytes);
+    }
+
+    /// Sort the items, smallest first if `ascending`.
+    pub(crate) fn sort(&mut self, ascending: bool) {
+        match self {
+            Items::Int(items) => sort_by(items, i64::cmp, ascending),
+            Items::Float(items) => sort_by(items, f32::total_cmp, ascending),
+            Items::String(items) => sort_by(items, String::cmp, ascending),
+        }
+    }
+
+    /// Find the value of the same kind at `address` in these items, which must be sorted smallest
+    /// first. Returns the index of its first appearance, if any.
+    pub(crate) fn search(&self, memory: &Memory, address: usize) -> Result<Option<usize>, String> {
+        return Ok(match self {
+            Items::Int(items) => {
+                check_bounds(memory, address, 8)?;
+                first_index(items, &memory.read_typed::<i64>(address), i64::cmp)
+            },
+            Items::Float(items) => {
+                check_bounds(memory, address, 4)?;
+                first_index(items, &memory.read_typed::<f32>(address), f32::total_cmp)
+            },
+            Items::String(items) => {
+                check_bounds(memory, address, 1)?;
+                first_index(items, &memory.read_string(address), String::cmp)
+            },
+        });
+    }
+}
@@ -1,11 +1,67 @@
 //! ConcordeVM's Memory system.
 //! 
 //! Provides linear memory for the VM to use along with utils for reading and writing typed data.
+//! Coroutines each have their own memory, but can coordinate through shared regions, which they
+//! reach with atomic instructions or while holding a region's lock.
 
 use crate::log_and_return_err;
 
 use log::error;
-use std::{cmp::{self, max}, mem};
+use serde_json::{Map, Value, json};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::rc::Rc;
+use std::{cmp, mem};
+
+// Number of bytes on each row of a JSON memory dump.
+const JSON_ROW_SIZE: usize = 16;
+
+/// Error returned when an instruction tries to write a frozen symbol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteProtected {
+    /// The address the write started at.
+    pub address: usize,
+    /// How many bytes were being written.
+    pub n: usize,
+}
+
+impl fmt::Display for WriteProtected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Write protected: tried to write {} bytes to symbol {}, which is frozen", self.n, self.address)
+    }
+}
+
+impl std::error::Error for WriteProtected {}
+
+impl From<WriteProtected> for String {
+    fn from(e: WriteProtected) -> Self {
+        e.to_string()
+    }
+}
+
+/// Error returned when memory would grow past its limit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutOfMemory {
+    /// The size memory would have grown to, in bytes.
+    pub requested: usize,
+    /// The most bytes memory may hold.
+    pub limit: usize,
+}
+
+impl fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Out of memory: tried to grow memory to {} bytes, but the limit is {}", self.requested, self.limit)
+    }
+}
+
+impl std::error::Error for OutOfMemory {}
+
+impl From<OutOfMemory> for String {
+    fn from(e: OutOfMemory) -> Self {
+        e.to_string()
+    }
+}
 
 pub trait ByteSerialisable {
     fn to_bytes(&self) -> Vec<u8>;
@@ -131,6 +187,11 @@ impl<T: ByteSerialisable + Clone> ByteSerialisable for [T] {
 
 pub trait ByteParseable {
     fn from_bytes(bytes: & [u8]) -> Self;
+
+    /// How many bytes every value of the type takes, if they all take the same.
+    fn fixed_size() -> Option<usize> {
+        return None;
+    }
 }
 
 
@@ -143,6 +204,10 @@ macro_rules! impl_for_numerics {
                     let buf: [u8; mem::size_of::<Self>()] = bytes.try_into().expect("wrong buffer size for from_bytes");
                     return Self::from_ne_bytes(buf);
                 }
+
+                fn fixed_size() -> Option<usize> {
+                    return Some(mem::size_of::<Self>());
+                }
             }
         )*
     };
@@ -167,6 +232,10 @@ impl ByteParseable for bool {
         return bytes[0] == 1u8;
     }
 
+    fn fixed_size() -> Option<usize> {
+        return Some(1);
+    }
+
 }
 
 impl ByteParseable for Vec<u8> {
@@ -181,17 +250,67 @@ impl ByteParseable for Vec<u8> {
 ///
 /// It wraps a `HashMap<Symbol, Data>` and implements basic memory operations over that, including
 /// both typed and untyped reading, writing, and copying.
+///
+/// Cloning is cheap: clones share their bytes until one of them is written to.
 #[derive(Clone)]
 pub struct Memory{
     base_ptr: usize,
-    linear_memory: Vec<u8>,
+    // Shared between clones, and copied by the first one to write.
+    linear_memory: Rc<Vec<u8>>,
     write_pointer: usize,
+    // Open transactions, innermost last.
+    transactions: Vec<Transaction>,
+    audit: Option<AuditLog>,
+    // Ranges of bytes that instructions can't write, as (address, length).
+    frozen: Vec<(usize, usize)>,
+    // Named symbols, as (address, length).
+    names: BTreeMap<String, (usize, usize)>,
+    // The most bytes memory can grow to, if limited.
+    limit: Option<usize>,
+    shared: SharedRegions,
+    // Host values, by their handle less one.
+    host_values: Vec<HostValue>,
+}
+
+/// A host closure kept in memory, which programs call with InvokeHostValue. It's given the
+/// argument bytes, and returns the bytes to write back, or an error that fails the instruction.
+pub type HostValue = Rc<dyn Fn(&[u8]) -> Result<Vec<u8>, String>>;
+
+// Separates the parts of a namespaced symbol name, like `module::function::local`.
+const NAMESPACE_SEPARATOR: &str = "::";
+
+/// A write recorded by a memory's audit log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriteRecord {
+    pub address: usize,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+    /// The instruction that made the write, or None if the host made it.
+    pub pc: Option<usize>,
+}
+
+/// The most recent writes to a memory, up to a fixed number.
+#[derive(Clone)]
+struct AuditLog {
+    capacity: usize,
+    records: VecDeque<WriteRecord>,
+    // The instruction currently running.
+    pc: Option<usize>,
+}
+
+/// What's needed to undo the writes made since a transaction began.
+#[derive(Clone)]
+struct Transaction {
+    // The size of memory when the transaction began.
+    length: usize,
+    // The bytes each write replaced, oldest first.
+    undo: Vec<(usize, Vec<u8>)>,
 }
 
 impl Memory {
     /// Create a new block of memory
     pub fn new(size: usize) -> Memory {
-        let mut m = Memory{base_ptr: 0, linear_memory: vec![0; size], write_pointer: 0};
+        let mut m = Memory{base_ptr: 0, linear_memory: Rc::new(vec![0; size]), write_pointer: 0, transactions: Vec::new(), audit: None, frozen: Vec::new(), names: BTreeMap::new(), limit: None, shared: SharedRegions::default(), host_values: Vec::new()};
         m.update_base_ptr();
         return m;
     }
@@ -199,11 +318,33 @@ impl Memory {
     fn update_base_ptr(&mut self) {
         self.base_ptr = self.linear_memory.as_ptr() as usize;
     }
+
+    // Mutably borrow the bytes, first copying them if they're shared with a clone.
+    fn unshared_bytes(&mut self) -> &mut Vec<u8> {
+        let bytes = Rc::make_mut(&mut self.linear_memory);
+        self.base_ptr = bytes.as_ptr() as usize;
+        return bytes;
+    }
     
+    /// Create a block of memory holding the given bytes, eg. from a snapshot. It starts with no
+    /// frozen ranges, names, or host values.
+    pub fn from_dump(bytes: Vec<u8>) -> Memory {
+        let mut m = Memory{base_ptr: 0, linear_memory: Rc::new(bytes), write_pointer: 0, transactions: Vec::new(), audit: None, frozen: Vec::new(), names: BTreeMap::new(), limit: None, shared: SharedRegions::default(), host_values: Vec::new()};
+        m.update_base_ptr();
+        return m;
+    }
+
+    /// A copy of this memory for a coroutine it spawns, sharing its bytes until either writes to
+    /// them. Names, frozen ranges, and the limit are kept, but open transactions and the audit log
+    /// stay with the original.
+    pub fn fork(&self) -> Memory {
+        return Memory { transactions: Vec::new(), audit: None, ..self.clone() };
+    }
+
     /// Create a new block of memory with a given capacity
     #[allow(dead_code)]
     pub fn with_capacity(capacity: usize) -> Memory {
-        let mut m = Memory{base_ptr: 0, linear_memory: Vec::with_capacity(capacity), write_pointer: 0};
+        let mut m = Memory{base_ptr: 0, linear_memory: Rc::new(Vec::with_capacity(capacity)), write_pointer: 0, transactions: Vec::new(), audit: None, frozen: Vec::new(), names: BTreeMap::new(), limit: None, shared: SharedRegions::default(), host_values: Vec::new()};
         m.update_base_ptr();
         return m;
     }
@@ -211,9 +352,217 @@ impl Memory {
     /// Write the given data to the symbol. If the symbol does not already exist, create it.
     ///
     /// Returns nothing and should never be able to fail, since any Symbol can we written to, even
-    /// if it is undefined.
+    /// if it is undefined or frozen.
     pub fn write(&mut self, address: usize, data: & dyn ByteSerialisable) {
-        data.write_bytes_to(&mut self.linear_memory, address);
+        let old = self.before_write(address, data.get_size());
+        data.write_bytes_to(self.unshared_bytes(), address);
+        self.after_write(address, old);
+    }
+
+    /// Write the given data to the symbol on behalf of the program being run. Unlike `write`, this
+    /// fails with a WriteProtected error if any of the bytes written are frozen.
+    pub fn store(&mut self, address: usize, data: & dyn ByteSerialisable) -> Result<(), WriteProtected> {
+        if let Err(e) = self.check_writable(address, data.get_size()) {
+            error!("{}", e);
+            return Err(e);
+        }
+        self.write(address, data);
+        return Ok(());
+    }
+
+    /// Stop instructions from writing the `n` bytes at `address`, eg. to protect constants or
+    /// configuration the host has written. The host can still change them with `write`.
+    pub fn freeze(&mut self, address: usize, n: usize) {
+        if n > 0 {
+            self.frozen.push((address, n));
+        }
+    }
+
+    /// Every frozen range, as (address, length).
+    pub(crate) fn frozen_ranges(&self) -> &[(usize, usize)] {
+        return &self.frozen;
+    }
+
+    /// Whether any of the `n` bytes at `address` are frozen.
+    pub fn is_frozen(&self, address: usize, n: usize) -> bool {
+        return self.frozen.iter().any(|&(start, len)| start < address + n && address < start + len);
+    }
+
+    /// Check that instructions may write the `n` bytes at `address`.
+    pub fn check_writable(&self, address: usize, n: usize) -> Result<(), WriteProtected> {
+        if self.is_frozen(address, n) {
+            return Err(WriteProtected { address, n });
+        }
+        return Ok(());
+    }
+
+    // Called before `n` bytes at `address` are overwritten. Returns the bytes being replaced if
+    // they're needed for the audit log.
+    fn before_write(&mut self, address: usize, n: usize) -> Option<Vec<u8>> {
+        if self.transactions.is_empty() && self.audit.is_none() {
+            return None;
+        }
+        let end = cmp::min(address.saturating_add(n), self.linear_memory.len());
+        let old = self.linear_memory[cmp::min(address, end)..end].to_vec();
+        if let Some(transaction) = self.transactions.last_mut() && !old.is_empty() {
+            transaction.undo.push((address, old.clone()));
+        }
+        return self.audit.is_some().then_some(old);
+    }
+
+    // Called once the bytes returned by `before_write` have been overwritten.
+    fn after_write(&mut self, address: usize, old: Option<Vec<u8>>) {
+        let (Some(audit), Some(old)) = (&mut self.audit, old) else {
+            return;
+        };
+        let new = self.linear_memory[address..address + old.len()].to_vec();
+        if audit.records.len() == audit.capacity {
+            audit.records.pop_front();
+        }
+        audit.records.push_back(WriteRecord { address, old, new, pc: audit.pc });
+    }
+
+    /// Start recording every write, keeping the most recent `capacity` of them. Any writes
+    /// already recorded are discarded.
+    pub fn enable_audit(&mut self, capacity: usize) {
+        self.audit = Some(AuditLog { capacity, records: VecDeque::with_capacity(capacity), pc: None });
+    }
+
+    /// Stop recording writes, and discard the ones recorded so far.
+    pub fn disable_audit(&mut self) {
+        self.audit = None;
+    }
+
+    /// The writes recorded since auditing was enabled, oldest first.
+    pub fn audit_log(&self) -> Vec<&WriteRecord> {
+        return match &self.audit {
+            Some(audit) => audit.records.iter().collect(),
+            None => Vec::new(),
+        };
+    }
+
+    /// The recorded writes that changed any of the `n` bytes at `address`, oldest first.
+    pub fn audit_writes_to(&self, address: usize, n: usize) -> Vec<&WriteRecord> {
+        return self.audit_log().into_iter()
+            .filter(|record| record.address < address + n && address < record.address + record.old.len())
+            .collect();
+    }
+
+    // Set the instruction that audited writes are attributed to.
+    pub(crate) fn set_audit_pc(&mut self, pc: Option<usize>) {
+        if let Some(audit) = &mut self.audit {
+            audit.pc = pc;
+        }
+    }
+
+    /// Start logging writes, so they can be undone by `rollback_transaction`. Transactions can be
+    /// nested, and committing or rolling back applies to the innermost one.
+    pub fn begin_transaction(&mut self) {
+        self.transactions.push(Transaction { length: self.linear_memory.len(), undo: Vec::new() });
+    }
+
+    /// Keep the writes made in the innermost transaction. If it's nested, they can still be
+    /// undone by rolling back the one outside it.
+    pub fn commit_transaction(&mut self) -> Result<(), String> {
+        let Some(transaction) = self.transactions.pop() else {
+            log_and_return_err!("Tried to commit a transaction, but none is open");
+        };
+        if let Some(outer) = self.transactions.last_mut() {
+            outer.undo.extend(transaction.undo);
+        }
+        return Ok(());
+    }
+
+    /// Undo every write made in the innermost transaction, and shrink memory back to the size it
+    /// was when the transaction began.
+    pub fn rollback_transaction(&mut self) -> Result<(), String> {
+        let Some(transaction) = self.transactions.pop() else {
+            log_and_return_err!("Tried to roll back a transaction, but none is open");
+        };
+        for (address, bytes) in transaction.undo.into_iter().rev() {
+            let old = self.audit.is_some().then(|| self.linear_memory[address..address + bytes.len()].to_vec());
+            self.unshared_bytes()[address..address + bytes.len()].copy_from_slice(&bytes);
+            self.after_write(address, old);
+        }
+        self.unshared_bytes().truncate(transaction.length);
+        self.update_base_ptr();
+        return Ok(());
+    }
+
+    /// Whether any transaction is open.
+    pub fn in_transaction(&self) -> bool {
+        return !self.transactions.is_empty();
+    }
+
+    /// Name the `n` bytes at `address`. Names can be namespaced, like `module::function::local`,
+    /// so that a whole module, coroutine, or call frame can be looked up or dropped at once.
+    pub fn bind(&mut self, name: &str, address: usize, n: usize) {
+        self.names.insert(name.to_string(), (address, n));
+    }
+
+    /// Every named symbol, as (name, address, length), in name order.
+    pub(crate) fn symbols(&self) -> Vec<(String, usize, usize)> {
+        return self.names.iter().map(|(name, &(address, n))| (name.clone(), address, n)).collect();
+    }
+
+    /// The number of named symbols.
+    pub fn symbol_count(&self) -> usize {
+        return self.names.len();
+    }
+
+    /// Get the address and length of a named symbol.
+    pub fn lookup(&self, name: &str) -> Option<(usize, usize)> {
+        return self.names.get(name).copied();
+    }
+
+    /// Get every named symbol in a namespace, including any nested in it, in name order. The
+    /// namespace `module` holds `module` itself and `module::x`, but not `module_two`.
+    pub fn namespace(&self, namespace: &str) -> Vec<(&str, usize, usize)> {
+        return self.names.range(namespace.to_string()..)
+            .take_while(|(name, _)| name.starts_with(namespace))
+            .filter(|(name, _)| name.len() == namespace.len() || name[namespace.len()..].starts_with(NAMESPACE_SEPARATOR))
+            .map(|(name, &(address, n))| (name.as_str(), address, n))
+            .collect();
+    }
+
+    /// Remove every named symbol in a namespace and zero the bytes they held. Returns how many
+    /// symbols were dropped.
+    pub fn drop_namespace(&mut self, namespace: &str) -> usize {
+        let dropped: Vec<(String, usize, usize)> = self.namespace(namespace).into_iter()
+            .map(|(name, address, n)| (name.to_string(), address, n))
+            .collect();
+        for (name, address, n) in &dropped {
+            self.names.remove(name);
+            let end = cmp::min(address + n, self.linear_memory.len());
+            if *address < end {
+                self.write(*address, &vec![0u8; end - address]);
+            }
+        }
+        return dropped.len();
+    }
+
+    /// Keep `f` in memory as an opaque value, and write a handle to it at `address` as a usize, so
+    /// the program can call it with InvokeHostValue, eg. to log or validate data at a point the
+    /// host picks. Forks of this memory can call it too. Snapshots and dumps only keep the handle,
+    /// and a CPU restored from a snapshot keeps the host values it had.
+    pub fn store_host_value(&mut self, address: usize, f: impl Fn(&[u8]) -> Result<Vec<u8>, String> + 'static) {
+        self.host_values.push(Rc::new(f));
+        self.write(address, &self.host_values.len());
+    }
+
+    /// The host value with the handle `handle`, if there is one.
+    pub(crate) fn host_value(&self, handle: usize) -> Option<HostValue> {
+        return handle.checked_sub(1).and_then(|index| self.host_values.get(index)).cloned();
+    }
+
+    /// Every host value, in handle order.
+    pub(crate) fn host_values(&self) -> &[HostValue] {
+        return &self.host_values;
+    }
+
+    /// Replace every host value, eg. to keep them over a restore.
+    pub(crate) fn set_host_values(&mut self, host_values: Vec<HostValue>) {
+        self.host_values = host_values;
     }
 
     /// Read from the given symbol, expecting a specific type. Guaranteed to return that type or error.
@@ -225,22 +574,50 @@ impl Memory {
         return T::from_bytes(slice);
     }
 
+    /// Read a NUL-terminated string starting at the given address.
+    ///
+    /// If no NUL byte is found, the string runs to the end of memory.
+    pub fn read_string(&self, address: usize) -> String {
+        return String::from_bytes(&self.linear_memory[address..]);
+    }
+
+    /// Read a list of strings starting at the given address.
+    ///
+    /// Lists are stored as an i64 count, followed by that many NUL-terminated strings.
+    pub fn read_string_list(&self, address: usize) -> Vec<String> {
+        let count = self.read_typed::<i64>(address);
+        let mut offset = address + mem::size_of::<i64>();
+        let mut strings = Vec::new();
+        for _ in 0..count {
+            let string = self.read_string(offset);
+            offset += string.len() + 1;
+            strings.push(string);
+        }
+        return strings;
+    }
+
     pub fn read(&self, address: usize, n: usize) -> Vec<u8> {
         return self.linear_memory[address..address + n].to_vec();
     }
 
     /// Copy the data from source to dest. If dest doesn't exist yet, create it.
     ///
-    /// If the source doesn't exist, return an error.
+    /// If the source doesn't exist, or any byte of dest is frozen, return an error.
     ///
     /// While this could arguably be implented at the instruction level, having this be a memory
     /// level operation may be good for operations besides just copying.
     pub fn memcpy(&mut self, source: usize, dest: usize, n: usize) -> Result<(), String> {
 
         if cmp::max(source, dest) + n <= self.linear_memory.capacity() {
+            if let Err(e) = self.check_writable(dest, n) {
+                log_and_return_err!("{}", e);
+            }
+            let old = self.before_write(dest, n);
+            let bytes = self.unshared_bytes();
             for offset in 0..n {
-                self.linear_memory[dest + offset] = self.linear_memory[source + offset];
+                bytes[dest + offset] = bytes[source + offset];
             };
+            self.after_write(dest, old);
             return Ok(());
         } else {
             log_and_return_err!("Tried memcpy of {} bytes from {} to {}, but max memory address is {}", n, source, dest, self.linear_memory.capacity());
@@ -249,16 +626,145 @@ impl Memory {
 
     /// Get an iterator over all of the symbols currently in memory. Useful for debugging purposes.
     pub fn dump(&self) -> Vec<u8> {
-        return self.linear_memory.clone();
+        return self.linear_memory.to_vec();
     }
 
-    pub fn extend_memory(&mut self, n: usize) {
-        self.linear_memory.extend(vec![0u8; n]);
+    /// The number of bytes of memory.
+    pub fn len(&self) -> usize {
+        return self.linear_memory.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.linear_memory.is_empty();
+    }
+
+    /// Iterate over every byte of memory along with its address.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, u8)> + '_ {
+        return self.linear_memory.iter().copied().enumerate();
+    }
+
+    /// Get the address and length of every run of non-zero bytes, ie. the regions holding data.
+    pub fn regions(&self) -> Vec<(usize, usize)> {
+        let mut regions = Vec::new();
+        let mut start = None;
+        for (address, byte) in self.iter() {
+            match (start, byte) {
+                (None, 0) | (Some(_), 1..) => {},
+                (None, _) => start = Some(address),
+                (Some(region_start), 0) => {
+                    regions.push((region_start, address - region_start));
+                    start = None;
+                },
+            }
+        }
+        if let Some(region_start) = start {
+            regions.push((region_start, self.len() - region_start));
+        }
+        return regions;
+    }
+
+    /// Get every address the given bytes can be found at, eg. to locate a string.
+    pub fn addresses_matching(&self, pattern: &[u8]) -> Vec<usize> {
+        if pattern.is_empty() {
+            return Vec::new();
+        }
+        return self.linear_memory.windows(pattern.len())
+            .enumerate()
+            .filter(|(_, window)| *window == pattern)
+            .map(|(address, _)| address)
+            .collect();
+    }
+
+    /// Export memory as pretty-printed JSON, for inspecting and diffing.
+    ///
+    /// The dump holds the memory size and a map from row addresses to 16 bytes of hex. Rows that
+    /// are entirely zero are left out.
+    pub fn to_json(&self) -> String {
+        let mut rows = Map::new();
+        for (i, row) in self.linear_memory.chunks(JSON_ROW_SIZE).enumerate() {
+            if row.iter().any(|byte| *byte != 0) {
+                let hex: Vec<String> = row.iter().map(|byte| format!("{:02x}", byte)).collect();
+                rows.insert((i * JSON_ROW_SIZE).to_string(), Value::String(hex.join(" ")));
+            }
+        }
+        let dump = json!({ "size": self.linear_memory.len(), "rows": rows });
+        return serde_json::to_string_pretty(&dump).unwrap();
+    }
+
+    /// Create memory from a dump made by `to_json`. Rows may be any length and at any
+    /// address, so dumps can be written by hand to seed a program's initial state.
+    pub fn from_json(json: &str) -> Result<Memory, String> {
+        let dump: Value = match serde_json::from_str(json) {
+            Ok(dump) => dump,
+            Err(e) => log_and_return_err!("Invalid memory JSON: {}", e),
+        };
+        let Some(size) = dump["size"].as_u64() else {
+            log_and_return_err!("Memory JSON must have an integer \"size\"");
+        };
+        let mut memory = Memory::new(size as usize);
+        let Some(rows) = dump["rows"].as_object() else {
+            return Ok(memory);
+        };
+        for (address, row) in rows {
+            let Ok(address) = address.parse::<usize>() else {
+                log_and_return_err!("Memory JSON row address {} is not an integer", address);
+            };
+            let Some(hex) = row.as_str() else {
+                log_and_return_err!("Memory JSON row {} is not a string", address);
+            };
+            let mut bytes = Vec::new();
+            for pair in hex.split_whitespace() {
+                match u8::from_str_radix(pair, 16) {
+                    Ok(byte) => bytes.push(byte),
+                    Err(_) => log_and_return_err!("Memory JSON row {} has invalid byte {}", address, pair),
+                }
+            }
+            if address + bytes.len() > memory.linear_memory.len() {
+                log_and_return_err!("Memory JSON row {} goes past the end of memory at {}", address, size);
+            }
+            memory.unshared_bytes()[address..address + bytes.len()].copy_from_slice(&bytes);
+        }
+        return Ok(memory);
+    }
+
+    /// Grow memory by `n` bytes. Fails with an OutOfMemory error if that would take it past the
+    /// limit.
+    pub fn extend_memory(&mut self, n: usize) -> Result<(), OutOfMemory> {
+        if let Err(e) = self.check_growth(n) {
+            error!("{}", e);
+            return Err(e);
+        }
+        self.unshared_bytes().extend(vec![0u8; n]);
         self.update_base_ptr();
+        return Ok(());
+    }
+
+    pub fn extend_memory_to(&mut self, n: usize) -> Result<(), OutOfMemory> {
+        return self.extend_memory(n.saturating_sub(self.linear_memory.len()));
+    }
+
+    /// Limit how many bytes memory can grow to, or lift the limit with None. Memory that's
+    /// already in use is kept, even if it's over the new limit.
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        return self.limit;
+    }
+
+    /// Check that memory may grow by `n` bytes without going past its limit.
+    pub fn check_growth(&self, n: usize) -> Result<(), OutOfMemory> {
+        let requested = self.linear_memory.len().saturating_add(n);
+        match self.limit {
+            Some(limit) if requested > limit => Err(OutOfMemory { requested, limit }),
+            _ => Ok(()),
+        }
     }
 
-    pub fn extend_memory_to(&mut self, n: usize) {
-        self.extend_memory(max(0, n - self.linear_memory.len()));
+    /// How many bytes memory is using towards its limit.
+    pub fn usage(&self) -> usize {
+        return self.linear_memory.len();
     }
 
     pub fn addr_to_idx(&self, addr: usize) -> usize {
@@ -273,12 +779,120 @@ impl Memory {
         return self.base_ptr;
     }
 
+    /// The shared regions this memory can reach.
+    pub(crate) fn shared(&self) -> &SharedRegions {
+        return &self.shared;
+    }
+
+    /// Reach `shared` instead of this memory's current shared regions.
+    pub(crate) fn set_shared(&mut self, shared: SharedRegions) {
+        self.shared = shared;
+    }
+
     pub fn get_slice(&self, address: usize, n: usize) -> &[u8] {
         return &self.linear_memory[address..address + n];
     }
 
 }
 
+// A region of bytes shared between the memories of several coroutines.
+struct SharedRegion {
+    bytes: Vec<u8>,
+    // The coroutine holding the region's lock, if any.
+    holder: Option<usize>,
+}
+
+/// The shared regions a memory can reach, by id, and which coroutine it belongs to when taking
+/// their locks. Clones reach the same regions.
+#[derive(Clone, Default)]
+pub(crate) struct SharedRegions {
+    regions: Rc<RefCell<HashMap<usize, SharedRegion>>>,
+    owner: usize,
+}
+
+impl SharedRegions {
+    /// The same regions, reached on behalf of the coroutine `owner`.
+    pub(crate) fn for_owner(&self, owner: usize) -> SharedRegions {
+        return SharedRegions { regions: Rc::clone(&self.regions), owner };
+    }
+
+    /// Create region `id` with `n` zeroed bytes. Does nothing if it already exists with that
+    /// size, so every coroutine using a region can create it.
+    pub(crate) fn create(&self, id: usize, n: usize) -> Result<(), String> {
+        let mut regions = self.regions.borrow_mut();
+        if let Some(region) = regions.get(&id) {
+            if region.bytes.len() != n {
+                log_and_return_err!("Shared region {} already exists with {} bytes, not {}", id, region.bytes.len(), n);
+            }
+            return Ok(());
+        }
+        regions.insert(id, SharedRegion { bytes: vec![0; n], holder: None });
+        return Ok(());
+    }
+
+    /// Run `f` on the `n` bytes at `offset` in region `id`. Returns None without running it if
+    /// another coroutine holds the region's lock.
+    pub(crate) fn access<T>(&self, id: usize, offset: usize, n: usize, f: impl FnOnce(&mut [u8]) -> T) -> Result<Option<T>, String> {
+        let mut regions = self.regions.borrow_mut();
+        let Some(region) = regions.get_mut(&id) else {
+            log_and_return_err!("Shared region {} does not exist", id);
+        };
+        if region.holder.is_some_and(|holder| holder != self.owner) {
+            return Ok(None);
+        }
+        if offset.saturating_add(n) > region.bytes.len() {
+            log_and_return_err!("Tried to access {} bytes at {} in shared region {}, which is only {} bytes", n, offset, id, region.bytes.len());
+        }
+        return Ok(Some(f(&mut region.bytes[offset..offset + n])));
+    }
+
+    /// Take the lock of region `id`. Returns false if another coroutine holds it.
+    pub(crate) fn lock(&self, id: usize) -> Result<bool, String> {
+        let mut regions = self.regions.borrow_mut();
+        let Some(region) = regions.get_mut(&id) else {
+            log_and_return_err!("Tried to lock shared region {}, which does not exist", id);
+        };
+        match region.holder {
+            None => region.holder = Some(self.owner),
+            Some(holder) if holder == self.owner => log_and_return_err!("Tried to lock shared region {}, which is already locked by this coroutine", id),
+            Some(_) => return Ok(false),
+        }
+        return Ok(true);
+    }
+
+    /// Release the lock of region `id`, which must be held by this memory's coroutine.
+    pub(crate) fn unlock(&self, id: usize) -> Result<(), String> {
+        let mut regions = self.regions.borrow_mut();
+        let Some(region) = regions.get_mut(&id) else {
+            log_and_return_err!("Tried to unlock shared region {}, which does not exist", id);
+        };
+        if region.holder != Some(self.owner) {
+            log_and_return_err!("Tried to unlock shared region {}, which this coroutine does not hold", id);
+        }
+        region.holder = None;
+        return Ok(());
+    }
+
+    /// Whether there are no regions.
+    pub(crate) fn is_empty(&self) -> bool {
+        return self.regions.borrow().is_empty();
+    }
+
+    /// Remove every region, eg. before a restore.
+    pub(crate) fn clear(&self) {
+        self.regions.borrow_mut().clear();
+    }
+
+    /// Release every lock this memory's coroutine holds, eg. once it has finished.
+    pub(crate) fn release_all(&self) {
+        for region in self.regions.borrow_mut().values_mut() {
+            if region.holder == Some(self.owner) {
+                region.holder = None;
+            }
+        }
+    }
+}
+
 impl Default for Memory {
    fn default() -> Self {
        Memory::new(0)
@@ -0,0 +1,72 @@
+//! ConcordeVM's metrics.
+//!
+//! Counts what a CPU, or a scheduler's coroutines together, have done so far, for the host to
+//! poll. With the `metrics-text` feature, metrics can also be written in Prometheus' text format.
+
+/// How many coroutines are in each state.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CoroutineCounts {
+    pub runnable: usize,
+    pub suspended: usize,
+    pub running: usize,
+    pub finished: usize,
+    pub cancelled: usize,
+}
+
+/// What has been executed so far, and the resources in use right now.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Metrics {
+    /// Instructions executed. A superinstruction counts every instruction fused into it.
+    pub instructions: u64,
+    /// Cycles run, including ones that failed.
+    pub cycles: u64,
+    /// Cycles that failed with an error.
+    pub errors: u64,
+    /// Named symbols in memory.
+    pub memory_entries: usize,
+    /// Bytes of memory.
+    pub memory_bytes: usize,
+    /// Streams the guest has open.
+    pub open_streams: usize,
+    /// Coroutines by state. Always zero for a lone CPU.
+    pub coroutines: CoroutineCounts,
+}
+
+impl Metrics {
+    // Add another CPU's metrics to these.
+    pub(crate) fn add(&mut self, other: &Metrics) {
+        self.instructions += other.instructions;
+        self.cycles += other.cycles;
+        self.errors += other.errors;
+        self.memory_entries += other.memory_entries;
+        self.memory_bytes += other.memory_bytes;
+        self.open_streams += other.open_streams;
+    }
+
+    /// Write the metrics in Prometheus' text exposition format, with names prefixed by
+    /// `concordevm_`.
+    #[cfg(feature = "metrics-text")]
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+            text.push_str(&format!("# HELP concordevm_{} {}\n# TYPE concordevm_{} {}\n", name, help, name, kind));
+            for (labels, value) in samples {
+                text.push_str(&format!("concordevm_{}{} {}\n", name, labels, value));
+            }
+        };
+        metric("instructions_total", "counter", "Instructions executed.", &[("", self.instructions)]);
+        metric("cycles_total", "counter", "Cycles run.", &[("", self.cycles)]);
+        metric("errors_total", "counter", "Cycles that failed with an error.", &[("", self.errors)]);
+        metric("memory_entries", "gauge", "Named symbols in memory.", &[("", self.memory_entries as u64)]);
+        metric("memory_bytes", "gauge", "Bytes of memory.", &[("", self.memory_bytes as u64)]);
+        metric("open_streams", "gauge", "Streams the guest has open.", &[("", self.open_streams as u64)]);
+        metric("coroutines", "gauge", "Coroutines by state.", &[
+            ("{state=\"runnable\"}", self.coroutines.runnable as u64),
+            ("{state=\"suspended\"}", self.coroutines.suspended as u64),
+            ("{state=\"running\"}", self.coroutines.running as u64),
+            ("{state=\"finished\"}", self.coroutines.finished as u64),
+            ("{state=\"cancelled\"}", self.coroutines.cancelled as u64),
+        ]);
+        return text;
+    }
+}
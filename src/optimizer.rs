@@ -0,0 +1,246 @@
+//! ConcordeVM's optimizer.
+//!
+//! A pass run over programs as they're loaded, which folds arithmetic and comparisons on symbols
+//! with known values into plain writes, removes writes that are overwritten before anything reads
+//! them, and points jumps that land on another jump straight at the final target.
+//!
+//! Instructions are replaced with `NoOp`s rather than removed, so every index stays valid.
+//! Values are only tracked through straight-line code, and are forgotten at every jump target and
+//! after any instruction whose effect on memory isn't known. `CreateCoroutineIndirect` can start
+//! a coroutine anywhere, so values are not folded at all in programs that use it.
+
+use crate::linker::targets_mut;
+
+use concordeisa::instructions::Instruction;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy)]
+enum Constant {
+    Int(i64),
+    Bool(bool),
+}
+
+// An address and a number of bytes.
+type Range = (usize, usize);
+
+/// Optimize `instructions` in place, for a program entered at index `entrypoint`.
+pub(crate) fn optimize(instructions: &mut [Instruction], entrypoint: usize) {
+    let indirect = instructions.iter().any(|instruction| matches!(instruction, Instruction::CreateCoroutineIndirect(..)));
+    if !indirect {
+        fold_constants(instructions, entrypoint);
+    }
+    remove_dead_stores(instructions);
+    collapse_jumps(instructions);
+}
+
+// The bytes an instruction reads and writes, or None if it does anything else.
+fn effects(instruction: &Instruction) -> Option<(Vec<Range>, Vec<Range>)> {
+    let effects = match *instruction {
+        Instruction::WriteStringToSymbol(symbol, ref value) => (vec![], vec![(symbol, value.len())]),
+        Instruction::WriteIntToSymbol(symbol, _) => (vec![], vec![(symbol, 8)]),
+        Instruction::WriteBoolToSymbol(symbol, _) => (vec![], vec![(symbol, 1)]),
+        Instruction::WriteBytesToSymbol(symbol, ref value) => (vec![], vec![(symbol, value.len())]),
+        Instruction::MemCpy(source, dest, n) => (vec![(source, n)], vec![(dest, n)]),
+
+        Instruction::AddSymbols(a, b, dest)
+        | Instruction::SubtractSymbols(a, b, dest)
+        | Instruction::MultiplySymbols(a, b, dest)
+        | Instruction::DivideSymbols(a, b, dest)
+        | Instruction::ModuloSymbols(a, b, dest)
+        | Instruction::MinSymbols(a, b, dest)
+        | Instruction::MaxSymbols(a, b, dest)
+        | Instruction::CheckedAdd(a, b, dest)
+        | Instruction::CheckedSubtract(a, b, dest)
+        | Instruction::CheckedMultiply(a, b, dest)
+        | Instruction::WrappingAdd(a, b, dest)
+        | Instruction::WrappingSubtract(a, b, dest)
+        | Instruction::WrappingMultiply(a, b, dest) => (vec![(a, 8), (b, 8)], vec![(dest, 8)]),
+        Instruction::FmaSymbols(a, b, c, dest) => (vec![(a, 8), (b, 8), (c, 8)], vec![(dest, 8)]),
+
+        Instruction::AddImmediate(a, _, dest)
+        | Instruction::SubtractImmediate(a, _, dest)
+        | Instruction::MultiplyImmediate(a, _, dest)
+        | Instruction::DivideImmediate(a, _, dest)
+        | Instruction::ModuloImmediate(a, _, dest) => (vec![(a, 8)], vec![(dest, 8)]),
+
+        Instruction::SinSymbol(a, dest)
+        | Instruction::CosSymbol(a, dest)
+        | Instruction::TanSymbol(a, dest)
+        | Instruction::ArcsinSymbol(a, dest)
+        | Instruction::ArccosSymbol(a, dest)
+        | Instruction::ArctanSymbol(a, dest)
+        | Instruction::SqrtSymbol(a, dest)
+        | Instruction::AbsSymbol(a, dest)
+        | Instruction::FloorSymbol(a, dest)
+        | Instruction::CeilSymbol(a, dest)
+        | Instruction::ExpSymbol(a, dest)
+        | Instruction::LnSymbol(a, dest) => (vec![(a, 4)], vec![(dest, 4)]),
+        Instruction::PowSymbols(a, b, dest)
+        | Instruction::FloatMinSymbols(a, b, dest)
+        | Instruction::FloatMaxSymbols(a, b, dest)
+        | Instruction::Arctan2Symbols(a, b, dest) => (vec![(a, 4), (b, 4)], vec![(dest, 4)]),
+
+        Instruction::CompareEqual(a, b, dest)
+        | Instruction::CompareGreater(a, b, dest)
+        | Instruction::CompareLesser(a, b, dest) => (vec![(a, 8), (b, 8)], vec![(dest, 1)]),
+        Instruction::CompareEqualImmediate(a, _, dest)
+        | Instruction::CompareGreaterImmediate(a, _, dest)
+        | Instruction::CompareLesserImmediate(a, _, dest) => (vec![(a, 8)], vec![(dest, 1)]),
+        Instruction::CompareBoolsEqual(a, b, dest) => (vec![(a, 1), (b, 1)], vec![(dest, 1)]),
+        Instruction::CompareFloatsEqual(a, b, dest)
+        | Instruction::CompareFloatsGreater(a, b, dest)
+        | Instruction::CompareFloatsLesser(a, b, dest) => (vec![(a, 4), (b, 4)], vec![(dest, 1)]),
+
+        Instruction::NoOp() => (vec![], vec![]),
+        _ => return None,
+    };
+    return Some(effects);
+}
+
+fn overlaps(a: Range, b: Range) -> bool {
+    a.0 < b.0 + b.1 && b.0 < a.0 + a.1
+}
+
+fn covers(outer: Range, inner: Range) -> bool {
+    outer.0 <= inner.0 && inner.0 + inner.1 <= outer.0 + outer.1
+}
+
+// The plain instruction to run in place of this one, given the values known before it runs.
+// Operations that would overflow or fail are left alone, so they still fail at runtime.
+fn fold(instruction: &Instruction, known: &HashMap<usize, Constant>) -> Option<Instruction> {
+    let int = |symbol: usize| match known.get(&symbol) {
+        Some(Constant::Int(value)) => Some(*value),
+        _ => None,
+    };
+    let boolean = |symbol: usize| match known.get(&symbol) {
+        Some(Constant::Bool(value)) => Some(*value),
+        _ => None,
+    };
+    let folded = match *instruction {
+        Instruction::AddSymbols(a, b, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.checked_add(int(b)?)?),
+        Instruction::SubtractSymbols(a, b, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.checked_sub(int(b)?)?),
+        Instruction::MultiplySymbols(a, b, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.checked_mul(int(b)?)?),
+        Instruction::DivideSymbols(a, b, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.checked_div(int(b)?)?),
+        Instruction::ModuloSymbols(a, b, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.checked_rem(int(b)?)?),
+        Instruction::MinSymbols(a, b, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.min(int(b)?)),
+        Instruction::MaxSymbols(a, b, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.max(int(b)?)),
+
+        Instruction::CheckedAdd(a, b, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.checked_add(int(b)?)?),
+        Instruction::CheckedSubtract(a, b, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.checked_sub(int(b)?)?),
+        Instruction::CheckedMultiply(a, b, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.checked_mul(int(b)?)?),
+        Instruction::WrappingAdd(a, b, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.wrapping_add(int(b)?)),
+        Instruction::WrappingSubtract(a, b, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.wrapping_sub(int(b)?)),
+        Instruction::WrappingMultiply(a, b, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.wrapping_mul(int(b)?)),
+
+        Instruction::AddImmediate(a, literal, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.checked_add(literal)?),
+        Instruction::SubtractImmediate(a, literal, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.checked_sub(literal)?),
+        Instruction::MultiplyImmediate(a, literal, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.checked_mul(literal)?),
+        Instruction::DivideImmediate(a, literal, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.checked_div(literal)?),
+        Instruction::ModuloImmediate(a, literal, dest) => Instruction::WriteIntToSymbol(dest, int(a)?.checked_rem(literal)?),
+
+        Instruction::CompareEqual(a, b, dest) => Instruction::WriteBoolToSymbol(dest, int(a)? == int(b)?),
+        Instruction::CompareGreater(a, b, dest) => Instruction::WriteBoolToSymbol(dest, int(a)? > int(b)?),
+        Instruction::CompareLesser(a, b, dest) => Instruction::WriteBoolToSymbol(dest, int(a)? < int(b)?),
+        Instruction::CompareEqualImmediate(a, literal, dest) => Instruction::WriteBoolToSymbol(dest, int(a)? == literal),
+        Instruction::CompareGreaterImmediate(a, literal, dest) => Instruction::WriteBoolToSymbol(dest, int(a)? > literal),
+        Instruction::CompareLesserImmediate(a, literal, dest) => Instruction::WriteBoolToSymbol(dest, int(a)? < literal),
+        Instruction::CompareBoolsEqual(a, b, dest) => Instruction::WriteBoolToSymbol(dest, boolean(a)? == boolean(b)?),
+
+        Instruction::JumpIfTrue(target, condition) => if boolean(condition)? { Instruction::Jump(target) } else { Instruction::NoOp() },
+        Instruction::JumpIfFalse(target, condition) => if boolean(condition)? { Instruction::NoOp() } else { Instruction::Jump(target) },
+        _ => return None,
+    };
+    return Some(folded);
+}
+
+fn fold_constants(instructions: &mut [Instruction], entrypoint: usize) {
+    let mut leaders = HashSet::from([entrypoint]);
+    for instruction in instructions.iter() {
+        leaders.extend(targets_mut(&mut instruction.clone()).into_iter().map(|target| *target));
+    }
+
+    let mut known: HashMap<usize, Constant> = HashMap::new();
+    for (index, instruction) in instructions.iter_mut().enumerate() {
+        if leaders.contains(&index) {
+            known.clear();
+        }
+        if let Some(folded) = fold(instruction, &known) {
+            *instruction = folded;
+        }
+        let Some((_, writes)) = effects(instruction) else {
+            known.clear();
+            continue;
+        };
+        known.retain(|&symbol, constant| {
+            let size = match constant { Constant::Int(_) => 8, Constant::Bool(_) => 1 };
+            !writes.iter().any(|write| overlaps(*write, (symbol, size)))
+        });
+        match *instruction {
+            Instruction::WriteIntToSymbol(symbol, value) => { known.insert(symbol, Constant::Int(value)); },
+            Instruction::WriteBoolToSymbol(symbol, value) => { known.insert(symbol, Constant::Bool(value)); },
+            _ => {},
+        }
+    }
+}
+
+// Whether an instruction always writes what `effects` says it does. Ones that can fault first,
+// like DivideSymbols or CheckedAdd, may leave the bytes as they were.
+fn always_writes(instruction: &Instruction) -> bool {
+    return matches!(
+        instruction,
+        Instruction::WriteStringToSymbol(..)
+            | Instruction::WriteIntToSymbol(..)
+            | Instruction::WriteBoolToSymbol(..)
+            | Instruction::WriteBytesToSymbol(..)
+            | Instruction::MemCpy(..)
+    );
+}
+
+// A write is dead if straight-line code after it overwrites every byte before reading any of them.
+fn remove_dead_stores(instructions: &mut [Instruction]) {
+    for index in 0..instructions.len() {
+        let write = match instructions[index] {
+            Instruction::WriteStringToSymbol(..)
+            | Instruction::WriteIntToSymbol(..)
+            | Instruction::WriteBoolToSymbol(..)
+            | Instruction::WriteBytesToSymbol(..) => effects(&instructions[index]).unwrap().1[0],
+            _ => continue,
+        };
+        let mut dead = false;
+        for next in &instructions[index + 1..] {
+            let Some((reads, writes)) = effects(next) else { break };
+            if reads.iter().any(|read| overlaps(*read, write)) {
+                break;
+            }
+            if always_writes(next) && writes.iter().any(|other| covers(*other, write)) {
+                dead = true;
+                break;
+            }
+        }
+        if dead {
+            instructions[index] = Instruction::NoOp();
+        }
+    }
+}
+
+// Follow jumps and NoOps from `target` to the first instruction that does something.
+fn final_target(instructions: &[Instruction], mut target: usize) -> usize {
+    let mut seen = HashSet::new();
+    while seen.insert(target) {
+        match instructions.get(target) {
+            Some(Instruction::Jump(next)) => target = *next,
+            Some(Instruction::NoOp()) => target += 1,
+            _ => break,
+        }
+    }
+    return target;
+}
+
+fn collapse_jumps(instructions: &mut [Instruction]) {
+    let original = instructions.to_vec();
+    for instruction in instructions.iter_mut() {
+        for target in targets_mut(instruction) {
+            *target = final_target(&original, *target);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! ConcordeVM's struct packing.
+//!
+//! Converts between lists of integers and fixed-layout binary records, described by format
+//! strings in the spirit of Python's struct module. A format starts with an optional byte order,
+//! `<` for little-endian, `>` or `!` for big-endian, or `=` or `@` for the host's order, followed
+//! by fields, each an optional repeat count and a code:
+//!
+//! - `x`: a pad byte, which takes no value
+//! - `?`: a bool, 1 byte
+//! - `b` / `B`: a signed / unsigned 1 byte integer
+//! - `h` / `H`: a signed / unsigned 2 byte integer
+//! - `i` / `I`: a signed / unsigned 4 byte integer
+//! - `q` / `Q`: a signed / unsigned 8 byte integer
+//!
+//! Fields are packed without alignment padding. Whitespace between fields is ignored.
+
+use crate::log_and_return_err;
+
+use log::error;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Field {
+    Pad,
+    Bool,
+    Int { width: usize, signed: bool },
+}
+
+struct Format {
+    little_endian: bool,
+    fields: Vec<Field>,
+}
+
+impl Format {
+    fn parse(format: &str) -> Result<Format, String> {
+        let mut chars = format.chars().peekable();
+        let little_endian = match chars.peek() {
+            Some('<') => { chars.next(); true },
+            Some('>' | '!') => { chars.next(); false },
+            Some('=' | '@') => { chars.next(); cfg!(target_endian = "little") },
+            _ => cfg!(target_endian = "little"),
+        };
+
+        let mut fields = Vec::new();
+        let mut count: Option<usize> = None;
+        for c in chars {
+            if let Some(digit) = c.to_digit(10) {
+                count = count.unwrap_or(0).checked_mul(10).and_then(|count| count.checked_add(digit as usize));
+                if count.is_none() {
+                    log_and_return_err!("Repeat count in struct format {:?} is too large", format);
+                }
+                continue;
+            }
+            if c.is_whitespace() {
+                if count.is_some() {
+                    log_and_return_err!("Repeat count in struct format {:?} is not followed by a code", format);
+                }
+                continue;
+            }
+            let field = match c {
+                'x' => Field::Pad,
+                '?' => Field::Bool,
+                'b' => Field::Int { width: 1, signed: true },
+                'B' => Field::Int { width: 1, signed: false },
+                'h' => Field::Int { width: 2, signed: true },
+                'H' => Field::Int { width: 2, signed: false },
+                'i' => Field::Int { width: 4, signed: true },
+                'I' => Field::Int { width: 4, signed: false },
+                'q' => Field::Int { width: 8, signed: true },
+                'Q' => Field::Int { width: 8, signed: false },
+                _ => log_and_return_err!("Unknown code {:?} in struct format {:?}", c, format),
+            };
+            fields.extend(std::iter::repeat_n(field, count.take().unwrap_or(1)));
+        }
+        if count.is_some() {
+            log_and_return_err!("Struct format {:?} ends with a repeat count", format);
+        }
+        return Ok(Format { little_endian, fields });
+    }
+
+    fn size(&self) -> usize {
+        return self.fields.iter().map(|field| match field {
+            Field::Pad | Field::Bool => 1,
+            Field::Int { width, .. } => *width,
+        }).sum();
+    }
+}
+
+/// How many values a record with this format holds, and how many bytes it takes up.
+pub(crate) fn shape(format: &str) -> Result<(usize, usize), String> {
+    let format = Format::parse(format)?;
+    let values = format.fields.iter().filter(|field| **field != Field::Pad).count();
+    return Ok((values, format.size()));
+}
+
+/// Pack `values` into a record with the given format. There must be one value per field that
+/// isn't padding, and integers must fit in their fields.
+pub(crate) fn pack(format: &str, values: &[i64]) -> Result<Vec<u8>, String> {
+    let format = Format::parse(format)?;
+    let mut values = values.iter();
+    let mut bytes = Vec::with_capacity(format.size());
+    for field in &format.fields {
+        let (width, encoded) = match *field {
+            Field::Pad => { bytes.push(0); continue },
+            Field::Bool => (1, (*next_value(&mut values)? != 0) as i64),
+            Field::Int { width, signed } => {
+                let value = *next_value(&mut values)?;
+                let bits = width as u32 * 8;
+                let fits = match (signed, bits) {
+                    (_, 64) => signed || value >= 0,
+                    (true, _) => value >= -(1 << (bits - 1)) && value < 1 << (bits - 1),
+                    (false, _) => value >= 0 && value < 1 << bits,
+                };
+                if !fits {
+                    log_and_return_err!("{} doesn't fit in a {} {} byte integer", value, if signed { "signed" } else { "unsigned" }, width);
+                }
+                (width, value)
+            },
+        };
+        if format.little_endian {
+            bytes.extend_from_slice(&encoded.to_le_bytes()[..width]);
+        } else {
+            bytes.extend_from_slice(&encoded.to_be_bytes()[8 - width..]);
+        }
+    }
+    if values.next().is_some() {
+        log_and_return_err!("Too many values to pack into struct format");
+    }
+    return Ok(bytes);
+}
+
+fn next_value<'a>(values: &mut impl Iterator<Item = &'a i64>) -> Result<&'a i64, String> {
+    match values.next() {
+        Some(value) => Ok(value),
+        None => log_and_return_err!("Too few values to pack into struct format"),
+    }
+}
+
+/// Unpack a record with the given format into one value per field that isn't padding. Signed
+/// fields are sign-extended.
+pub(crate) fn unpack(format: &str, bytes: &[u8]) -> Result<Vec<i64>, String> {
+    let format = Format::parse(format)?;
+    if bytes.len() != format.size() {
+        log_and_return_err!("Struct format needs {} bytes, but was given {}", format.size(), bytes.len());
+    }
+    let mut values = Vec::new();
+    let mut offset = 0;
+    for field in &format.fields {
+        let (width, signed) = match *field {
+            Field::Pad => { offset += 1; continue },
+            Field::Bool => (1, false),
+            Field::Int { width, signed } => (width, signed),
+        };
+        let data = &bytes[offset..offset + width];
+        offset += width;
+        let mut padded = [0u8; 8];
+        let mut value = if format.little_endian {
+            padded[..width].copy_from_slice(data);
+            i64::from_le_bytes(padded)
+        } else {
+            padded[8 - width..].copy_from_slice(data);
+            i64::from_be_bytes(padded)
+        };
+        if signed && width < 8 {
+            let shift = 64 - width as u32 * 8;
+            value = (value << shift) >> shift;
+        }
+        values.push(if *field == Field::Bool { (value != 0) as i64 } else { value });
+    }
+    return Ok(values);
+}
@@ -0,0 +1,202 @@
+//! ConcordeVM's VM pool.
+//!
+//! Runs many small CPUs on a few worker threads, for hosts like servers that run a guest program
+//! per request. CPUs can't move between threads, so each instance is built on the worker it's
+//! given to and stays there. Workers take turns between their instances, running each for a slice
+//! of instructions at a time, so one long-running instance can't hold up the others.
+//!
+//! Instances finish by returning, running off the end of their program, or with an error. An
+//! instance that goes over its `InstanceLimits` fails with a `Timeout` or `OutOfMemory` error,
+//! and one that panics while being built or run fails with an error, leaving its worker running.
+//! Programs that need a scheduler, such as ones that Await, can't be run in a pool.
+
+use crate::{CPU, Interrupt, Timeout};
+use crate::domain::panic_message;
+use crate::log_and_return_err;
+
+use log::error;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How much an instance may use over its whole run. `None` leaves that resource unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct InstanceLimits {
+    /// The most instructions the instance may execute.
+    pub max_instructions: Option<u64>,
+    /// The most time the instance may spend running, not counting turns given to others.
+    pub max_time: Option<Duration>,
+    /// The most bytes of memory the instance may use.
+    pub memory_limit: Option<usize>,
+}
+
+// An instance given to a worker, before the worker has built its CPU.
+struct Submission {
+    build: Box<dyn FnOnce() -> CPU + Send>,
+    limits: InstanceLimits,
+    result: Sender<Result<Vec<u8>, String>>,
+}
+
+// An instance a worker is running.
+struct Running {
+    cpu: CPU,
+    limits: InstanceLimits,
+    time: Duration,
+    result: Sender<Result<Vec<u8>, String>>,
+}
+
+/// Where an instance's result will arrive.
+pub struct InstanceHandle {
+    result: Receiver<Result<Vec<u8>, String>>,
+}
+
+impl InstanceHandle {
+    /// Wait for the instance to finish, and return the bytes it returned. An instance that ran
+    /// off the end of its program returns no bytes.
+    pub fn wait(self) -> Result<Vec<u8>, String> {
+        match self.result.recv() {
+            Ok(result) => return result,
+            Err(_) => log_and_return_err!("The worker running the instance panicked"),
+        }
+    }
+
+    /// The instance's result, if it has finished.
+    pub fn try_result(&self) -> Option<Result<Vec<u8>, String>> {
+        return self.result.try_recv().ok();
+    }
+}
+
+/// Worker threads sharing many CPU instances between them.
+pub struct VmPool {
+    workers: Vec<(Sender<Submission>, Arc<AtomicUsize>)>,
+}
+
+impl VmPool {
+    /// Start `workers` threads, which run their instances for `slice` instructions per turn.
+    pub fn new(workers: usize, slice: u64) -> VmPool {
+        let workers = (0..workers.max(1)).map(|_| {
+            let (submissions, received) = channel();
+            let load = Arc::new(AtomicUsize::new(0));
+            let worker_load = Arc::clone(&load);
+            thread::spawn(move || work(received, worker_load, slice.max(1)));
+            (submissions, load)
+        }).collect();
+        return VmPool { workers };
+    }
+
+    /// Run the CPU `build` makes, within `limits`, on the worker with the fewest instances.
+    /// `build` runs on that worker.
+    pub fn submit(&self, build: impl FnOnce() -> CPU + Send + 'static, limits: InstanceLimits) -> InstanceHandle {
+        let (result, received) = channel();
+        let (submissions, load) = self.workers.iter().min_by_key(|(_, load)| load.load(Ordering::Relaxed)).unwrap();
+        load.fetch_add(1, Ordering::Relaxed);
+        let submission = Submission { build: Box::new(build), limits, result };
+        // A worker only stops once the pool is dropped, so this can only fail if it panicked, in
+        // which case the handle reports it.
+        let _ = submissions.send(submission);
+        return InstanceHandle { result: received };
+    }
+
+    /// How many instances have been submitted and haven't finished yet.
+    pub fn pending(&self) -> usize {
+        return self.workers.iter().map(|(_, load)| load.load(Ordering::Relaxed)).sum();
+    }
+}
+
+// Run a worker until the pool is dropped and every instance it was given has finished.
+fn work(submissions: Receiver<Submission>, load: Arc<AtomicUsize>, slice: u64) {
+    let mut instances: VecDeque<Running> = VecDeque::new();
+    let mut open = true;
+    loop {
+        // Only wait for submissions when there's nothing else to do.
+        let next = match instances.is_empty() {
+            true if !open => return,
+            true => submissions.recv().map_err(|_| TryRecvError::Disconnected),
+            false => submissions.try_recv(),
+        };
+        match next {
+            Ok(submission) => match panic::catch_unwind(AssertUnwindSafe(submission.build)) {
+                Ok(mut cpu) => {
+                    if let Some(limit) = submission.limits.memory_limit {
+                        cpu.set_memory_limit(Some(limit));
+                    }
+                    instances.push_back(Running { cpu, limits: submission.limits, time: Duration::ZERO, result: submission.result });
+                },
+                Err(payload) => {
+                    load.fetch_sub(1, Ordering::Relaxed);
+                    let _ = submission.result.send(Err(panicked(&*payload)));
+                },
+            },
+            Err(TryRecvError::Disconnected) => open = false,
+            Err(TryRecvError::Empty) => {},
+        }
+
+        let Some(mut instance) = instances.pop_front() else {
+            continue;
+        };
+        // A panic only fails the instance that raised it, so the worker carries on with the rest.
+        let finished = panic::catch_unwind(AssertUnwindSafe(|| run_slice(&mut instance, slice)))
+            .unwrap_or_else(|payload| Some(Err(panicked(&*payload))));
+        match finished {
+            Some(result) => {
+                load.fetch_sub(1, Ordering::Relaxed);
+                let _ = instance.result.send(result);
+            },
+            None => instances.push_back(instance),
+        }
+    }
+}
+
+// The error for an instance that panicked.
+fn panicked(payload: &(dyn std::any::Any + Send)) -> String {
+    let message = format!("The VM panicked running the instance: {}", panic_message(payload));
+    error!("{}", message);
+    return message;
+}
+
+// Give an instance a turn, returning its result if it finished.
+fn run_slice(instance: &mut Running, slice: u64) -> Option<Result<Vec<u8>, String>> {
+    let executed = instance.cpu.metrics().instructions;
+    let fuel = match instance.limits.max_instructions {
+        Some(max) => slice.min(max.saturating_sub(executed)),
+        None => slice,
+    };
+    let start = Instant::now();
+    let result = instance.cpu.run_for(fuel);
+    instance.time += start.elapsed();
+    let result = match result {
+        Ok(Some(Interrupt::Ret(address, n))) => {
+            let memory = instance.cpu.memory();
+            if address.saturating_add(n) > memory.len() {
+                let message = format!("Tried to return {} bytes at {}, but memory is only {} bytes", n, address, memory.len());
+                error!("{}", message);
+                Err(message)
+            } else {
+                Ok(memory.read(address, n))
+            }
+        },
+        Ok(Some(Interrupt::Ok)) | Ok(Some(Interrupt::EOF)) => Ok(Vec::new()),
+        Ok(Some(_)) => {
+            let message = "The program needs a scheduler to run".to_string();
+            error!("{}", message);
+            Err(message)
+        },
+        Ok(None) => {
+            let instructions = instance.cpu.metrics().instructions;
+            let out_of_instructions = instance.limits.max_instructions.is_some_and(|max| instructions >= max);
+            let out_of_time = instance.limits.max_time.is_some_and(|max| instance.time >= max);
+            if !out_of_instructions && !out_of_time {
+                return None;
+            }
+            let timeout = Timeout { instructions, elapsed: instance.time };
+            error!("{}", timeout);
+            Err(timeout.to_string())
+        },
+        Err(e) => Err(e),
+    };
+    return Some(result);
+}
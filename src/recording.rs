@@ -0,0 +1,240 @@
+//! ConcordeVM's IO recording.
+//!
+//! Every operation ConcordeIO performs on behalf of a guest can be logged to a file, and later
+//! served back from that file instead of touching the real world, making IO-heavy programs
+//! reproducible.
+//!
+//! Recordings are text files with one event per line, holding the operation, a key describing
+//! its arguments, whether it succeeded, was denied by the sandbox, or failed, and its result as
+//! hex. Replays check that each operation and key match the recording, and fail with a
+//! divergence error otherwise. The keys of writes hold a digest of the bytes written, so a replay
+//! that writes different data diverges too.
+
+use crate::io::IoError;
+use crate::log_and_return_err;
+use crate::sandbox::PermissionDenied;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use log::error;
+
+/// A result that can be written into a recording and read back out again.
+pub trait Recordable: Sized {
+    fn to_record(&self) -> Vec<u8>;
+    fn from_record(bytes: &[u8]) -> Result<Self, String>;
+}
+
+impl Recordable for () {
+    fn to_record(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn from_record(_bytes: &[u8]) -> Result<Self, String> {
+        Ok(())
+    }
+}
+
+impl Recordable for Vec<u8> {
+    fn to_record(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn from_record(bytes: &[u8]) -> Result<Self, String> {
+        Ok(bytes.to_vec())
+    }
+}
+
+macro_rules! impl_recordable_for_numerics {
+    ($($t:ty),*) => {
+        $(
+            impl Recordable for $t {
+                fn to_record(&self) -> Vec<u8> {
+                    self.to_le_bytes().to_vec()
+                }
+
+                fn from_record(bytes: &[u8]) -> Result<Self, String> {
+                    match bytes.try_into() {
+                        Ok(buf) => Ok(<$t>::from_le_bytes(buf)),
+                        Err(_) => log_and_return_err!("Recorded value has the wrong size for {}", stringify!($t)),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_recordable_for_numerics!(u32, u64, i64, usize);
+
+impl Recordable for Option<String> {
+    // A leading 1 byte marks Some, so empty strings and None can be told apart.
+    fn to_record(&self) -> Vec<u8> {
+        match self {
+            Some(value) => [&[1u8], value.as_bytes()].concat(),
+            None => Vec::new(),
+        }
+    }
+
+    fn from_record(bytes: &[u8]) -> Result<Self, String> {
+        match bytes.split_first() {
+            Some((1, value)) => match String::from_utf8(value.to_vec()) {
+                Ok(value) => Ok(Some(value)),
+                Err(e) => log_and_return_err!("Recorded string is not valid UTF-8: {}", e),
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+struct IoEvent {
+    operation: String,
+    key: String,
+    result: Result<Vec<u8>, IoError>,
+}
+
+enum Mode {
+    Live,
+    Record(BufWriter<File>),
+    Replay(VecDeque<IoEvent>),
+}
+
+/// Decides whether IO is performed for real, logged to a recording, or served from one.
+pub struct IoRecorder {
+    mode: Mode,
+}
+
+impl Default for IoRecorder {
+    fn default() -> Self {
+        IoRecorder::live()
+    }
+}
+
+impl IoRecorder {
+    /// Perform IO for real without recording it.
+    pub fn live() -> IoRecorder {
+        IoRecorder { mode: Mode::Live }
+    }
+
+    /// Start recording to the file at `path`, replacing it if it exists.
+    pub fn record_to(path: &str) -> Result<IoRecorder, String> {
+        match File::create(path) {
+            Ok(file) => Ok(IoRecorder { mode: Mode::Record(BufWriter::new(file)) }),
+            Err(e) => log_and_return_err!("Could not create IO recording {}: {}", path, e),
+        }
+    }
+
+    /// Load the recording at `path` for replaying.
+    pub fn replay_from(path: &str) -> Result<IoRecorder, String> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => log_and_return_err!("Could not read IO recording {}: {}", path, e),
+        };
+        let mut events = VecDeque::new();
+        for (n, line) in contents.lines().enumerate() {
+            match parse_event(line) {
+                Some(event) => events.push_back(event),
+                None => log_and_return_err!("Malformed event on line {} of IO recording {}", n + 1, path),
+            }
+        }
+        Ok(IoRecorder { mode: Mode::Replay(events) })
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        matches!(self.mode, Mode::Replay(_))
+    }
+
+    /// Add the result of an operation to the recording, if recording.
+    pub fn record<T: Recordable>(&mut self, operation: &str, key: &str, result: &Result<T, IoError>) -> Result<(), String> {
+        if let Mode::Record(writer) = &mut self.mode {
+            let (status, payload) = match result {
+                Ok(value) => ("ok", value.to_record()),
+                // The capability and target, separated by a nul.
+                Err(IoError::PermissionDenied(e)) => ("denied", [e.capability.as_bytes(), &[0], e.target.as_bytes()].concat()),
+                Err(IoError::Failed(e)) => ("err", e.as_bytes().to_vec()),
+            };
+            let line = format!("{}\t{}\t{}\t{}\n", operation, escape(key), status, to_hex(&payload));
+            if let Err(e) = writer.write_all(line.as_bytes()).and_then(|_| writer.flush()) {
+                log_and_return_err!("Failed to write IO recording: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Take the next recorded result, checking it belongs to the given operation.
+    pub fn replay<T: Recordable>(&mut self, operation: &str, key: &str) -> Result<T, IoError> {
+        match self.next_event(operation, key)?.result {
+            Ok(payload) => Ok(T::from_record(&payload)?),
+            Err(e) => {
+                error!("{}", e);
+                Err(e)
+            },
+        }
+    }
+
+    fn next_event(&mut self, operation: &str, key: &str) -> Result<IoEvent, String> {
+        let events = match &mut self.mode {
+            Mode::Replay(events) => events,
+            _ => log_and_return_err!("Tried to replay {} without a recording", operation),
+        };
+        let event = match events.pop_front() {
+            Some(event) => event,
+            None => log_and_return_err!("Replay diverged: program performed {} {} after the recording ended", operation, key),
+        };
+        if event.operation != operation || event.key != key {
+            log_and_return_err!("Replay diverged: program performed {} {}, but the recording has {} {}", operation, key, event.operation, event.key);
+        }
+        Ok(event)
+    }
+}
+
+fn parse_event(line: &str) -> Option<IoEvent> {
+    let mut fields = line.split('\t');
+    let operation = fields.next()?.to_string();
+    let key = unescape(fields.next()?);
+    let status = fields.next()?;
+    let payload = from_hex(fields.next()?)?;
+    let result = match status {
+        "ok" => Ok(payload),
+        "denied" => {
+            let (capability, target) = String::from_utf8(payload).ok()?.split_once('\0').map(|(c, t)| (c.to_string(), t.to_string()))?;
+            Err(IoError::PermissionDenied(PermissionDenied { capability, target }))
+        },
+        "err" => Err(IoError::Failed(String::from_utf8(payload).ok()?)),
+        _ => return None,
+    };
+    Some(IoEvent { operation, key, result })
+}
+
+// Keys hold paths and command lines, which may contain tabs or newlines.
+fn escape(key: &str) -> String {
+    key.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape(key: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = key.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => {},
+        }
+    }
+    unescaped
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
@@ -0,0 +1,348 @@
+//! ConcordeVM's remote execution protocol.
+//!
+//! Lets a client run a program on a ConcordeVM server over a byte stream, usually a TCP
+//! connection. Every message is a frame: a little-endian u32 length, then that many bytes encoded
+//! like snapshots are. A session is any number of runs, one after another:
+//!
+//! 1. The client sends a `RemoteRequest` with a program file, as written by `encode_program`,
+//!    the memory to start it with, and whether to trace it.
+//! 2. While it runs, the server sends a `RemoteResponse::Trace` with a line of the execution log
+//!    for every instruction, if tracing, and a `RemoteResponse::Stdout` or `Stderr` with whatever
+//!    it prints.
+//! 3. Once it stops, the server sends a `RemoteResponse::Finished` with the error it raised, if
+//!    any, and its memory.
+//!
+//! Programs run on a fresh CPU for each request, restricted by the sandbox policy and instance
+//! limits given to the server. One that goes over its limits finishes with a `Timeout` or
+//! `OutOfMemory` error. Programs that need a scheduler, such as ones that Await, fail. A binary
+//! can offer a server mode by calling `serve` with a listener.
+
+use crate::bytecode::{decode_program_with_debug_info, Decoder, Encoder};
+use crate::domain::panic_message;
+use crate::execution_log::ExecutionLog;
+use crate::log_and_return_err;
+use crate::pool::InstanceLimits;
+use crate::sandbox::SandboxPolicy;
+use crate::{CPU, Interrupt, Program, Timeout};
+
+use log::{error, info};
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const REQUEST_MAGIC: &[u8; 4] = b"CVRQ";
+const RESPONSE_MAGIC: &[u8; 4] = b"CVRP";
+const VERSION: u16 = 1;
+
+/// Frames longer than this are rejected, so a bad length can't make the reader allocate without
+/// bound.
+pub const MAX_FRAME_LENGTH: usize = 64 << 20;
+
+/// The most connections `serve` handles at once. Any more are closed as soon as they're accepted.
+pub const MAX_CONNECTIONS: usize = 64;
+
+// How many instructions the server runs between sending what the program printed.
+const SLICE: u64 = 1024;
+
+/// A program for a server to run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteRequest {
+    /// The program file, as written by `encode_program`.
+    pub program: Vec<u8>,
+    /// The memory the program starts with.
+    pub memory: Vec<u8>,
+    /// Whether to send a trace of every instruction.
+    pub trace: bool,
+}
+
+/// How a run ended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteResult {
+    /// The error the program raised, if any.
+    pub error: Option<String>,
+    /// The program's memory once it stopped.
+    pub memory: Vec<u8>,
+}
+
+/// What a server sends back while running a request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RemoteResponse {
+    /// A line of the execution log, as written by `ExecutionLog`, without its newline.
+    Trace(String),
+    /// Bytes the program wrote to stdout.
+    Stdout(Vec<u8>),
+    /// Bytes the program wrote to stderr.
+    Stderr(Vec<u8>),
+    /// The program stopped. This is the last response to a request.
+    Finished(RemoteResult),
+}
+
+impl RemoteRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.header(REQUEST_MAGIC, VERSION);
+        encoder.bytes(&self.program);
+        encoder.bytes(&self.memory);
+        encoder.bool(self.trace);
+        return encoder.finish();
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<RemoteRequest, String> {
+        let mut decoder = Decoder::new(bytes);
+        decoder.header(REQUEST_MAGIC, VERSION)?;
+        let request = RemoteRequest { program: decoder.bytes()?, memory: decoder.bytes()?, trace: decoder.bool()? };
+        finish(&decoder)?;
+        return Ok(request);
+    }
+}
+
+impl RemoteResponse {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.header(RESPONSE_MAGIC, VERSION);
+        match self {
+            RemoteResponse::Trace(line) => {
+                encoder.u8(0);
+                encoder.string(line);
+            },
+            RemoteResponse::Stdout(bytes) => {
+                encoder.u8(1);
+                encoder.bytes(bytes);
+            },
+            RemoteResponse::Stderr(bytes) => {
+                encoder.u8(2);
+                encoder.bytes(bytes);
+            },
+            RemoteResponse::Finished(result) => {
+                encoder.u8(3);
+                encoder.bool(result.error.is_some());
+                if let Some(error) = &result.error {
+                    encoder.string(error);
+                }
+                encoder.bytes(&result.memory);
+            },
+        }
+        return encoder.finish();
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<RemoteResponse, String> {
+        let mut decoder = Decoder::new(bytes);
+        decoder.header(RESPONSE_MAGIC, VERSION)?;
+        let response = match decoder.u8()? {
+            0 => RemoteResponse::Trace(decoder.string()?),
+            1 => RemoteResponse::Stdout(decoder.bytes()?),
+            2 => RemoteResponse::Stderr(decoder.bytes()?),
+            3 => {
+                let error = match decoder.bool()? {
+                    true => Some(decoder.string()?),
+                    false => None,
+                };
+                RemoteResponse::Finished(RemoteResult { error, memory: decoder.bytes()? })
+            },
+            kind => log_and_return_err!("Unknown response kind {}", kind),
+        };
+        finish(&decoder)?;
+        return Ok(response);
+    }
+}
+
+/// Write `payload` to `writer` as a frame.
+pub fn write_frame(writer: &mut impl Write, payload: &[u8]) -> Result<(), String> {
+    if payload.len() > MAX_FRAME_LENGTH {
+        log_and_return_err!("Frame of {} bytes is longer than the most allowed, {}", payload.len(), MAX_FRAME_LENGTH);
+    }
+    let written = writer.write_all(&(payload.len() as u32).to_le_bytes())
+        .and_then(|_| writer.write_all(payload))
+        .and_then(|_| writer.flush());
+    if let Err(e) = written {
+        log_and_return_err!("Failed to write frame: {}", e);
+    }
+    Ok(())
+}
+
+/// Read the payload of the next frame from `reader`, or None if it's ended cleanly.
+pub fn read_frame(reader: &mut impl Read) -> Result<Option<Vec<u8>>, String> {
+    let mut length = [0; 4];
+    if let Err(e) = reader.read_exact(&mut length) {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            return Ok(None);
+        }
+        log_and_return_err!("Failed to read frame: {}", e);
+    }
+    let length = u32::from_le_bytes(length) as usize;
+    if length > MAX_FRAME_LENGTH {
+        log_and_return_err!("Frame of {} bytes is longer than the most allowed, {}", length, MAX_FRAME_LENGTH);
+    }
+    let mut payload = vec![0; length];
+    if let Err(e) = reader.read_exact(&mut payload) {
+        log_and_return_err!("Failed to read frame: {}", e);
+    }
+    Ok(Some(payload))
+}
+
+/// Send `request` to the server at the other end of `stream`, call `on_response` with every
+/// trace and output response, and return how the run ended.
+pub fn run_remote(stream: &mut (impl Read + Write), request: &RemoteRequest, mut on_response: impl FnMut(&RemoteResponse)) -> Result<RemoteResult, String> {
+    write_frame(stream, &request.to_bytes())?;
+    loop {
+        let Some(frame) = read_frame(stream)? else {
+            log_and_return_err!("The server closed the connection before the program finished");
+        };
+        match RemoteResponse::from_bytes(&frame)? {
+            RemoteResponse::Finished(result) => return Ok(result),
+            response => on_response(&response),
+        }
+    }
+}
+
+/// Accept connections from `listener` forever, serving each on a thread of its own, with at most
+/// `MAX_CONNECTIONS` at once. Every request is held to `limits`. Connections that fail to be
+/// accepted are logged and skipped.
+pub fn serve(listener: TcpListener, policy: SandboxPolicy, limits: InstanceLimits) -> Result<(), String> {
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Failed to accept a connection: {}", e);
+                continue;
+            },
+        };
+        let peer = stream.peer_addr().map(|address| address.to_string()).unwrap_or_default();
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            error!("Refused {}: already serving {} connections", peer, MAX_CONNECTIONS);
+            continue;
+        }
+        let policy = policy.clone();
+        let slot = ConnectionSlot(Arc::clone(&connections));
+        thread::spawn(move || {
+            let _slot = slot;
+            info!("Serving {}", peer);
+            if let Err(e) = serve_connection(stream, policy, limits) {
+                error!("Connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+// Gives back a connection's place in the count once its thread is done, even if it panicked.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Run every request the client at the other end of `stream` sends, until it hangs up, holding
+/// each to `limits`. A request that panics the VM finishes with an error, and the connection
+/// carries on.
+pub fn serve_connection(mut stream: impl Read + Write, policy: SandboxPolicy, limits: InstanceLimits) -> Result<(), String> {
+    let policy = Rc::new(policy);
+    while let Some(frame) = read_frame(&mut stream)? {
+        let request = RemoteRequest::from_bytes(&frame)?;
+        let served = panic::catch_unwind(AssertUnwindSafe(|| serve_request(&mut stream, &request, &policy, limits)));
+        let result = match served {
+            Ok(result) => result?,
+            Err(payload) => {
+                let message = format!("The VM panicked running the program: {}", panic_message(&*payload));
+                error!("{}", message);
+                RemoteResult { error: Some(message), memory: Vec::new() }
+            },
+        };
+        write_frame(&mut stream, &RemoteResponse::Finished(result).to_bytes())?;
+    }
+    Ok(())
+}
+
+// Run one request, sending what it traces and prints as it goes.
+fn serve_request(stream: &mut impl Write, request: &RemoteRequest, policy: &Rc<SandboxPolicy>, limits: InstanceLimits) -> Result<RemoteResult, String> {
+    let (instructions, debug_info) = match decode_program_with_debug_info(&request.program) {
+        Ok(program) => program,
+        Err(e) => return Ok(RemoteResult { error: Some(e), memory: Vec::new() }),
+    };
+    let mut program = Program::new(instructions);
+    program.debug_info = debug_info.map(Rc::new);
+    let mut cpu = CPU::with_program(0, program);
+    cpu.set_sandbox_policy(Rc::clone(policy));
+    cpu.set_memory_limit(limits.memory_limit);
+    let output = cpu.capture_output();
+    let trace = Rc::new(RefCell::new(Vec::new()));
+    if request.trace {
+        cpu.set_execution_log(Rc::new(RefCell::new(ExecutionLog::new(TraceWriter(Rc::clone(&trace))))));
+    }
+    if let Err(e) = cpu.memory_mut().extend_memory_to(request.memory.len()) {
+        return Ok(RemoteResult { error: Some(e.to_string()), memory: Vec::new() });
+    }
+    cpu.memory_mut().write(0, &request.memory);
+
+    let mut time = Duration::ZERO;
+    loop {
+        let fuel = match limits.max_instructions {
+            Some(max) => SLICE.min(max.saturating_sub(cpu.metrics().instructions)),
+            None => SLICE,
+        };
+        let start = Instant::now();
+        let result = cpu.run_for(fuel);
+        time += start.elapsed();
+        for line in String::from_utf8_lossy(&trace.borrow()).lines() {
+            write_frame(stream, &RemoteResponse::Trace(line.to_string()).to_bytes())?;
+        }
+        trace.borrow_mut().clear();
+        let (stdout, stderr) = (output.stdout(), output.stderr());
+        output.clear();
+        if !stdout.is_empty() {
+            write_frame(stream, &RemoteResponse::Stdout(stdout).to_bytes())?;
+        }
+        if !stderr.is_empty() {
+            write_frame(stream, &RemoteResponse::Stderr(stderr).to_bytes())?;
+        }
+
+        let error = match result {
+            Ok(None) => {
+                let instructions = cpu.metrics().instructions;
+                let out_of_instructions = limits.max_instructions.is_some_and(|max| instructions >= max);
+                let out_of_time = limits.max_time.is_some_and(|max| time >= max);
+                if !out_of_instructions && !out_of_time {
+                    continue;
+                }
+                let timeout = Timeout { instructions, elapsed: time };
+                error!("{}", timeout);
+                Some(timeout.to_string())
+            },
+            Ok(Some(Interrupt::Ok)) | Ok(Some(Interrupt::EOF)) | Ok(Some(Interrupt::Ret(_, _))) => None,
+            Ok(Some(_)) => Some("The program needs a scheduler to run".to_string()),
+            Err(e) => Some(e),
+        };
+        return Ok(RemoteResult { error, memory: cpu.memory().dump() });
+    }
+}
+
+// Collects execution log lines until they're sent.
+struct TraceWriter(Rc<RefCell<Vec<u8>>>);
+
+impl Write for TraceWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn finish(decoder: &Decoder) -> Result<(), String> {
+    if !decoder.is_finished() {
+        log_and_return_err!("Trailing data after message");
+    }
+    Ok(())
+}
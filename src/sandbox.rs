@@ -0,0 +1,261 @@
+//! ConcordeVM's IO sandbox.
+//!
+//! Provides a policy object that the embedder can use to restrict what guest programs are allowed
+//! to touch through ConcordeIO. The default policy is unrestricted, matching the behaviour of the
+//! VM before sandboxing existed.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+/// How much access a path prefix grants.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// Error returned when a guest tries to do something the sandbox doesn't allow.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PermissionDenied {
+    /// The capability that was missing, eg. "write" or "connect".
+    pub capability: String,
+    /// What the guest tried to use the capability on, eg. a path or host:port.
+    pub target: String,
+}
+
+impl fmt::Display for PermissionDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Permission denied: {} access to {} is not allowed by the sandbox", self.capability, self.target)
+    }
+}
+
+impl std::error::Error for PermissionDenied {}
+
+/// A set of capabilities granted to guest code.
+///
+/// Paths are matched against the allowlisted prefixes after being made absolute and having the
+/// part of them that exists resolved by the filesystem, so neither `..` components nor symlinks
+/// can be used to escape a prefix. Prefixes are resolved the same way when they're allowed.
+#[derive(Debug, Clone)]
+pub struct SandboxPolicy {
+    // None means every path is allowed with read-write access.
+    paths: Option<Vec<(PathBuf, Access)>>,
+    hosts: HashMap<(String, u16), bool>,
+    allow_network_by_default: bool,
+    max_open_streams: Option<usize>,
+    // None means every environment variable may be read.
+    env_vars: Option<HashSet<String>>,
+    allow_env_writes: bool,
+    allow_subprocesses: bool,
+    allow_standard_streams: bool,
+}
+
+impl Default for SandboxPolicy {
+    fn default() -> Self {
+        SandboxPolicy::unrestricted()
+    }
+}
+
+impl SandboxPolicy {
+    /// A policy that allows everything except spawning subprocesses, which always has to be
+    /// enabled explicitly with `allow_subprocesses`.
+    pub fn unrestricted() -> SandboxPolicy {
+        SandboxPolicy {
+            paths: None,
+            hosts: HashMap::new(),
+            allow_network_by_default: true,
+            max_open_streams: None,
+            env_vars: None,
+            allow_env_writes: true,
+            allow_subprocesses: false,
+            allow_standard_streams: true,
+        }
+    }
+
+    /// A policy that allows nothing until capabilities are granted with the methods below.
+    pub fn deny_all() -> SandboxPolicy {
+        SandboxPolicy {
+            paths: Some(Vec::new()),
+            hosts: HashMap::new(),
+            allow_network_by_default: false,
+            max_open_streams: None,
+            env_vars: Some(HashSet::new()),
+            allow_env_writes: false,
+            allow_subprocesses: false,
+            allow_standard_streams: false,
+        }
+    }
+
+    /// Allow access to every path under `prefix`.
+    /// If several prefixes match a path, the longest one decides the access level.
+    pub fn allow_path(mut self, prefix: impl AsRef<Path>, access: Access) -> SandboxPolicy {
+        let prefix = resolve(prefix.as_ref());
+        self.paths.get_or_insert_with(Vec::new).push((prefix, access));
+        self
+    }
+
+    /// Allow connections to the given host and port, regardless of the network default.
+    pub fn allow_host(mut self, host: &str, port: u16) -> SandboxPolicy {
+        self.hosts.insert((host.to_string(), port), true);
+        self
+    }
+
+    /// Deny connections to the given host and port, regardless of the network default.
+    pub fn deny_host(mut self, host: &str, port: u16) -> SandboxPolicy {
+        self.hosts.insert((host.to_string(), port), false);
+        self
+    }
+
+    /// Set whether hosts that have no explicit rule may be connected to.
+    pub fn allow_network_by_default(mut self, allow: bool) -> SandboxPolicy {
+        self.allow_network_by_default = allow;
+        self
+    }
+
+    /// Limit how many streams may be open at once.
+    pub fn max_open_streams(mut self, n: usize) -> SandboxPolicy {
+        self.max_open_streams = Some(n);
+        self
+    }
+
+    /// Allow the environment variable `name` to be read.
+    pub fn allow_env_var(mut self, name: &str) -> SandboxPolicy {
+        self.env_vars.get_or_insert_with(HashSet::new).insert(name.to_string());
+        self
+    }
+
+    /// Set whether guests may set environment variables.
+    pub fn allow_env_writes(mut self, allow: bool) -> SandboxPolicy {
+        self.allow_env_writes = allow;
+        self
+    }
+
+    /// Set whether guests may spawn subprocesses.
+    pub fn allow_subprocesses(mut self, allow: bool) -> SandboxPolicy {
+        self.allow_subprocesses = allow;
+        self
+    }
+
+    /// Set whether guests may open stdin, stdout, and stderr, and print to stdout.
+    pub fn allow_standard_streams(mut self, allow: bool) -> SandboxPolicy {
+        self.allow_standard_streams = allow;
+        self
+    }
+
+    /// Check that the path may be read.
+    pub fn check_read(&self, path: &str) -> Result<(), PermissionDenied> {
+        match self.access_for(path) {
+            Some(_) => Ok(()),
+            None => Err(denied("read", path)),
+        }
+    }
+
+    /// Check that the path may be written, created, or removed.
+    pub fn check_write(&self, path: &str) -> Result<(), PermissionDenied> {
+        match self.access_for(path) {
+            Some(Access::ReadWrite) => Ok(()),
+            _ => Err(denied("write", path)),
+        }
+    }
+
+    /// Check that a connection to `host:port` may be made.
+    pub fn check_connect(&self, host: &str, port: u16) -> Result<(), PermissionDenied> {
+        let allowed = self.hosts.get(&(host.to_string(), port)).copied().unwrap_or(self.allow_network_by_default);
+        if allowed {
+            Ok(())
+        } else {
+            Err(denied("connect", &format!("{}:{}", host, port)))
+        }
+    }
+
+    /// Check that another stream may be opened while `n_open` are already open.
+    pub fn check_open_streams(&self, n_open: usize) -> Result<(), PermissionDenied> {
+        match self.max_open_streams {
+            Some(max) if n_open >= max => Err(denied("open", &format!("more than {} streams", max))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that the environment variable may be read.
+    pub fn check_env_read(&self, name: &str) -> Result<(), PermissionDenied> {
+        match &self.env_vars {
+            Some(names) if !names.contains(name) => Err(denied("read", &format!("environment variable {}", name))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that the environment variable may be set.
+    pub fn check_env_write(&self, name: &str) -> Result<(), PermissionDenied> {
+        if self.allow_env_writes {
+            Ok(())
+        } else {
+            Err(denied("write", &format!("environment variable {}", name)))
+        }
+    }
+
+    /// Check that the command may be run as a subprocess.
+    pub fn check_spawn(&self, command: &str) -> Result<(), PermissionDenied> {
+        if self.allow_subprocesses {
+            Ok(())
+        } else {
+            Err(denied("spawn", command))
+        }
+    }
+
+    /// Check that the standard stream `name`, eg. "stdout", may be used.
+    pub fn check_standard_stream(&self, name: &str) -> Result<(), PermissionDenied> {
+        if self.allow_standard_streams {
+            Ok(())
+        } else {
+            Err(denied("open", name))
+        }
+    }
+
+    fn access_for(&self, path: &str) -> Option<Access> {
+        let paths = match &self.paths {
+            Some(paths) => paths,
+            None => return Some(Access::ReadWrite),
+        };
+        let path = resolve(Path::new(path));
+        return paths.iter()
+            .filter(|(prefix, _)| path.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.components().count())
+            .map(|(_, access)| *access);
+    }
+}
+
+fn denied(capability: &str, target: &str) -> PermissionDenied {
+    PermissionDenied { capability: capability.to_string(), target: target.to_string() }
+}
+
+/// Make a path absolute, and resolve symlinks and `..` components in the longest part of it that
+/// exists. The rest doesn't exist yet, so has no symlinks, and is normalised lexically.
+fn resolve(path: &Path) -> PathBuf {
+    let joined = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().unwrap_or_default().join(path)
+    };
+    for existing in joined.ancestors() {
+        if let Ok(resolved) = std::fs::canonicalize(existing) {
+            // `ancestors` only strips components, so what's left is always a suffix.
+            let rest = joined.strip_prefix(existing).unwrap_or(Path::new(""));
+            return normalise(&resolved.join(rest));
+        }
+    }
+    return normalise(&joined);
+}
+
+/// Remove `.` and `..` components without touching the filesystem.
+fn normalise(path: &Path) -> PathBuf {
+    let mut normalised = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => { normalised.pop(); },
+            other => normalised.push(other.as_os_str()),
+        }
+    }
+    return normalised;
+}
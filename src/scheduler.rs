@@ -1,16 +1,26 @@
 use core::panic;
-use std::{collections::{HashMap, HashSet, VecDeque}, ops::Deref, sync::{Arc, RwLock}, thread};
-use crate::{CPU, Interrupt, Memory, domain::{FFIFuncTable, FFIFunctionInfo, FFIFunctionSignature}, memory::ByteSerialisable};
+use std::{cell::RefCell, collections::{HashMap, HashSet, VecDeque}, fmt, ops::Deref, path::PathBuf, rc::Rc, sync::{mpsc::{channel, Receiver, Sender}, Arc, Mutex, RwLock, RwLockWriteGuard}, thread, time::Duration};
+use crate::{CPU, Fault, Interrupt, Memory, Timeout, domain::{FFIFuncTable, FFIFunctionInfo, FFIFunctionSignature}, memory::ByteSerialisable};
 use libffi::raw::ffi_type;
 use log::info;
 use crate::cpu::Program;
+use crate::instructions::{ArithmeticMode, DispatchTable, Handler};
 use crate::domain::generic_ffi_call;
-
-struct FFIResult {
-    fut_id: Id,
-    value: Vec<u8>,
-    
-}
+use crate::clock::{Clock, SystemClock};
+use crate::channel::ChannelEnd;
+use crate::io::{CapturedOutput, Environment};
+use crate::memory::SharedRegions;
+use crate::event_sources::{self, EventSender, Wakeup};
+use crate::events::{Event, EventBus};
+use crate::coverage::Coverage;
+use crate::execution_log::ExecutionLog;
+use crate::metrics::Metrics;
+use crate::recording::IoRecorder;
+use crate::isolated_domains::DomainIsolation;
+use crate::sandbox::SandboxPolicy;
+use crate::semaphores::{Kind, Semaphores};
+use crate::verifier;
+use crate::snapshot::{CoroutineSnapshot, FutureSnapshot, VmSnapshot};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum FutureState {
@@ -30,6 +40,26 @@ pub enum CoroutineState {
 
 type Id = usize;
 
+/// Error returned when spawning a coroutine would go over the scheduler's coroutine limit. Every
+/// call spawns a coroutine, so this is what runaway recursion runs into.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackOverflow {
+    /// The most coroutines that may be alive at once.
+    pub limit: usize,
+    /// The coroutine that tried to spawn another, then the ones awaiting it, innermost first.
+    pub backtrace: Vec<Id>,
+    /// Every live coroutine, with its state and where it is, one per line.
+    pub coroutines: String,
+}
+
+impl fmt::Display for StackOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Stack overflow: more than {} coroutines alive at once. Live coroutines:\n{}", self.limit, self.coroutines)
+    }
+}
+
+impl std::error::Error for StackOverflow {}
+
 pub struct Future {
     id: Id,
     state: FutureState,
@@ -54,6 +84,7 @@ pub struct Coroutine {
     state: CoroutineState,
     depends_on: HashMap<Id, usize>,   // Futures awaited by this coro, with the location to write the value to
     return_to_fut: Option<Id>,      // Future whose value is the return value of this coro, if any
+    scopes: Vec<Vec<Id>>,   // Futures of the coroutines spawned in each open scope, innermost last
     cpu: CPU
 }
 
@@ -65,6 +96,7 @@ impl Coroutine {
             state: CoroutineState::Runnable,
             depends_on: HashMap::new(),
             return_to_fut: None,
+            scopes: Vec::new(),
             cpu: CPU::with_program(0, program)
         }
     }
@@ -74,34 +106,489 @@ impl Coroutine {
         self.state = CoroutineState::Suspended;
     }
 
+    /// Borrow this coroutine's memory.
+    pub fn memory(&self) -> &Memory {
+        return self.cpu.memory();
+    }
+
     pub fn memory_dump(&self) -> Memory {
         return self.cpu.memory.clone();
     }
+
+    /// The error the coroutine's last instruction raised, if it failed.
+    pub fn fault(&self) -> Option<&Fault> {
+        return self.cpu.fault();
+    }
+
+    /// The `Timeout` the watchdog stopped this coroutine with, if it did.
+    pub fn timed_out(&self) -> Option<&Timeout> {
+        return self.cpu.timed_out();
+    }
+}
+
+// A handler block and the messages waiting for it. Messages are handled one at a time, in the
+// order they were sent, each by a new coroutine started at the handler with the message at
+// symbol 0.
+struct Actor {
+    handler: Program,
+    message_size: usize,
+    mailbox: VecDeque<Vec<u8>>,
+    handling: Option<Id>,   // Coroutine handling the current message, if any
 }
 
 pub struct Scheduler {
     coroutines: HashMap<Id, Coroutine>,
     futures: HashMap<Id, Future>,
+    actors: HashMap<Id, Actor>,
     ready_queue: VecDeque<Id>,
     _new_spawned_coro_id: Id,
     _new_spawned_future_id: Id,
+    _new_spawned_actor_id: Id,
     running: bool,
     ffi_func_table: Arc<RwLock<FFIFuncTable>>,
-    curr_coro_id: usize
+    ffi_calls: HashMap<Id, usize>,  // Domains of the FFI calls in flight, by the future they complete
+    running_calls: Arc<Mutex<HashMap<usize, usize>>>,  // Calls still running into each domain, including timed out ones
+    domain_isolation: Option<DomainIsolation>,
+    domain_call_timeout: Option<Duration>,
+    curr_coro_id: usize,
+    sandbox_policy: Rc<SandboxPolicy>,
+    environment: Rc<RefCell<Environment>>,
+    io_recorder: Rc<RefCell<IoRecorder>>,
+    execution_log: Option<Rc<RefCell<ExecutionLog>>>,
+    coverage: Option<Rc<RefCell<Coverage>>>,
+    events: Rc<RefCell<EventBus>>,
+    shared: SharedRegions,
+    core_dumps: Option<(PathBuf, usize)>,
+    watchdog: (Option<Duration>, Option<u64>),
+    max_coroutines: Option<usize>,
+    stack_overflow: Option<StackOverflow>,
+    memory_limit: Option<usize>,
+    rng_seed: Option<u64>,
+    inherit_memory: bool,
+    semaphores: Semaphores,
+    clock: Rc<dyn Clock>,
+    capture: Option<CapturedOutput>,
+    channels: Rc<RefCell<HashMap<String, ChannelEnd>>>,
+    wakeup_sender: Sender<Wakeup>,
+    wakeups: Receiver<Wakeup>,
+    event_waiters: HashMap<String, Vec<Id>>,    // Futures completed when each event fires
+    pending_events: HashMap<String, VecDeque<Vec<u8>>>,    // Payloads of events fired with nothing waiting
+    arithmetic: Option<ArithmeticMode>,
+    assertions: Option<bool>,
+    dispatch: Rc<DispatchTable>,
+    optimize: bool,
+    verify: bool,
 }
 
 impl Scheduler {
     pub fn new() -> Self {
+        let (wakeup_sender, wakeups) = channel();
         Scheduler {
             coroutines: HashMap::new(),
             futures: HashMap::new(),
+            actors: HashMap::new(),
             ready_queue: VecDeque::new(),
             _new_spawned_coro_id: 0,     // Id that will be assigned to any coro that spawns, NOT the id of the coro currently being run
             _new_spawned_future_id: 0,
+            _new_spawned_actor_id: 0,
             running: false,
             ffi_func_table: Arc::new(RwLock::new(FFIFuncTable::new())),
+            ffi_calls: HashMap::new(),
+            running_calls: Arc::new(Mutex::new(HashMap::new())),
+            domain_isolation: None,
+            domain_call_timeout: None,
             curr_coro_id: 0,
+            sandbox_policy: Rc::new(SandboxPolicy::unrestricted()),
+            environment: Rc::new(RefCell::new(Environment::default())),
+            io_recorder: Rc::new(RefCell::new(IoRecorder::live())),
+            execution_log: None,
+            coverage: None,
+            events: Rc::new(RefCell::new(EventBus::default())),
+            shared: SharedRegions::default(),
+            core_dumps: None,
+            watchdog: (None, None),
+            max_coroutines: None,
+            stack_overflow: None,
+            memory_limit: None,
+            rng_seed: None,
+            inherit_memory: false,
+            semaphores: Semaphores::default(),
+            clock: Rc::new(SystemClock::new()),
+            capture: None,
+            channels: Rc::new(RefCell::new(HashMap::new())),
+            wakeup_sender,
+            wakeups,
+            event_waiters: HashMap::new(),
+            pending_events: HashMap::new(),
+            arithmetic: None,
+            assertions: None,
+            dispatch: DispatchTable::standard(),
+            optimize: true,
+            verify: false,
+        }
+    }
+
+    /// Restrict the IO that coroutines spawned from now on can perform.
+    pub fn set_sandbox_policy(&mut self, policy: SandboxPolicy) {
+        self.sandbox_policy = Rc::new(policy);
+    }
+
+    /// Record all IO performed by coroutines spawned from now on to the file at `path`.
+    pub fn record_io(&mut self, path: &str) -> Result<(), String> {
+        self.io_recorder = Rc::new(RefCell::new(IoRecorder::record_to(path)?));
+        Ok(())
+    }
+
+    /// Serve all IO performed by coroutines spawned from now on from the recording at `path`,
+    /// instead of performing it for real.
+    pub fn replay_io(&mut self, path: &str) -> Result<(), String> {
+        self.io_recorder = Rc::new(RefCell::new(IoRecorder::replay_from(path)?));
+        Ok(())
+    }
+
+    /// Log every instruction coroutines spawned from now on execute to `writer`, as a line of
+    /// JSON each.
+    pub fn log_execution(&mut self, writer: impl std::io::Write + 'static) {
+        self.execution_log = Some(Rc::new(RefCell::new(ExecutionLog::new(writer))));
+    }
+
+    /// Count every instruction coroutines spawned from now on execute, in the returned coverage.
+    pub fn track_coverage(&mut self) -> Rc<RefCell<Coverage>> {
+        let coverage = Rc::new(RefCell::new(Coverage::new()));
+        self.coverage = Some(Rc::clone(&coverage));
+        return coverage;
+    }
+
+    /// Count every instruction coroutines spawned from now on execute in `coverage`, which may be
+    /// shared with others.
+    pub fn set_coverage(&mut self, coverage: Rc<RefCell<Coverage>>) {
+        self.coverage = Some(coverage);
+    }
+
+    /// Call `listener` with every event from every coroutine, along with the scheduler's own
+    /// events, like coroutines being spawned.
+    pub fn subscribe(&mut self, listener: impl FnMut(&Event) + 'static) {
+        self.events.borrow_mut().subscribe(Box::new(listener));
+    }
+
+    /// Write a `CoreDump` to `path` whenever an instruction in a coroutine spawned from now on
+    /// fails, holding the pcs of its last `trace_length` cycles.
+    pub fn enable_core_dumps(&mut self, path: impl Into<PathBuf>, trace_length: usize) {
+        self.core_dumps = Some((path.into(), trace_length));
+    }
+
+    /// Stop a coroutine spawned from now on with a `Timeout` error if it runs for `max_time`, or
+    /// executes `max_instructions`, without giving way to another.
+    pub fn set_watchdog(&mut self, max_time: Option<Duration>, max_instructions: Option<u64>) {
+        self.watchdog = (max_time, max_instructions);
+    }
+
+    /// Limit how many coroutines may be alive at once. Since calls spawn a coroutine, this bounds
+    /// recursion depth, and runaway recursion fails with a `StackOverflow` error instead of using
+    /// up all memory.
+    pub fn set_max_coroutines(&mut self, max: usize) {
+        self.max_coroutines = Some(max);
+    }
+
+    /// The `StackOverflow` the last run failed with, if it went over the coroutine limit.
+    pub fn stack_overflow(&self) -> Option<&StackOverflow> {
+        return self.stack_overflow.as_ref();
+    }
+
+    /// Limit how many bytes of memory each coroutine spawned from now on can use. Coroutines that
+    /// try to grow past it fail with an OutOfMemory error.
+    pub fn set_memory_limit(&mut self, limit: usize) {
+        self.memory_limit = Some(limit);
+    }
+
+    /// Give coroutines spawned from now on the time from `clock`, such as a `VirtualClock` for
+    /// runs that must not depend on the host.
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Keep what coroutines spawned from now on write to stdout and stderr in memory instead of
+    /// passing it to the host's. Every coroutine shares the returned capture.
+    pub fn capture_output(&mut self) -> CapturedOutput {
+        let capture = CapturedOutput::new();
+        self.capture = Some(capture.clone());
+        return capture;
+    }
+
+    /// Connect `end` to every coroutine, so the first to open the stream "channel://`name`" gets
+    /// it.
+    pub fn connect_channel(&mut self, name: &str, end: ChannelEnd) {
+        self.channels.borrow_mut().insert(name.to_string(), end);
+    }
+
+    /// Load the libraries of domains loaded from now on in helper processes started as
+    /// `isolation` says, so a crash in one fails the calls into it instead of the whole VM.
+    pub fn isolate_domains(&mut self, isolation: DomainIsolation) {
+        self.domain_isolation = Some(isolation);
+    }
+
+    /// Fail calls into domains that haven't returned after `timeout`, or let them run for as
+    /// long as they take if it's None. A call that times out keeps running in the background and
+    /// holding the domain table, so until it finishes, loading, reloading and unloading domains and
+    /// adding functions to them fail instead of waiting for it.
+    pub fn set_domain_call_timeout(&mut self, timeout: Option<Duration>) {
+        self.domain_call_timeout = timeout;
+    }
+
+    /// Load the library of domain `domain_id` again from its path, and look up the functions
+    /// added to it again, so a rebuilt plugin can be used without restarting. Fails while calls
+    /// into it are running. If the library can't be loaded, the domain is left unloaded.
+    pub fn reload_domain(&mut self, domain_id: usize) -> Result<(), String> {
+        self.check_no_calls_in_flight(domain_id)?;
+        unsafe { if let Err(x) = self.domains_mut()?.reload_domain(domain_id) {
+            return Err(format!("Error reloading domain {}: {}", domain_id, x.deref()));
+        }};
+        return Ok(());
+    }
+
+    // Closing a library while one of its functions runs would pull the code out from under it.
+    // Calls that timed out count until they return, since they are still running.
+    fn check_no_calls_in_flight(&self, domain_id: usize) -> Result<(), String> {
+        let in_flight = self.running_calls.lock().unwrap().get(&domain_id).copied().unwrap_or_default();
+        if in_flight > 0 {
+            return Err(format!("Can't unload domain {} while {} calls into it are in flight", domain_id, in_flight));
+        }
+        return Ok(());
+    }
+
+    // Running calls hold the domain table for reading, and one that timed out may never let go,
+    // so changing the table fails rather than blocking the scheduler on the lock.
+    fn domains_mut(&self) -> Result<RwLockWriteGuard<'_, FFIFuncTable>, String> {
+        return self.ffi_func_table.try_write().map_err(|_| {
+            let running: usize = self.running_calls.lock().unwrap().values().sum();
+            format!("Can't change domains while {} calls into them are in flight", running)
+        });
+    }
+
+    /// A handle for firing events that coroutines wait for with AwaitEvent.
+    pub fn event_sender(&self) -> EventSender {
+        return EventSender::new(self.wakeup_sender.clone());
+    }
+
+    /// Fire the event `name` every `interval`, with the number of times it has fired as an i64.
+    pub fn add_timer(&mut self, name: &str, interval: Duration) {
+        event_sources::start_timer(self.event_sender(), name.to_string(), interval);
+    }
+
+    /// Fire the event `name` whenever the file at `path` is created, changed, or removed,
+    /// checking every `poll_interval`, with the number of changes seen as an i64.
+    pub fn watch_file(&mut self, name: &str, path: impl Into<PathBuf>, poll_interval: Duration) {
+        event_sources::watch_file(self.event_sender(), name.to_string(), path.into(), poll_interval);
+    }
+
+    /// Handle integer overflow with `mode` in coroutines spawned from now on, in place of the mode
+    /// of their program.
+    pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode) {
+        self.arithmetic = Some(mode);
+    }
+
+    /// Choose whether coroutines created with CreateCoroutine start with a copy of their parent's
+    /// memory, rather than empty memory. Either way, their arguments are written at address 0.
+    ///
+    /// Every coroutine has memory of its own: a copy shares its bytes with the parent's until
+    /// either writes to them, and writes are never seen by the other. Coroutines only share data
+    /// through shared regions and channels.
+    pub fn set_inherit_memory(&mut self, enabled: bool) {
+        self.inherit_memory = enabled;
+    }
+
+    /// Choose whether coroutines spawned from now on check Assert and AssertType, in place of the
+    /// setting of their program.
+    pub fn set_assertions(&mut self, enabled: bool) {
+        self.assertions = Some(enabled);
+    }
+
+    /// Seed the random numbers of coroutines spawned from now on, so a program using RandomInt or
+    /// RandomBytes can be replayed exactly. Each coroutine draws from its own stream, picked by its
+    /// id.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng_seed = Some(seed);
+    }
+
+    /// Choose whether programs passed to `run` have their symbol types checked first, so a
+    /// program that reads a symbol as the wrong type is rejected before it starts.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// Run `opcode` with `handler` in coroutines spawned from now on.
+    pub fn register_handler(&mut self, opcode: u8, handler: Handler) -> Result<(), String> {
+        return Rc::make_mut(&mut self.dispatch).register(opcode, handler);
+    }
+
+    /// Choose whether programs passed to `run` are optimized first. On by default; turn it off to
+    /// debug a program exactly as it was written.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// Set the arguments guest programs see through GetArgs.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.environment.borrow_mut().set_args(args);
+    }
+
+    /// Add a directory to search for modules loaded by Import.
+    pub fn add_module_path(&mut self, path: impl Into<std::path::PathBuf>) {
+        self.environment.borrow_mut().add_module_path(path);
+    }
+
+    // Give a coroutine's CPU the sandbox, environment, recorder, execution log, coverage, event
+    // listeners, shared regions, core dump settings, limits, watchdog, RNG seed, clock, output capture,
+    // channels, arithmetic mode, and assertion setting shared by all coroutines.
+    fn share_host_state(&self, id: Id, cpu: &mut CPU) {
+        cpu.set_memory_limit(self.memory_limit);
+        cpu.set_watchdog(self.watchdog.0, self.watchdog.1);
+        if let Some(mode) = self.arithmetic {
+            cpu.program.arithmetic = mode;
+        }
+        if let Some(enabled) = self.assertions {
+            cpu.program.assertions = enabled;
         }
+        if let Some(seed) = self.rng_seed {
+            cpu.seed_rng(seed, id as u64);
+        }
+        cpu.set_sandbox_policy(Rc::clone(&self.sandbox_policy));
+        cpu.set_environment(Rc::clone(&self.environment));
+        cpu.set_io_recorder(Rc::clone(&self.io_recorder));
+        if let Some(log) = &self.execution_log {
+            cpu.set_execution_log(Rc::clone(log));
+        }
+        if let Some(coverage) = &self.coverage {
+            cpu.set_coverage(Rc::clone(coverage));
+        }
+        cpu.set_event_bus(Rc::clone(&self.events));
+        cpu.memory.set_shared(self.shared.for_owner(id));
+        if let Some((path, trace_length)) = &self.core_dumps {
+            cpu.enable_core_dumps(path.clone(), *trace_length);
+        }
+        cpu.set_clock(Rc::clone(&self.clock));
+        if let Some(capture) = &self.capture {
+            cpu.set_output_capture(capture.clone());
+        }
+        cpu.set_channels(Rc::clone(&self.channels));
+        cpu.set_dispatch_table(Rc::clone(&self.dispatch));
+    }
+
+    /// What every coroutine alive has executed so far, and the memory and streams they're using,
+    /// along with how many are in each state.
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = Metrics::default();
+        for coroutine in self.coroutines.values() {
+            metrics.add(&coroutine.cpu.metrics());
+            let count = match coroutine.state {
+                CoroutineState::Runnable => &mut metrics.coroutines.runnable,
+                CoroutineState::Suspended => &mut metrics.coroutines.suspended,
+                CoroutineState::Running => &mut metrics.coroutines.running,
+                CoroutineState::Finished => &mut metrics.coroutines.finished,
+                CoroutineState::Cancelled => &mut metrics.coroutines.cancelled,
+            };
+            *count += 1;
+        }
+        return metrics;
+    }
+
+    /// Capture the state of every coroutine and future.
+    /// Loaded FFI domains, in-flight FFI calls, and open streams are not included. Fails if there
+    /// are shared regions, actors, mutexes or semaphores, fired or awaited events, or open scopes,
+    /// which snapshots can't hold.
+    pub fn snapshot(&self) -> Result<VmSnapshot, String> {
+        if !self.shared.is_empty() {
+            return Err("Can't snapshot a scheduler with shared regions".to_string());
+        }
+        if !self.actors.is_empty() {
+            return Err("Can't snapshot a scheduler with actors".to_string());
+        }
+        if !self.semaphores.is_empty() {
+            return Err("Can't snapshot a scheduler with mutexes or semaphores".to_string());
+        }
+        if !self.event_waiters.is_empty() || !self.pending_events.is_empty() {
+            return Err("Can't snapshot a scheduler with events being waited for or not yet awaited".to_string());
+        }
+        if let Some(coroutine) = self.coroutines.values().find(|coroutine| !coroutine.scopes.is_empty()) {
+            return Err(format!("Can't snapshot a scheduler while coroutine {} has a scope open", coroutine.id));
+        }
+
+        let mut coroutines: Vec<CoroutineSnapshot> = self.coroutines.values().map(|coroutine| {
+            let mut depends_on: Vec<(Id, usize)> = coroutine.depends_on.iter().map(|(id, location)| (*id, *location)).collect();
+            depends_on.sort();
+            CoroutineSnapshot {
+                id: coroutine.id,
+                priority: coroutine.priority,
+                state: coroutine.state.clone(),
+                depends_on,
+                return_to_fut: coroutine.return_to_fut,
+                cpu: coroutine.cpu.snapshot(),
+            }
+        }).collect();
+        coroutines.sort_by_key(|coroutine| coroutine.id);
+
+        let mut futures: Vec<FutureSnapshot> = self.futures.values().map(|future| {
+            let mut dependants: Vec<Id> = future.dependants.iter().copied().collect();
+            dependants.sort();
+            FutureSnapshot { id: future.id, state: future.state.clone(), dependants, value: future.value.clone() }
+        }).collect();
+        futures.sort_by_key(|future| future.id);
+
+        return Ok(VmSnapshot {
+            coroutines,
+            futures,
+            ready_queue: self.ready_queue.iter().copied().collect(),
+            next_coro_id: self._new_spawned_coro_id,
+            next_future_id: self._new_spawned_future_id,
+            curr_coro_id: self.curr_coro_id,
+        });
+    }
+
+    /// Replace every coroutine and future with those from a snapshot, and remove any shared
+    /// regions, actors, mutexes, semaphores and events. Execution continues from where the
+    /// snapshot was taken on the next call to `_run`, with the current sandbox, environment, and
+    /// IO recorder. FFI domains must be loaded again before they are used.
+    pub fn restore(&mut self, snapshot: &VmSnapshot) {
+        self.coroutines.clear();
+        self.futures.clear();
+        self.shared.clear();
+        self.actors.clear();
+        self.semaphores = Semaphores::default();
+        self.event_waiters.clear();
+        self.pending_events.clear();
+        self.ready_queue = snapshot.ready_queue.iter().copied().collect();
+
+        for saved in &snapshot.coroutines {
+            let mut coroutine = Coroutine::new(saved.id, saved.priority, Program::default());
+            coroutine.cpu.restore(&saved.cpu);
+            self.share_host_state(saved.id, &mut coroutine.cpu);
+            coroutine.depends_on = saved.depends_on.iter().copied().collect();
+            coroutine.return_to_fut = saved.return_to_fut;
+            // A coroutine that was running when the snapshot was taken resumes first.
+            coroutine.state = match saved.state {
+                CoroutineState::Running => {
+                    self.ready_queue.push_front(saved.id);
+                    CoroutineState::Runnable
+                },
+                ref state => state.clone(),
+            };
+            self.coroutines.insert(saved.id, coroutine);
+        }
+
+        for saved in &snapshot.futures {
+            let future = Future {
+                id: saved.id,
+                state: saved.state.clone(),
+                dependants: saved.dependants.iter().copied().collect(),
+                value: saved.value.clone(),
+            };
+            self.futures.insert(saved.id, future);
+        }
+
+        self._new_spawned_coro_id = snapshot.next_coro_id;
+        self._new_spawned_future_id = snapshot.next_future_id;
+        self.curr_coro_id = snapshot.curr_coro_id;
+        self.running = false;
     }
 
     fn get_new_fut_id(&mut self) -> Id {
@@ -115,16 +602,35 @@ impl Scheduler {
     }
 
     pub fn spawn_coro(&mut self, program: Program, priority: i32, args: & dyn ByteSerialisable) -> Result<Id, String> {
+        return self.spawn_coro_with_memory(program, priority, None, args);
+    }
+
+    // Spawn a coroutine like spawn_coro, starting with `memory` if given.
+    fn spawn_coro_with_memory(&mut self, program: Program, priority: i32, memory: Option<Memory>, args: & dyn ByteSerialisable) -> Result<Id, String> {
+        if let Some(max) = self.max_coroutines && self.coroutines.len() >= max {
+            let backtrace = std::iter::once(self.curr_coro_id).chain(self.callers(self.curr_coro_id).iter().map(|caller| caller.id)).collect();
+            let overflow = StackOverflow { limit: max, backtrace, coroutines: self.dump_coroutines() };
+            let error = format!("{}\n{}", overflow, self.backtrace(self.curr_coro_id));
+            self.stack_overflow = Some(overflow);
+            return Err(error);
+        }
         let id = self.get_new_coro_id();
         
         let fut_id = self.spawn_fut();
 
         let mut coroutine = Coroutine::new(id, priority, program);
         coroutine.return_to_fut = Some(fut_id);
+        if let Some(memory) = memory {
+            coroutine.cpu.memory = memory;
+        }
+        self.share_host_state(id, &mut coroutine.cpu);
+        if self.events.borrow().has_listeners() {
+            self.events.borrow_mut().publish(&Event::CoroutineSpawned { id, pc: coroutine.cpu.program.pc });
+        }
         
         {
-            let memory = coroutine.cpu.get_memory_mut();
-            memory.extend_memory_to(args.get_size());
+            let memory = coroutine.cpu.memory_mut();
+            memory.extend_memory_to(args.get_size())?;
             memory.write(0, args);
         }
 
@@ -135,6 +641,80 @@ impl Scheduler {
         return Ok(fut_id);
     }
 
+    /// Start an actor that handles each `message_size` byte message sent to it with a coroutine
+    /// running `handler` from its pc, and return the actor's id.
+    pub fn spawn_actor(&mut self, handler: Program, message_size: usize) -> Id {
+        self._new_spawned_actor_id += 1;
+        let id = self._new_spawned_actor_id;
+        self.actors.insert(id, Actor { handler, message_size, mailbox: VecDeque::new(), handling: None });
+        info!("Spawned new actor with id {}", id);
+        return id;
+    }
+
+    /// Put `message` in the mailbox of actor `actor_id`. It's handled once the actor has handled
+    /// every message sent before it.
+    pub fn send_message(&mut self, actor_id: Id, message: Vec<u8>) -> Result<(), String> {
+        let actor = self.actors.get_mut(&actor_id)
+            .ok_or_else(|| format!("Actor {} not found", actor_id))?;
+        if message.len() != actor.message_size {
+            return Err(format!("Actor {} takes {} byte messages, but was sent {} bytes", actor_id, actor.message_size, message.len()));
+        }
+        actor.mailbox.push_back(message);
+        return self.handle_next_message(actor_id);
+    }
+
+    // Spawn a coroutine for the next message of an idle actor, if it has one.
+    fn handle_next_message(&mut self, actor_id: Id) -> Result<(), String> {
+        let (handler, message) = {
+            let actor = self.actors.get_mut(&actor_id).unwrap();
+            if actor.handling.is_some() {
+                return Ok(());
+            }
+            let Some(message) = actor.mailbox.pop_front() else {
+                return Ok(());
+            };
+            (actor.handler.fork_to_pc(actor.handler.pc), message)
+        };
+        let fut_id = self.spawn_coro(handler, 0, &message)?;
+        let coroutine_id = self._new_spawned_coro_id;
+        self.actors.get_mut(&actor_id).unwrap().handling = Some(coroutine_id);
+        info!("Actor {} handling a message in coroutine {}, with future {}", actor_id, coroutine_id, fut_id);
+        return Ok(());
+    }
+
+    // One line per live coroutine, with its state and where it is in its program.
+    fn dump_coroutines(&self) -> String {
+        let mut ids: Vec<&Id> = self.coroutines.keys().collect();
+        ids.sort();
+        let lines: Vec<String> = ids.into_iter().map(|id| {
+            let coroutine = &self.coroutines[id];
+            format!("  coroutine {} ({:?}) at {}", id, coroutine.state, coroutine.cpu.program.describe_location(coroutine.cpu.program.pc))
+        }).collect();
+        return lines.join("\n");
+    }
+
+    // The chain of coroutines waiting on the given one, innermost first.
+    fn backtrace(&self, coroutine_id: Id) -> String {
+        let mut lines = vec![format!("  in coroutine {}", coroutine_id)];
+        for caller in self.callers(coroutine_id) {
+            // The caller's pc has already moved past its Await.
+            let program = &caller.cpu.program;
+            lines.push(format!("  awaited by coroutine {} at {}", caller.id, program.describe_location(program.pc.saturating_sub(1))));
+        }
+        return lines.join("\n");
+    }
+
+    // The coroutines awaiting a coroutine's result, then the ones awaiting theirs, and so on.
+    fn callers(&self, coroutine_id: Id) -> Vec<&Coroutine> {
+        let mut callers = Vec::new();
+        let mut current = self.coroutines.get(&coroutine_id);
+        while let Some(fut_id) = current.and_then(|coroutine| coroutine.return_to_fut) {
+            current = self.coroutines.values().find(|caller| caller.depends_on.contains_key(&fut_id));
+            callers.extend(current);
+        }
+        return callers;
+    }
+
     pub fn await_future(&mut self, coroutine_id: Id, future_id: Id, write_location: usize) -> Result<(), String> {
         let coroutine = self.coroutines.get_mut(&coroutine_id)
             .ok_or_else(|| format!("Coroutine {} not found", coroutine_id))?;
@@ -170,12 +750,14 @@ impl Scheduler {
 
         // Wake up all dependent coroutines
         future.set_complete();
+        let mut protected = None;
         for coroutine_id in &future.dependants {
             if let Some(coroutine) = self.coroutines.get_mut(&coroutine_id) {
                 coroutine.state = CoroutineState::Runnable;
                 self.ready_queue.push_back(*coroutine_id);
-                if let Some(write_location) = coroutine.depends_on.get(&future_id) {
-                    coroutine.cpu.get_memory_mut().write(*write_location, val);
+                if let Some(write_location) = coroutine.depends_on.get(&future_id)
+                    && let Err(e) = coroutine.cpu.memory_mut().store(*write_location, val) {
+                    protected.get_or_insert((*coroutine_id, e));
                 }
                 coroutine.depends_on.remove(&future_id);
             }
@@ -183,12 +765,15 @@ impl Scheduler {
 
         future.dependants.clear();
 
+        if let Some((coroutine_id, e)) = protected {
+            return Err(format!("{}\n{}", e, self.backtrace(coroutine_id)));
+        }
         info!("Completed future at symbol {}", future_id);
         Ok(())
     }
 
     // Can only be called on a complete future, panics otherwise
-    pub fn complete_future_for(&mut self, future_id: Id, coroutine_id: Id) {
+    pub fn complete_future_for(&mut self, future_id: Id, coroutine_id: Id) -> Result<(), String> {
         let value = {
             if let Some(fut) = self.futures.get(&future_id) {
                 if let Some(value) = &fut.value {
@@ -204,10 +789,58 @@ impl Scheduler {
         if let Some(coroutine) = self.coroutines.get_mut(&coroutine_id) {
             coroutine.state = CoroutineState::Runnable;
             self.ready_queue.push_back(coroutine_id);
-            if let Some(write_location) = coroutine.depends_on.get(&future_id) {
-                coroutine.cpu.get_memory_mut().write(*write_location, value);
+            if let Some(write_location) = coroutine.depends_on.remove(&future_id)
+                && let Err(e) = coroutine.cpu.memory_mut().store(write_location, value) {
+                return Err(format!("{}\n{}", e, self.backtrace(coroutine_id)));
+            }
+        }
+        Ok(())
+    }
+
+    fn make_runnable(&mut self, coroutines: impl IntoIterator<Item = Id>) {
+        for id in coroutines {
+            if let Some(coroutine) = self.coroutines.get_mut(&id) {
+                coroutine.state = CoroutineState::Runnable;
+                self.ready_queue.push_back(id);
+            }
+        }
+    }
+
+    fn is_waiting(&self, future_id: Id) -> bool {
+        return self.futures.get(&future_id).is_some_and(|future| future.state == FutureState::Waiting);
+    }
+
+    // Stop the coroutine that completes `future_id`, along with every coroutine in its open scopes,
+    // and mark the future cancelled. Coroutines already awaiting it run their Await again, which
+    // fails.
+    fn cancel_coroutine(&mut self, future_id: Id) {
+        let Some(future) = self.futures.get_mut(&future_id).filter(|future| future.state == FutureState::Waiting) else {
+            return;
+        };
+        future.state = FutureState::Cancelled;
+        let dependants = std::mem::take(&mut future.dependants);
+
+        let coroutine_id = self.coroutines.values().find(|coroutine| coroutine.return_to_fut == Some(future_id)).map(|coroutine| coroutine.id);
+        if let Some(coroutine) = coroutine_id.and_then(|id| self.coroutines.remove(&id)) {
+            info!("Cancelled coroutine {}", coroutine.id);
+            self.ready_queue.retain(|queued| *queued != coroutine.id);
+            self.shared.for_owner(coroutine.id).release_all();
+            let woken = self.semaphores.release_all(coroutine.id);
+            self.make_runnable(woken);
+            for child in coroutine.scopes.into_iter().flatten() {
+                self.cancel_coroutine(child);
+            }
+        }
+
+        for dependant in dependants {
+            if let Some(coroutine) = self.coroutines.get_mut(&dependant) {
+                // Coroutines waiting at EndScope or Select have already stepped back to it.
+                if coroutine.depends_on.remove(&future_id).is_some() {
+                    coroutine.cpu.program.pc -= 1;
+                }
+                coroutine.state = CoroutineState::Runnable;
+                self.ready_queue.push_back(dependant);
             }
-            coroutine.depends_on.remove(&future_id);
         }
     }
 
@@ -219,6 +852,36 @@ impl Scheduler {
         self.futures.remove(&future_id);
     }
 
+    // Complete the futures waiting for an event, or keep its payload until something waits for it.
+    fn fire_event(&mut self, name: String, payload: Vec<u8>) -> Result<(), String> {
+        let waiting = self.event_waiters.remove(&name).unwrap_or_default();
+        if waiting.is_empty() {
+            self.pending_events.entry(name).or_default().push_back(payload);
+            return Ok(());
+        }
+        for future_id in waiting {
+            self.complete_future(future_id, Ok(&payload))?;
+            // Only the coroutine that awaited the event knew of its future.
+            self.delete_future(future_id);
+        }
+        return Ok(());
+    }
+
+    fn wake(&mut self, wakeup: Wakeup) -> Result<(), String> {
+        match wakeup {
+            Wakeup::Ffi(future_id, value) => {
+                self.ffi_calls.remove(&future_id);
+                let _ = self.complete_future(future_id, Ok(&value));
+            },
+            Wakeup::FfiFailed(future_id, e) => {
+                let domain_id = self.ffi_calls.remove(&future_id).unwrap_or_default();
+                return Err(format!("Call into domain {} failed: {}", domain_id, e));
+            },
+            Wakeup::Event(name, payload) => self.fire_event(name, payload)?,
+        }
+        return Ok(());
+    }
+
     pub fn get_next_runnable(&mut self) -> Option<Id> {
         while let Some(id) = self.ready_queue.pop_front() {
             if let Some(coroutine) = self.coroutines.get_mut(&id) {
@@ -273,11 +936,27 @@ impl Scheduler {
     // This function handles returns only for Concorde ret opcodes, not for general future completion or FFI Calls.
     // It returns Some(i8) if the return value is from the main method and should be returned by the scheduler, and None otherwise.
     fn handle_return(&mut self, coroutine_id: Id, ret_val: & dyn ByteSerialisable, ret_val_addr: usize) -> Result<Option<i8>, String> {
+        // Coroutines can't outlive the scope they were spawned in.
+        let open: Vec<Id> = std::mem::take(&mut self.get_curr_coro_mut(coroutine_id).scopes).into_iter().flatten().collect();
+        for fut_id in open {
+            self.cancel_coroutine(fut_id);
+        }
         if let Some(fut_id) = self.get_curr_coro_mut(coroutine_id).return_to_fut {
             // coro id 1 is the entrypoint coro
             if coroutine_id != 1 {
                 self.complete_future(fut_id, Ok(ret_val))?;
                 self.coroutines.remove(&coroutine_id);
+                // Locks left held would block every other coroutine forever.
+                self.shared.for_owner(coroutine_id).release_all();
+                let woken = self.semaphores.release_all(coroutine_id);
+                self.make_runnable(woken);
+                let actor_id = self.actors.iter().find(|(_, actor)| actor.handling == Some(coroutine_id)).map(|(id, _)| *id);
+                if let Some(actor_id) = actor_id {
+                    // Nothing can await a handler, so its future isn't needed once it's complete.
+                    self.delete_future(fut_id);
+                    self.actors.get_mut(&actor_id).unwrap().handling = None;
+                    self.handle_next_message(actor_id)?;
+                }
                 
                 if let Some(next_coro_id) = self.get_next_runnable(){
                     self.curr_coro_id = next_coro_id;
@@ -298,29 +977,35 @@ impl Scheduler {
     
 
     pub fn _run(&mut self) -> Result<i8, String>{
-
-        let (tx, rx) = std::sync::mpsc::channel::<FFIResult>();
-        
         if let Some(current_coro_id) = self.get_next_runnable() {
             self.curr_coro_id = current_coro_id;
             self.running = true;
             loop {
                 if !self.running {
-                    // Block until we get an FFI future completion. 
-                    rx.recv().ok().map(|FFIResult{fut_id, value}| {
-                        let _ = self.complete_future(fut_id, Ok(&value));
-                    });
+                    // Block until an FFI call finishes or an event fires.
+                    if let Ok(wakeup) = self.wakeups.recv() {
+                        self.wake(wakeup)?;
+                    }
+                    match self.get_next_runnable() {
+                        Some(next_coro_id) => self.curr_coro_id = next_coro_id,
+                        None => continue,
+                    }
                 }
                 self.running = true;
-                // Consume all available FFI messages
-                for FFIResult{fut_id, value} in rx.try_iter() {
-                    let _ = self.complete_future(fut_id, Ok(&value));
+                // Consume all available wakeups
+                while let Ok(wakeup) = self.wakeups.try_recv() {
+                    self.wake(wakeup)?;
                 }
 
                 let interrupt = {
                     if let Some(coro)= self.coroutines.get_mut(&self.curr_coro_id){
+                        #[cfg(feature = "tracing")]
+                        let _span = tracing::info_span!("coroutine", id = self.curr_coro_id).entered();
                         coro.state = CoroutineState::Running;
-                        coro.cpu.run()?
+                        match coro.cpu.run() {
+                            Ok(interrupt) => interrupt,
+                            Err(e) => return Err(format!("{}\n{}", e, self.backtrace(self.curr_coro_id))),
+                        }
                     } else {
                         panic!("Current coroutine not found");
                     }
@@ -330,7 +1015,11 @@ impl Scheduler {
                     Interrupt::Await(fut_id, return_write_addr) => {
                         if let Some(fut) = self.futures.get_mut(&fut_id) {
                             if fut.state == FutureState::Complete {
-                                self.complete_future_for(fut_id, self.curr_coro_id);
+                                // Record where the value goes, so it's written like it would be had we waited.
+                                self.get_curr_coro_mut(self.curr_coro_id).depends_on.insert(fut_id, return_write_addr);
+                                self.complete_future_for(fut_id, self.curr_coro_id)?;
+                            } else if fut.state == FutureState::Cancelled {
+                                return Err(format!("Awaited future {}, whose coroutine was cancelled\n{}", fut_id, self.backtrace(self.curr_coro_id)));
                             } else {
                                 self.await_future(self.curr_coro_id,fut_id, return_write_addr)?;
                                        
@@ -346,17 +1035,24 @@ impl Scheduler {
                     },
                     Interrupt::CreateCoroutine(dest, arg_addr, n_arg_bytes, write_coro_fut_id_addr) => {
 
-                        let (program, args) = {
+                        let inherit_memory = self.inherit_memory;
+                        let (program, memory, args) = {
                             let curr_coro = self.get_curr_coro_mut(self.curr_coro_id);
                             let program = curr_coro.cpu.program.fork_to_pc(dest);
+                            let memory = inherit_memory.then(|| curr_coro.cpu.memory.fork());
                             let args = curr_coro.cpu.memory.read(arg_addr, n_arg_bytes);
-                            (program, args)
+                            (program, memory, args)
                         };
 
-                        let coro_fut_id = self.spawn_coro(program, 0, &args)?;
+                        let coro_fut_id = self.spawn_coro_with_memory(program, 0, memory, &args)?;
                         
                         let curr_coro = self.get_curr_coro_mut(self.curr_coro_id);
-                        curr_coro.cpu.get_memory_mut().write(write_coro_fut_id_addr, &coro_fut_id);
+                        if let Some(scope) = curr_coro.scopes.last_mut() {
+                            scope.push(coro_fut_id);
+                        }
+                        if let Err(e) = curr_coro.cpu.memory_mut().store(write_coro_fut_id_addr, &coro_fut_id) {
+                            return Err(format!("{}\n{}", e, self.backtrace(self.curr_coro_id)));
+                        }
                     }    
                     Interrupt::Ret(ret_val_addr, n_ret_bytes) => {
                         let ret_val = {
@@ -372,29 +1068,190 @@ impl Scheduler {
                     Interrupt::DeleteFuture(future_id) => {
                         self.delete_future(future_id);
                     },
+                    Interrupt::SpawnActor(handler, message_size, write_actor_id_addr) => {
+                        let handler = self.get_curr_coro_mut(self.curr_coro_id).cpu.program.fork_to_pc(handler);
+                        let actor_id = self.spawn_actor(handler, message_size);
+                        if let Err(e) = self.get_curr_coro_mut(self.curr_coro_id).cpu.memory_mut().store(write_actor_id_addr, &actor_id) {
+                            return Err(format!("{}\n{}", e, self.backtrace(self.curr_coro_id)));
+                        }
+                    },
+                    Interrupt::SendMessage(actor_id, message_addr) => {
+                        let message_size = match self.actors.get(&actor_id) {
+                            Some(actor) => actor.message_size,
+                            None => return Err(format!("Sent a message to actor {}, which does not exist\n{}", actor_id, self.backtrace(self.curr_coro_id))),
+                        };
+                        let message = self.get_curr_coro_mut(self.curr_coro_id).cpu.memory.read(message_addr, message_size);
+                        self.send_message(actor_id, message)?;
+                    },
+                    Interrupt::AwaitEvent(name, write_addr) => {
+                        let pending = self.pending_events.get_mut(&name).and_then(VecDeque::pop_front);
+                        if let Some(payload) = pending {
+                            if let Err(e) = self.get_curr_coro_mut(self.curr_coro_id).cpu.memory_mut().store(write_addr, &payload) {
+                                return Err(format!("{}\n{}", e, self.backtrace(self.curr_coro_id)));
+                            }
+                        } else {
+                            let fut_id = self.spawn_fut();
+                            self.event_waiters.entry(name).or_default().push(fut_id);
+                            self.await_future(self.curr_coro_id, fut_id, write_addr)?;
+                            if let Some(next_coro_id) = self.get_next_runnable() {
+                                self.curr_coro_id = next_coro_id;
+                            } else {
+                                self.running = false;
+                            }
+                        }
+                    },
+                    Interrupt::Select(fut_ids, write_addr) => {
+                        let mut ready = None;
+                        for (index, fut_id) in fut_ids.iter().enumerate() {
+                            match self.futures.get(fut_id) {
+                                Some(fut) if fut.state == FutureState::Waiting => {},
+                                Some(_) => { ready = Some(index); break; },
+                                None => return Err(format!("Selected future {}, which does not exist\n{}", fut_id, self.backtrace(self.curr_coro_id))),
+                            }
+                        }
+                        if let Some(index) = ready {
+                            // Stop listening to the rest, so they don't wake us later.
+                            for fut_id in &fut_ids {
+                                self.futures.get_mut(fut_id).unwrap().dependants.remove(&self.curr_coro_id);
+                            }
+                            if let Err(e) = self.get_curr_coro_mut(self.curr_coro_id).cpu.memory_mut().store(write_addr, &(index as i64)) {
+                                return Err(format!("{}\n{}", e, self.backtrace(self.curr_coro_id)));
+                            }
+                        } else {
+                            // Wake on whichever completes first, and then run Select again to find it.
+                            for fut_id in &fut_ids {
+                                self.futures.get_mut(fut_id).unwrap().add_dependant(self.curr_coro_id);
+                            }
+                            let coroutine = self.get_curr_coro_mut(self.curr_coro_id);
+                            coroutine.cpu.program.pc -= 1;
+                            coroutine.state = CoroutineState::Suspended;
+                            if let Some(next_coro_id) = self.get_next_runnable() {
+                                self.curr_coro_id = next_coro_id;
+                            } else {
+                                self.running = false;
+                            }
+                        }
+                    },
+                    Interrupt::MutexCreate(write_addr) => {
+                        let id = self.semaphores.create(Kind::Mutex, 1);
+                        if let Err(e) = self.get_curr_coro_mut(self.curr_coro_id).cpu.memory_mut().store(write_addr, &id) {
+                            return Err(format!("{}\n{}", e, self.backtrace(self.curr_coro_id)));
+                        }
+                    },
+                    Interrupt::SemaphoreCreate(permits, write_addr) => {
+                        let id = self.semaphores.create(Kind::Semaphore, permits);
+                        if let Err(e) = self.get_curr_coro_mut(self.curr_coro_id).cpu.memory_mut().store(write_addr, &id) {
+                            return Err(format!("{}\n{}", e, self.backtrace(self.curr_coro_id)));
+                        }
+                    },
+                    Interrupt::MutexLock(id) | Interrupt::SemaphoreAcquire(id) => {
+                        let kind = if matches!(interrupt, Interrupt::MutexLock(_)) { Kind::Mutex } else { Kind::Semaphore };
+                        let acquired = match self.semaphores.acquire(id, kind, self.curr_coro_id) {
+                            Ok(acquired) => acquired,
+                            Err(e) => return Err(format!("{}\n{}", e, self.backtrace(self.curr_coro_id))),
+                        };
+                        if !acquired {
+                            // Whoever releases it next hands it to us, and wakes us up.
+                            self.get_curr_coro_mut(self.curr_coro_id).state = CoroutineState::Suspended;
+                            if let Some(next_coro_id) = self.get_next_runnable() {
+                                self.curr_coro_id = next_coro_id;
+                            } else {
+                                self.running = false;
+                            }
+                        }
+                    },
+                    Interrupt::MutexUnlock(id) | Interrupt::SemaphoreRelease(id) => {
+                        let kind = if matches!(interrupt, Interrupt::MutexUnlock(_)) { Kind::Mutex } else { Kind::Semaphore };
+                        match self.semaphores.release(id, kind, self.curr_coro_id) {
+                            Ok(woken) => self.make_runnable(woken),
+                            Err(e) => return Err(format!("{}\n{}", e, self.backtrace(self.curr_coro_id))),
+                        }
+                    },
+                    Interrupt::GetStackDepth(write_addr) => {
+                        let depth = self.callers(self.curr_coro_id).len() as i64 + 1;
+                        if let Err(e) = self.get_curr_coro_mut(self.curr_coro_id).cpu.memory_mut().store(write_addr, &depth) {
+                            return Err(format!("{}\n{}", e, self.backtrace(self.curr_coro_id)));
+                        }
+                    },
+                    Interrupt::GetCallerSymbol(write_addr) => {
+                        let symbol = self.callers(self.curr_coro_id).first()
+                            .and_then(|caller| caller.cpu.program.symbol_at(caller.cpu.program.pc.saturating_sub(1)))
+                            .unwrap_or_default();
+                        if let Err(e) = self.get_curr_coro_mut(self.curr_coro_id).cpu.memory_mut().store(write_addr, &symbol) {
+                            return Err(format!("{}\n{}", e, self.backtrace(self.curr_coro_id)));
+                        }
+                    },
+                    Interrupt::BeginScope => {
+                        self.get_curr_coro_mut(self.curr_coro_id).scopes.push(Vec::new());
+                    },
+                    Interrupt::EndScope(cancel) => {
+                        let Some(scope) = self.get_curr_coro_mut(self.curr_coro_id).scopes.pop() else {
+                            return Err(format!("EndScope without a matching BeginScope\n{}", self.backtrace(self.curr_coro_id)));
+                        };
+                        if cancel {
+                            for fut_id in scope {
+                                self.cancel_coroutine(fut_id);
+                            }
+                        } else if let Some(fut_id) = scope.iter().copied().find(|fut_id| self.is_waiting(*fut_id)) {
+                            // Wait for the first coroutine still running, and then run EndScope again
+                            // to check the rest.
+                            let coroutine = self.get_curr_coro_mut(self.curr_coro_id);
+                            coroutine.scopes.push(scope);
+                            coroutine.cpu.program.pc -= 1;
+                            coroutine.state = CoroutineState::Suspended;
+                            self.futures.get_mut(&fut_id).unwrap().add_dependant(self.curr_coro_id);
+                            if let Some(next_coro_id) = self.get_next_runnable() {
+                                self.curr_coro_id = next_coro_id;
+                            } else {
+                                self.running = false;
+                            }
+                        }
+                    },
+                    Interrupt::Yield => {
+                        self.yield_coroutine(self.curr_coro_id)?;
+                        if let Some(next_coro_id) = self.get_next_runnable() {
+                            self.curr_coro_id = next_coro_id;
+                        }
+                    },
                     Interrupt::Ok => {},    // we will never actually get this since CPU.run() just continues without returning in this case
                     Interrupt::EOF => {return Ok(0);},
                     Interrupt::LoadSO(domain_id, lib_path) => {
-                        unsafe { if let Err(x) = self.ffi_func_table.write().unwrap().add_domain(domain_id, lib_path) {
+                        #[cfg(feature = "tracing")]
+                        tracing::debug!(domain = domain_id, path = %lib_path, "load domain");
+                        let mut domains = self.domains_mut()?;
+                        let added = match &self.domain_isolation {
+                            Some(isolation) => domains.add_isolated_domain(domain_id, lib_path, isolation),
+                            None => unsafe { domains.add_domain(domain_id, lib_path) },
+                        };
+                        if let Err(x) = added {
                             return Err(format!("Error loading SO for domain {}: {}", domain_id, x.deref()));
-                        }};
+                        }
                     },
                     Interrupt::AddFFIFn(domain_id, function_id, function_name, arg_types, ret_type) => {
-                        unsafe { if let Err(x) = self.ffi_func_table.write().unwrap().load_function_from_so(domain_id, FFIFunctionInfo::new(function_id, function_name, arg_types, ret_type)) {
+                        #[cfg(feature = "tracing")]
+                        tracing::debug!(domain = domain_id, function = function_id, name = %function_name, "add domain function");
+                        unsafe { if let Err(x) = self.domains_mut()?.load_function_from_so(domain_id, FFIFunctionInfo::new(function_id, function_name, arg_types, ret_type)) {
                             return Err(format!("Error loading FFI function from domain {}: {}", domain_id, x.deref()));
                         }};
                     },
+                    Interrupt::UnloadDomain(domain_id) => {
+                        self.check_no_calls_in_flight(domain_id)?;
+                        unsafe { if let Err(x) = self.domains_mut()?.remove_domain(domain_id) {
+                            return Err(format!("Error unloading domain {}: {}", domain_id, x.deref()));
+                        }};
+                    },
                     Interrupt::CallFFIFn(domain_id, function_id, arg_addr, n_arg_bytes, ret_addr) => {
-                        let n_ret_bytes = {
-                            if let Some(fn_n_ret_bytes) = self.ffi_func_table.read().unwrap().get_n_ret_bytes(domain_id, function_id) {
-                                fn_n_ret_bytes
-                            } else {
-                                return Err(format!("FFI function with id {} not found in domain {}", function_id, domain_id));
-                            }
-                        };
+                        #[cfg(feature = "tracing")]
+                        tracing::debug!(domain = domain_id, function = function_id, "call domain function");
+                        if self.ffi_func_table.read().unwrap().get_n_ret_bytes(domain_id, function_id).is_none() {
+                            return Err(format!("FFI function with id {} not found in domain {}", function_id, domain_id));
+                        }
 
                         let fut_id = self.spawn_fut();
-                        self.get_curr_coro_mut(self.curr_coro_id).cpu.memory.write(ret_addr, &fut_id);
+                        self.ffi_calls.insert(fut_id, domain_id);
+                        if let Err(e) = self.get_curr_coro_mut(self.curr_coro_id).cpu.memory_mut().store(ret_addr, &fut_id) {
+                            return Err(format!("{}\n{}", e, self.backtrace(self.curr_coro_id)));
+                        }
                         
                         let args = {
                             let curr_coro = self.get_curr_coro_mut(self.curr_coro_id);
@@ -404,15 +1261,32 @@ impl Scheduler {
                         
                         
                         let ffi: Arc<RwLock<FFIFuncTable>> = Arc::clone(&self.ffi_func_table);
-                        let thread_tx = tx.clone();
+                        let thread_tx = self.wakeup_sender.clone();
+                        let timeout = self.domain_call_timeout;
+                        let running = Arc::clone(&self.running_calls);
+                        *running.lock().unwrap().entry(domain_id).or_default() += 1;
                         thread::spawn(move || {
-                            let mut ret_buf = vec![0u8; n_ret_bytes];
-                            
-                            unsafe { ffi.read().unwrap().call_function(domain_id, function_id, &args, ret_buf.as_mut_ptr())
-                                .unwrap_or_else(|e| panic!("Error calling FFI function with id {} in domain {}: {}", function_id, domain_id, e.deref()));
-                            };  // TODO: make this return the error through TX so the future can be set to ERROR state.
-                            print!("FFI return buffer: {:?}\n", ret_buf);
-                            thread_tx.send(FFIResult { fut_id, value: ret_buf }).unwrap();
+                            let call = move || {
+                                let called = unsafe { ffi.read().unwrap().call_function(domain_id, function_id, &args) }.map_err(|e| e.to_string());
+                                *running.lock().unwrap().entry(domain_id).or_default() -= 1;
+                                called
+                            };
+                            let called = match timeout {
+                                None => call(),
+                                Some(timeout) => {
+                                    // A call that overruns is left to finish by itself, since a
+                                    // thread can't be stopped from outside.
+                                    let (call_tx, call_rx) = channel();
+                                    thread::spawn(move || { let _ = call_tx.send(call()); });
+                                    call_rx.recv_timeout(timeout).unwrap_or_else(|_| Err(format!("Timed out after {:?}", timeout)))
+                                },
+                            };
+                            let wakeup = match called {
+                                Ok(value) => Wakeup::Ffi(fut_id, value),
+                                Err(e) => Wakeup::FfiFailed(fut_id, format!("function {}: {}", function_id, e)),
+                            };
+                            // The scheduler may have stopped waiting.
+                            let _ = thread_tx.send(wakeup);
                         });
 
                         
@@ -425,7 +1299,20 @@ impl Scheduler {
         
     }
 
-    pub fn run(&mut self, program: Program) -> Result<(), String>{
+    pub fn run(&mut self, mut program: Program) -> Result<(), String>{
+        self.stack_overflow = None;
+        if self.verify {
+            let problems = verifier::verify(&program.instructions, program.pc);
+            if !problems.is_empty() {
+                return Err(format!("Program failed verification:\n  {}", problems.join("\n  ")));
+            }
+        }
+        if self.optimize {
+            program.optimize();
+        }
+        if self.events.borrow().has_listeners() {
+            self.events.borrow_mut().publish(&Event::ProgramLoaded { instructions: program.instructions.len(), entrypoint: program.pc });
+        }
         self.spawn_coro(program,  0, &Vec::new())?;
         let x = self.ready_queue.front();
         print!("{}", self.ready_queue.len());
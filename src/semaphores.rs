@@ -0,0 +1,112 @@
+//! ConcordeVM's mutexes and semaphores.
+//!
+//! Let coroutines take turns at something, like a shared region or a file, without spinning. A
+//! semaphore holds a number of permits: acquiring one takes a permit if there's one left, and
+//! otherwise suspends the coroutine until another releases one. A mutex is a semaphore with one
+//! permit that only its holder can release.
+//!
+//! Waiting coroutines are woken in the order they started waiting, and a released permit goes
+//! straight to the first of them, so none can be starved. A coroutine that finishes or is
+//! cancelled gives back everything it holds. Mutexes and semaphores belong to the scheduler, so
+//! a lone CPU can't use them, and a scheduler using them can't be snapshotted.
+
+use crate::log_and_return_err;
+
+use log::error;
+use std::collections::{HashMap, VecDeque};
+
+type Id = usize;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Kind {
+    Mutex,
+    Semaphore,
+}
+
+struct Semaphore {
+    kind: Kind,
+    permits: usize,
+    // Coroutines holding a permit, once for each they hold.
+    holders: Vec<Id>,
+    waiters: VecDeque<Id>,
+}
+
+// Every mutex and semaphore of a scheduler, by id.
+#[derive(Default)]
+pub(crate) struct Semaphores {
+    semaphores: HashMap<Id, Semaphore>,
+    last_id: Id,
+}
+
+impl Semaphores {
+    pub(crate) fn is_empty(&self) -> bool {
+        return self.semaphores.is_empty();
+    }
+
+    pub(crate) fn create(&mut self, kind: Kind, permits: usize) -> Id {
+        self.last_id += 1;
+        self.semaphores.insert(self.last_id, Semaphore { kind, permits, holders: Vec::new(), waiters: VecDeque::new() });
+        return self.last_id;
+    }
+
+    // Take a permit for `coroutine`, returning false if it has to wait for one.
+    pub(crate) fn acquire(&mut self, id: Id, kind: Kind, coroutine: Id) -> Result<bool, String> {
+        let semaphore = self.get(id, kind)?;
+        if kind == Kind::Mutex && semaphore.holders.contains(&coroutine) {
+            log_and_return_err!("Coroutine {} tried to lock mutex {}, which it already holds", coroutine, id);
+        }
+        if semaphore.permits == 0 {
+            semaphore.waiters.push_back(coroutine);
+            return Ok(false);
+        }
+        semaphore.permits -= 1;
+        semaphore.holders.push(coroutine);
+        return Ok(true);
+    }
+
+    // Give back a permit held by `coroutine`, returning the waiting coroutine it was handed to.
+    pub(crate) fn release(&mut self, id: Id, kind: Kind, coroutine: Id) -> Result<Option<Id>, String> {
+        let semaphore = self.get(id, kind)?;
+        match semaphore.holders.iter().position(|holder| *holder == coroutine) {
+            Some(index) => { semaphore.holders.remove(index); },
+            None if kind == Kind::Mutex => log_and_return_err!("Coroutine {} tried to unlock mutex {}, which it doesn't hold", coroutine, id),
+            None => {},
+        }
+        return Ok(semaphore.hand_over());
+    }
+
+    // Stop `coroutine` waiting, and give back everything it holds, returning the coroutines woken.
+    pub(crate) fn release_all(&mut self, coroutine: Id) -> Vec<Id> {
+        let mut woken = Vec::new();
+        for semaphore in self.semaphores.values_mut() {
+            semaphore.waiters.retain(|waiter| *waiter != coroutine);
+            while let Some(index) = semaphore.holders.iter().position(|holder| *holder == coroutine) {
+                semaphore.holders.remove(index);
+                woken.extend(semaphore.hand_over());
+            }
+        }
+        return woken;
+    }
+
+    fn get(&mut self, id: Id, kind: Kind) -> Result<&mut Semaphore, String> {
+        let Some(semaphore) = self.semaphores.get_mut(&id) else {
+            log_and_return_err!("{:?} {} does not exist", kind, id);
+        };
+        if semaphore.kind != kind {
+            log_and_return_err!("{} is a {:?}, not a {:?}", id, semaphore.kind, kind);
+        }
+        return Ok(semaphore);
+    }
+}
+
+impl Semaphore {
+    // Pass a freed permit to the first waiter, or keep it if there are none.
+    fn hand_over(&mut self) -> Option<Id> {
+        let Some(waiter) = self.waiters.pop_front() else {
+            self.permits += 1;
+            return None;
+        };
+        self.holders.push(waiter);
+        return Some(waiter);
+    }
+}
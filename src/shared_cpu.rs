@@ -0,0 +1,87 @@
+//! ConcordeVM's thread-safe CPU handle.
+//!
+//! A CPU isn't `Send`: its memory holds boxed values, and it shares host state like the clock and
+//! event listeners through `Rc`s. Instead of moving between threads, its CPU stays on a thread of
+//! its own, and a `SharedCpu` sends that thread work to do to it. Handles are `Send + Sync` and
+//! cheap to clone, so any number of host threads can use the same CPU.
+//!
+//! Work runs one request at a time, in the order the CPU's thread receives it, so each request
+//! sees everything the ones before it did and none of them ever see the CPU mid-instruction.
+//! Results and errors come back by value, so they must be `Send` too; every error the VM raises
+//! is a `String`, but an `Interrupt` may hold FFI types that aren't. The CPU's thread stops once
+//! every handle to it has been dropped.
+
+use crate::{CPU, Interrupt};
+use crate::log_and_return_err;
+
+use log::error;
+
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+// Work for the CPU's thread to do.
+type Job = Box<dyn FnOnce(&mut CPU) + Send>;
+
+/// A handle to a CPU on its own thread, usable from any other.
+#[derive(Clone)]
+pub struct SharedCpu {
+    jobs: Sender<Job>,
+}
+
+impl SharedCpu {
+    /// Start a thread for the CPU `build` makes, which runs on that thread.
+    pub fn spawn(build: impl FnOnce() -> CPU + Send + 'static) -> SharedCpu {
+        let (jobs, received) = channel::<Job>();
+        thread::spawn(move || {
+            let mut cpu = build();
+            for job in received {
+                job(&mut cpu);
+            }
+        });
+        return SharedCpu { jobs };
+    }
+
+    /// Call `f` with the CPU, on its thread, and return what it returns. Fails if the CPU's
+    /// thread has stopped because an earlier call panicked.
+    pub fn with<R: Send + 'static>(&self, f: impl FnOnce(&mut CPU) -> R + Send + 'static) -> Result<R, String> {
+        let (result, received) = channel();
+        let job: Job = Box::new(move |cpu| {
+            let _ = result.send(f(cpu));
+        });
+        if self.jobs.send(job).is_err() {
+            log_and_return_err!("The CPU's thread has stopped");
+        }
+        match received.recv() {
+            Ok(value) => return Ok(value),
+            Err(_) => log_and_return_err!("The CPU's thread panicked"),
+        }
+    }
+
+    /// Run the CPU's program until it finishes or returns. Interrupts that need a scheduler,
+    /// like Await, are errors.
+    pub fn run(&self) -> Result<(), String> {
+        return self.with(|cpu| match cpu.run()? {
+            Interrupt::Ok | Interrupt::EOF | Interrupt::Ret(_, _) => Ok(()),
+            _ => log_and_return_err!("The program needs a scheduler to run"),
+        })?;
+    }
+
+    /// Copy `n` bytes of the CPU's memory from `address`.
+    pub fn read(&self, address: usize, n: usize) -> Result<Vec<u8>, String> {
+        return self.with(move |cpu| {
+            if address + n > cpu.memory().len() {
+                log_and_return_err!("Can't read {} bytes at {} from {} bytes of memory", n, address, cpu.memory().len());
+            }
+            Ok(cpu.memory().read(address, n))
+        })?;
+    }
+
+    /// Write `bytes` to the CPU's memory at `address`, growing it if needed.
+    pub fn write(&self, address: usize, bytes: Vec<u8>) -> Result<(), String> {
+        return self.with(move |cpu| {
+            cpu.memory_mut().extend_memory_to(address + bytes.len())?;
+            cpu.memory_mut().write(address, &bytes);
+            Ok(())
+        })?;
+    }
+}
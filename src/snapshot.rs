@@ -0,0 +1,430 @@
+//! ConcordeVM's snapshots.
+//!
+//! Captures the state of a CPU or a whole scheduler so it can be saved to bytes and restored
+//! later, possibly in another process.
+//!
+//! Snapshots hold memory along with its frozen ranges and symbol names, program counters, list
+//! loops and interrupt handlers in progress, the loaded program with its named blocks and debug
+//! info, and the scheduler's coroutines, futures and ready queue. IO state (open streams and
+//! subprocesses) and FFI state (loaded domains and in-flight calls) belong to the host and are not
+//! captured, so programs must reopen streams and reload domains after a restore. Host values
+//! aren't either; a restored CPU keeps the ones it had, and so do its shared memory regions.
+//!
+//! A scheduler can't be snapshotted while it has shared regions, actors, mutexes or semaphores,
+//! events that were fired or are being waited for, or coroutines with a scope open, since none of
+//! those are captured. Restoring a snapshot removes them.
+//!
+//! Core dumps are CPU snapshots taken when an instruction fails, along with the error and the
+//! instructions that ran just before it, for post-mortem debugging.
+
+use crate::bytecode::{Decoder, Encoder};
+use crate::cpu::{ListLoop, LoopKind, Program};
+use crate::debug_info::DebugInfo;
+use crate::log_and_return_err;
+use crate::scheduler::{CoroutineState, FutureState};
+
+use log::error;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::rc::Rc;
+
+const CPU_MAGIC: &[u8; 4] = b"CVCS";
+const VM_MAGIC: &[u8; 4] = b"CVVS";
+const CORE_MAGIC: &[u8; 4] = b"CVCD";
+const VERSION: u16 = 1;
+
+/// The state of a single `CPU`.
+#[derive(Clone)]
+pub struct CpuSnapshot {
+    pub memory: Vec<u8>,
+    /// Ranges of memory that instructions can't write, as (address, length).
+    pub frozen: Vec<(usize, usize)>,
+    /// Named symbols, as (name, address, length).
+    pub names: Vec<(String, usize, usize)>,
+    pub program: Program,
+}
+
+impl CpuSnapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.header(CPU_MAGIC, VERSION);
+        encode_memory(&mut encoder, self);
+        encode_code(&mut encoder, &self.program);
+        encode_progress(&mut encoder, &self.program);
+        return encoder.finish();
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<CpuSnapshot, String> {
+        let mut decoder = Decoder::new(bytes);
+        decoder.header(CPU_MAGIC, VERSION)?;
+        let (memory, frozen, names) = decode_memory(&mut decoder)?;
+        let mut program = decode_code(&mut decoder)?;
+        decode_progress(&mut decoder, &mut program)?;
+        finish(&decoder)?;
+        return Ok(CpuSnapshot { memory, frozen, names, program });
+    }
+}
+
+/// The state of a `CPU` when an instruction failed, written by CPUs with core dumps enabled.
+#[derive(Clone)]
+pub struct CoreDump {
+    /// The CPU, with its pc on the failed instruction.
+    pub snapshot: CpuSnapshot,
+    /// The pcs of the most recent cycles, oldest first, ending with the one that failed.
+    pub trace: Vec<usize>,
+    pub error: String,
+}
+
+impl CoreDump {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        encoder.header(CORE_MAGIC, VERSION);
+        encoder.bytes(&self.snapshot.to_bytes());
+        encoder.usize(self.trace.len());
+        for pc in &self.trace {
+            encoder.usize(*pc);
+        }
+        encoder.string(&self.error);
+        return encoder.finish();
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<CoreDump, String> {
+        let mut decoder = Decoder::new(bytes);
+        decoder.header(CORE_MAGIC, VERSION)?;
+        let snapshot = CpuSnapshot::from_bytes(&decoder.bytes()?)?;
+        let mut trace = Vec::new();
+        for _ in 0..decoder.usize()? {
+            trace.push(decoder.usize()?);
+        }
+        let error = decoder.string()?;
+        finish(&decoder)?;
+        return Ok(CoreDump { snapshot, trace, error });
+    }
+
+    /// Load the core dump written to `path`.
+    pub fn load(path: &str) -> Result<CoreDump, String> {
+        match std::fs::read(path) {
+            Ok(bytes) => CoreDump::from_bytes(&bytes),
+            Err(e) => log_and_return_err!("Could not read core dump {}: {}", path, e),
+        }
+    }
+}
+
+pub(crate) struct CoroutineSnapshot {
+    pub(crate) id: usize,
+    pub(crate) priority: i32,
+    pub(crate) state: CoroutineState,
+    pub(crate) depends_on: Vec<(usize, usize)>,
+    pub(crate) return_to_fut: Option<usize>,
+    pub(crate) cpu: CpuSnapshot,
+}
+
+pub(crate) struct FutureSnapshot {
+    pub(crate) id: usize,
+    pub(crate) state: FutureState,
+    pub(crate) dependants: Vec<usize>,
+    pub(crate) value: Option<Vec<u8>>,
+}
+
+/// The state of a whole `Scheduler`, taken with `Scheduler::snapshot`.
+pub struct VmSnapshot {
+    pub(crate) coroutines: Vec<CoroutineSnapshot>,
+    pub(crate) futures: Vec<FutureSnapshot>,
+    pub(crate) ready_queue: Vec<usize>,
+    pub(crate) next_coro_id: usize,
+    pub(crate) next_future_id: usize,
+    pub(crate) curr_coro_id: usize,
+}
+
+impl VmSnapshot {
+    /// Encode the snapshot. Program blocks shared between coroutines are only stored once.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut blocks: Vec<&Program> = Vec::new();
+        let mut block_ids = Vec::new();
+        for coroutine in &self.coroutines {
+            let program = &coroutine.cpu.program;
+            let block_id = match blocks.iter().position(|block| same_code(block, program)) {
+                Some(block_id) => block_id,
+                None => {
+                    blocks.push(program);
+                    blocks.len() - 1
+                },
+            };
+            block_ids.push(block_id);
+        }
+
+        let mut encoder = Encoder::new();
+        encoder.header(VM_MAGIC, VERSION);
+        encoder.usize(self.next_coro_id);
+        encoder.usize(self.next_future_id);
+        encoder.usize(self.curr_coro_id);
+
+        encoder.usize(blocks.len());
+        for block in blocks {
+            encode_code(&mut encoder, block);
+        }
+
+        encoder.usize(self.coroutines.len());
+        for (coroutine, block_id) in self.coroutines.iter().zip(block_ids) {
+            encoder.usize(coroutine.id);
+            encoder.i32(coroutine.priority);
+            encoder.u8(coroutine_state_code(&coroutine.state));
+            encoder.usize(coroutine.depends_on.len());
+            for (future_id, write_location) in &coroutine.depends_on {
+                encoder.usize(*future_id);
+                encoder.usize(*write_location);
+            }
+            encode_option(&mut encoder, coroutine.return_to_fut.as_ref(), |encoder, id| encoder.usize(*id));
+            encode_memory(&mut encoder, &coroutine.cpu);
+            encoder.usize(block_id);
+            encode_progress(&mut encoder, &coroutine.cpu.program);
+        }
+
+        encoder.usize(self.futures.len());
+        for future in &self.futures {
+            encoder.usize(future.id);
+            encoder.u8(future_state_code(&future.state));
+            encoder.usize(future.dependants.len());
+            for dependant in &future.dependants {
+                encoder.usize(*dependant);
+            }
+            encode_option(&mut encoder, future.value.as_ref(), |encoder, value| encoder.bytes(value));
+        }
+
+        encoder.usize(self.ready_queue.len());
+        for id in &self.ready_queue {
+            encoder.usize(*id);
+        }
+        return encoder.finish();
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<VmSnapshot, String> {
+        let mut decoder = Decoder::new(bytes);
+        decoder.header(VM_MAGIC, VERSION)?;
+        let next_coro_id = decoder.usize()?;
+        let next_future_id = decoder.usize()?;
+        let curr_coro_id = decoder.usize()?;
+
+        let mut blocks = Vec::new();
+        for _ in 0..decoder.usize()? {
+            blocks.push(decode_code(&mut decoder)?);
+        }
+
+        let mut coroutines = Vec::new();
+        for _ in 0..decoder.usize()? {
+            let id = decoder.usize()?;
+            let priority = decoder.i32()?;
+            let state = coroutine_state_from_code(decoder.u8()?)?;
+            let mut depends_on = Vec::new();
+            for _ in 0..decoder.usize()? {
+                depends_on.push((decoder.usize()?, decoder.usize()?));
+            }
+            let return_to_fut = decode_option(&mut decoder, |decoder| decoder.usize())?;
+            let (memory, frozen, names) = decode_memory(&mut decoder)?;
+            let block_id = decoder.usize()?;
+            let mut program = match blocks.get(block_id) {
+                Some(block) => block.clone(),
+                None => log_and_return_err!("Coroutine {} uses program block {}, which is not in the snapshot", id, block_id),
+            };
+            decode_progress(&mut decoder, &mut program)?;
+            let cpu = CpuSnapshot { memory, frozen, names, program };
+            coroutines.push(CoroutineSnapshot { id, priority, state, depends_on, return_to_fut, cpu });
+        }
+
+        let mut futures = Vec::new();
+        for _ in 0..decoder.usize()? {
+            let id = decoder.usize()?;
+            let state = future_state_from_code(decoder.u8()?)?;
+            let mut dependants = Vec::new();
+            for _ in 0..decoder.usize()? {
+                dependants.push(decoder.usize()?);
+            }
+            let value = decode_option(&mut decoder, |decoder| decoder.bytes())?;
+            futures.push(FutureSnapshot { id, state, dependants, value });
+        }
+
+        let mut ready_queue = Vec::new();
+        for _ in 0..decoder.usize()? {
+            ready_queue.push(decoder.usize()?);
+        }
+        finish(&decoder)?;
+
+        return Ok(VmSnapshot { coroutines, futures, ready_queue, next_coro_id, next_future_id, curr_coro_id });
+    }
+}
+
+fn encode_memory(encoder: &mut Encoder, snapshot: &CpuSnapshot) {
+    encoder.bytes(&snapshot.memory);
+    encoder.usize(snapshot.frozen.len());
+    for (address, n) in &snapshot.frozen {
+        encoder.usize(*address);
+        encoder.usize(*n);
+    }
+    encoder.usize(snapshot.names.len());
+    for (name, address, n) in &snapshot.names {
+        encoder.string(name);
+        encoder.usize(*address);
+        encoder.usize(*n);
+    }
+}
+
+type MemoryParts = (Vec<u8>, Vec<(usize, usize)>, Vec<(String, usize, usize)>);
+
+fn decode_memory(decoder: &mut Decoder) -> Result<MemoryParts, String> {
+    let memory = decoder.bytes()?;
+    let mut frozen = Vec::new();
+    for _ in 0..decoder.usize()? {
+        frozen.push((decoder.usize()?, decoder.usize()?));
+    }
+    let mut names = Vec::new();
+    for _ in 0..decoder.usize()? {
+        names.push((decoder.string()?, decoder.usize()?, decoder.usize()?));
+    }
+    return Ok((memory, frozen, names));
+}
+
+// Whether two programs run the same code, so can share a program block in a snapshot.
+fn same_code(a: &Program, b: &Program) -> bool {
+    let same_debug_info = match (&a.debug_info, &b.debug_info) {
+        (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+        (None, None) => true,
+        _ => false,
+    };
+    return Rc::ptr_eq(&a.instructions, &b.instructions) && Rc::ptr_eq(&a.blocks, &b.blocks) && same_debug_info;
+}
+
+// Encode the instructions of a program, along with its named blocks and debug info.
+fn encode_code(encoder: &mut Encoder, program: &Program) {
+    encoder.instructions(&program.instructions);
+    let mut blocks: Vec<(&String, &Range<usize>)> = program.blocks.iter().collect();
+    blocks.sort_by_key(|(name, _)| *name);
+    encoder.usize(blocks.len());
+    for (name, range) in blocks {
+        encoder.string(name);
+        encoder.usize(range.start);
+        encoder.usize(range.end);
+    }
+    encode_option(encoder, program.debug_info.as_ref(), |encoder, debug_info| debug_info.encode(encoder));
+}
+
+fn decode_code(decoder: &mut Decoder) -> Result<Program, String> {
+    let instructions = decoder.instructions()?;
+    let mut blocks = HashMap::new();
+    for _ in 0..decoder.usize()? {
+        let name = decoder.string()?;
+        blocks.insert(name, decoder.usize()?..decoder.usize()?);
+    }
+    let debug_info = decode_option(decoder, DebugInfo::decode)?;
+    return Ok(Program { instructions: Rc::new(instructions), blocks: Rc::new(blocks), debug_info: debug_info.map(Rc::new), ..Program::default() });
+}
+
+// Encode how far a program has got: its pc, list loops, and the interrupted pc.
+fn encode_progress(encoder: &mut Encoder, program: &Program) {
+    encoder.usize(program.pc);
+    encode_loops(encoder, &program.loops);
+    encode_option(encoder, program.interrupted.as_ref(), |encoder, pc| encoder.usize(*pc));
+}
+
+fn decode_progress(decoder: &mut Decoder, program: &mut Program) -> Result<(), String> {
+    program.pc = decoder.usize()?;
+    program.loops = decode_loops(decoder)?;
+    program.interrupted = decode_option(decoder, |decoder| decoder.usize())?;
+    return Ok(());
+}
+
+fn encode_loops(encoder: &mut Encoder, loops: &[ListLoop]) {
+    encoder.usize(loops.len());
+    for each in loops {
+        let (code, operand) = match each.kind {
+            LoopKind::Each => (0, 0),
+            LoopKind::Map(dest) => (1, dest),
+            LoopKind::Filter(dest) => (2, dest),
+            LoopKind::Reduce(accumulator) => (3, accumulator),
+        };
+        encoder.u8(code);
+        for value in [operand, each.list, each.item, each.body, each.next, each.count, each.kept, each.resume] {
+            encoder.usize(value);
+        }
+    }
+}
+
+fn decode_loops(decoder: &mut Decoder) -> Result<Vec<ListLoop>, String> {
+    let mut loops = Vec::new();
+    for _ in 0..decoder.usize()? {
+        let code = decoder.u8()?;
+        let operand = decoder.usize()?;
+        let kind = match code {
+            0 => LoopKind::Each,
+            1 => LoopKind::Map(operand),
+            2 => LoopKind::Filter(operand),
+            3 => LoopKind::Reduce(operand),
+            _ => log_and_return_err!("Unknown loop kind {} in snapshot", code),
+        };
+        let (list, item, body) = (decoder.usize()?, decoder.usize()?, decoder.usize()?);
+        let (next, count, kept, resume) = (decoder.usize()?, decoder.usize()?, decoder.usize()?, decoder.usize()?);
+        loops.push(ListLoop { kind, list, item, body, next, count, kept, resume });
+    }
+    return Ok(loops);
+}
+
+fn finish(decoder: &Decoder) -> Result<(), String> {
+    if !decoder.is_finished() {
+        log_and_return_err!("Trailing data after snapshot");
+    }
+    Ok(())
+}
+
+fn encode_option<T>(encoder: &mut Encoder, value: Option<&T>, encode: impl FnOnce(&mut Encoder, &T)) {
+    match value {
+        Some(value) => {
+            encoder.bool(true);
+            encode(encoder, value);
+        },
+        None => encoder.bool(false),
+    }
+}
+
+fn decode_option<T>(decoder: &mut Decoder, decode: impl FnOnce(&mut Decoder) -> Result<T, String>) -> Result<Option<T>, String> {
+    if decoder.bool()? {
+        return Ok(Some(decode(decoder)?));
+    }
+    Ok(None)
+}
+
+fn coroutine_state_code(state: &CoroutineState) -> u8 {
+    match state {
+        CoroutineState::Runnable => 0,
+        CoroutineState::Suspended => 1,
+        CoroutineState::Running => 2,
+        CoroutineState::Finished => 3,
+        CoroutineState::Cancelled => 4,
+    }
+}
+
+fn coroutine_state_from_code(code: u8) -> Result<CoroutineState, String> {
+    match code {
+        0 => Ok(CoroutineState::Runnable),
+        1 => Ok(CoroutineState::Suspended),
+        2 => Ok(CoroutineState::Running),
+        3 => Ok(CoroutineState::Finished),
+        4 => Ok(CoroutineState::Cancelled),
+        _ => log_and_return_err!("Unknown coroutine state {} in snapshot", code),
+    }
+}
+
+fn future_state_code(state: &FutureState) -> u8 {
+    match state {
+        FutureState::Cancelled => 0,
+        FutureState::Waiting => 1,
+        FutureState::Complete => 2,
+    }
+}
+
+fn future_state_from_code(code: u8) -> Result<FutureState, String> {
+    match code {
+        0 => Ok(FutureState::Cancelled),
+        1 => Ok(FutureState::Waiting),
+        2 => Ok(FutureState::Complete),
+        _ => log_and_return_err!("Unknown future state {} in snapshot", code),
+    }
+}
@@ -0,0 +1,115 @@
+//! ConcordeVM's standard library.
+//!
+//! A module of common routines written in Concorde instructions, so programs don't have to
+//! reimplement them. Link it alongside a program with `link`, or append it to a loaded program
+//! with `CPU::load_stdlib`, and call the routines by block name with CreateCoroutine.
+//!
+//! Every routine takes its arguments at address 0 and returns an 8 byte result. Routines keep
+//! their locals from `SCRATCH` upwards, so arguments must fit below it.
+
+use crate::linker::{Block, Module};
+
+use concordeisa::instructions::Instruction;
+
+/// Address routines keep their locals at.
+pub const SCRATCH: usize = 1 << 16;
+
+const PTR: usize = SCRATCH;
+const BYTE: usize = SCRATCH + 8;
+const RESULT: usize = SCRATCH + 16;
+const COND: usize = SCRATCH + 24;
+const EXTRA: usize = SCRATCH + 32;
+
+/// The standard library module. Its blocks are:
+///
+/// - `std::strlen`: the length of a NUL-terminated string.
+/// - `std::parse_int`: parse a NUL-terminated decimal string, with an optional leading `-`, as an
+///   i64. Parsing stops at the first byte that isn't a digit.
+/// - `std::list_sum`: the sum of a list given as an i64 count followed by that many i64s.
+/// - `std::list_max`: the largest item of a list laid out like `std::list_sum`'s, or i64::MIN if
+///   it's empty.
+pub fn stdlib() -> Module {
+    Module::new(vec![strlen(), parse_int(), list_sum(), list_max()])
+}
+
+fn strlen() -> Block {
+    Block::new("std::strlen", vec![
+        Instruction::MemExtendTo(SCRATCH + 64),
+        Instruction::WriteIntToSymbol(PTR, 0),
+        Instruction::WriteIntToSymbol(BYTE, 0),
+        Instruction::Ind(PTR, BYTE, 1),             // 3: loop
+        Instruction::CompareEqualImmediate(BYTE, 0, COND),
+        Instruction::JumpIfTrue(8, COND),
+        Instruction::AddImmediate(PTR, 1, PTR),
+        Instruction::Jump(3),
+        Instruction::Return(PTR, 8),                // 8: end
+    ])
+}
+
+fn parse_int() -> Block {
+    const SIGN: usize = EXTRA;
+    Block::new("std::parse_int", vec![
+        Instruction::MemExtendTo(SCRATCH + 64),
+        Instruction::WriteIntToSymbol(PTR, 0),
+        Instruction::WriteIntToSymbol(BYTE, 0),
+        Instruction::WriteIntToSymbol(RESULT, 0),
+        Instruction::WriteIntToSymbol(SIGN, 1),
+        Instruction::Ind(PTR, BYTE, 1),
+        Instruction::CompareEqualImmediate(BYTE, b'-' as i64, COND),
+        Instruction::JumpIfFalse(10, COND),
+        Instruction::WriteIntToSymbol(SIGN, -1),
+        Instruction::AddImmediate(PTR, 1, PTR),
+        Instruction::Ind(PTR, BYTE, 1),             // 10: loop
+        Instruction::CompareLesserImmediate(BYTE, b'0' as i64, COND),
+        Instruction::JumpIfTrue(20, COND),
+        Instruction::CompareGreaterImmediate(BYTE, b'9' as i64, COND),
+        Instruction::JumpIfTrue(20, COND),
+        Instruction::MultiplyImmediate(RESULT, 10, RESULT),
+        Instruction::SubtractImmediate(BYTE, b'0' as i64, BYTE),
+        Instruction::AddSymbols(RESULT, BYTE, RESULT),
+        Instruction::AddImmediate(PTR, 1, PTR),
+        Instruction::Jump(10),
+        Instruction::MultiplySymbols(RESULT, SIGN, RESULT),   // 20: end
+        Instruction::Return(RESULT, 8),
+    ])
+}
+
+fn list_sum() -> Block {
+    const REMAINING: usize = EXTRA;
+    const ITEM: usize = EXTRA + 8;
+    Block::new("std::list_sum", vec![
+        Instruction::MemExtendTo(SCRATCH + 64),
+        Instruction::WriteIntToSymbol(PTR, 8),
+        Instruction::MemCpy(0, REMAINING, 8),
+        Instruction::WriteIntToSymbol(RESULT, 0),
+        Instruction::CompareGreaterImmediate(REMAINING, 0, COND),   // 4: loop
+        Instruction::JumpIfFalse(11, COND),
+        Instruction::Ind(PTR, ITEM, 8),
+        Instruction::AddSymbols(RESULT, ITEM, RESULT),
+        Instruction::AddImmediate(PTR, 8, PTR),
+        Instruction::SubtractImmediate(REMAINING, 1, REMAINING),
+        Instruction::Jump(4),
+        Instruction::Return(RESULT, 8),             // 11: end
+    ])
+}
+
+fn list_max() -> Block {
+    const REMAINING: usize = EXTRA;
+    const ITEM: usize = EXTRA + 8;
+    Block::new("std::list_max", vec![
+        Instruction::MemExtendTo(SCRATCH + 64),
+        Instruction::WriteIntToSymbol(PTR, 8),
+        Instruction::MemCpy(0, REMAINING, 8),
+        Instruction::WriteIntToSymbol(RESULT, i64::MIN),
+        Instruction::CompareGreaterImmediate(REMAINING, 0, COND),   // 4: loop
+        Instruction::JumpIfFalse(13, COND),
+        Instruction::Ind(PTR, ITEM, 8),
+        Instruction::CompareGreater(ITEM, RESULT, COND),
+        Instruction::JumpIfFalse(10, COND),
+        Instruction::MemCpy(ITEM, RESULT, 8),
+        Instruction::AddImmediate(PTR, 8, PTR),     // 10
+        Instruction::SubtractImmediate(REMAINING, 1, REMAINING),
+        Instruction::Jump(4),
+        Instruction::Return(RESULT, 8),             // 13: end
+    ])
+}
@@ -5,9 +5,10 @@ use cloneable_any::CloneableAny;
 use concordeisa::{instructions::Instruction};
 use libffi::middle::Type;
 
+use crate::bigint::BigInt;
 use crate::memory::{ByteParseable, ByteSerialisable};
 
-use crate::{CPU, Memory, Program, Scheduler};
+use crate::{link, opcode, stdlib, Access, ArithmeticMode, Block, ChannelEnd, CPU, InstanceLimits, CoreDump, CoroutineCounts, CpuSnapshot, DebugInfo, Event, ExecutionLog, HashAlgorithm, Interrupt, ListKind, Memory, Module, Program, ProgramBuilder, ReloadPolicy, RemoteRequest, RemoteResponse, SandboxPolicy, Scheduler, SharedCpu, VmPool, VirtualClock, VmSnapshot};
 
 fn execute(instructions: Vec<Instruction>) -> Result<Memory, String> {
     execute_entrypoint(instructions, 0)
@@ -15,7 +16,7 @@ fn execute(instructions: Vec<Instruction>) -> Result<Memory, String> {
 
 fn execute_entrypoint(instructions: Vec<Instruction>, entrypoint: usize) -> Result<Memory, String> {
     let instructions: std::rc::Rc<Vec<Instruction>> = Rc::new(instructions);
-    let program: Program = Program { instructions: instructions, pc: entrypoint };
+    let program: Program = Program { instructions: instructions, pc: entrypoint, ..Program::default() };
 
     let mut scheduler = Scheduler::new();
     scheduler.run(program)?;
@@ -28,22 +29,32 @@ fn check_symbol_eq<T: PartialEq + Debug + CloneableAny + ByteSerialisable + Byte
     assert_eq!(x, value)
 }
 
-// #[test]
-// fn basic_io() {
-//     let a = Symbol("a".to_string());
-//     let b = Symbol("b".to_string());
-//     let s = Symbol("s".to_string());
-//     let n = Symbol("n".to_string());
-//     let instructions = vec![
-//         Instruction::WriteStringToSymbol(a.clone(), "stdio".to_string()),
-//         Instruction::WriteBytesToSymbol(b.clone(), "test output\n".as_bytes().to_vec()),
-//         Instruction::WriteIntToSymbol(n.clone(), 12),
-//         Instruction::OpenStream(a, s.clone()),
-//         Instruction::WriteStream(s.clone(), n, b),
-//     ];
+#[test]
+fn basic_io() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteStringToSymbol(0, "stdio".to_string()),
+        Instruction::WriteBytesToSymbol(16, "test output\n".as_bytes().to_vec()),
+        Instruction::WriteIntToSymbol(32, 12),
+        Instruction::OpenStream(0, 1, 1),
+        Instruction::WriteStream(1, 32, 16),
+        Instruction::WriteStringToSymbol(0, "stderr".to_string()),
+        Instruction::OpenStream(0, 2, 1),
+        Instruction::WriteStream(2, 32, 16),
+        Instruction::WriteStringToSymbol(48, "printed".to_string()),
+        Instruction::PrintSymbol(48),
+        Instruction::Return(0, 8),
+    ];
+    let mut scheduler = Scheduler::new();
+    let output = scheduler.capture_output();
+    scheduler.run(Program::new(instructions))?;
+    assert_eq!(output.stdout(), b"test output\nprinted");
+    assert_eq!(output.stderr(), b"test output\n");
 
-//     execute(instructions);
-// }
+    output.clear();
+    assert!(output.stdout().is_empty());
+    Ok(())
+}
 
 
 
@@ -144,4 +155,3049 @@ fn test_ffi() -> Result<(), Box<dyn std::error::Error>> {
     print!("Done executing");
     check_symbol_eq(memory, 0, 100i64);
     Ok(())
-}
\ No newline at end of file
+}
+#[test]
+fn file_open_modes() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("concordevm_file_open_modes.txt");
+    let path = path.to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&path);
+
+    let instructions = vec![
+        Instruction::MemExtend(1000),
+        Instruction::WriteStringToSymbol(0, path.clone()),
+        Instruction::WriteBytesToSymbol(200, "hello".as_bytes().to_vec()),
+        Instruction::WriteIntToSymbol(300, 5),
+        Instruction::OpenStream(0, 1, 4),   // truncate
+        Instruction::WriteStream(1, 300, 200),
+        Instruction::CloseStream(1),
+
+        Instruction::WriteBytesToSymbol(200, " world".as_bytes().to_vec()),
+        Instruction::WriteIntToSymbol(300, 6),
+        Instruction::OpenStream(0, 1, 2),   // append
+        Instruction::WriteStream(1, 300, 200),
+        Instruction::CloseStream(1),
+
+        Instruction::WriteIntToSymbol(300, 11),
+        Instruction::OpenStream(0, 1, 0),   // read
+        Instruction::ReadStream(1, 300, 400),
+        Instruction::CloseStream(1),
+        Instruction::Return(400, 8)
+    ];
+
+    let memory = execute(instructions)?;
+    std::fs::remove_file(&path)?;
+    check_symbol_eq(memory, 400, String::from("hello world"));
+    Ok(())
+}
+
+#[test]
+fn read_write_streams_share_an_offset() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("concordevm_read_write_stream.txt");
+    std::fs::write(&path, "abcdef\nghi\n")?;
+
+    let instructions = vec![
+        Instruction::MemExtend(1000),
+        Instruction::WriteStringToSymbol(0, path.to_str().unwrap().to_string()),
+        Instruction::OpenStream(0, 1, 5),   // read-write
+        Instruction::WriteIntToSymbol(300, 2),
+        Instruction::ReadStream(1, 300, 400),
+        Instruction::WriteBytesToSymbol(200, "XY".as_bytes().to_vec()),
+        Instruction::WriteStream(1, 300, 200),
+        Instruction::WriteIntToSymbol(310, 3),
+        Instruction::ReadStream(1, 310, 500),
+        Instruction::WriteStream(1, 300, 200),
+        Instruction::CloseStream(1),
+        Instruction::Return(0, 8)
+    ];
+
+    let memory = execute(instructions)?;
+    assert_eq!(std::fs::read_to_string(&path)?, "abXYef\nXYi\n");
+    std::fs::remove_file(&path)?;
+    check_symbol_eq(memory.clone(), 400, String::from("ab"));
+    check_symbol_eq(memory, 500, String::from("ef\n"));
+    Ok(())
+}
+
+#[test]
+fn sandbox_denies_writes_outside_allowlist() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("concordevm_sandbox_denied.txt");
+    let path = path.to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&path);
+
+    let instructions = vec![
+        Instruction::MemExtend(1000),
+        Instruction::WriteStringToSymbol(0, path.clone()),
+        Instruction::OpenStream(0, 1, 4),   // truncate
+        Instruction::Return(0, 8)
+    ];
+
+    let mut scheduler = Scheduler::new();
+    scheduler.set_sandbox_policy(SandboxPolicy::deny_all().allow_path(std::env::temp_dir(), Access::ReadOnly));
+    let result = scheduler.run(Program::new(instructions));
+
+    assert!(result.unwrap_err().starts_with("Permission denied"));
+    assert!(!std::path::Path::new(&path).exists());
+    let denied = scheduler.get_coro(1).fault().unwrap().denied.clone();
+    assert_eq!(denied, Some(crate::PermissionDenied { capability: "write".to_string(), target: path }));
+    Ok(())
+}
+
+#[test]
+fn sandbox_standard_streams() -> Result<(), Box<dyn std::error::Error>> {
+    let opening = |name: &str| vec![
+        Instruction::MemExtend(1000),
+        Instruction::WriteStringToSymbol(0, name.to_string()),
+        Instruction::OpenStream(0, 1, 1),
+        Instruction::CloseStream(1),
+        Instruction::Return(0, 8)
+    ];
+    for name in ["stdin", "stdout", "stderr", "stdio"] {
+        let mut cpu = CPU::with_program(0, Program::new(opening(name)));
+        cpu.set_sandbox_policy(Rc::new(SandboxPolicy::deny_all()));
+        assert!(cpu.run().err().unwrap().starts_with("Permission denied"));
+        let denied = cpu.fault().unwrap().denied.clone().unwrap();
+        assert_eq!((denied.capability.as_str(), denied.target.as_str()), ("open", name));
+    }
+    // Captured, so the test doesn't take the host's stdout.
+    let mut cpu = CPU::with_program(0, Program::new(opening("stdout")));
+    cpu.set_sandbox_policy(Rc::new(SandboxPolicy::deny_all().allow_standard_streams(true)));
+    cpu.capture_output();
+    cpu.run()?;
+
+    let printing = vec![Instruction::MemExtend(16), Instruction::WriteStringToSymbol(0, "hi".to_string()), Instruction::PrintSymbol(0)];
+    let mut cpu = CPU::with_program(0, Program::new(printing));
+    cpu.set_sandbox_policy(Rc::new(SandboxPolicy::deny_all()));
+    assert!(cpu.run().err().unwrap().starts_with("Permission denied: open access to stdout"));
+    assert!(cpu.fault().unwrap().denied.is_some());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn sandbox_resolves_symlinks() -> Result<(), Box<dyn std::error::Error>> {
+    let jail = std::env::temp_dir().join("concordevm_sandbox_jail");
+    let outside = std::env::temp_dir().join("concordevm_sandbox_outside");
+    let _ = std::fs::remove_dir_all(&jail);
+    std::fs::create_dir_all(&jail)?;
+    std::fs::create_dir_all(&outside)?;
+    std::fs::write(jail.join("inside.txt"), "inside")?;
+    std::fs::write(outside.join("secret.txt"), "secret")?;
+    std::os::unix::fs::symlink(&outside, jail.join("escape"))?;
+
+    let policy = SandboxPolicy::deny_all().allow_path(&jail, Access::ReadWrite);
+    let mut io = crate::io::ConcordeIO::with_policy(Rc::new(policy));
+    assert_eq!(io.read_file(jail.join("inside.txt").to_str().unwrap())?, b"inside");
+    match io.read_file(jail.join("escape/secret.txt").to_str().unwrap()) {
+        Err(crate::IoError::PermissionDenied(e)) => assert_eq!(e.capability, "read"),
+        other => panic!("Read through a symlink out of the sandbox gave {:?}", other),
+    }
+    assert!(matches!(io.write_file(jail.join("escape/new.txt").to_str().unwrap(), b"", false), Err(crate::IoError::PermissionDenied(_))));
+    std::fs::remove_dir_all(&jail)?;
+    std::fs::remove_dir_all(&outside)?;
+    Ok(())
+}
+
+#[test]
+fn file_management() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir();
+    let original = dir.join("concordevm_file_management_a.txt");
+    let copied = dir.join("concordevm_file_management_b.txt");
+    let renamed = dir.join("concordevm_file_management_c.txt");
+    std::fs::write(&original, "contents")?;
+    let _ = std::fs::remove_file(&renamed);
+
+    let instructions = vec![
+        Instruction::MemExtend(1000),
+        Instruction::WriteStringToSymbol(0, original.to_str().unwrap().to_string()),
+        Instruction::WriteStringToSymbol(200, copied.to_str().unwrap().to_string()),
+        Instruction::WriteStringToSymbol(400, renamed.to_str().unwrap().to_string()),
+        Instruction::CopyFile(0, 200),
+        Instruction::RenameFile(200, 400),
+        Instruction::DeleteFile(0),
+        Instruction::Return(0, 8)
+    ];
+
+    execute(instructions)?;
+    assert!(!original.exists());
+    assert!(!copied.exists());
+    assert_eq!(std::fs::read_to_string(&renamed)?, "contents");
+    std::fs::remove_file(&renamed)?;
+    Ok(())
+}
+
+#[test]
+fn whole_file_io() -> Result<(), Box<dyn std::error::Error>> {
+    let source = std::env::temp_dir().join("concordevm_whole_file_in.txt");
+    let dest = std::env::temp_dir().join("concordevm_whole_file_out.txt");
+    std::fs::write(&source, "slurped")?;
+
+    let instructions = vec![
+        Instruction::MemExtend(500),
+        Instruction::WriteStringToSymbol(0, source.to_str().unwrap().to_string()),
+        Instruction::WriteStringToSymbol(200, dest.to_str().unwrap().to_string()),
+        Instruction::ReadFileToSymbol(0, 400),
+        Instruction::WriteSymbolToFile(200, 400, true),
+        Instruction::Return(0, 8)
+    ];
+
+    let memory = execute(instructions)?;
+    check_symbol_eq(memory.clone(), 400, 7i64);
+    check_symbol_eq(memory, 408, String::from("slurped"));
+    assert_eq!(std::fs::read_to_string(&dest)?, "slurped");
+    std::fs::remove_file(&source)?;
+    std::fs::remove_file(&dest)?;
+    Ok(())
+}
+
+#[test]
+fn read_lines() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("concordevm_read_lines.txt");
+    std::fs::write(&path, "first\nsecond")?;
+
+    let instructions = vec![
+        Instruction::MemExtend(500),
+        Instruction::WriteStringToSymbol(0, path.to_str().unwrap().to_string()),
+        Instruction::OpenStream(0, 1, 0),   // read
+        Instruction::ReadLine(1, 200),
+        Instruction::ReadLine(1, 300),
+        Instruction::ReadLine(1, 400),
+        Instruction::CloseStream(1),
+        Instruction::Return(0, 8)
+    ];
+
+    let memory = execute(instructions)?;
+    std::fs::remove_file(&path)?;
+    check_symbol_eq(memory.clone(), 200, String::from("first\n"));
+    check_symbol_eq(memory.clone(), 300, String::from("second"));
+    check_symbol_eq(memory, 400, String::new());
+    Ok(())
+}
+
+#[test]
+fn unclosed_streams_are_flushed() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("concordevm_unclosed_stream.txt");
+    let _ = std::fs::remove_file(&path);
+
+    let instructions = vec![
+        Instruction::MemExtend(500),
+        Instruction::WriteStringToSymbol(0, path.to_str().unwrap().to_string()),
+        Instruction::WriteBytesToSymbol(200, "buffered".as_bytes().to_vec()),
+        Instruction::WriteIntToSymbol(300, 8),
+        Instruction::OpenStream(0, 1, 4),   // truncate
+        Instruction::WriteStream(1, 300, 200),
+        Instruction::Return(0, 8)
+    ];
+
+    execute(instructions)?;
+    assert_eq!(std::fs::read_to_string(&path)?, "buffered");
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn environment_access() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(500),
+        Instruction::WriteStringToSymbol(0, String::from("CONCORDEVM_TEST_VAR")),
+        Instruction::WriteStringToSymbol(100, String::from("set by guest")),
+        Instruction::SetEnv(0, 100),
+        Instruction::GetEnv(0, 200),
+        Instruction::GetArgs(300),
+        Instruction::Return(0, 8)
+    ];
+
+    let mut scheduler = Scheduler::new();
+    scheduler.set_args(vec![String::from("first"), String::from("second")]);
+    scheduler.run(Program::new(instructions))?;
+    let memory = scheduler.get_coro(1).memory_dump();
+
+    assert!(std::env::var("CONCORDEVM_TEST_VAR").is_err());
+    check_symbol_eq(memory.clone(), 200, String::from("set by guest"));
+    check_symbol_eq(memory.clone(), 300, 2i64);
+    check_symbol_eq(memory.clone(), 308, String::from("first"));
+    check_symbol_eq(memory, 314, String::from("second"));
+    Ok(())
+}
+
+#[test]
+fn subprocesses() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = 2i64.to_bytes();
+    args.extend("-c\0printf captured; exit 3\0".as_bytes());
+
+    let instructions = vec![
+        Instruction::MemExtend(500),
+        Instruction::WriteStringToSymbol(0, String::from("sh")),
+        Instruction::WriteBytesToSymbol(100, args),
+        Instruction::SpawnProcess(0, 100, 1, 2, 3, 200),
+        Instruction::CloseStream(1),
+        Instruction::WriteIntToSymbol(208, 8),
+        Instruction::ReadStream(2, 208, 300),
+        Instruction::WaitProcess(200, 216),
+        Instruction::Return(0, 8)
+    ];
+
+    let mut scheduler = Scheduler::new();
+    scheduler.set_sandbox_policy(SandboxPolicy::unrestricted().allow_subprocesses(true));
+    scheduler.run(Program::new(instructions))?;
+    let memory = scheduler.get_coro(1).memory_dump();
+
+    check_symbol_eq(memory.clone(), 300, String::from("captured"));
+    check_symbol_eq(memory, 216, 3i64);
+
+    // Children only see the variables the sandbox lets the guest read.
+    let mut args = 2i64.to_bytes();
+    args.extend("-c\0printf %s-%s \"$CONCORDEVM_CHILD_VAR\" \"$CONCORDEVM_HIDDEN_VAR\"\0".as_bytes());
+    let instructions = vec![
+        Instruction::MemExtend(500),
+        Instruction::WriteStringToSymbol(0, String::from("/bin/sh")),
+        Instruction::WriteBytesToSymbol(100, args),
+        Instruction::WriteStringToSymbol(400, String::from("CONCORDEVM_CHILD_VAR")),
+        Instruction::WriteStringToSymbol(440, String::from("CONCORDEVM_HIDDEN_VAR")),
+        Instruction::WriteStringToSymbol(480, String::from("set")),
+        Instruction::SetEnv(400, 480),
+        Instruction::SetEnv(440, 480),
+        Instruction::SpawnProcess(0, 100, 1, 2, 3, 200),
+        Instruction::CloseStream(1),
+        Instruction::WriteIntToSymbol(208, 4),
+        Instruction::ReadStream(2, 208, 300),
+        Instruction::WaitProcess(200, 216),
+        Instruction::Return(0, 8)
+    ];
+    let mut scheduler = Scheduler::new();
+    scheduler.set_sandbox_policy(SandboxPolicy::unrestricted().allow_subprocesses(true).allow_env_var("CONCORDEVM_CHILD_VAR"));
+    scheduler.run(Program::new(instructions))?;
+    check_symbol_eq(scheduler.get_coro(1).memory_dump(), 300, String::from("set-"));
+    Ok(())
+}
+
+#[test]
+fn record_and_replay_io() -> Result<(), Box<dyn std::error::Error>> {
+    let input = std::env::temp_dir().join("concordevm_replay_input.txt");
+    let recording = std::env::temp_dir().join("concordevm_replay_recording.txt");
+    let recording = recording.to_str().unwrap();
+    std::fs::write(&input, "recorded line\n")?;
+
+    let instructions = vec![
+        Instruction::MemExtend(500),
+        Instruction::WriteStringToSymbol(0, input.to_str().unwrap().to_string()),
+        Instruction::OpenStream(0, 1, 0),   // read
+        Instruction::ReadLine(1, 200),
+        Instruction::CloseStream(1),
+        Instruction::Return(0, 8)
+    ];
+
+    let mut scheduler = Scheduler::new();
+    scheduler.record_io(recording)?;
+    scheduler.run(Program::new(instructions.clone()))?;
+    std::fs::remove_file(&input)?;
+
+    let mut scheduler = Scheduler::new();
+    scheduler.replay_io(recording)?;
+    scheduler.run(Program::new(instructions))?;
+    std::fs::remove_file(recording)?;
+
+    check_symbol_eq(scheduler.get_coro(1).memory_dump(), 200, String::from("recorded line\n"));
+
+    // Writing different bytes of the same length diverges from the recording.
+    let writing = |text: &str| vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteStringToSymbol(0, "stdio".to_string()),
+        Instruction::WriteBytesToSymbol(16, text.as_bytes().to_vec()),
+        Instruction::WriteIntToSymbol(32, 4),
+        Instruction::OpenStream(0, 1, 1),
+        Instruction::WriteStream(1, 32, 16),
+        Instruction::Return(0, 8),
+    ];
+    let mut scheduler = Scheduler::new();
+    scheduler.capture_output();
+    scheduler.record_io(recording)?;
+    scheduler.run(Program::new(writing("same")))?;
+    let mut scheduler = Scheduler::new();
+    scheduler.replay_io(recording)?;
+    let error = scheduler.run(Program::new(writing("diff"))).unwrap_err();
+    std::fs::remove_file(recording)?;
+    assert!(error.starts_with("Replay diverged"));
+    Ok(())
+}
+
+#[test]
+fn snapshot_and_restore() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteIntToSymbol(0, 20),
+        Instruction::WriteIntToSymbol(8, 22),
+        Instruction::AddSymbols(0, 8, 16),
+        Instruction::Return(16, 8)
+    ];
+
+    // Stop a CPU partway through, and finish running it from the snapshot.
+    let mut cpu = CPU::with_program(0, Program::new(instructions.clone()));
+    cpu.cycle()?;
+    cpu.cycle()?;
+    let snapshot = CpuSnapshot::from_bytes(&cpu.snapshot().to_bytes())?;
+    let mut restored = CPU::default();
+    restored.restore(&snapshot);
+    restored.run()?;
+    check_symbol_eq(restored.memory().clone(), 16, 42i64);
+
+    // Move a scheduler with a spawned coroutine into a fresh one.
+    let mut scheduler = Scheduler::new();
+    scheduler.spawn_coro(Program::new(instructions), 0, &Vec::new())?;
+    let snapshot = VmSnapshot::from_bytes(&scheduler.snapshot()?.to_bytes())?;
+    let mut scheduler = Scheduler::new();
+    scheduler.restore(&snapshot);
+    scheduler._run()?;
+    check_symbol_eq(scheduler.get_coro(1).memory_dump(), 16, 42i64);
+
+    // FFI signatures survive the program format, including nested structs.
+    let program = vec![
+        Instruction::LoadSO(0, String::from("./ffi.so")),
+        Instruction::AddFFIFn(0, 0, String::from("f"), vec![Type::structure(vec![Type::u8(), Type::f64()]), Type::pointer()], Type::i32()),
+    ];
+    let encoded = crate::encode_program(&program);
+    assert_eq!(crate::encode_program(&crate::decode_program(&encoded)?), encoded);
+    Ok(())
+}
+
+#[test]
+fn snapshot_memory_and_program_state() -> Result<(), Box<dyn std::error::Error>> {
+    let mut debug_info = DebugInfo::new();
+    debug_info.set_location(1, "main.cvm", 3, 1);
+    let mut cpu = CPU::new(16);
+    cpu.program = Program::new(vec![Instruction::NoOp(), Instruction::NoOp()]);
+    cpu.program.debug_info = Some(Rc::new(debug_info));
+    Rc::make_mut(&mut cpu.program.blocks).insert(String::from("main"), 0..2);
+    cpu.program.interrupted = Some(1);
+    cpu.memory.bind("x", 8, 8);
+    cpu.memory.freeze(0, 8);
+    cpu.memory.store_host_value(8, |args| Ok(args.to_vec()));
+
+    let snapshot = CpuSnapshot::from_bytes(&cpu.snapshot().to_bytes())?;
+    let mut restored = CPU::default();
+    restored.restore(&snapshot);
+    assert_eq!(restored.memory.lookup("x"), Some((8, 8)));
+    assert!(restored.memory.is_frozen(0, 8));
+    assert_eq!(restored.program.block("main"), Some(0..2));
+    assert_eq!(restored.program.debug_info.as_ref().and_then(|info| info.location(1)).map(|location| location.line), Some(3));
+    assert_eq!(restored.program.interrupted, Some(1));
+
+    // Restoring on the same CPU keeps its host values.
+    cpu.restore(&snapshot);
+    assert!(cpu.memory.host_value(1).is_some());
+
+    // Schedulers refuse snapshots holding state they can't capture.
+    let mut scheduler = Scheduler::new();
+    scheduler.spawn_actor(Program::new(vec![]), 8);
+    assert!(scheduler.snapshot().is_err());
+    Ok(())
+}
+
+#[test]
+fn memory_json() -> Result<(), Box<dyn std::error::Error>> {
+    let mut memory = Memory::new(40);
+    memory.write(0, &7i64);
+    memory.write(36, &-1i32);
+    let json = memory.to_json();
+    assert_eq!(Memory::from_json(&json)?.dump(), memory.dump());
+
+    let seeded = Memory::from_json(r#"{ "size": 16, "rows": { "8": "2a 00 00 00 00 00 00 00" } }"#)?;
+    check_symbol_eq(seeded.clone(), 8, 42i64);
+    check_symbol_eq(seeded, 0, 0i64);
+    assert!(Memory::from_json(r#"{ "size": 4, "rows": { "2": "01 02 03" } }"#).is_err());
+    Ok(())
+}
+
+#[test]
+fn memory_inspection() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cpu = CPU::new(32);
+    cpu.memory.write(4, &String::from("hi"));
+    cpu.memory.write(16, &258i16);
+
+    let memory = cpu.memory_view();
+    assert_eq!(memory.len(), 32);
+    assert_eq!(memory.iter().filter(|(_, byte)| *byte != 0).count(), 4);
+    assert_eq!(memory.regions(), vec![(4, 2), (16, 2)]);
+    assert_eq!(memory.addresses_matching(b"hi"), vec![4]);
+    Ok(())
+}
+
+#[test]
+fn immediate_arithmetic() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteIntToSymbol(0, 0),
+        Instruction::AddImmediate(0, 3, 0),
+        Instruction::CompareLesserImmediate(0, 15, 8),
+        Instruction::JumpIfTrue(2, 8),
+        Instruction::MultiplyImmediate(0, 2, 16),
+        Instruction::Return(16, 8)
+    ];
+    let memory = execute(instructions)?;
+    check_symbol_eq(memory.clone(), 0, 15i64);
+    check_symbol_eq(memory, 16, 30i64);
+
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::DivideImmediate(0, 0, 8),
+        Instruction::Return(8, 8)
+    ];
+    assert!(execute(instructions).is_err());
+    Ok(())
+}
+
+#[test]
+fn switch() -> Result<(), Box<dyn std::error::Error>> {
+    let program = |value: i64| vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteIntToSymbol(0, value),
+        Instruction::Switch(0, vec![(1, 5), (2, 7)], 3),
+        Instruction::WriteIntToSymbol(8, 100),
+        Instruction::Return(8, 8),
+        Instruction::WriteIntToSymbol(8, 101),
+        Instruction::Return(8, 8),
+        Instruction::WriteIntToSymbol(8, 102),
+        Instruction::Return(8, 8)
+    ];
+    check_symbol_eq(execute(program(1))?, 8, 101i64);
+    check_symbol_eq(execute(program(2))?, 8, 102i64);
+    check_symbol_eq(execute(program(9))?, 8, 100i64);
+    Ok(())
+}
+
+#[test]
+fn jump_if_false() -> Result<(), Box<dyn std::error::Error>> {
+    // Count down from 4 to 0 in a loop.
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteIntToSymbol(0, 4),
+        Instruction::WriteIntToSymbol(16, 0),
+        Instruction::SubtractImmediate(0, 1, 0),
+        Instruction::AddImmediate(16, 1, 16),
+        Instruction::CompareEqualImmediate(0, 0, 8),
+        Instruction::JumpIfFalse(3, 8),
+        Instruction::Return(16, 8)
+    ];
+    let memory = execute(instructions)?;
+    check_symbol_eq(memory.clone(), 0, 0i64);
+    check_symbol_eq(memory, 16, 4i64);
+    Ok(())
+}
+
+#[test]
+fn coroutine_limit() -> Result<(), Box<dyn std::error::Error>> {
+    // Calls itself forever.
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::CreateCoroutine(0, 0, 0, 0),
+        Instruction::Await(0, 8),
+        Instruction::Return(8, 8)
+    ];
+    let mut scheduler = Scheduler::new();
+    scheduler.set_max_coroutines(10);
+    let error = scheduler.run(Program::new(instructions)).unwrap_err();
+    assert!(error.starts_with("Stack overflow"));
+    assert!(error.contains("coroutine 10 (Running) at instruction 2"));
+    let overflow = scheduler.stack_overflow().unwrap();
+    assert_eq!((overflow.limit, overflow.backtrace.clone()), (10, (1..=10).rev().collect()));
+    assert!(error.contains(&overflow.coroutines));
+    Ok(())
+}
+
+#[test]
+fn error_backtraces() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::CreateCoroutine(4, 0, 0, 0),
+        Instruction::Await(0, 8),
+        Instruction::Return(8, 8),
+
+        Instruction::MemExtend(100),
+        Instruction::DivideImmediate(0, 0, 8),
+        Instruction::Return(8, 8)
+    ];
+    let error = execute(instructions).err().unwrap();
+    assert!(error.contains("at instruction 5: DivideImmediate(0, 0, 8)"));
+    assert!(error.contains("in coroutine 2\n  awaited by coroutine 1 at instruction 2"));
+    Ok(())
+}
+
+#[test]
+fn program_validation() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::CreateCoroutine(5, 0, 0, 0),
+        Instruction::Await(0, 8),
+        Instruction::Return(8, 8),
+        Instruction::NoOp(),
+        Instruction::JumpIfFalse(20, 0),
+        Instruction::Return(0, 8)
+    ];
+    let cpu = CPU::with_program(0, Program::new(instructions));
+    assert_eq!(cpu.validate(0), vec![
+        String::from("Instruction 4 is unreachable"),
+        String::from("Instruction 5 jumps to 20, but the program only has 7 instructions"),
+    ]);
+    assert_eq!(cpu.validate(9).len(), 1);
+    Ok(())
+}
+
+#[test]
+fn linking() -> Result<(), Box<dyn std::error::Error>> {
+    let main = Block::new("main", vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteIntToSymbol(0, 20),
+        Instruction::CreateCoroutine(0, 0, 8, 8),
+        Instruction::Await(8, 16),
+        Instruction::Return(16, 8)
+    ]).with_reference(2, "double");
+    let double = Block::new("double", vec![
+        Instruction::MemExtend(100),
+        Instruction::Jump(2),
+        Instruction::MultiplyImmediate(0, 2, 8),
+        Instruction::Return(8, 8)
+    ]);
+    let library = Module::from_bytes(&Module::new(vec![double.clone()]).to_bytes())?;
+    let program = link(&[Module::new(vec![main.clone()]), library], "main")?;
+    assert_eq!(program.pc, 0);
+
+    let mut scheduler = Scheduler::new();
+    scheduler.run(program)?;
+    check_symbol_eq(scheduler.get_coro(1).memory_dump(), 16, 40i64);
+
+    assert!(link(&[Module::new(vec![main.clone(), double.clone(), double])], "main").is_err());
+    assert!(link(&[Module::new(vec![main.clone()])], "main").is_err());
+    Ok(())
+}
+
+#[test]
+fn module_imports() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join("concordevm_modules");
+    std::fs::create_dir_all(&dir)?;
+    let triple = Block::new("triple", vec![
+        Instruction::MemExtend(100),
+        Instruction::MultiplyImmediate(0, 3, 8),
+        Instruction::Return(8, 8)
+    ]);
+    std::fs::write(dir.join("maths.cvo"), Module::new(vec![triple]).to_bytes())?;
+
+    let instructions = vec![
+        Instruction::MemExtend(200),
+        Instruction::WriteStringToSymbol(0, String::from("maths")),
+        Instruction::Import(0, 16),
+        Instruction::WriteIntToSymbol(40, 14),
+        Instruction::CreateCoroutineIndirect(24, 40, 8, 48),
+        Instruction::Await(48, 56),
+        Instruction::Return(56, 8)
+    ];
+    let mut scheduler = Scheduler::new();
+    scheduler.add_module_path(&dir);
+    scheduler.run(Program::new(instructions.clone()))?;
+    let memory = scheduler.get_coro(1).memory_dump();
+    check_symbol_eq(memory.clone(), 16, 1i64);
+    check_symbol_eq(memory.clone(), 24, 7usize);
+    check_symbol_eq(memory, 56, 42i64);
+
+    assert!(execute(instructions).is_err());
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn standard_library() -> Result<(), Box<dyn std::error::Error>> {
+    let main = Block::new("main", vec![
+        Instruction::MemExtend(200),
+        Instruction::WriteStringToSymbol(0, String::from("-1234")),
+        Instruction::CreateCoroutine(0, 0, 6, 100),
+        Instruction::Await(100, 108),
+        Instruction::WriteBytesToSymbol(16, [4i64, 3, 9, -2, 7].iter().flat_map(|x| x.to_le_bytes()).collect()),
+        Instruction::CreateCoroutine(0, 16, 40, 100),
+        Instruction::Await(100, 116),
+        Instruction::CreateCoroutine(0, 16, 40, 100),
+        Instruction::Await(100, 124),
+        Instruction::CreateCoroutine(0, 0, 6, 100),
+        Instruction::Await(100, 132),
+        Instruction::Return(108, 8)
+    ]).with_reference(2, "std::parse_int").with_reference(5, "std::list_sum").with_reference(7, "std::list_max").with_reference(9, "std::strlen");
+
+    let mut scheduler = Scheduler::new();
+    scheduler.run(link(&[Module::new(vec![main]), stdlib()], "main")?)?;
+    let memory = scheduler.get_coro(1).memory_dump();
+    check_symbol_eq(memory.clone(), 108, -1234i64);
+    check_symbol_eq(memory.clone(), 116, 17i64);
+    check_symbol_eq(memory.clone(), 124, 9i64);
+    check_symbol_eq(memory, 132, 5i64);
+
+    let mut cpu = CPU::with_program(0, Program::new(vec![Instruction::NoOp()]));
+    let routines = cpu.load_stdlib();
+    assert_eq!(routines["std::strlen"], 1);
+    assert!(cpu.validate(0).iter().all(|problem| problem.contains("unreachable")));
+    Ok(())
+}
+
+#[test]
+fn program_builder() -> Result<(), Box<dyn std::error::Error>> {
+    // Sum the numbers from 1 to 10 in a coroutine.
+    let instructions = ProgramBuilder::new()
+        .mem_extend(100)
+        .call("sum", 0, 0, 0)
+        .await_future(0, 8)
+        .ret(8, 8)
+        .label("sum")
+        .mem_extend(100)
+        .write_int(0, 10)
+        .write_int(8, 0)
+        .label("loop")
+        .add(8, 0, 8)
+        .subtract_immediate(0, 1, 0)
+        .instruction(Instruction::CompareGreaterImmediate(0, 0, 16))
+        .jump_if("loop", 16)
+        .ret(8, 8)
+        .build()?;
+    check_symbol_eq(execute(instructions)?, 8, 55i64);
+
+    assert!(ProgramBuilder::new().jump("nowhere").build().is_err());
+    assert!(ProgramBuilder::new().label("a").label("a").build().is_err());
+    Ok(())
+}
+
+#[test]
+fn embedded_assembly() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = crate::concorde_asm! {
+        MemExtend(100);
+        CreateCoroutine(@factorial, 0, 0, 0);
+        Await(0, 8);
+        Return(8, 8);
+
+        factorial:
+        MemExtend(100);
+        WriteIntToSymbol(0, 5);
+        WriteIntToSymbol(8, 1);
+        top:
+        MultiplySymbols(8, 0, 8);
+        SubtractImmediate(0, 1, 0);
+        CompareEqualImmediate(0, 0, 16);
+        JumpIfFalse(@top, 16);
+        Return(8, 8);
+    };
+    assert_eq!(instructions.len(), 12);
+    check_symbol_eq(execute(instructions)?, 8, 120i64);
+    Ok(())
+}
+
+#[test]
+fn superinstruction_fusion() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteIntToSymbol(0, 0),
+        Instruction::WriteIntToSymbol(8, 0),
+        Instruction::AddImmediate(0, 1, 0),          // 3: loop
+        Instruction::WriteIntToSymbol(16, 2),
+        Instruction::AddSymbols(8, 16, 8),
+        Instruction::CompareLesserImmediate(0, 10, 24),
+        Instruction::JumpIfTrue(3, 24),
+        Instruction::Return(8, 8)
+    ];
+    let mut program = Program::new(instructions.clone());
+    program.fuse_superinstructions();
+    let fused = program.fused.clone().unwrap();
+    assert_eq!(fused.iter().filter(|op| op.is_some()).count(), 2);
+
+    let mut scheduler = Scheduler::new();
+    scheduler.run(program)?;
+    assert_eq!(scheduler.get_coro(1).memory().dump(), execute(instructions)?.dump());
+    check_symbol_eq(scheduler.get_coro(1).memory_dump(), 8, 20i64);
+    Ok(())
+}
+
+#[test]
+fn optimizer() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteIntToSymbol(0, 6),
+        Instruction::WriteIntToSymbol(8, 7),
+        Instruction::MultiplySymbols(0, 8, 16),
+        Instruction::WriteIntToSymbol(24, 1),
+        Instruction::WriteIntToSymbol(24, 2),
+        Instruction::CompareEqualImmediate(16, 42, 32),
+        Instruction::JumpIfTrue(9, 32),
+        Instruction::WriteIntToSymbol(40, 1),
+        Instruction::Jump(11),
+        Instruction::WriteIntToSymbol(40, 2),
+        Instruction::Return(16, 8)
+    ];
+    let mut program = Program::new(instructions.clone());
+    program.optimize();
+    assert!(matches!(program.instructions[3], Instruction::WriteIntToSymbol(16, 42)));
+    assert!(matches!(program.instructions[4], Instruction::NoOp()));
+    assert!(matches!(program.instructions[6], Instruction::WriteBoolToSymbol(32, true)));
+    assert!(matches!(program.instructions[7], Instruction::Jump(11)));
+
+    let mut scheduler = Scheduler::new();
+    scheduler.set_optimize(false);
+    scheduler.run(Program::new(instructions.clone()))?;
+    assert_eq!(scheduler.get_coro(1).memory().dump(), execute(instructions)?.dump());
+    check_symbol_eq(scheduler.get_coro(1).memory_dump(), 40, 0i64);
+
+    // A write that might fault doesn't make the store before it dead.
+    let mut program = Program::new(vec![
+        Instruction::MemExtend(24),
+        Instruction::WriteIntToSymbol(0, 5),
+        Instruction::DivideSymbols(8, 16, 0),
+    ]);
+    program.optimize();
+    assert!(matches!(program.instructions[1], Instruction::WriteIntToSymbol(0, 5)));
+    Ok(())
+}
+
+#[test]
+fn dispatch_table() -> Result<(), Box<dyn std::error::Error>> {
+    let mut scheduler = Scheduler::new();
+    scheduler.register_handler(opcode(&Instruction::NoOp()), |memory, _, _, _| {
+        memory.write(0, &7i64);
+        Ok(Interrupt::Ok)
+    })?;
+    assert!(scheduler.register_handler(200, |_, _, _, _| Ok(Interrupt::Ok)).is_err());
+    scheduler.run(Program::new(vec![
+        Instruction::MemExtend(8),
+        Instruction::NoOp(),
+        Instruction::Return(0, 8)
+    ]))?;
+    check_symbol_eq(scheduler.get_coro(1).memory_dump(), 0, 7i64);
+    Ok(())
+}
+
+#[test]
+fn type_verification() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteStringToSymbol(0, String::from("five")),
+        Instruction::WriteIntToSymbol(8, 5),
+        Instruction::CompareGreaterImmediate(8, 0, 16),
+        Instruction::JumpIfFalse(6, 16),
+        Instruction::WriteIntToSymbol(0, 1),          // only on one path
+        Instruction::AddSymbols(0, 8, 24),
+        Instruction::AddSymbols(16, 8, 24),
+        Instruction::Return(24, 8)
+    ];
+    let cpu = CPU::with_program(0, Program::new(instructions.clone()));
+    assert_eq!(cpu.verify(0), vec![
+        String::from("Instruction 7 (AddSymbols(16, 8, 24)) reads symbol 16 as an integer, but it holds a bool"),
+    ]);
+
+    let mut scheduler = Scheduler::new();
+    scheduler.set_verify(true);
+    let error = scheduler.run(Program::new(instructions)).err().unwrap();
+    assert!(error.starts_with("Program failed verification"));
+    Ok(())
+}
+
+#[test]
+fn debug_info() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::CreateCoroutine(4, 0, 0, 0),
+        Instruction::Await(0, 8),
+        Instruction::Return(8, 8),
+
+        Instruction::MemExtend(100),
+        Instruction::DivideImmediate(0, 0, 8),
+        Instruction::Return(8, 8)
+    ];
+    let mut debug_info = DebugInfo::new();
+    debug_info.set_location(2, "main.src", 7, 1);
+    debug_info.set_name(2, "main");
+    debug_info.set_location(5, "maths.src", 3, 9);
+    debug_info.set_name(5, "divide");
+
+    let encoded = crate::encode_program_with_debug_info(&instructions, &debug_info);
+    let (decoded, decoded_info) = crate::decode_program_with_debug_info(&encoded)?;
+    assert_eq!(decoded_info.as_ref(), Some(&debug_info));
+    assert_eq!(crate::decode_program(&encoded)?.len(), instructions.len());
+
+    let program = Program { debug_info: decoded_info.map(Rc::new), ..Program::new(decoded) };
+    let mut scheduler = Scheduler::new();
+    let error = scheduler.run(program).err().unwrap();
+    assert!(error.contains("at instruction 5 (in divide at maths.src:3:9): DivideImmediate(0, 0, 8)"));
+    assert!(error.contains("awaited by coroutine 1 at instruction 2 (in main at main.src:7:1)"));
+    Ok(())
+}
+
+#[test]
+fn resumable_faults() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteIntToSymbol(0, 12),
+        Instruction::DivideSymbols(0, 8, 16),
+        Instruction::Return(16, 8)
+    ];
+    let mut cpu = CPU::with_program(0, Program::new(instructions.clone()));
+    assert!(cpu.run().is_err());
+    assert_eq!(cpu.fault().map(|fault| fault.pc), Some(2));
+    assert_eq!(cpu.program.pc, 2);
+
+    // Retry the instruction once the divisor is fixed.
+    cpu.memory_mut().write(8, &4i64);
+    assert!(cpu.run().is_ok());
+    assert!(cpu.fault().is_none());
+    check_symbol_eq(cpu.memory().clone(), 16, 3i64);
+
+    // Or skip past it.
+    let mut cpu = CPU::with_program(0, Program::new(instructions));
+    assert!(cpu.run().is_err());
+    cpu.skip_fault();
+    assert_eq!(cpu.program.pc, 3);
+    assert!(cpu.run().is_ok());
+    Ok(())
+}
+
+#[test]
+fn transactions() -> Result<(), Box<dyn std::error::Error>> {
+    let memory = execute(vec![
+        Instruction::MemExtend(16),
+        Instruction::WriteIntToSymbol(0, 1),
+        Instruction::BeginTransaction(),
+        Instruction::WriteIntToSymbol(0, 2),
+        Instruction::MemExtend(100),
+        Instruction::WriteIntToSymbol(16, 5),
+        Instruction::RollbackTransaction(),
+        Instruction::BeginTransaction(),
+        Instruction::BeginTransaction(),
+        Instruction::MemCpy(0, 8, 8),
+        Instruction::CommitTransaction(),
+        Instruction::WriteIntToSymbol(0, 3),
+        Instruction::CommitTransaction(),
+        Instruction::Return(0, 8)
+    ])?;
+    assert_eq!(memory.len(), 16);
+    check_symbol_eq(memory.clone(), 0, 3i64);
+    check_symbol_eq(memory, 8, 1i64);
+
+    assert!(execute(vec![Instruction::RollbackTransaction()]).is_err());
+    Ok(())
+}
+
+#[test]
+fn write_audit() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cpu = CPU::with_program(0, Program::new(vec![
+        Instruction::MemExtend(16),
+        Instruction::WriteIntToSymbol(0, 1),
+        Instruction::WriteIntToSymbol(0, 2),
+        Instruction::MemCpy(0, 8, 8),
+        Instruction::Return(8, 8)
+    ]));
+    cpu.memory_mut().enable_audit(2);
+    cpu.run()?;
+
+    // Only the most recent writes are kept.
+    let log = cpu.memory().audit_log();
+    assert_eq!(log.len(), 2);
+    assert_eq!((log[0].address, log[0].pc), (0, Some(2)));
+    assert_eq!(log[0].old, 1i64.to_ne_bytes());
+    assert_eq!(log[0].new, 2i64.to_ne_bytes());
+    assert_eq!((log[1].address, log[1].pc), (8, Some(3)));
+
+    let writes = cpu.memory().audit_writes_to(12, 1);
+    assert_eq!(writes.len(), 1);
+    assert_eq!(writes[0].new, 2i64.to_ne_bytes());
+
+    cpu.memory_mut().write(0, &7i64);
+    assert_eq!(cpu.memory().audit_log()[1].pc, None);
+    Ok(())
+}
+
+#[test]
+fn frozen_symbols() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cpu = CPU::with_program(0, Program::new(vec![
+        Instruction::MemExtend(24),
+        Instruction::WriteIntToSymbol(0, 1),
+        Instruction::MakeConst(0, 8),
+        Instruction::WriteIntToSymbol(8, 2),
+        Instruction::AddSymbols(0, 8, 4),
+        Instruction::Return(0, 8)
+    ]));
+    let Err(error) = cpu.run() else { panic!("Expected a write to a frozen symbol to fail") };
+    assert!(error.starts_with("Write protected"));
+    assert_eq!(cpu.fault().map(|fault| fault.pc), Some(4));
+
+    // The host can still write frozen symbols, and copies into them are refused too.
+    let memory = cpu.memory_mut();
+    assert!(memory.is_frozen(4, 8) && !memory.is_frozen(8, 8));
+    memory.write(0, &5i64);
+    assert!(memory.memcpy(8, 0, 8).is_err());
+    assert!(memory.store(16, &3i64).is_ok());
+    assert_eq!(memory.store(0, &3i64), Err(crate::WriteProtected { address: 0, n: 8 }));
+    check_symbol_eq(memory.clone(), 0, 5i64);
+
+    // Nor can the scheduler write future ids or awaited values into them.
+    let error = Scheduler::new().run(Program::new(vec![
+        Instruction::MemExtend(16),
+        Instruction::MakeConst(0, 8),
+        Instruction::CreateCoroutine(4, 0, 0, 0),
+        Instruction::Return(0, 8),
+        Instruction::Return(0, 8),
+    ])).err().unwrap();
+    assert!(error.starts_with("Write protected") && error.contains("in coroutine 1"), "{}", error);
+    let error = Scheduler::new().run(Program::new(vec![
+        Instruction::MemExtend(16),
+        Instruction::CreateCoroutine(5, 0, 0, 8),
+        Instruction::MakeConst(0, 8),
+        Instruction::Await(8, 0),
+        Instruction::Return(0, 8),
+        Instruction::MemExtend(8),
+        Instruction::WriteIntToSymbol(0, 3),
+        Instruction::Return(0, 8),
+    ])).err().unwrap();
+    assert!(error.starts_with("Write protected") && error.contains("in coroutine 1"), "{}", error);
+    Ok(())
+}
+
+#[test]
+fn symbol_namespaces() -> Result<(), Box<dyn std::error::Error>> {
+    let mut memory = Memory::new(32);
+    memory.write(0, &1i64);
+    memory.write(8, &2i64);
+    memory.write(16, &3i64);
+    memory.bind("main::counter", 0, 8);
+    memory.bind("main::loop::i", 8, 8);
+    memory.bind("main_two::x", 16, 8);
+    assert_eq!(memory.lookup("main::loop::i"), Some((8, 8)));
+
+    let names: Vec<&str> = memory.namespace("main").into_iter().map(|(name, _, _)| name).collect();
+    assert_eq!(names, vec!["main::counter", "main::loop::i"]);
+
+    assert_eq!(memory.drop_namespace("main"), 2);
+    assert_eq!(memory.lookup("main::counter"), None);
+    check_symbol_eq(memory.clone(), 8, 0i64);
+    check_symbol_eq(memory.clone(), 16, 3i64);
+    Ok(())
+}
+
+#[test]
+fn memory_limit() -> Result<(), Box<dyn std::error::Error>> {
+    let mut scheduler = Scheduler::new();
+    scheduler.set_memory_limit(64);
+    let result = scheduler.run(Program::new(vec![
+        Instruction::MemExtend(32),
+        Instruction::MemExtendTo(64),
+        Instruction::MemExtend(1),
+        Instruction::Return(0, 8)
+    ]));
+    let Err(error) = result else { panic!("Expected growing past the memory limit to fail") };
+    assert!(error.contains("Out of memory"));
+    assert_eq!(scheduler.get_coro(1).memory_dump().len(), 64);
+
+    let mut memory = Memory::new(8);
+    memory.set_limit(Some(16));
+    assert!(memory.extend_memory_to(16).is_ok());
+    assert_eq!(memory.extend_memory(1), Err(crate::OutOfMemory { requested: 17, limit: 16 }));
+    assert_eq!(memory.usage(), 16);
+    Ok(())
+}
+
+#[test]
+fn copy_on_write_memory() -> Result<(), Box<dyn std::error::Error>> {
+    let mut original = Memory::new(16);
+    original.write(0, &1i64);
+
+    // Clones share their bytes until one of them writes.
+    let mut clone = original.clone();
+    assert_eq!(clone.get_base_ptr(), original.get_base_ptr());
+    clone.write(8, &2i64);
+    assert_ne!(clone.get_base_ptr(), original.get_base_ptr());
+
+    check_symbol_eq(original.clone(), 8, 0i64);
+    check_symbol_eq(clone.clone(), 0, 1i64);
+    check_symbol_eq(clone, 8, 2i64);
+    Ok(())
+}
+
+#[test]
+fn bulk_bytes() -> Result<(), Box<dyn std::error::Error>> {
+    let memory = execute(vec![
+        Instruction::MemExtend(80),
+        Instruction::WriteIntToSymbol(0, 3),
+        Instruction::WriteIntToSymbol(8, 2),
+        Instruction::WriteStringToSymbol(16, "abc".to_string()),
+        Instruction::WriteStringToSymbol(24, "de".to_string()),
+        Instruction::ConcatBytes(16, 0, 24, 8, 32),
+        Instruction::WriteIntToSymbol(40, 5),
+        Instruction::FindBytes(32, 40, 24, 8, 48),
+        Instruction::WriteBytesToSymbol(56, vec![b'x']),
+        Instruction::FillBytes(57, 56, 8),
+        Instruction::WriteIntToSymbol(64, 1),
+        Instruction::CopyBytesRange(32, 64, 72, 64, 8),
+        Instruction::FindBytes(32, 40, 56, 8, 40),
+        Instruction::Return(0, 8)
+    ])?;
+    assert_eq!(memory.read(32, 5), b"abcde");
+    check_symbol_eq(memory.clone(), 48, 3i64);
+    assert_eq!(memory.read(56, 3), b"xxx");
+    assert_eq!(memory.read(72, 3), b"\0bc");
+    check_symbol_eq(memory, 40, -1i64);
+    Ok(())
+}
+
+#[test]
+fn integer_encoding() -> Result<(), Box<dyn std::error::Error>> {
+    let memory = execute(vec![
+        Instruction::MemExtend(64),
+        Instruction::WriteIntToSymbol(0, 0x0102),
+        Instruction::WriteIntToSymbol(8, 1),
+        Instruction::EncodeIntBE(16, 8, 2, 0),
+        Instruction::EncodeIntLE(16, 8, 4, 0),
+        Instruction::DecodeIntBE(16, 8, 2, 24),
+        Instruction::DecodeIntLE(16, 8, 4, 32),
+        Instruction::WriteIntToSymbol(0, -1),
+        Instruction::EncodeIntLE(40, 8, 8, 0),
+        Instruction::DecodeIntBE(40, 8, 8, 48),
+        Instruction::Return(0, 8)
+    ])?;
+    assert_eq!(memory.read(16, 6), [0, 2, 1, 0, 0, 0]);
+    check_symbol_eq(memory.clone(), 24, 0x0201i64);
+    check_symbol_eq(memory.clone(), 32, 0x0102i64);
+    check_symbol_eq(memory, 48, -1i64);
+
+    assert!(execute(vec![
+        Instruction::MemExtend(16),
+        Instruction::EncodeIntLE(0, 8, 3, 0),
+    ]).is_err());
+    Ok(())
+}
+
+#[test]
+fn struct_packing() -> Result<(), Box<dyn std::error::Error>> {
+    let memory = execute(vec![
+        Instruction::MemExtend(96),
+        Instruction::WriteStringToSymbol(0, ">hBx?I\0".to_string()),
+        Instruction::WriteIntToSymbol(16, -2),
+        Instruction::WriteIntToSymbol(24, 200),
+        Instruction::WriteIntToSymbol(32, 7),
+        Instruction::WriteIntToSymbol(40, 0x01020304),
+        Instruction::PackStruct(0, 16, 48),
+        Instruction::UnpackStruct(0, 48, 64),
+        Instruction::Return(0, 8)
+    ])?;
+    assert_eq!(memory.read(48, 9), [0xff, 0xfe, 200, 0, 1, 1, 2, 3, 4]);
+    check_symbol_eq(memory.clone(), 64, -2i64);
+    check_symbol_eq(memory.clone(), 72, 200i64);
+    check_symbol_eq(memory.clone(), 80, 1i64);
+    check_symbol_eq(memory, 88, 0x01020304i64);
+
+    // Values that don't fit their fields are rejected.
+    assert!(execute(vec![
+        Instruction::MemExtend(32),
+        Instruction::WriteStringToSymbol(0, "<2b\0".to_string()),
+        Instruction::WriteIntToSymbol(8, 1),
+        Instruction::WriteIntToSymbol(16, 128),
+        Instruction::PackStruct(0, 8, 24),
+    ]).is_err());
+    Ok(())
+}
+
+#[test]
+fn hashing() -> Result<(), Box<dyn std::error::Error>> {
+    let memory = execute(vec![
+        Instruction::MemExtend(16),
+        Instruction::WriteStringToSymbol(0, "abc".to_string()),
+        Instruction::WriteIntToSymbol(8, 3),
+        Instruction::Hash(0, 0, 8, 16),
+        Instruction::Hash(1, 0, 8, 20),
+        Instruction::Hash(2, 0, 8, 52),
+        Instruction::Return(0, 8)
+    ])?;
+    let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    assert_eq!(hex(&memory.read(16, 4)), "352441c2");
+    assert_eq!(hex(&memory.read(20, 32)), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    assert_eq!(hex(&memory.read(52, 32)), "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85");
+
+    // Inputs spanning several blocks and BLAKE3 chunks.
+    let data = |n: usize| (0..n).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    assert_eq!(hex(&HashAlgorithm::Crc32.digest(b"123456789")), "cbf43926");
+    assert_eq!(hex(&HashAlgorithm::Sha256.digest(&data(1000))), "4e4c294b331f7a2099a379bec34b9f9fc03dc46ab465d998f4d683da53487e6d");
+    assert_eq!(hex(&HashAlgorithm::Blake3.digest(&[])), "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262");
+    assert_eq!(hex(&HashAlgorithm::Blake3.digest(&data(1024))), "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7");
+    assert_eq!(hex(&HashAlgorithm::Blake3.digest(&data(3073))), "7124b49501012f81cc7f11ca069ec9226cecb8a2c850cfe644e327d22d3e1cd3");
+    assert_eq!(hex(&HashAlgorithm::Blake3.digest(&data(8193))), "bab6c09cb8ce8cf459261398d2e7aef35700bf488116ceb94a36d0f5f1b7bc3b");
+    Ok(())
+}
+
+#[cfg(feature = "aead")]
+#[test]
+fn authenticated_encryption() -> Result<(), Box<dyn std::error::Error>> {
+    let key: Vec<u8> = (0..32).collect();
+    let nonce: Vec<u8> = (100..112).collect();
+    let setup = vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteBytesToSymbol(0, key.clone()),
+        Instruction::WriteBytesToSymbol(32, nonce.clone()),
+        Instruction::WriteBytesToSymbol(44, "attack at dawn".as_bytes().to_vec()),
+        Instruction::WriteIntToSymbol(64, 14),
+        Instruction::WriteIntToSymbol(72, 30),
+        Instruction::WriteIntToSymbol(80, 16),
+        Instruction::AeadEncrypt(0, 32, 44, 64, 100),
+    ];
+    let memory = execute([setup.clone(), vec![
+        Instruction::AeadDecrypt(0, 32, 100, 72, 130),
+        Instruction::RandomBytes(80, 144),
+        Instruction::Return(0, 8),
+    ]].concat())?;
+    let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    assert_eq!(hex(&memory.read(100, 30)), "5565cfabfbce56338ffe711e85e78bbd1978d881377eec1a128409ee14bd");
+    assert_eq!(memory.read(130, 14), "attack at dawn".as_bytes());
+    assert_eq!(memory.read(144, 16).len(), 16);
+
+    // Changing the ciphertext, or decrypting with the wrong nonce, fails instead of returning garbage.
+    assert!(execute([setup.clone(), vec![
+        Instruction::WriteBytesToSymbol(101, vec![0]),
+        Instruction::AeadDecrypt(0, 32, 100, 72, 130),
+    ]].concat()).is_err());
+    assert!(execute([setup, vec![
+        Instruction::AeadDecrypt(0, 0, 100, 72, 130),
+    ]].concat()).is_err());
+
+    // Empty and multi-block messages.
+    let key: [u8; 32] = key.try_into().unwrap();
+    let nonce: [u8; 12] = nonce.try_into().unwrap();
+    assert_eq!(hex(&crate::crypto::seal(&key, &nonce, &[])?), "a33126986fcb1312c2476c125059ffe4");
+    let data: Vec<u8> = (0..200).collect();
+    let sealed = crate::crypto::seal(&key, &nonce, &data)?;
+    assert_eq!(hex(&sealed[..16]), "3410b9c99ca07055f3d71f74fe84d69c");
+    assert_eq!(hex(&sealed[200..]), "9c903e22e7ec13ed50f48b25c7914a7f");
+    assert_eq!(crate::crypto::open(&key, &nonce, &sealed)?, data);
+    Ok(())
+}
+
+#[cfg(not(feature = "aead"))]
+#[test]
+fn authenticated_encryption_needs_feature() {
+    let error = execute(vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteIntToSymbol(80, 0),
+        Instruction::AeadEncrypt(0, 32, 44, 80, 100),
+    ]).err().unwrap();
+    assert!(error.starts_with("AeadEncrypt needs ConcordeVM to be built with the aead feature"), "{}", error);
+}
+
+#[test]
+fn seeded_random_numbers() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = Rc::new(vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteIntToSymbol(0, -3),
+        Instruction::WriteIntToSymbol(8, 3),
+        Instruction::WriteIntToSymbol(16, 32),
+        Instruction::RandomInt(0, 8, 24),
+        Instruction::RandomInt(0, 0, 32),
+        Instruction::RandomBytes(16, 40),
+        Instruction::Return(0, 8),
+    ]);
+    let run = |seed: Option<u64>| -> Result<Memory, String> {
+        let mut scheduler = Scheduler::new();
+        if let Some(seed) = seed {
+            scheduler.seed_rng(seed);
+        }
+        scheduler.run(Program { instructions: Rc::clone(&instructions), ..Program::default() })?;
+        return Ok(scheduler.get_coro(1).memory_dump());
+    };
+
+    let memory = run(Some(42))?;
+    assert!((-3..=3).contains(&memory.read_typed::<i64>(24)));
+    assert_eq!(memory.read_typed::<i64>(32), -3);
+    assert_eq!(memory.read(40, 32), run(Some(42))?.read(40, 32));
+    assert_ne!(memory.read(40, 32), run(Some(43))?.read(40, 32));
+    assert_ne!(run(None)?.read(40, 32), run(None)?.read(40, 32));
+
+    // Every value in a small range turns up, and nothing outside it.
+    let mut cpu = CPU::new(24);
+    cpu.seed_rng(7, 0);
+    cpu.memory_mut().write(0, &-3i64);
+    cpu.memory_mut().write(8, &3i64);
+    cpu.load_program(Program::new(vec![Instruction::RandomInt(0, 8, 16)]));
+    let mut seen = [false; 7];
+    for _ in 0..200 {
+        cpu.resume_at(0);
+        cpu.cycle()?;
+        seen[(cpu.memory().read_typed::<i64>(16) + 3) as usize] = true;
+    }
+    assert!(seen.iter().all(|seen| *seen));
+
+    assert!(execute(vec![
+        Instruction::MemExtend(24),
+        Instruction::WriteIntToSymbol(0, 1),
+        Instruction::RandomInt(0, 8, 16),
+    ]).is_err());
+    Ok(())
+}
+
+#[test]
+fn clocks() -> Result<(), Box<dyn std::error::Error>> {
+    let memory = execute(vec![
+        Instruction::MemExtend(24),
+        Instruction::NowUnixMillis(0),
+        Instruction::MonotonicNanos(8),
+        Instruction::MonotonicNanos(16),
+        Instruction::Return(0, 8),
+    ])?;
+    // After 2020, and monotonic time doesn't go backwards.
+    assert!(memory.read_typed::<i64>(0) > 1_577_836_800_000);
+    assert!(memory.read_typed::<i64>(16) >= memory.read_typed::<i64>(8));
+
+    let clock = Rc::new(VirtualClock::new(1_700_000_000_000));
+    let mut cpu = CPU::new(16);
+    cpu.set_clock(clock.clone());
+    cpu.load_program(Program::new(vec![Instruction::NowUnixMillis(0), Instruction::MonotonicNanos(8)]));
+    cpu.cycle()?;
+    cpu.cycle()?;
+    assert_eq!(cpu.memory().read_typed::<i64>(0), 1_700_000_000_000);
+    assert_eq!(cpu.memory().read_typed::<i64>(8), 0);
+
+    clock.advance(2_500_000_000);
+    cpu.resume_at(0);
+    cpu.cycle()?;
+    cpu.cycle()?;
+    assert_eq!(cpu.memory().read_typed::<i64>(0), 1_700_000_002_500);
+    assert_eq!(cpu.memory().read_typed::<i64>(8), 2_500_000_000);
+    Ok(())
+}
+
+#[test]
+fn timestamps() -> Result<(), Box<dyn std::error::Error>> {
+    let memory = execute(vec![
+        Instruction::MemExtend(200),
+        Instruction::WriteIntToSymbol(0, 1_700_000_000_123),
+        Instruction::WriteStringToSymbol(8, "%FT%T.%f".to_string()),
+        Instruction::FormatTimestamp(0, 8, 100),
+        Instruction::ParseTimestamp(100, 8, 16),
+        Instruction::Return(0, 8),
+    ])?;
+    assert_eq!(memory.read_string(100), "2023-11-14T22:13:20.123");
+    check_symbol_eq(memory, 16, 1_700_000_000_123i64);
+
+    let format = |millis, format| crate::timestamps::format(millis, format);
+    assert_eq!(format(0, "%a %d %b %Y %H:%M:%S %j")?, "Thu 01 Jan 1970 00:00:00 001");
+    assert_eq!(format(-1, "%F %T.%f %a %j %s")?, "1969-12-31 23:59:59.999 Wed 365 -1");
+    assert_eq!(format(951_782_400_000, "%F %a %j 100%%")?, "2000-02-29 Tue 060 100%");
+    assert_eq!(format(253_402_300_799_999, "%F %T.%f %a")?, "9999-12-31 23:59:59.999 Fri");
+    assert_eq!(format(-62_135_596_800_000, "%F %a")?, "0001-01-01 Mon");
+    assert!(format(0, "%Q").is_err());
+
+    let parse = |text, format| crate::timestamps::parse(text, format);
+    assert_eq!(parse("29 feb 2000", "%d %b %Y")?, 951_782_400_000);
+    assert_eq!(parse("2000-060", "%Y-%j")?, 951_782_400_000);
+    assert_eq!(parse("12:30", "%H:%M")?, 45_000_000);
+    assert_eq!(parse("-1.999", "%s.%f")?, -1);
+    assert_eq!(parse("Tue 14 Nov 2023 22:13:20", "%a %d %b %Y %T")?, 1_700_000_000_000);
+    assert!(parse("2023-02-29", "%F").is_err());
+    assert!(parse("2023-1-05", "%F").is_err());
+    assert!(parse("2023-01-05 extra", "%F").is_err());
+    assert!(parse("24:00", "%H:%M").is_err());
+    Ok(())
+}
+
+#[test]
+fn arithmetic_modes() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = Rc::new(vec![
+        Instruction::MemExtend(64),
+        Instruction::WriteIntToSymbol(0, i64::MAX),
+        Instruction::WriteIntToSymbol(8, 2),
+        Instruction::WriteIntToSymbol(16, i64::MIN),
+        Instruction::WriteIntToSymbol(24, -1),
+        Instruction::AddSymbols(0, 8, 32),
+        Instruction::DivideSymbols(16, 24, 40),
+        Instruction::FmaSymbols(0, 8, 16, 48),
+        Instruction::SubtractImmediate(16, 1, 56),
+        Instruction::Return(0, 8),
+    ]);
+    let run = |mode: Option<ArithmeticMode>| -> Result<Memory, String> {
+        let mut scheduler = Scheduler::new();
+        scheduler.set_optimize(false);
+        if let Some(mode) = mode {
+            scheduler.set_arithmetic_mode(mode);
+        }
+        scheduler.run(Program { instructions: Rc::clone(&instructions), ..Program::default() })?;
+        return Ok(scheduler.get_coro(1).memory_dump());
+    };
+
+    // Wrapping by default. i64::MAX * 2 + i64::MIN fits, even though i64::MAX * 2 doesn't.
+    let memory = run(None)?;
+    assert_eq!(memory.read_typed::<i64>(32), i64::MIN + 1);
+    assert_eq!(memory.read_typed::<i64>(40), i64::MIN);
+    assert_eq!(memory.read_typed::<i64>(48), i64::MAX - 1);
+    assert_eq!(memory.read_typed::<i64>(56), i64::MAX);
+
+    let memory = run(Some(ArithmeticMode::Saturating))?;
+    assert_eq!(memory.read_typed::<i64>(32), i64::MAX);
+    assert_eq!(memory.read_typed::<i64>(40), i64::MAX);
+    assert_eq!(memory.read_typed::<i64>(48), i64::MAX - 1);
+    assert_eq!(memory.read_typed::<i64>(56), i64::MIN);
+
+    let Err(error) = run(Some(ArithmeticMode::Trap)) else { panic!("overflow should trap") };
+    assert!(error.contains("Integer overflow: 9223372036854775807 + 2"), "{}", error);
+
+    // The explicit variants ignore the mode.
+    let mut cpu = CPU::new(40);
+    cpu.program.arithmetic = ArithmeticMode::Saturating;
+    cpu.memory_mut().write(0, &i64::MAX);
+    cpu.memory_mut().write(8, &2i64);
+    cpu.program.instructions = Rc::new(vec![
+        Instruction::WrappingAdd(0, 8, 16),
+        Instruction::CheckedMultiply(8, 8, 24),
+        Instruction::ModuloSymbols(0, 8, 32),
+        Instruction::CheckedAdd(0, 8, 32),
+    ]);
+    for _ in 0..3 {
+        cpu.cycle()?;
+    }
+    assert_eq!(cpu.memory().read_typed::<i64>(16), i64::MIN + 1);
+    assert_eq!(cpu.memory().read_typed::<i64>(24), 4);
+    assert_eq!(cpu.memory().read_typed::<i64>(32), 1);
+    assert!(cpu.cycle().is_err());
+    Ok(())
+}
+
+#[test]
+fn big_integers() -> Result<(), Box<dyn std::error::Error>> {
+    let memory = execute(vec![
+        Instruction::MemExtend(400),
+        Instruction::WriteStringToSymbol(0, "815915283247897734345611269596115894272000000000".to_string()),
+        Instruction::WriteIntToSymbol(56, i64::MIN),
+        Instruction::BigFromString(0, 100),
+        Instruction::BigFromInt(56, 150),
+        Instruction::BigMultiply(100, 150, 200),
+        Instruction::BigDivide(200, 100, 300),
+        Instruction::BigToInt(300, 64),
+        Instruction::BigCompareLesser(200, 150, 72),
+        Instruction::BigToString(200, 320),
+        Instruction::Return(0, 8),
+    ])?;
+    assert_eq!(memory.read_string(320), "-7525490207951103864248838393495911577666423174979991371776000000000");
+    assert_eq!(memory.read_typed::<i64>(64), i64::MIN);
+    check_symbol_eq(memory, 72, true);
+
+    let big = |text: &str| BigInt::parse(text).unwrap();
+    let a = big("815915283247897734345611269596115894272000000000");
+    let b = big("-2954312706550833698643");
+    assert_eq!(a.add(&b).to_string(), "815915283247897734345611266641803187721166301357");
+    assert_eq!(a.subtract(&b).to_string(), "815915283247897734345611272550428600822833698643");
+    assert_eq!(a.multiply(&b).to_string(), "-2410468888768286857616642455968409583582653750860725297872896000000000");
+    let (quotient, remainder) = a.divide(&b).unwrap();
+    assert_eq!((quotient.to_string(), remainder.to_string()), ("-276177698264203231344742340".to_string(), "1308887240517957355380".to_string()));
+    assert!(a.divide(&big("0")).is_none());
+
+    // Multi-limb divisors, and powers.
+    let x = big("6864797660130609714981900799081393217269435300143305409394463459185543183397656052122559640661454554977296311391480858037121987999716643812574028291115057151");
+    let product = x.multiply(&big("265613988875874769338781322035779626829233452653394495974574961739092490901302182994384699044008"));
+    let (quotient, remainder) = product.divide(&big("2037035976334486086268445688409378161051468393665936250636140449354381299763336706183397387")).unwrap();
+    assert_eq!(quotient.to_string(), "895117371767840887636177487698379370330563533297658216045302941346929950430844134923325170329134693601557597187532573179952992123290594090380614403658212478180414");
+    assert_eq!(remainder.to_string(), "1315176812555722814528235739334887346118149134707548170465285264761927092292434957541922990");
+    let modulus = big("170141183460469231731687303715884105727");
+    assert_eq!(big("12345678901234567890").pow_mod(&big("65537"), &modulus)?.to_string(), "127352203508635771842305703701533661349");
+    assert_eq!(big("-5").pow_mod(&big("3"), &big("7"))?.to_string(), "1");
+    assert!(big("2").pow_mod(&big("-1"), &big("7")).is_err());
+
+    // Shortest two's complement bytes.
+    for (text, bytes) in [("0", vec![0]), ("127", vec![0x7f]), ("128", vec![0x80, 0]), ("-128", vec![0x80]), ("-129", vec![0x7f, 0xff]), ("-1", vec![0xff])] {
+        assert_eq!(big(text).to_bytes(), bytes);
+        assert_eq!(BigInt::from_bytes(&bytes), big(text));
+    }
+    assert_eq!(big("18446744073709551616").to_i64(), None);
+    assert!(BigInt::parse("12a").is_err());
+    Ok(())
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_streams() -> Result<(), Box<dyn std::error::Error>> {
+    for (prefix, file) in [("gzip:", "concordevm_compressed.gz"), ("zstd:", "concordevm_compressed.zst")] {
+        let path = std::env::temp_dir().join(file);
+        let path = path.to_str().unwrap().to_string();
+        let memory = execute(vec![
+            Instruction::MemExtend(1000),
+            Instruction::WriteStringToSymbol(0, format!("{}{}", prefix, path)),
+            Instruction::WriteBytesToSymbol(200, "hello world\n".as_bytes().to_vec()),
+            Instruction::WriteIntToSymbol(300, 12),
+            Instruction::OpenStream(0, 1, 4),   // truncate
+            Instruction::WriteStream(1, 300, 200),
+            Instruction::CloseStream(1),
+
+            Instruction::OpenStream(0, 1, 0),   // read
+            Instruction::ReadLine(1, 400),
+            Instruction::CloseStream(1),
+            Instruction::Return(400, 8)
+        ])?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(memory.read_string(400), "hello world\n");
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "compression"))]
+#[test]
+fn compressed_streams_need_feature() {
+    let path = std::env::temp_dir().join("concordevm_compressed.gz");
+    assert!(execute(vec![
+        Instruction::MemExtend(1000),
+        Instruction::WriteStringToSymbol(0, format!("gzip:{}", path.to_str().unwrap())),
+        Instruction::OpenStream(0, 1, 4),   // truncate
+    ]).is_err());
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn float_math() -> Result<(), Box<dyn std::error::Error>> {
+    let float = |address: usize, value: f32| Instruction::WriteBytesToSymbol(address, value.to_le_bytes().to_vec());
+    let mut scheduler = Scheduler::new();
+    scheduler.set_optimize(false);
+    scheduler.run(Program {
+        instructions: Rc::new(vec![
+            Instruction::MemExtend(64),
+            float(0, 2.0),
+            float(4, -3.5),
+            float(8, 1.0),
+            Instruction::SqrtSymbol(0, 12),
+            Instruction::PowSymbols(0, 4, 16),
+            Instruction::AbsSymbol(4, 20),
+            Instruction::FloorSymbol(4, 24),
+            Instruction::CeilSymbol(4, 28),
+            Instruction::FloatMinSymbols(0, 4, 32),
+            Instruction::FloatMaxSymbols(0, 4, 36),
+            Instruction::ExpSymbol(8, 40),
+            Instruction::LnSymbol(0, 44),
+            Instruction::Arctan2Symbols(8, 8, 48),
+            Instruction::SqrtSymbol(4, 52),
+            Instruction::Return(0, 8),
+        ]),
+        ..Program::default()
+    })?;
+    let memory = scheduler.get_coro(1).memory_dump();
+    assert_eq!(memory.read_typed::<f32>(12), 2.0f32.sqrt());
+    assert_eq!(memory.read_typed::<f32>(16), 2.0f32.powf(-3.5));
+    assert_eq!(memory.read_typed::<f32>(20), 3.5);
+    assert_eq!(memory.read_typed::<f32>(24), -4.0);
+    assert_eq!(memory.read_typed::<f32>(28), -3.0);
+    assert_eq!(memory.read_typed::<f32>(32), -3.5);
+    assert_eq!(memory.read_typed::<f32>(36), 2.0);
+    assert_eq!(memory.read_typed::<f32>(40), std::f32::consts::E);
+    assert_eq!(memory.read_typed::<f32>(44), std::f32::consts::LN_2);
+    assert_eq!(memory.read_typed::<f32>(48), std::f32::consts::FRAC_PI_4);
+    // Like the host's floats, math outside a function's domain gives NaN rather than an error.
+    assert!(memory.read_typed::<f32>(52).is_nan());
+    return Ok(());
+}
+
+#[test]
+fn typed_comparisons() -> Result<(), Box<dyn std::error::Error>> {
+    let float = |address: usize, value: f32| Instruction::WriteBytesToSymbol(address, value.to_le_bytes().to_vec());
+    let instructions = vec![
+        Instruction::MemExtend(128),
+        Instruction::WriteStringToSymbol(0, String::from("apple")),
+        Instruction::WriteStringToSymbol(8, String::from("apples")),
+        Instruction::WriteBoolToSymbol(16, true),
+        Instruction::WriteBoolToSymbol(17, true),
+        float(20, 1.5),
+        float(24, f32::NAN),
+        Instruction::WriteBytesToSymbol(28, vec![1, 2]),
+        Instruction::WriteBytesToSymbol(30, vec![1, 3, 0]),
+        Instruction::WriteIntToSymbol(40, 2),
+        Instruction::WriteIntToSymbol(48, 3),
+        Instruction::CompareStringsLesser(0, 8, 64),
+        Instruction::CompareStringsEqual(0, 0, 65),
+        Instruction::CompareStringsGreater(0, 8, 66),
+        Instruction::CompareBoolsEqual(16, 17, 67),
+        Instruction::CompareFloatsGreater(20, 20, 68),
+        Instruction::CompareFloatsEqual(24, 24, 69),
+        Instruction::CompareBytesLesser(28, 40, 30, 48, 70),
+        Instruction::CompareBytesEqual(30, 40, 28, 40, 71),
+        Instruction::Return(0, 8),
+    ];
+    let mut scheduler = Scheduler::new();
+    scheduler.set_optimize(false);
+    scheduler.run(Program::new(instructions))?;
+    let memory = scheduler.get_coro(1).memory_dump();
+    let results = (64..72).map(|address| memory.read_typed::<bool>(address)).collect::<Vec<bool>>();
+    // NaN equals nothing, not even itself, and [1, 3] differs from [1, 2].
+    assert_eq!(results, vec![true, true, false, true, false, false, true, false]);
+
+    let cpu = CPU::with_program(0, Program::new(vec![
+        Instruction::MemExtend(64),
+        Instruction::WriteStringToSymbol(0, String::from("five")),
+        Instruction::WriteIntToSymbol(8, 5),
+        Instruction::CompareStringsEqual(0, 8, 16),
+        Instruction::CompareFloatsLesser(16, 16, 17),
+        Instruction::Return(0, 8),
+    ]));
+    assert_eq!(cpu.verify(0), vec![
+        String::from("Instruction 3 (CompareStringsEqual(0, 8, 16)) reads symbol 8 as a string, but it holds an integer"),
+        String::from("Instruction 4 (CompareFloatsLesser(16, 16, 17)) reads symbol 16 as a float, but it holds a bool"),
+    ]);
+    return Ok(());
+}
+
+#[test]
+fn for_each_loops() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = crate::concorde_asm! {
+        MemExtend(128);
+        WriteIntToSymbol(0, 3);
+        WriteIntToSymbol(8, 1);
+        WriteIntToSymbol(16, 2);
+        WriteIntToSymbol(24, 3);
+        WriteIntToSymbol(32, 2);
+        WriteIntToSymbol(40, 10);
+        WriteIntToSymbol(48, 20);
+        WriteIntToSymbol(64, 0);
+        WriteIntToSymbol(96, 0);
+        ForEach(0, 72, @outer);
+        ForEach(96, 72, @outer);    // empty, so the body never runs
+        Return(64, 8);
+
+        outer:
+        ForEach(32, 80, @inner);
+        Return(0, 0);
+
+        inner:
+        MultiplySymbols(72, 80, 88);
+        AddSymbols(64, 88, 64);
+        Return(0, 0);
+    };
+    check_symbol_eq(execute(instructions.clone())?, 64, 180i64);
+
+    // Loops in progress survive a snapshot.
+    let mut cpu = CPU::with_program(0, Program::new(instructions));
+    for _ in 0..20 {
+        cpu.cycle()?;
+    }
+    let snapshot = CpuSnapshot::from_bytes(&cpu.snapshot().to_bytes())?;
+    let mut restored = CPU::default();
+    restored.restore(&snapshot);
+    restored.run()?;
+    check_symbol_eq(restored.memory().clone(), 64, 180i64);
+
+    // Items past the end of memory are an error, not a read of whatever follows.
+    assert!(execute(vec![
+        Instruction::MemExtend(16),
+        Instruction::WriteIntToSymbol(0, 5),
+        Instruction::ForEach(0, 8, 3),
+        Instruction::Return(0, 0),
+    ]).is_err());
+    Ok(())
+}
+
+#[test]
+fn sort_and_search_lists() -> Result<(), Box<dyn std::error::Error>> {
+    let floats: Vec<u8> = [2.5f32, f32::NAN, -1.0].iter().flat_map(|item| item.to_ne_bytes()).collect();
+    let instructions = vec![
+        Instruction::MemExtend(256),
+        Instruction::WriteIntToSymbol(0, 5),
+        Instruction::WriteIntToSymbol(8, 5),
+        Instruction::WriteIntToSymbol(16, 3),
+        Instruction::WriteIntToSymbol(24, 9),
+        Instruction::WriteIntToSymbol(32, 3),
+        Instruction::WriteIntToSymbol(40, -1),
+        Instruction::WriteIntToSymbol(64, 3),
+        Instruction::WriteBytesToSymbol(72, floats),
+        Instruction::WriteIntToSymbol(96, 3),
+        Instruction::WriteBytesToSymbol(104, b"pear\0apple\0fig\0".to_vec()),
+        Instruction::WriteIntToSymbol(128, 3),
+        Instruction::WriteStringToSymbol(136, String::from("pear")),
+        Instruction::WriteIntToSymbol(184, 4),
+        Instruction::SortList(0, ListKind::Int as usize, true),
+        Instruction::SortList(64, ListKind::Float as usize, true),
+        Instruction::SortList(96, ListKind::String as usize, true),
+        Instruction::BinarySearch(0, ListKind::Int as usize, 128, 160),
+        Instruction::BinarySearch(96, ListKind::String as usize, 136, 168),
+        Instruction::BinarySearch(0, ListKind::Int as usize, 184, 176),
+        Instruction::SortList(0, ListKind::Int as usize, false),
+        Instruction::Return(0, 8),
+    ];
+    let memory = execute(instructions)?;
+    let ints = (1..6).map(|i| memory.read_typed::<i64>(i * 8)).collect::<Vec<i64>>();
+    assert_eq!(ints, vec![9, 5, 3, 3, -1]);
+    assert_eq!(memory.read_typed::<f32>(72), -1.0);
+    assert_eq!(memory.read_typed::<f32>(76), 2.5);
+    assert!(memory.read_typed::<f32>(80).is_nan());
+    assert_eq!(memory.read(104, 15), b"apple\0fig\0pear\0".to_vec());
+    // The first of the two 3s, the last string, and no 4.
+    assert_eq!(memory.read_typed::<i64>(160), 1);
+    assert_eq!(memory.read_typed::<i64>(168), 2);
+    assert_eq!(memory.read_typed::<i64>(176), -1);
+
+    let error = execute(vec![
+        Instruction::MemExtend(16),
+        Instruction::WriteIntToSymbol(0, 4),
+        Instruction::SortList(0, ListKind::Int as usize, true),
+    ]).err().unwrap();
+    assert!(error.starts_with("Tried to read 32 bytes at 8"));
+    assert!(execute(vec![Instruction::MemExtend(16), Instruction::SortList(0, 3, true)]).is_err());
+    Ok(())
+}
+
+#[test]
+fn list_higher_order_operations() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = crate::concorde_asm! {
+        MemExtend(256);
+        WriteIntToSymbol(0, 5);
+        WriteIntToSymbol(8, 3);
+        WriteIntToSymbol(16, 1);
+        WriteIntToSymbol(24, 4);
+        WriteIntToSymbol(32, 1);
+        WriteIntToSymbol(40, 5);
+        WriteIntToSymbol(192, 100);
+        MapList(0, 200, @square, 64);
+        FilterList(0, 200, @above_one, 128);
+        ReduceList(64, 200, @subtract, 192);
+        Return(192, 8);
+
+        square:
+        MultiplySymbols(200, 200, 208);
+        Return(208, 8);
+
+        above_one:
+        CompareGreaterImmediate(200, 1, 208);
+        Return(208, 1);
+
+        subtract:
+        SubtractSymbols(192, 200, 208);
+        Return(208, 8);
+    };
+    let list = |memory: &Memory, address: usize| {
+        (0..memory.read_typed::<i64>(address) as usize).map(|i| memory.read_typed::<i64>(address + 8 + i * 8)).collect::<Vec<i64>>()
+    };
+    let memory = execute(instructions.clone())?;
+    assert_eq!(list(&memory, 64), vec![9, 1, 16, 1, 25]);
+    assert_eq!(list(&memory, 128), vec![3, 4, 5]);
+    check_symbol_eq(memory, 192, 48i64);
+
+    // The accumulator and loop survive a snapshot partway through the reduction.
+    let mut cpu = CPU::with_program(0, Program::new(instructions.clone()));
+    for _ in 0..35 {
+        cpu.cycle()?;
+    }
+    assert!(matches!(cpu.get_stack().get_instruction(), Instruction::SubtractSymbols(..)));
+    let snapshot = CpuSnapshot::from_bytes(&cpu.snapshot().to_bytes())?;
+    let mut restored = CPU::default();
+    restored.restore(&snapshot);
+    restored.run()?;
+    check_symbol_eq(restored.memory().clone(), 192, 48i64);
+
+    // A block has to return a value of the right size.
+    let mut instructions = instructions;
+    instructions[15] = Instruction::Return(208, 8);
+    let error = execute(instructions).err().unwrap();
+    assert!(error.starts_with("A FilterList block must return 1 bytes, but returned 8"));
+    Ok(())
+}
+
+#[test]
+fn format_strings() -> Result<(), Box<dyn std::error::Error>> {
+    let mut scheduler = Scheduler::new();
+    let output = scheduler.capture_output();
+    scheduler.run(Program::new(vec![
+        Instruction::MemExtend(256),
+        Instruction::WriteIntToSymbol(0, 5),
+        Instruction::WriteIntToSymbol(8, 42),
+        Instruction::WriteIntToSymbol(16, -1),
+        Instruction::WriteIntToSymbol(24, 1),
+        Instruction::WriteIntToSymbol(32, 1.5f32.to_bits() as i64),
+        Instruction::WriteIntToSymbol(40, 64),
+        Instruction::WriteStringToSymbol(64, "hi".to_string()),
+        Instruction::WriteStringToSymbol(96, "{} {x} {b} {f} {{{s}}}".to_string()),
+        Instruction::FormatString(96, 0, 160),
+        Instruction::WriteStringToSymbol(200, "".to_string()),
+        Instruction::PrintSymbol(200),
+        Instruction::Return(0, 8),
+    ]))?;
+    assert_eq!(scheduler.get_coro(1).memory_dump().read_string(160), "42 ffffffffffffffff true 1.5 {hi}");
+    assert!(output.stdout().is_empty());
+
+    let format = |template, items: &[i64]| crate::formatting::format(template, items, |_| Ok("s".to_string()));
+    assert_eq!(format("{{}} {s}", &[0])?, "{} s");
+    assert!(format("{} {}", &[1]).is_err());
+    assert!(format("{}", &[1, 2]).is_err());
+    assert!(format("{q}", &[1]).is_err());
+    assert!(format("{", &[]).is_err());
+    assert!(format("}", &[]).is_err());
+    Ok(())
+}
+
+#[test]
+fn execution_log() -> Result<(), Box<dyn std::error::Error>> {
+    struct Shared(Rc<std::cell::RefCell<Vec<u8>>>);
+    impl std::io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buffer = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let mut cpu = CPU::with_program(0, Program::new(vec![
+        Instruction::MemExtend(16),
+        Instruction::WriteIntToSymbol(0, 7),
+        Instruction::DivideSymbols(0, 8, 0),
+    ]));
+    cpu.set_execution_log(Rc::new(std::cell::RefCell::new(ExecutionLog::new(Shared(Rc::clone(&buffer))))));
+    assert!(cpu.run().is_err());
+
+    let text = String::from_utf8(buffer.borrow().clone())?;
+    let lines = text.lines().map(serde_json::from_str).collect::<Result<Vec<serde_json::Value>, _>>()?;
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[1]["pc"], 1);
+    assert_eq!(lines[1]["opcode"], opcode(&Instruction::WriteIntToSymbol(0, 7)));
+    assert_eq!(lines[1]["instruction"], "WriteIntToSymbol");
+    assert_eq!(lines[1]["operands"], "0, 7");
+    assert!(lines[1]["duration_ns"].is_u64());
+    assert!(lines[1]["error"].is_null());
+    assert_eq!(lines[2]["instruction"], "DivideSymbols");
+    assert!(lines[2]["error"].as_str().is_some_and(|error| !error.is_empty()));
+    Ok(())
+}
+
+#[test]
+fn metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cpu = CPU::with_program(0, Program::new(vec![
+        Instruction::MemExtend(16),
+        Instruction::WriteIntToSymbol(0, 7),
+        Instruction::DivideSymbols(0, 8, 0),
+    ]));
+    cpu.memory_mut().bind("x", 0, 8);
+    assert!(cpu.run().is_err());
+    assert!(cpu.run().is_err());
+    let metrics = cpu.metrics();
+    assert_eq!((metrics.instructions, metrics.cycles, metrics.errors), (2, 4, 2));
+    assert_eq!((metrics.memory_entries, metrics.memory_bytes, metrics.open_streams), (1, 16, 0));
+    assert_eq!(metrics.coroutines, CoroutineCounts::default());
+
+    let mut scheduler = Scheduler::new();
+    scheduler.set_optimize(false);
+    scheduler.run(Program::new(vec![
+        Instruction::MemExtend(16),
+        Instruction::WriteIntToSymbol(0, 7),
+        Instruction::Return(0, 8),
+    ]))?;
+    let metrics = scheduler.metrics();
+    assert_eq!((metrics.instructions, metrics.cycles, metrics.errors), (3, 3, 0));
+    assert_eq!(metrics.memory_bytes, 16);
+    // The entrypoint coroutine is kept around after it returns, so its memory can be read.
+    assert_eq!(metrics.coroutines, CoroutineCounts { running: 1, ..CoroutineCounts::default() });
+    Ok(())
+}
+
+#[cfg(feature = "metrics-text")]
+#[test]
+fn prometheus_metrics() {
+    let metrics = crate::Metrics { instructions: 5, coroutines: CoroutineCounts { running: 1, ..CoroutineCounts::default() }, ..crate::Metrics::default() };
+    let text = metrics.to_prometheus();
+    assert!(text.contains("# TYPE concordevm_instructions_total counter\nconcordevm_instructions_total 5\n"));
+    assert!(text.contains("concordevm_coroutines{state=\"running\"} 1\n"));
+}
+
+#[test]
+fn lifecycle_events() -> Result<(), Box<dyn std::error::Error>> {
+    let events = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let mut cpu = CPU::new(0);
+    let seen = Rc::clone(&events);
+    cpu.subscribe(move |event| seen.borrow_mut().push(event.clone()));
+    cpu.load_program(Program::new(vec![
+        Instruction::MemExtend(64),
+        Instruction::WriteStringToSymbol(0, "stdout".to_string()),
+        Instruction::OpenStream(0, 1, 1),
+        Instruction::Jump(5),
+        Instruction::DivideSymbols(0, 8, 0),
+        Instruction::Jump(6),
+        Instruction::Return(16, 8),
+    ]));
+    cpu.run()?;
+    assert_eq!(*events.borrow(), vec![
+        Event::ProgramLoaded { instructions: 7, entrypoint: 0 },
+        Event::StreamOpened { symbol: 1, name: "stdout".to_string() },
+        Event::Jump { from: 3, to: 5 },
+        Event::Return { pc: 6, address: 16, n: 8 },
+    ]);
+
+    events.borrow_mut().clear();
+    cpu.resume_at(4);
+    assert!(cpu.run().is_err());
+    assert!(matches!(&events.borrow()[..], [Event::Halted { pc: 4, error: Some(_) }]));
+
+    let events = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let mut scheduler = Scheduler::new();
+    let seen = Rc::clone(&events);
+    scheduler.subscribe(move |event| seen.borrow_mut().push(event.clone()));
+    scheduler.run(Program::new(vec![Instruction::MemExtend(8), Instruction::Return(0, 8)]))?;
+    assert_eq!(*events.borrow(), vec![
+        Event::ProgramLoaded { instructions: 2, entrypoint: 0 },
+        Event::CoroutineSpawned { id: 1, pc: 0 },
+        Event::Return { pc: 1, address: 0, n: 8 },
+    ]);
+    Ok(())
+}
+
+#[test]
+fn core_dumps() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("concordevm_core_dumps.core");
+    let _ = std::fs::remove_file(&path);
+
+    let mut cpu = CPU::with_program(0, Program::new(vec![
+        Instruction::MemExtend(16),
+        Instruction::WriteIntToSymbol(0, 7),
+        Instruction::WriteIntToSymbol(8, 0),
+        Instruction::DivideSymbols(0, 8, 0),
+    ]));
+    cpu.enable_core_dumps(&path, 2);
+    let error = cpu.run().err().unwrap();
+
+    let dump = CoreDump::load(path.to_str().unwrap())?;
+    assert_eq!(dump.error, error);
+    assert_eq!(dump.trace, vec![2, 3]);
+    assert_eq!(dump.snapshot.program.pc, 3);
+    assert_eq!(Memory::from_dump(dump.snapshot.memory.clone()).read_typed::<i64>(0), 7);
+    assert!(CoreDump::from_bytes(&dump.snapshot.to_bytes()).is_err());
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn watchdog() {
+    let mut cpu = CPU::with_program(0, Program::new(vec![
+        Instruction::MemExtend(8),
+        Instruction::Jump(1),
+    ]));
+    cpu.set_watchdog(None, Some(100));
+    let error = cpu.run().err().unwrap();
+    assert!(error.starts_with("Timeout: the watchdog stopped the program after 100 instructions"));
+    assert!(matches!(cpu.timed_out(), Some(crate::Timeout { instructions: 100, .. })));
+    assert_eq!(cpu.metrics().instructions, 100);
+    assert!(cpu.fault().is_none());
+
+    // Each run gets a fresh allowance.
+    assert!(cpu.run().is_err());
+    assert_eq!(cpu.metrics().instructions, 200);
+
+    cpu.set_watchdog(Some(std::time::Duration::from_millis(10)), None);
+    assert!(cpu.run().err().unwrap().starts_with("Timeout"));
+    assert!(cpu.timed_out().is_some_and(|timeout| timeout.elapsed >= std::time::Duration::from_millis(10)));
+    cpu.load_program(Program::new(vec![Instruction::MemExtend(8)]));
+    cpu.run().unwrap();
+    assert_eq!(cpu.timed_out(), None);
+
+    let mut scheduler = Scheduler::new();
+    scheduler.set_watchdog(None, Some(1000));
+    assert!(scheduler.run(Program::new(vec![Instruction::MemExtend(8), Instruction::Jump(1)])).err().unwrap().starts_with("Timeout"));
+    assert!(matches!(scheduler.get_coro(1).timed_out(), Some(crate::Timeout { instructions: 1000, .. })));
+}
+
+#[test]
+fn shared_regions() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(64),             // 0    main
+        Instruction::CreateSharedRegion(7, 16),
+        Instruction::CreateCoroutine(8, 0, 0, 0),
+        Instruction::Await(0, 8),
+        // The child finished holding the lock, so it was released.
+        Instruction::Lock(7),
+        Instruction::ReadShared(7, 0, 16, 16),
+        Instruction::Unlock(7),
+        Instruction::Return(16, 8),
+
+        Instruction::MemExtend(64),             // 8    child
+        Instruction::CreateSharedRegion(7, 16),
+        Instruction::WriteIntToSymbol(0, 5),
+        Instruction::AtomicAdd(7, 0, 0, 8),
+        Instruction::AtomicAdd(7, 0, 0, 8),
+        Instruction::WriteIntToSymbol(16, 10),
+        Instruction::WriteIntToSymbol(24, 42),
+        Instruction::CompareAndSwap(7, 0, 16, 24, 32),
+        Instruction::CompareAndSwap(7, 0, 16, 24, 33),
+        Instruction::Lock(7),
+        Instruction::WriteShared(7, 8, 8, 8),
+        Instruction::Return(32, 8),
+    ];
+    let memory = execute(instructions)?;
+    check_symbol_eq(memory.clone(), 8, true);
+    check_symbol_eq(memory.clone(), 9, false);
+    check_symbol_eq(memory.clone(), 16, 42i64);
+    check_symbol_eq(memory, 24, 5i64);
+
+    // A coroutine that finds the lock held yields, and retries the same instruction.
+    let shared = crate::memory::SharedRegions::default();
+    let mut holder = CPU::with_program(0, Program::new(vec![
+        Instruction::MemExtend(16),
+        Instruction::CreateSharedRegion(1, 8),
+        Instruction::Lock(1),
+    ]));
+    holder.memory.set_shared(shared.for_owner(1));
+    holder.run()?;
+    let mut waiter = CPU::with_program(0, Program::new(vec![
+        Instruction::MemExtend(16),
+        Instruction::AtomicAdd(1, 0, 0, 8),
+        Instruction::Lock(1),
+        Instruction::Unlock(1),
+    ]));
+    waiter.memory.set_shared(shared.for_owner(2));
+    assert!(matches!(waiter.run()?, Interrupt::Yield));
+    assert_eq!(waiter.program.pc, 1);
+    shared.for_owner(1).unlock(1)?;
+    assert!(matches!(waiter.run()?, Interrupt::Ok));
+
+    assert!(execute(vec![Instruction::MemExtend(8), Instruction::Unlock(1)]).is_err());
+    assert!(execute(vec![Instruction::MemExtend(8), Instruction::ReadShared(1, 0, 8, 0)]).is_err());
+    assert!(execute(vec![Instruction::MemExtend(8), Instruction::CreateSharedRegion(1, 8), Instruction::ReadShared(1, 4, 8, 0)]).is_err());
+    assert!(execute(vec![Instruction::MemExtend(8), Instruction::CreateSharedRegion(1, 8), Instruction::CreateSharedRegion(1, 16)]).is_err());
+    assert!(execute(vec![Instruction::MemExtend(8), Instruction::CreateSharedRegion(1, 8), Instruction::Lock(1), Instruction::Lock(1)]).is_err());
+    Ok(())
+}
+
+#[test]
+fn actors() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(64),             // 0    main
+        Instruction::CreateSharedRegion(1, 8),
+        Instruction::SpawnActor(15, 8, 0),
+        Instruction::WriteIntToSymbol(8, 1),
+        Instruction::SendMessage(0, 8),
+        Instruction::WriteIntToSymbol(8, 2),
+        Instruction::SendMessage(0, 8),
+        Instruction::WriteIntToSymbol(8, 3),
+        Instruction::SendMessage(0, 8),
+        // Give way until every message has been handled.
+        Instruction::CreateCoroutine(18, 0, 0, 16),
+        Instruction::Await(16, 24),
+        Instruction::ReadShared(1, 0, 8, 32),
+        Instruction::CompareEqualImmediate(32, 6, 40),
+        Instruction::JumpIfFalse(9, 40),
+        Instruction::Return(32, 8),
+
+        Instruction::MemExtend(16),             // 15   handler
+        Instruction::AtomicAdd(1, 0, 0, 8),
+        Instruction::Return(0, 8),
+
+        Instruction::MemExtend(8),              // 18   give way
+        Instruction::Return(0, 8),
+    ];
+    let memory = execute(instructions)?;
+    check_symbol_eq(memory, 32, 6i64);
+
+    let mut scheduler = Scheduler::new();
+    let actor = scheduler.spawn_actor(Program::new(vec![]), 8);
+    assert!(scheduler.send_message(actor, vec![0; 4]).is_err());
+    assert!(scheduler.send_message(actor + 1, vec![0; 8]).is_err());
+    assert!(execute(vec![Instruction::MemExtend(16), Instruction::SendMessage(0, 8)]).is_err());
+    let error = execute(vec![Instruction::MemExtend(16), Instruction::MakeConst(0, 8), Instruction::SpawnActor(0, 8, 0)]).err().unwrap();
+    assert!(error.starts_with("Write protected") && error.contains("in coroutine 1"), "{}", error);
+    Ok(())
+}
+
+#[test]
+fn shared_cpu() -> Result<(), Box<dyn std::error::Error>> {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<SharedCpu>();
+    assert_send_sync::<crate::Timeout>();
+    assert_send_sync::<crate::OutOfMemory>();
+    assert_send_sync::<crate::StackOverflow>();
+
+    let cpu = SharedCpu::spawn(|| CPU::with_program(16, Program::new(vec![
+        Instruction::AddImmediate(8, 1, 8),
+    ])));
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            let cpu = cpu.clone();
+            scope.spawn(move || {
+                // Rewinding and running in one request keeps other threads from running between.
+                cpu.with(|cpu| {
+                    cpu.program.pc = 0;
+                    cpu.run().map(|_| ())
+                }).unwrap().unwrap();
+            });
+        }
+    });
+    // Each run was done whole before the next started, so none of the additions were lost.
+    assert_eq!(cpu.with(|cpu| cpu.memory().read_typed::<i64>(8))?, 4);
+    cpu.with(|cpu| cpu.program.pc = 0)?;
+    cpu.run()?;
+    assert_eq!(cpu.read(8, 8)?, 5i64.to_ne_bytes().to_vec());
+
+    cpu.write(0, 42i64.to_ne_bytes().to_vec())?;
+    assert_eq!(cpu.read(0, 8)?, 42i64.to_ne_bytes().to_vec());
+    assert!(cpu.read(12, 8).is_err());
+
+    assert!(cpu.with(|_| panic!("host bug")).is_err());
+    assert!(cpu.read(0, 8).is_err());
+    Ok(())
+}
+
+#[test]
+fn vm_pool() -> Result<(), Box<dyn std::error::Error>> {
+    let pool = VmPool::new(2, 10);
+    let counters: Vec<_> = (0..20i64).map(|n| pool.submit(move || CPU::with_program(16, Program::new(vec![
+        Instruction::WriteIntToSymbol(0, n),
+        Instruction::AddImmediate(0, 1, 0),
+        Instruction::CompareLesserImmediate(0, 100, 8),
+        Instruction::JumpIfTrue(1, 8),
+        Instruction::Return(0, 8),
+    ])), InstanceLimits::default())).collect();
+    for counter in counters {
+        assert_eq!(counter.wait()?, 100i64.to_ne_bytes().to_vec());
+    }
+
+    // A runaway instance shares its worker with the ones submitted after it.
+    let pool = VmPool::new(1, 10);
+    let limits = InstanceLimits { max_instructions: Some(2_000_000), ..InstanceLimits::default() };
+    let runaway = pool.submit(|| CPU::with_program(8, Program::new(vec![Instruction::Jump(0)])), limits);
+    let quick = pool.submit(|| CPU::with_program(8, Program::new(vec![Instruction::NoOp()])), InstanceLimits::default());
+    assert_eq!(quick.wait()?, Vec::<u8>::new());
+    assert!(runaway.try_result().is_none());
+    assert!(runaway.wait().err().unwrap().starts_with("Timeout: the watchdog stopped the program after 2000000 instructions"));
+    assert_eq!(pool.pending(), 0);
+
+    let limits = InstanceLimits { memory_limit: Some(100), ..InstanceLimits::default() };
+    let greedy = pool.submit(|| CPU::with_program(0, Program::new(vec![Instruction::MemExtend(1000)])), limits);
+    assert!(greedy.wait().err().unwrap().starts_with("Out of memory"));
+    let awaiting = pool.submit(|| CPU::with_program(8, Program::new(vec![Instruction::Await(0, 0)])), InstanceLimits::default());
+    assert!(awaiting.wait().is_err());
+    let overreaching = pool.submit(|| CPU::with_program(8, Program::new(vec![Instruction::Return(0, 100)])), InstanceLimits::default());
+    assert!(overreaching.wait().err().unwrap().starts_with("Tried to return 100 bytes at 0"));
+    assert_eq!(pool.pending(), 0);
+
+    // A panicking instance fails alone, and the worker keeps running the ones queued with it.
+    let looping = |n: i64| move || CPU::with_program(16, Program::new(vec![
+        Instruction::WriteIntToSymbol(0, 0),
+        Instruction::AddImmediate(0, 1, 0),
+        Instruction::CompareLesserImmediate(0, n, 8),
+        Instruction::JumpIfTrue(1, 8),
+        Instruction::Return(0, 8),
+    ]));
+    let before = pool.submit(looping(1000), InstanceLimits::default());
+    let panicking = pool.submit(|| CPU::with_program(0, Program::new(vec![Instruction::WriteIntToSymbol(0, 1)])), InstanceLimits::default());
+    let unbuilt = pool.submit(|| panic!("no CPU"), InstanceLimits::default());
+    let after = pool.submit(looping(10), InstanceLimits::default());
+    assert!(panicking.wait().err().unwrap().starts_with("The VM panicked running the instance"));
+    assert_eq!(unbuilt.wait().err().unwrap(), "The VM panicked running the instance: no CPU");
+    assert_eq!(before.wait()?, 1000i64.to_ne_bytes().to_vec());
+    assert_eq!(after.wait()?, 10i64.to_ne_bytes().to_vec());
+    assert_eq!(pool.pending(), 0);
+    Ok(())
+}
+
+#[test]
+fn channels() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::{Read, Write};
+
+    let open_and_echo = |name: &str| vec![
+        Instruction::MemExtend(64),
+        Instruction::WriteStringToSymbol(0, format!("channel://{}", name)),
+        Instruction::WriteIntToSymbol(32, 4),
+        Instruction::OpenStream(0, 1, 1),
+        Instruction::ReadStream(1, 32, 40),
+        Instruction::WriteStream(1, 32, 40),
+    ];
+
+    // A worker on another thread answers a supervisor.
+    let (supervisor_end, worker_end) = ChannelEnd::pair();
+    let worker = SharedCpu::spawn(move || {
+        let mut cpu = CPU::with_program(0, Program::new(open_and_echo("supervisor")));
+        cpu.connect_channel("supervisor", worker_end);
+        cpu
+    });
+    let supervisor = SharedCpu::spawn(move || {
+        let mut cpu = CPU::with_program(0, Program::new(vec![
+            Instruction::MemExtend(64),
+            Instruction::WriteStringToSymbol(0, "channel://worker".to_string()),
+            Instruction::WriteIntToSymbol(32, 4),
+            Instruction::WriteBytesToSymbol(40, b"ping".to_vec()),
+            Instruction::OpenStream(0, 1, 1),
+            Instruction::WriteStream(1, 32, 40),
+            Instruction::ReadStream(1, 32, 48),
+        ]));
+        cpu.connect_channel("worker", supervisor_end);
+        cpu
+    });
+    let worker_thread = std::thread::spawn(move || worker.run());
+    supervisor.run()?;
+    worker_thread.join().unwrap()?;
+    assert_eq!(supervisor.read(48, 4)?, b"ping".to_vec());
+
+    // The host can hold an end itself, and sees the end of the stream once the CPU is dropped.
+    let (mut host_end, cpu_end) = ChannelEnd::pair();
+    let mut cpu = CPU::with_program(0, Program::new(open_and_echo("host")));
+    cpu.connect_channel("host", cpu_end);
+    host_end.write_all(b"pong")?;
+    cpu.run()?;
+    let mut reply = [0; 8];
+    assert_eq!(host_end.read(&mut reply)?, 4);
+    assert_eq!(&reply[..4], b"pong");
+    drop(cpu);
+    assert_eq!(host_end.read(&mut reply)?, 0);
+    assert!(host_end.write(b"gone").is_err());
+
+    // Each end can only be opened once, and only if it's connected.
+    let (_other, end) = ChannelEnd::pair();
+    let mut scheduler = Scheduler::new();
+    scheduler.connect_channel("once", end);
+    assert!(scheduler.run(Program::new(vec![
+        Instruction::MemExtend(64),
+        Instruction::WriteStringToSymbol(0, "channel://once".to_string()),
+        Instruction::OpenStream(0, 1, 1),
+        Instruction::OpenStream(0, 2, 1),
+    ])).is_err());
+    assert!(execute(vec![
+        Instruction::MemExtend(64),
+        Instruction::WriteStringToSymbol(0, "channel://missing".to_string()),
+        Instruction::OpenStream(0, 1, 1),
+    ]).is_err());
+    Ok(())
+}
+
+#[test]
+fn remote_execution() -> Result<(), Box<dyn std::error::Error>> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?;
+    let limits = InstanceLimits { max_instructions: Some(100_000), memory_limit: Some(1024), ..InstanceLimits::default() };
+    std::thread::spawn(move || crate::serve(listener, SandboxPolicy::unrestricted(), limits));
+    let mut stream = std::net::TcpStream::connect(address)?;
+
+    let request = RemoteRequest {
+        program: crate::encode_program(&[
+            Instruction::MemExtend(32),
+            Instruction::WriteStringToSymbol(8, "hi".to_string()),
+            Instruction::PrintSymbol(8),
+            Instruction::AddImmediate(0, 1, 0),
+        ]),
+        memory: 41i64.to_ne_bytes().to_vec(),
+        trace: true,
+    };
+    let mut responses = Vec::new();
+    let result = crate::run_remote(&mut stream, &request, |response| responses.push(response.clone()))?;
+    assert_eq!(result.error, None);
+    assert_eq!(Memory::from_dump(result.memory).read_typed::<i64>(0), 42);
+    let traced = responses.iter().filter(|response| matches!(response, RemoteResponse::Trace(_))).count();
+    assert_eq!(traced, 4);
+    assert!(responses.contains(&RemoteResponse::Stdout(b"hi".to_vec())));
+
+    // The connection can be reused, and errors come back in the result.
+    let request = RemoteRequest {
+        program: crate::encode_program(&[Instruction::MemExtend(16), Instruction::DivideSymbols(0, 8, 0)]),
+        memory: Vec::new(),
+        trace: false,
+    };
+    let result = crate::run_remote(&mut stream, &request, |_| panic!("not tracing"))?;
+    assert!(result.error.is_some());
+    let request = RemoteRequest { program: b"not a program".to_vec(), memory: Vec::new(), trace: false };
+    assert!(crate::run_remote(&mut stream, &request, |_| {})?.error.is_some());
+
+    // Requests are held to the server's limits.
+    let request = RemoteRequest { program: crate::encode_program(&[Instruction::Jump(0)]), memory: Vec::new(), trace: false };
+    let error = crate::run_remote(&mut stream, &request, |_| {})?.error.unwrap();
+    assert!(error.starts_with("Timeout: the watchdog stopped the program after 100000 instructions"));
+    let request = RemoteRequest { program: crate::encode_program(&[]), memory: vec![0; 2048], trace: false };
+    assert!(crate::run_remote(&mut stream, &request, |_| {})?.error.unwrap().starts_with("Out of memory"));
+
+    // A request that panics the VM fails on its own, and doesn't use up a connection for good.
+    let request = RemoteRequest { program: crate::encode_program(&[Instruction::WriteIntToSymbol(0, 1)]), memory: Vec::new(), trace: false };
+    for _ in 0..crate::MAX_CONNECTIONS + 1 {
+        let mut stream = std::net::TcpStream::connect(address)?;
+        let error = crate::run_remote(&mut stream, &request, |_| {})?.error.unwrap();
+        assert!(error.starts_with("The VM panicked running the program"));
+    }
+    let request = RemoteRequest { program: crate::encode_program(&[Instruction::NoOp()]), memory: Vec::new(), trace: false };
+    assert_eq!(crate::run_remote(&mut stream, &request, |_| {})?.error, None);
+
+    let mut frame = Vec::new();
+    crate::write_frame(&mut frame, b"payload")?;
+    assert_eq!(crate::read_frame(&mut frame.as_slice())?, Some(b"payload".to_vec()));
+    assert_eq!(crate::read_frame(&mut [].as_slice())?, None);
+    let too_long = (crate::MAX_FRAME_LENGTH as u32 + 1).to_le_bytes();
+    assert!(crate::read_frame(&mut too_long.as_slice()).is_err());
+    Ok(())
+}
+
+#[test]
+fn hot_reloading() -> Result<(), Box<dyn std::error::Error>> {
+    let main = Block::new("main", vec![Instruction::Jump(0)]).with_reference(0, "step");
+    let step = Block::new("step", vec![Instruction::AddImmediate(0, 1, 0), Instruction::Return(0, 8)]);
+    let program = link(&[Module::new(vec![main, step])], "main")?;
+    assert_eq!(program.block("step"), Some(1..3));
+    let mut cpu = CPU::with_program(16, program);
+    cpu.run()?;
+    assert_eq!(cpu.memory().read_typed::<i64>(0), 1);
+
+    // Jumps made after the reload run the new block.
+    let faster = Block::new("step", vec![Instruction::AddImmediate(0, 10, 0), Instruction::Return(0, 8)]);
+    cpu.replace_block(faster.clone(), ReloadPolicy::FinishOld)?;
+    assert_eq!(cpu.get_stack().block("step"), Some(3..5));
+    cpu.program.pc = 0;
+    cpu.run()?;
+    assert_eq!(cpu.memory().read_typed::<i64>(0), 11);
+
+    // Execution inside the old block either finishes there or starts the new one over.
+    cpu.program.pc = 0;
+    cpu.run_for(2)?;
+    cpu.replace_block(faster.clone(), ReloadPolicy::FinishOld)?;
+    assert_eq!(cpu.program.pc, 4);
+    cpu.run()?;
+    assert_eq!(cpu.memory().read_typed::<i64>(0), 21);
+    cpu.program.pc = 0;
+    cpu.run_for(2)?;
+    cpu.replace_block(faster, ReloadPolicy::Restart)?;
+    assert_eq!(cpu.program.pc, 7);
+    cpu.run()?;
+    assert_eq!(cpu.memory().read_typed::<i64>(0), 41);
+
+    assert!(cpu.replace_block(Block::new("missing", Vec::new()), ReloadPolicy::Restart).is_err());
+    Ok(())
+}
+
+#[test]
+fn dynamic_code_loading() -> Result<(), Box<dyn std::error::Error>> {
+    let double = Block::new("double", vec![
+        Instruction::MemExtend(100),
+        Instruction::MultiplyImmediate(0, 2, 8),
+        Instruction::Return(8, 8)
+    ]);
+    let code = Module::new(vec![double]).to_bytes();
+    let n = code.len() as i64;
+    let mut scheduler = Scheduler::new();
+    scheduler.run(Program::new(vec![
+        Instruction::MemExtend(300),
+        Instruction::WriteBytesToSymbol(100, code),
+        Instruction::WriteIntToSymbol(0, n),
+        Instruction::LoadCode(100, 0, 16),
+        Instruction::WriteIntToSymbol(40, 21),
+        Instruction::CreateCoroutineIndirect(24, 40, 8, 48),
+        Instruction::Await(48, 56),
+        Instruction::Return(56, 8)
+    ]))?;
+    let memory = scheduler.get_coro(1).memory_dump();
+    check_symbol_eq(memory.clone(), 16, 1i64);
+    check_symbol_eq(memory.clone(), 24, 8usize);
+    check_symbol_eq(memory, 56, 42i64);
+
+    // Code that fails the verifier isn't loaded.
+    let bad = Module::new(vec![Block::new("bad", vec![
+        Instruction::WriteStringToSymbol(0, String::from("one")),
+        Instruction::AddImmediate(0, 1, 0)
+    ])]).to_bytes();
+    let n = bad.len() as i64;
+    assert!(execute(vec![
+        Instruction::MemExtend(300),
+        Instruction::WriteBytesToSymbol(100, bad),
+        Instruction::WriteIntToSymbol(0, n),
+        Instruction::LoadCode(100, 0, 16)
+    ]).is_err());
+    Ok(())
+}
+
+#[test]
+fn interrupt_handlers() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cpu = CPU::with_program(24, Program::new(vec![
+        Instruction::AddImmediate(0, 1, 0),
+        Instruction::CompareLesserImmediate(0, 10, 8),
+        Instruction::JumpIfTrue(0, 8),
+        Instruction::Return(0, 8),
+        // Handler for "tick"
+        Instruction::AddImmediate(16, 1, 16),
+        Instruction::ReturnFromInterrupt(),
+    ]));
+    let line = cpu.register_interrupt("tick", 4);
+
+    // Raising a line twice before it's handled runs the handler once.
+    line.raise();
+    cpu.raise_interrupt("tick")?;
+    cpu.run()?;
+    assert!(!line.is_raised());
+    assert_eq!(cpu.memory().read_typed::<i64>(0), 10);
+    assert_eq!(cpu.memory().read_typed::<i64>(16), 1);
+
+    // Lines can be raised from other threads.
+    let remote = cpu.interrupt_line("tick").unwrap();
+    std::thread::spawn(move || remote.raise()).join().unwrap();
+    cpu.program.pc = 3;
+    cpu.run()?;
+    assert_eq!(cpu.memory().read_typed::<i64>(16), 2);
+
+    assert!(cpu.raise_interrupt("missing").is_err());
+    assert!(execute(vec![Instruction::ReturnFromInterrupt()]).is_err());
+    Ok(())
+}
+
+#[test]
+fn awaiting_events() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("concordevm_watched_file");
+    let _ = std::fs::remove_file(&path);
+    let mut scheduler = Scheduler::new();
+    scheduler.add_timer("tick", std::time::Duration::from_millis(1));
+    scheduler.watch_file("changed", &path, std::time::Duration::from_millis(5));
+    let sender = scheduler.event_sender();
+    // Fired before anything waits for it, so it's kept until the program does.
+    sender.fire("early", 3i64.to_ne_bytes().to_vec())?;
+    let writer = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        sender.fire("go", 7i64.to_ne_bytes().to_vec()).unwrap();
+        std::fs::write(&path, b"changed").unwrap();
+    });
+
+    scheduler.run(Program::new(vec![
+        Instruction::MemExtend(300),
+        Instruction::WriteStringToSymbol(0, String::from("tick")),
+        Instruction::WriteStringToSymbol(50, String::from("go")),
+        Instruction::WriteStringToSymbol(100, String::from("early")),
+        Instruction::WriteStringToSymbol(150, String::from("changed")),
+        Instruction::AwaitEvent(0, 200),
+        Instruction::AwaitEvent(50, 208),
+        Instruction::AwaitEvent(100, 216),
+        Instruction::AwaitEvent(150, 224),
+        Instruction::Return(200, 8)
+    ]))?;
+    writer.join().unwrap();
+    let memory = scheduler.get_coro(1).memory_dump();
+    check_symbol_eq(memory.clone(), 200, 1i64);
+    check_symbol_eq(memory.clone(), 208, 7i64);
+    check_symbol_eq(memory.clone(), 216, 3i64);
+    check_symbol_eq(memory, 224, 1i64);
+    std::fs::remove_file(std::env::temp_dir().join("concordevm_watched_file"))?;
+    Ok(())
+}
+
+#[test]
+fn stepping_over_and_out() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = crate::concorde_asm! {
+        MemExtend(64);
+        WriteIntToSymbol(0, 2);
+        WriteIntToSymbol(8, 5);
+        WriteIntToSymbol(16, 6);
+        WriteIntToSymbol(40, 0);
+        ForEach(0, 24, @body);
+        Return(40, 8);
+
+        body:
+        AddSymbols(40, 24, 40);
+        Return(0, 0);
+    };
+
+    // Stepping over a loop runs its body for every item.
+    let mut cpu = CPU::with_program(0, Program::new(instructions.clone()));
+    for pc in 1..=5 {
+        assert!(cpu.step_over()?.is_none());
+        assert_eq!(cpu.program.pc, pc);
+    }
+    assert!(cpu.step_over()?.is_none());
+    assert_eq!((cpu.program.pc, cpu.frame_depth()), (6, 0));
+    check_symbol_eq(cpu.memory().clone(), 40, 11i64);
+    assert!(matches!(cpu.step_over()?, Some(Interrupt::Ret(40, 8))));
+
+    // Stepping out of a body finishes the loop.
+    let mut cpu = CPU::with_program(0, Program::new(instructions.clone()));
+    for _ in 0..6 {
+        cpu.cycle()?;
+    }
+    assert_eq!((cpu.program.pc, cpu.frame_depth()), (7, 1));
+    assert!(cpu.step_out()?.is_none());
+    assert_eq!((cpu.program.pc, cpu.frame_depth()), (6, 0));
+
+    // Breakpoints stop stepping part way through.
+    let mut cpu = CPU::with_program(0, Program::new(instructions));
+    cpu.add_breakpoint(8);
+    assert!(cpu.continue_to_breakpoint()?.is_none());
+    assert_eq!(cpu.program.pc, 8);
+    assert!(cpu.step_out()?.is_none());
+    assert_eq!(cpu.program.pc, 8);
+    check_symbol_eq(cpu.memory().clone(), 40, 11i64);
+    cpu.remove_breakpoint(8);
+    assert!(matches!(cpu.continue_to_breakpoint()?, Some(Interrupt::Ret(40, 8))));
+    Ok(())
+}
+
+#[test]
+fn conditional_breakpoints() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cpu = CPU::with_program(0, Program::new(vec![
+        Instruction::MemExtend(16),
+        Instruction::AddImmediate(0, 1, 0),
+        Instruction::CompareLesserImmediate(0, 10, 8),
+        Instruction::JumpIfTrue(1, 8),
+        Instruction::Return(0, 8),
+    ]));
+    cpu.memory_mut().bind("counter", 0, 8);
+    cpu.memory_mut().bind("more", 8, 1);
+    cpu.add_conditional_breakpoint(1, "counter == 5 && (more == true || !more)")?;
+    assert!(cpu.continue_to_breakpoint()?.is_none());
+    assert_eq!(cpu.program.pc, 1);
+    check_symbol_eq(cpu.memory().clone(), 0, 5i64);
+
+    let mut memory = Memory::new(0);
+    memory.extend_memory(8)?;
+    memory.write(0, &42i64);
+    assert!(crate::Condition::parse("@0 > -3 && @0 != 41")?.evaluate(&memory)?);
+    assert!(crate::Condition::parse("missing > 1")?.evaluate(&memory).is_err());
+    assert!(crate::Condition::parse("@0 == true")?.evaluate(&memory).is_err());
+    let far = format!("@{} == 0", usize::MAX - 3);
+    assert!(crate::Condition::parse(&far)?.evaluate(&memory).err().unwrap().ends_with("is outside memory"));
+    for invalid in ["counter >", "(counter", "counter + 1", "counter == 1 2", "$"] {
+        assert!(crate::Condition::parse(invalid).is_err(), "{}", invalid);
+    }
+
+    // A condition that can't be evaluated stops the run with an error.
+    cpu.add_conditional_breakpoint(2, "missing")?;
+    assert!(cpu.continue_to_breakpoint().is_err());
+    Ok(())
+}
+
+#[test]
+fn coverage_reports() -> Result<(), Box<dyn std::error::Error>> {
+    let main = Block::new("main", vec![
+        Instruction::WriteBoolToSymbol(8, false),
+        Instruction::JumpIfTrue(0, 8),
+        Instruction::Return(0, 8),
+    ]).with_reference(1, "never");
+    let never = Block::new("never", vec![Instruction::AddImmediate(0, 1, 0), Instruction::Return(0, 8)]);
+    let mut program = link(&[Module::new(vec![main, never])], "main")?;
+    let mut debug_info = DebugInfo::new();
+    debug_info.set_location(0, "main.cv", 1, 1);
+    debug_info.set_location(1, "main.cv", 1, 8);
+    debug_info.set_location(2, "main.cv", 2, 1);
+    debug_info.set_location(3, "lib.cv", 1, 1);
+    program.debug_info = Some(Rc::new(debug_info));
+
+    let coverage = Rc::new(std::cell::RefCell::new(crate::Coverage::new()));
+    let mut cpu = CPU::with_program(16, program.clone());
+    cpu.set_coverage(Rc::clone(&coverage));
+    cpu.run()?;
+    let coverage = coverage.borrow();
+    assert_eq!((coverage.hits(0), coverage.hits(3), coverage.covered(5)), (1, 0, 3));
+    let report = coverage.report(&program);
+    assert!(report.starts_with("Covered 3 of 5 instructions (60.0%)\n  main: 3 of 3 (100.0%)\n  never: 0 of 2 (0.0%)\nNot covered:\n"));
+    assert_eq!(report.lines().count(), 6);
+    assert_eq!(coverage.to_lcov(&program), "\
+SF:lib.cv\nDA:1,0\nLF:1\nLH:0\nend_of_record\n\
+SF:main.cv\nDA:1,1\nDA:2,1\nLF:2\nLH:2\nend_of_record\n\
+SF:program\nDA:5,0\nLF:1\nLH:0\nend_of_record\n");
+
+    let mut scheduler = Scheduler::new();
+    let coverage = scheduler.track_coverage();
+    scheduler.run(Program::new(vec![Instruction::MemExtend(8), Instruction::Return(0, 1)]))?;
+    assert_eq!(coverage.borrow().covered(2), 2);
+    Ok(())
+}
+
+#[test]
+fn trace_replay() -> Result<(), Box<dyn std::error::Error>> {
+    struct Shared(Rc<std::cell::RefCell<Vec<u8>>>);
+    impl std::io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let instructions = vec![
+        Instruction::MemExtend(16),
+        Instruction::WriteIntToSymbol(0, 2),
+        Instruction::SubtractImmediate(0, 1, 0),
+        Instruction::CompareGreaterImmediate(0, 0, 8),
+        Instruction::JumpIfTrue(2, 8),
+        Instruction::DivideSymbols(8, 0, 8),
+    ];
+    let buffer = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let mut cpu = CPU::with_program(0, Program::new(instructions.clone()));
+    cpu.set_execution_log(Rc::new(std::cell::RefCell::new(ExecutionLog::new(Shared(Rc::clone(&buffer))))));
+    assert!(cpu.run().is_err());
+    let trace = crate::read_trace(buffer.borrow().as_slice())?;
+    assert_eq!(trace.len(), 9);
+    assert!(trace[8].error.is_some());
+
+    // The same program replays the trace exactly, including the error it ended with.
+    CPU::with_program(0, Program::new(instructions.clone())).replay(&trace)?;
+
+    // A changed program diverges at the first step that differs.
+    let mut changed = instructions;
+    changed[1] = Instruction::WriteIntToSymbol(0, 1);
+    let error = CPU::with_program(0, Program::new(changed)).replay(&trace).unwrap_err();
+    assert!(error.starts_with("Replay diverged at step 2: the trace ran WriteIntToSymbol(0, 2) at 1, but the program ran WriteIntToSymbol(0, 1) at 1"), "{}", error);
+
+    assert!(crate::read_trace("not json".as_bytes()).is_err());
+    assert!(crate::read_trace("{\"pc\": 0}".as_bytes()).is_err());
+    Ok(())
+}
+
+#[test]
+fn assertions() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(32),
+        Instruction::WriteIntToSymbol(0, 7),
+        Instruction::WriteBoolToSymbol(8, false),
+        Instruction::WriteStringToSymbol(16, String::from("ready")),
+        Instruction::AssertType(0, String::from("int")),
+        Instruction::AssertType(8, String::from("bool")),
+        Instruction::Assert(8, 16),
+        Instruction::Return(0, 8)
+    ];
+    let error = execute(instructions.clone()).err().unwrap();
+    assert!(error.starts_with("Assertion failed: ready"), "{}", error);
+    assert!(error.contains("at instruction 6: Assert(8, 16)"), "{}", error);
+
+    // Release runs skip every check.
+    let mut scheduler = Scheduler::new();
+    scheduler.set_assertions(false);
+    scheduler.run(Program::new(instructions.clone()))?;
+
+    let mut wrong_type = instructions;
+    wrong_type[5] = Instruction::AssertType(0, String::from("bool"));
+    let error = execute(wrong_type.clone()).err().unwrap();
+    assert!(error.starts_with("Assertion failed: symbol 0 does not hold a bool"), "{}", error);
+    wrong_type[5] = Instruction::AssertType(0, String::from("matrix"));
+    assert!(execute(wrong_type).err().unwrap().starts_with("AssertType names unknown type matrix"));
+
+    let cpu = CPU::with_program(0, Program::new(vec![
+        Instruction::MemExtend(16),
+        Instruction::WriteIntToSymbol(0, 1),
+        Instruction::Assert(0, 8),
+        Instruction::Return(0, 8)
+    ]));
+    assert!(!cpu.verify(0).is_empty());
+    Ok(())
+}
+
+#[test]
+fn stack_introspection() -> Result<(), Box<dyn std::error::Error>> {
+    let main = Block::new("main", vec![
+        Instruction::MemExtend(100),
+        Instruction::GetStackDepth(0),
+        Instruction::GetCallerSymbol(8),
+        Instruction::CreateCoroutine(0, 0, 0, 16),
+        Instruction::Await(16, 24),
+        Instruction::Return(24, 8)
+    ]).with_reference(3, "log");
+    let log = Block::new("log", vec![
+        Instruction::MemExtend(100),
+        Instruction::GetCallerSymbol(8),
+        Instruction::GetStackDepth(0),
+        Instruction::Return(0, 13)
+    ]);
+    let mut scheduler = Scheduler::new();
+    scheduler.run(link(&[Module::new(vec![main, log])], "main")?)?;
+    let main_memory = scheduler.get_coro(1).memory_dump();
+    check_symbol_eq(main_memory.clone(), 0, 1i64);
+    check_symbol_eq(main_memory.clone(), 8, String::new());
+    check_symbol_eq(main_memory.clone(), 24, 2i64);
+    check_symbol_eq(main_memory, 32, String::from("main"));
+    Ok(())
+}
+
+#[test]
+fn code_reflection() -> Result<(), Box<dyn std::error::Error>> {
+    let main = Block::new("main", vec![
+        Instruction::MemExtend(100),
+        Instruction::ListCodeBlocks(0),
+        Instruction::WriteStringToSymbol(50, String::from("helper")),
+        Instruction::BlockLength(50, 60),
+        Instruction::WriteStringToSymbol(50, String::from("missing")),
+        Instruction::BlockLength(50, 60)
+    ]);
+    let helper = Block::new("helper", vec![
+        Instruction::MemExtend(100),
+        Instruction::Return(0, 8)
+    ]);
+    let program = link(&[Module::new(vec![main, helper])], "main")?;
+    assert_eq!(program.block_names(), vec![String::from("main"), String::from("helper")]);
+
+    let mut cpu = CPU::with_program(0, program);
+    let error = cpu.run().err().unwrap();
+    assert!(error.contains("No block named missing is loaded"), "{}", error);
+    assert_eq!(cpu.memory.read_string_list(0), vec![String::from("main"), String::from("helper")]);
+    check_symbol_eq(cpu.memory.clone(), 60, 2i64);
+    Ok(())
+}
+
+#[test]
+fn inherited_memory() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteIntToSymbol(16, 42),
+        Instruction::CreateCoroutine(6, 0, 0, 32),
+        Instruction::Await(32, 40),
+        Instruction::AddSymbols(16, 40, 48),
+        Instruction::Return(48, 8),
+
+        // The child sees the parent's value, but its own write stays its own.
+        Instruction::MemCpy(16, 24, 8),
+        Instruction::WriteIntToSymbol(16, 99),
+        Instruction::Return(24, 8)
+    ];
+    let mut scheduler = Scheduler::new();
+    scheduler.set_inherit_memory(true);
+    scheduler.run(Program::new(instructions.clone()))?;
+    check_symbol_eq(scheduler.get_coro(1).memory_dump(), 48, 84i64);
+
+    // By default, the child starts with empty memory.
+    assert!(Scheduler::new().run(Program::new(instructions)).is_err());
+    Ok(())
+}
+
+#[test]
+fn structured_scopes() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteStringToSymbol(50, String::from("never")),
+        Instruction::BeginScope(),
+        Instruction::CreateCoroutine(11, 0, 0, 0),
+        Instruction::EndScope(false),
+        Instruction::Await(0, 8),
+        Instruction::BeginScope(),
+        Instruction::CreateCoroutine(14, 50, 6, 16),
+        Instruction::EndScope(true),
+        Instruction::Await(16, 24),
+        Instruction::Return(8, 8),
+
+        Instruction::MemExtend(100),
+        Instruction::WriteIntToSymbol(0, 5),
+        Instruction::Return(0, 8),
+
+        // Waits for an event that never fires.
+        Instruction::MemExtend(100),
+        Instruction::AwaitEvent(0, 16),
+        Instruction::Return(16, 8)
+    ];
+    let error = execute(instructions.clone()).err().unwrap();
+    assert!(error.contains("whose coroutine was cancelled"), "{}", error);
+
+    let mut leaving_early = instructions.clone();
+    leaving_early[9] = Instruction::NoOp();
+    let mut scheduler = Scheduler::new();
+    scheduler.run(Program::new(leaving_early))?;
+    check_symbol_eq(scheduler.get_coro(1).memory_dump(), 8, 5i64);
+    // The coroutine waiting for the event was stopped, not left behind.
+    assert_eq!(scheduler.metrics().coroutines.suspended, 0);
+
+    let mut unmatched = instructions;
+    unmatched[2] = Instruction::NoOp();
+    assert!(execute(unmatched).err().unwrap().starts_with("EndScope without a matching BeginScope"));
+    Ok(())
+}
+
+#[test]
+fn selecting_futures() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::WriteStringToSymbol(0, String::from("never")),
+        Instruction::CreateCoroutine(8, 0, 6, 48),
+        Instruction::CreateCoroutine(11, 0, 0, 56),
+        Instruction::WriteIntToSymbol(40, 2),
+        Instruction::Select(40, 64),
+        Instruction::Await(56, 72),
+        Instruction::Return(64, 8),
+
+        // Waits for an event that never fires.
+        Instruction::MemExtend(100),
+        Instruction::AwaitEvent(0, 16),
+        Instruction::Return(16, 8),
+
+        Instruction::MemExtend(100),
+        Instruction::WriteIntToSymbol(0, 7),
+        Instruction::Return(0, 8)
+    ];
+    let mut scheduler = Scheduler::new();
+    scheduler.run(Program::new(instructions.clone()))?;
+    let memory = scheduler.get_coro(1).memory_dump();
+    check_symbol_eq(memory.clone(), 64, 1i64);
+    check_symbol_eq(memory, 72, 7i64);
+
+    let mut empty = instructions;
+    empty[4] = Instruction::WriteIntToSymbol(40, 0);
+    assert!(execute(empty).err().unwrap().starts_with("Select needs at least one future"));
+    Ok(())
+}
+
+#[test]
+fn mutexes_and_semaphores() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::MutexCreate(0),
+        Instruction::WriteIntToSymbol(40, 0),
+        Instruction::SemaphoreCreate(40, 8),
+        Instruction::MutexLock(0),
+        Instruction::CreateCoroutine(11, 0, 16, 16),
+        Instruction::CreateCoroutine(16, 0, 16, 24),
+        Instruction::SemaphoreAcquire(8),
+        Instruction::MutexUnlock(0),
+        Instruction::Await(16, 32),
+        Instruction::Return(32, 8),
+
+        // Waits for the mutex, which is handed over when it's unlocked.
+        Instruction::MemExtend(100),
+        Instruction::MutexLock(0),
+        Instruction::WriteIntToSymbol(16, 3),
+        Instruction::MutexUnlock(0),
+        Instruction::Return(16, 8),
+
+        // Wakes the entrypoint, which is waiting on the semaphore.
+        Instruction::MemExtend(100),
+        Instruction::SemaphoreRelease(8),
+        Instruction::Return(0, 8)
+    ];
+    let mut scheduler = Scheduler::new();
+    scheduler.run(Program::new(instructions))?;
+    check_symbol_eq(scheduler.get_coro(1).memory_dump(), 32, 3i64);
+
+    let misuse = |last: Instruction| {
+        execute(vec![Instruction::MemExtend(16), Instruction::MutexCreate(0), Instruction::MutexLock(0), last]).err().unwrap()
+    };
+    assert!(misuse(Instruction::MutexLock(0)).starts_with("Coroutine 1 tried to lock mutex 1, which it already holds"));
+    assert!(misuse(Instruction::SemaphoreRelease(0)).starts_with("1 is a Mutex, not a Semaphore"));
+    assert!(execute(vec![Instruction::MemExtend(16), Instruction::MutexCreate(0), Instruction::MutexUnlock(0)]).err().unwrap()
+        .starts_with("Coroutine 1 tried to unlock mutex 1, which it doesn't hold"));
+    Ok(())
+}
+
+#[test]
+fn domain_reloading() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::LoadSO(1, "./ffi.so".to_string()),
+        Instruction::AddFFIFn(1, 1, "max".to_string(), vec![Type::u64(), Type::u64()], Type::u64()),
+        Instruction::Return(0, 8)
+    ];
+    let mut scheduler = Scheduler::new();
+    scheduler.run(Program::new(instructions.clone()))?;
+    scheduler.reload_domain(1)?;
+    assert!(scheduler.reload_domain(2).unwrap_err().contains("Domain 2 is not loaded"));
+
+    let mut unloaded = instructions;
+    unloaded[3] = Instruction::UnloadDomain(1);
+    unloaded.extend([
+        Instruction::WriteIntToSymbol(0, 10i64),
+        Instruction::WriteIntToSymbol(8, 100i64),
+        Instruction::CallFFIFn(1, 1, 0, 16, 16),
+        Instruction::Return(0, 8)
+    ]);
+    assert!(execute(unloaded).err().unwrap().starts_with("FFI function with id 1 not found in domain 1"));
+    Ok(())
+}
+
+#[test]
+fn domain_abi_checks() -> Result<(), Box<dyn std::error::Error>> {
+    unsafe { crate::Domain::new("./ffi.so")? };
+    let error = unsafe { crate::Domain::new("libm.so.6") }.err().unwrap().to_string();
+    assert_eq!(error, "libm.so.6 is not a domain library, since it doesn't export concorde_domain_abi");
+    Ok(())
+}
+
+// Serves isolated domains when `isolated_domains` runs this test binary as a helper.
+#[test]
+fn isolated_domain_helper() {
+    crate::serve_isolated_domain();
+}
+
+#[test]
+fn isolated_domains() -> Result<(), Box<dyn std::error::Error>> {
+    let isolated_scheduler = || -> Result<Scheduler, Box<dyn std::error::Error>> {
+        let mut scheduler = Scheduler::new();
+        let args = ["--exact", "tests::isolated_domain_helper", "--nocapture", "--test-threads=1"];
+        scheduler.isolate_domains(crate::DomainIsolation::new(std::env::current_exe()?, args.map(String::from).to_vec()));
+        Ok(scheduler)
+    };
+    let mut instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::LoadSO(1, "./ffi.so".to_string()),
+        Instruction::AddFFIFn(1, 1, "max".to_string(), vec![Type::u64(), Type::u64()], Type::u64()),
+        Instruction::AddFFIFn(1, 2, "crash".to_string(), vec![], Type::u64()),
+        Instruction::WriteIntToSymbol(0, 10i64),
+        Instruction::WriteIntToSymbol(8, 100i64),
+        Instruction::CallFFIFn(1, 1, 0, 16, 16),
+        Instruction::Await(16, 24),
+        Instruction::Return(24, 8)
+    ];
+    let mut scheduler = isolated_scheduler()?;
+    scheduler.run(Program::new(instructions.clone()))?;
+    check_symbol_eq(scheduler.get_coro(1).memory_dump(), 24, 100i64);
+    scheduler.reload_domain(1)?;
+
+    // A crash takes down the helper, and fails the call, but not the VM.
+    instructions[6] = Instruction::CallFFIFn(1, 2, 0, 0, 16);
+    let error = isolated_scheduler()?.run(Program::new(instructions)).unwrap_err();
+    assert!(error.starts_with("Call into domain 1 failed: function 2: The domain helper stopped"), "{}", error);
+    Ok(())
+}
+
+#[test]
+fn builtin_domains() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::LoadSO(1, "builtin:math".to_string()),
+        Instruction::LoadSO(2, "builtin:string".to_string()),
+        Instruction::AddFFIFn(1, 1, "gcd".to_string(), vec![Type::u64(), Type::u64()], Type::u64()),
+        Instruction::AddFFIFn(2, 1, "to_upper".to_string(), vec![Type::u32()], Type::u32()),
+        Instruction::WriteIntToSymbol(0, 84i64),
+        Instruction::WriteIntToSymbol(8, 36i64),
+        Instruction::CallFFIFn(1, 1, 0, 16, 16),
+        Instruction::Await(16, 24),
+        Instruction::WriteIntToSymbol(32, 'q' as i64),
+        Instruction::CallFFIFn(2, 1, 32, 4, 40),
+        Instruction::Await(40, 48),
+        Instruction::Return(0, 64)
+    ];
+    let mut scheduler = Scheduler::new();
+    scheduler.run(Program::new(instructions.clone()))?;
+    check_symbol_eq(scheduler.get_coro(1).memory_dump(), 24, 12i64);
+    assert_eq!(scheduler.get_coro(1).memory_dump().read_typed::<u32>(48), 'Q' as u32);
+
+    let mut wrong = instructions.clone();
+    wrong[3] = Instruction::AddFFIFn(1, 1, "gcd".to_string(), vec![Type::u64()], Type::u64());
+    assert!(execute(wrong).err().unwrap().starts_with("Error loading FFI function from domain 1: Function gcd of built-in domain math was added with the wrong signature"));
+    let mut unknown = instructions;
+    unknown[2] = Instruction::LoadSO(2, "builtin:network".to_string());
+    assert!(execute(unknown).err().unwrap().contains("There is no built-in domain named builtin:network"));
+    Ok(())
+}
+
+#[test]
+fn typed_domain_returns() -> Result<(), Box<dyn std::error::Error>> {
+    let instructions = vec![
+        Instruction::MemExtend(100),
+        Instruction::LoadSO(1, "./ffi.so".to_string()),
+        Instruction::LoadSO(2, "builtin:math".to_string()),
+        Instruction::AddFFIFn(1, 1, "negate".to_string(), vec![Type::i32()], Type::i32()),
+        Instruction::AddFFIFn(2, 1, "sqrt".to_string(), vec![Type::f64()], Type::f64()),
+        Instruction::WriteIntToSymbol(0, 5i64),
+        Instruction::WriteIntToSymbol(32, -1i64),
+        Instruction::CallFFIFn(1, 1, 0, 4, 16),
+        Instruction::Await(16, 32),
+        Instruction::WriteBytesToSymbol(48, 2.25f64.to_ne_bytes().to_vec()),
+        Instruction::CallFFIFn(2, 1, 48, 8, 56),
+        Instruction::Await(56, 64),
+        Instruction::Return(0, 8)
+    ];
+    let mut scheduler = Scheduler::new();
+    scheduler.run(Program::new(instructions))?;
+    let memory = scheduler.get_coro(1).memory_dump();
+    // Only the four bytes of the i32 are written.
+    assert_eq!(memory.read_typed::<i32>(32), -5);
+    assert_eq!(memory.read_typed::<i32>(36), -1);
+    assert_eq!(memory.read_typed::<f64>(64), 1.5);
+
+    let returned = crate::DomainValue::from_bytes(&Type::u16(), &[1, 0, 0]);
+    assert_eq!(returned.unwrap_err(), "Got 3 bytes for a return value that takes 2");
+    Ok(())
+}
+
+#[test]
+fn failing_domain_calls() -> Result<(), Box<dyn std::error::Error>> {
+    // Called with no arguments, gcd panics reading them.
+    let panicking = vec![
+        Instruction::MemExtend(100),
+        Instruction::LoadSO(1, "builtin:math".to_string()),
+        Instruction::AddFFIFn(1, 1, "gcd".to_string(), vec![Type::u64(), Type::u64()], Type::u64()),
+        Instruction::CallFFIFn(1, 1, 0, 0, 16),
+        Instruction::Await(16, 24),
+        Instruction::Return(24, 8)
+    ];
+    let error = Scheduler::new().run(Program::new(panicking)).unwrap_err();
+    assert!(error.starts_with("Call into domain 1 failed: function 1: Panicked: range end index 8 out of range"), "{}", error);
+
+    let napping = |ms: i64| vec![
+        Instruction::MemExtend(100),
+        Instruction::LoadSO(1, "./ffi.so".to_string()),
+        Instruction::AddFFIFn(1, 1, "nap".to_string(), vec![Type::u64()], Type::u64()),
+        Instruction::WriteIntToSymbol(0, ms),
+        Instruction::CallFFIFn(1, 1, 0, 8, 16),
+        Instruction::Await(16, 24),
+        Instruction::Return(24, 8)
+    ];
+    let mut scheduler = Scheduler::new();
+    scheduler.set_domain_call_timeout(Some(std::time::Duration::from_millis(500)));
+    scheduler.run(Program::new(napping(1)))?;
+    check_symbol_eq(scheduler.get_coro(1).memory_dump(), 24, 1i64);
+
+    let mut scheduler = Scheduler::new();
+    scheduler.set_domain_call_timeout(Some(std::time::Duration::from_millis(20)));
+    let error = scheduler.run(Program::new(napping(300))).unwrap_err();
+    assert!(error.starts_with("Call into domain 1 failed: function 1: Timed out after 20ms"), "{}", error);
+    // The call that timed out still runs and holds the domains.
+    assert_eq!(scheduler.reload_domain(1).unwrap_err(), "Can't unload domain 1 while 1 calls into it are in flight");
+    let loading = vec![Instruction::LoadSO(2, "builtin:math".to_string())];
+    assert_eq!(scheduler.run(Program::new(loading)).unwrap_err(), "Can't change domains while 1 calls into them are in flight");
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    scheduler.reload_domain(1)?;
+    Ok(())
+}
+
+#[test]
+fn cpu_builder() -> Result<(), Box<dyn std::error::Error>> {
+    let random = || crate::CpuBuilder::new()
+        .memory_size(64)
+        .program(Program::new(vec![Instruction::WriteIntToSymbol(32, 16), Instruction::RandomBytes(32, 0), Instruction::Jump(1)]))
+        .stdlib(true)
+        .deterministic(9)
+        .fuel(10);
+    let mut cpu = random().build()?;
+    assert!(cpu.program.block("std::strlen").is_some());
+    let error = cpu.run().err().unwrap();
+    assert!(error.starts_with("Timeout: the watchdog stopped the program after 10 instructions"), "{}", error);
+    let mut again = random().build()?;
+    assert!(again.run().is_err());
+    assert_eq!(cpu.memory().read(0, 16), again.memory().read(0, 16));
+    assert_ne!(cpu.memory().read(0, 16), vec![0; 16]);
+
+    let mut scheduler = crate::CpuBuilder::new().fuel(1000).optimize(false).build_scheduler()?;
+    assert!(scheduler.run(Program::new(vec![Instruction::MemExtend(8), Instruction::Jump(1)])).err().unwrap().starts_with("Timeout"));
+    assert!(crate::CpuBuilder::new().replay_io("/nonexistent/recording").build().is_err());
+    Ok(())
+}
+
+#[test]
+fn embedding_api() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cpu = CPU::with_program(0, Program::new(vec![Instruction::NoOp()]));
+    cpu.load_stdlib();
+    cpu.set_global("answer", &42i64)?;
+    cpu.set_global("answer", &43i64)?;
+    assert_eq!(cpu.get_global::<i64>("answer")?, 43);
+    assert_eq!(cpu.set_global("answer", &1u8).err().unwrap(), "Global answer holds 8 bytes, but the value takes 1");
+    assert_eq!(cpu.get_global::<u8>("answer").err().unwrap(), "Global answer holds 8 bytes, but a u8 takes 1");
+    assert!(cpu.get_global::<i64>("question").is_err());
+
+    cpu.memory_mut().extend_memory_to(8)?;
+    cpu.memory_mut().write(0, &7i64);
+    let length: i64 = cpu.call("std::strlen", &[&"hello\0".to_string()])?;
+    assert_eq!(length, 5);
+    assert_eq!(cpu.program.pc, 0);
+    assert_eq!(cpu.memory().read_typed::<i64>(0), 7);
+    assert_eq!(cpu.memory().len(), 8);
+    assert_eq!(cpu.call::<i64>("std::missing", &[]).err().unwrap(), "No block named std::missing is loaded");
+    Ok(())
+}
+
+#[test]
+fn host_values() -> Result<(), Box<dyn std::error::Error>> {
+    let logged = Rc::new(std::cell::RefCell::new(Vec::new()));
+    let mut cpu = CPU::with_program(0, Program::new(vec![
+        Instruction::WriteIntToSymbol(16, 20),
+        Instruction::InvokeHostValue(0, 16, 8, 24),
+        Instruction::InvokeHostValue(8, 24, 8, 32),
+        Instruction::WriteIntToSymbol(16, -1),
+        Instruction::InvokeHostValue(0, 16, 8, 24),
+    ]));
+    cpu.extend_memory(40)?;
+    // A validator that doubles valid numbers, and a logging sink.
+    cpu.memory_mut().store_host_value(0, |args| {
+        let n = i64::from_ne_bytes(args.try_into().unwrap());
+        if n < 0 {
+            return Err(format!("{} is negative", n));
+        }
+        Ok((n * 2).to_ne_bytes().to_vec())
+    });
+    let sink = Rc::clone(&logged);
+    cpu.memory_mut().store_host_value(8, move |args| {
+        sink.borrow_mut().push(args.to_vec());
+        Ok(Vec::new())
+    });
+
+    assert!(cpu.run().err().unwrap().starts_with("-1 is negative\n  at instruction 4"));
+    assert_eq!(cpu.memory().read_typed::<i64>(24), 40);
+    assert_eq!(*logged.borrow(), vec![40i64.to_ne_bytes().to_vec()]);
+
+    let error = execute(vec![Instruction::MemExtend(16), Instruction::InvokeHostValue(0, 0, 0, 8)]).err().unwrap();
+    assert!(error.starts_with("Symbol 0 does not hold a host value"), "{}", error);
+    Ok(())
+}
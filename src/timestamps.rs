@@ -0,0 +1,216 @@
+//! ConcordeVM's timestamp formatting.
+//!
+//! Converts between Unix timestamps in milliseconds and text, for the FormatTimestamp and
+//! ParseTimestamp instructions. Times are always UTC, on the proleptic Gregorian calendar.
+//! Formats are strftime-like, with these codes:
+//!
+//! - `%Y`: the year, at least 4 digits
+//! - `%m` / `%d`: the month / day of the month, 2 digits
+//! - `%H` / `%M` / `%S`: the hour / minute / second, 2 digits
+//! - `%f`: the millisecond, 3 digits
+//! - `%j`: the day of the year, 3 digits
+//! - `%a` / `%b`: the English abbreviation of the weekday / month, like `Mon` or `Jan`
+//! - `%s`: seconds since the Unix epoch
+//! - `%F` / `%T`: shorthand for `%Y-%m-%d` / `%H:%M:%S`
+//! - `%%`: a literal `%`
+//!
+//! Parsing needs every field to be exactly as wide as it's formatted, except `%s`, and leaves any
+//! missing ones at the epoch, so `%H:%M` parses to a time on 1970-01-01. A weekday is accepted,
+//! but not checked against the date.
+
+use crate::log_and_return_err;
+
+use log::error;
+
+const MILLIS_PER_DAY: i64 = 86_400_000;
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+fn is_leap_year(year: i64) -> bool {
+    return year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 of a date, counting years from March so leap days come last.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    return era * 146_097 + day_of_era - 719_468;
+}
+
+// The (year, month, day) of a number of days since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    return (year, month, day);
+}
+
+// Replace the %F and %T shorthands with what they stand for.
+fn expand(format: &str) -> String {
+    return format.replace("%%", "\0").replace("%F", "%Y-%m-%d").replace("%T", "%H:%M:%S").replace('\0', "%%");
+}
+
+/// Format a timestamp in milliseconds since the Unix epoch.
+pub(crate) fn format(millis: i64, format: &str) -> Result<String, String> {
+    let days = millis.div_euclid(MILLIS_PER_DAY);
+    let time = millis.rem_euclid(MILLIS_PER_DAY);
+    let (year, month, day) = civil_from_days(days);
+
+    let mut output = String::new();
+    let mut chars = expand(format).chars().collect::<Vec<char>>().into_iter();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            output.push(c);
+            continue;
+        }
+        let field = match chars.next() {
+            Some('Y') if year < 0 => format!("-{:04}", -year),
+            Some('Y') => format!("{:04}", year),
+            Some('m') => format!("{:02}", month),
+            Some('d') => format!("{:02}", day),
+            Some('H') => format!("{:02}", time / 3_600_000),
+            Some('M') => format!("{:02}", time / 60_000 % 60),
+            Some('S') => format!("{:02}", time / 1000 % 60),
+            Some('f') => format!("{:03}", time % 1000),
+            Some('j') => format!("{:03}", days - days_from_civil(year, 1, 1) + 1),
+            Some('a') => WEEKDAYS[(days + 3).rem_euclid(7) as usize].to_string(),
+            Some('b') => MONTHS[month as usize - 1].to_string(),
+            Some('s') => millis.div_euclid(1000).to_string(),
+            Some('%') => "%".to_string(),
+            Some(code) => log_and_return_err!("Unknown code %{} in timestamp format {:?}", code, format),
+            None => log_and_return_err!("Timestamp format {:?} ends with a lone %", format),
+        };
+        output.push_str(&field);
+    }
+    return Ok(output);
+}
+
+// Reads the fields of a timestamp out of text, one at a time.
+struct Parser<'a> {
+    text: &'a str,
+    original: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn number(&mut self, digits: usize) -> Result<i64, String> {
+        let field = self.text.get(..digits).filter(|field| field.bytes().all(|byte| byte.is_ascii_digit()));
+        let Some(field) = field else {
+            log_and_return_err!("Expected {} digits at {:?} in timestamp {:?}", digits, self.text, self.original);
+        };
+        self.text = &self.text[digits..];
+        return Ok(field.parse().unwrap());
+    }
+
+    fn seconds(&mut self) -> Result<i64, String> {
+        let sign = if self.text.starts_with('-') { 1 } else { 0 };
+        let digits = self.text[sign..].bytes().take_while(|byte| byte.is_ascii_digit()).count();
+        let Ok(value) = self.text[..sign + digits].parse() else {
+            log_and_return_err!("Expected a number of seconds at {:?} in timestamp {:?}", self.text, self.original);
+        };
+        self.text = &self.text[sign + digits..];
+        return Ok(value);
+    }
+
+    // The position of the name at the start of the text, ignoring case.
+    fn name(&mut self, names: &[&str]) -> Result<usize, String> {
+        let found = names.iter().position(|name| {
+            self.text.get(..name.len()).is_some_and(|start| start.eq_ignore_ascii_case(name))
+        });
+        let Some(index) = found else {
+            log_and_return_err!("Expected one of {:?} at {:?} in timestamp {:?}", names, self.text, self.original);
+        };
+        self.text = &self.text[names[index].len()..];
+        return Ok(index);
+    }
+
+    fn literal(&mut self, c: char) -> Result<(), String> {
+        let Some(rest) = self.text.strip_prefix(c) else {
+            log_and_return_err!("Expected {:?} at {:?} in timestamp {:?}", c, self.text, self.original);
+        };
+        self.text = rest;
+        return Ok(());
+    }
+}
+
+/// Parse a timestamp in the given format into milliseconds since the Unix epoch.
+pub(crate) fn parse(text: &str, format: &str) -> Result<i64, String> {
+    let mut parser = Parser { text, original: text };
+    let (mut year, mut month, mut day, mut day_of_year) = (1970, 1, 1, None);
+    let (mut hour, mut minute, mut second, mut milli) = (0, 0, 0, 0);
+    let mut epoch_seconds = None;
+
+    let mut chars = expand(format).chars().collect::<Vec<char>>().into_iter();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            parser.literal(c)?;
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => {
+                let negative = parser.text.starts_with('-');
+                if negative {
+                    parser.literal('-')?;
+                }
+                year = parser.number(4)?;
+                if negative {
+                    year = -year;
+                }
+            },
+            Some('m') => month = parser.number(2)?,
+            Some('d') => day = parser.number(2)?,
+            Some('H') => hour = parser.number(2)?,
+            Some('M') => minute = parser.number(2)?,
+            Some('S') => second = parser.number(2)?,
+            Some('f') => milli = parser.number(3)?,
+            Some('j') => day_of_year = Some(parser.number(3)?),
+            Some('a') => { parser.name(&WEEKDAYS)?; },
+            Some('b') => month = parser.name(&MONTHS)? as i64 + 1,
+            Some('s') => epoch_seconds = Some(parser.seconds()?),
+            Some('%') => parser.literal('%')?,
+            Some(code) => log_and_return_err!("Unknown code %{} in timestamp format {:?}", code, format),
+            None => log_and_return_err!("Timestamp format {:?} ends with a lone %", format),
+        }
+    }
+    if !parser.text.is_empty() {
+        log_and_return_err!("Unexpected {:?} at the end of timestamp {:?}", parser.text, text);
+    }
+
+    if let Some(seconds) = epoch_seconds {
+        let Some(millis) = seconds.checked_mul(1000).and_then(|millis| millis.checked_add(milli)) else {
+            log_and_return_err!("Timestamp {:?} is out of range", text);
+        };
+        return Ok(millis);
+    }
+    if !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+        log_and_return_err!("Timestamp {:?} has no such date", text);
+    }
+    if hour > 23 || minute > 59 || second > 59 {
+        log_and_return_err!("Timestamp {:?} has no such time", text);
+    }
+    let days = match day_of_year {
+        Some(day_of_year) if (1..=if is_leap_year(year) { 366 } else { 365 }).contains(&day_of_year) => {
+            days_from_civil(year, 1, 1) + day_of_year - 1
+        },
+        Some(_) => log_and_return_err!("Timestamp {:?} has no such day of the year", text),
+        None => days_from_civil(year, month, day),
+    };
+    return Ok(days * MILLIS_PER_DAY + ((hour * 60 + minute) * 60 + second) * 1000 + milli);
+}
@@ -0,0 +1,85 @@
+//! ConcordeVM's program validation.
+//!
+//! Provides a pass that walks a program's control flow before it is run, to catch mistakes that
+//! would otherwise only show up partway through execution.
+
+use concordeisa::instructions::Instruction;
+
+/// Where control can go after an instruction: the targets it may jump to, and whether it may
+/// also carry on to the next instruction.
+pub(crate) fn successors(instruction: &Instruction) -> (Vec<usize>, bool) {
+    match instruction {
+        Instruction::Jump(target) => (vec![*target], false),
+        Instruction::JumpIfTrue(target, _) | Instruction::JumpIfFalse(target, _) => (vec![*target], true),
+        Instruction::Switch(_, cases, default) => {
+            let mut targets: Vec<usize> = cases.iter().map(|(_, target)| *target).collect();
+            targets.push(*default);
+            (targets, false)
+        },
+        // The coroutine body is reached too, since it runs the same program from `dest`.
+        Instruction::CreateCoroutine(dest, _, _, _) => (vec![*dest], true),
+        // Likewise for an actor's handler, which runs once per message.
+        Instruction::SpawnActor(handler, _, _) => (vec![*handler], true),
+        // The loop carries on after itself once the body has run for every item.
+        Instruction::ForEach(_, _, body)
+        | Instruction::MapList(_, _, body, _)
+        | Instruction::FilterList(_, _, body, _)
+        | Instruction::ReduceList(_, _, body, _) => (vec![*body], true),
+        Instruction::Return(_, _) | Instruction::ReturnFromInterrupt() => (Vec::new(), false),
+        _ => (Vec::new(), true),
+    }
+}
+
+/// Check a program, starting from `entrypoint`, and describe every problem found.
+///
+/// Reports the entrypoint or jump targets being out of range, and instructions that can never be
+/// reached. Jumping to one past the last instruction is allowed, and ends the program.
+pub fn validate(instructions: &[Instruction], entrypoint: usize) -> Vec<String> {
+    let mut problems = Vec::new();
+    if entrypoint >= instructions.len() {
+        problems.push(format!("Entrypoint {} is outside the program, which has {} instructions", entrypoint, instructions.len()));
+        return problems;
+    }
+
+    let mut reachable = vec![false; instructions.len()];
+    let mut to_visit = vec![entrypoint];
+    while let Some(index) = to_visit.pop() {
+        if index >= instructions.len() || reachable[index] {
+            continue;
+        }
+        reachable[index] = true;
+
+        let (targets, falls_through) = successors(&instructions[index]);
+        for target in targets {
+            if target > instructions.len() {
+                problems.push(format!("Instruction {} jumps to {}, but the program only has {} instructions", index, target, instructions.len()));
+            } else {
+                to_visit.push(target);
+            }
+        }
+        if falls_through {
+            to_visit.push(index + 1);
+        }
+    }
+
+    let mut index = 0;
+    while index < instructions.len() {
+        if reachable[index] {
+            index += 1;
+            continue;
+        }
+        let start = index;
+        while index < instructions.len() && !reachable[index] {
+            index += 1;
+        }
+        if index - start == 1 {
+            problems.push(format!("Instruction {} is unreachable", start));
+        } else {
+            problems.push(format!("Instructions {} to {} are unreachable", start, index - 1));
+        }
+    }
+
+    problems.sort();
+    problems.dedup();
+    return problems;
+}
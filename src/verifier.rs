@@ -0,0 +1,316 @@
+//! ConcordeVM's bytecode verifier.
+//!
+//! Infers the type of each symbol at every point in a program, by walking its control flow with
+//! the types each instruction writes, and reports instructions that read a symbol as the wrong
+//! type, like an AddSymbols fed a string.
+//!
+//! Only symbols whose type is certain are checked: one written by an instruction with a known
+//! result type, on every path to the read, and not overwritten since. Memory read from streams,
+//! files, futures, or bytes literals has no known type, so it never causes a diagnostic.
+
+use crate::validation::successors;
+
+use concordeisa::instructions::Instruction;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Type {
+    Int,
+    Float,
+    Bool,
+    String(usize),
+}
+
+impl Type {
+    fn size(&self) -> usize {
+        match self {
+            Type::Int => 8,
+            Type::Float => 4,
+            Type::Bool => 1,
+            Type::String(len) => *len,
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Type::Int => "an integer",
+            Type::Float => "a float",
+            Type::Bool => "a bool",
+            Type::String(_) => "a string",
+        }
+    }
+}
+
+// The type of each symbol known to start at an address.
+type State = HashMap<usize, Type>;
+
+enum Write {
+    // Bytes of a known type at an address.
+    Typed(usize, Type),
+    // A number of bytes of unknown type at an address.
+    Untyped(usize, usize),
+    // An unknown number of bytes starting at an address.
+    From(usize),
+    // Anything at all.
+    Everything,
+}
+
+// The symbols an instruction reads with an expected type, and what it writes.
+fn effects(instruction: &Instruction, state: &State) -> (Vec<(usize, Type)>, Vec<Write>) {
+    match *instruction {
+        Instruction::WriteStringToSymbol(symbol, ref value) => (vec![], vec![Write::Typed(symbol, Type::String(value.len()))]),
+        Instruction::WriteIntToSymbol(symbol, _) => (vec![], vec![Write::Typed(symbol, Type::Int)]),
+        Instruction::WriteBoolToSymbol(symbol, _) => (vec![], vec![Write::Typed(symbol, Type::Bool)]),
+        Instruction::WriteBytesToSymbol(symbol, ref value) => (vec![], vec![Write::Untyped(symbol, value.len())]),
+        Instruction::MemCpy(source, dest, n) => match state.get(&source) {
+            Some(ty) if ty.size() == n => (vec![], vec![Write::Typed(dest, *ty)]),
+            _ => (vec![], vec![Write::Untyped(dest, n)]),
+        },
+        Instruction::MemExtend(_) | Instruction::MemExtendTo(_) | Instruction::NoOp() => (vec![], vec![]),
+        Instruction::BeginTransaction() | Instruction::CommitTransaction() | Instruction::MakeConst(_, _) => (vec![], vec![]),
+        Instruction::Ind(addr_location, dest, n) => (vec![(addr_location, Type::Int)], vec![Write::Untyped(dest, n)]),
+
+        Instruction::ConcatBytes(_, a_len, _, b_len, dest) => (vec![(a_len, Type::Int), (b_len, Type::Int)], vec![Write::From(dest)]),
+        Instruction::FillBytes(dest, _, n) => (vec![(n, Type::Int)], vec![Write::From(dest)]),
+        Instruction::CopyBytesRange(_, src_offset, dest, dest_offset, n) => (vec![(src_offset, Type::Int), (dest_offset, Type::Int), (n, Type::Int)], vec![Write::From(dest)]),
+        Instruction::FindBytes(_, haystack_len, _, needle_len, dest) => (vec![(haystack_len, Type::Int), (needle_len, Type::Int)], vec![Write::Typed(dest, Type::Int)]),
+        Instruction::EncodeIntLE(bytes, offset, _, value)
+        | Instruction::EncodeIntBE(bytes, offset, _, value) => (vec![(offset, Type::Int), (value, Type::Int)], vec![Write::From(bytes)]),
+        Instruction::DecodeIntLE(_, offset, _, dest)
+        | Instruction::DecodeIntBE(_, offset, _, dest) => (vec![(offset, Type::Int)], vec![Write::Typed(dest, Type::Int)]),
+        Instruction::PackStruct(_, _, dest) | Instruction::UnpackStruct(_, _, dest) => (vec![], vec![Write::From(dest)]),
+        Instruction::Hash(_, _, n, dest) => (vec![(n, Type::Int)], vec![Write::From(dest)]),
+        Instruction::AeadEncrypt(_, _, _, n, dest)
+        | Instruction::AeadDecrypt(_, _, _, n, dest)
+        | Instruction::RandomBytes(n, dest) => (vec![(n, Type::Int)], vec![Write::From(dest)]),
+        Instruction::Select(_, dest)
+        | Instruction::NowUnixMillis(dest)
+        | Instruction::MonotonicNanos(dest)
+        | Instruction::GetStackDepth(dest) => (vec![], vec![Write::Typed(dest, Type::Int)]),
+        Instruction::FormatTimestamp(millis, _, dest) => (vec![(millis, Type::Int)], vec![Write::From(dest)]),
+        Instruction::ParseTimestamp(_, _, dest) => (vec![], vec![Write::Typed(dest, Type::Int)]),
+        Instruction::FormatString(_, args, dest) => (vec![(args, Type::Int)], vec![Write::From(dest)]),
+        Instruction::BigFromInt(a, dest) => (vec![(a, Type::Int)], vec![Write::From(dest)]),
+        Instruction::BigToInt(_, dest) => (vec![], vec![Write::Typed(dest, Type::Int)]),
+        Instruction::BigFromString(_, dest)
+        | Instruction::BigToString(_, dest)
+        | Instruction::BigAdd(_, _, dest)
+        | Instruction::BigSubtract(_, _, dest)
+        | Instruction::BigMultiply(_, _, dest)
+        | Instruction::BigDivide(_, _, dest)
+        | Instruction::BigModulo(_, _, dest)
+        | Instruction::BigPowMod(_, _, _, dest) => (vec![], vec![Write::From(dest)]),
+        Instruction::BigCompareEqual(_, _, dest)
+        | Instruction::BigCompareGreater(_, _, dest)
+        | Instruction::BigCompareLesser(_, _, dest) => (vec![], vec![Write::Typed(dest, Type::Bool)]),
+        Instruction::RandomInt(min, max, dest) => (vec![(min, Type::Int), (max, Type::Int)], vec![Write::Typed(dest, Type::Int)]),
+
+        Instruction::AddSymbols(a, b, dest)
+        | Instruction::SubtractSymbols(a, b, dest)
+        | Instruction::MultiplySymbols(a, b, dest)
+        | Instruction::DivideSymbols(a, b, dest)
+        | Instruction::ModuloSymbols(a, b, dest)
+        | Instruction::MinSymbols(a, b, dest)
+        | Instruction::MaxSymbols(a, b, dest)
+        | Instruction::CheckedAdd(a, b, dest)
+        | Instruction::CheckedSubtract(a, b, dest)
+        | Instruction::CheckedMultiply(a, b, dest)
+        | Instruction::WrappingAdd(a, b, dest)
+        | Instruction::WrappingSubtract(a, b, dest)
+        | Instruction::WrappingMultiply(a, b, dest) => (vec![(a, Type::Int), (b, Type::Int)], vec![Write::Typed(dest, Type::Int)]),
+        Instruction::FmaSymbols(a, b, c, dest) => (vec![(a, Type::Int), (b, Type::Int), (c, Type::Int)], vec![Write::Typed(dest, Type::Int)]),
+        Instruction::AddImmediate(a, _, dest)
+        | Instruction::SubtractImmediate(a, _, dest)
+        | Instruction::MultiplyImmediate(a, _, dest)
+        | Instruction::DivideImmediate(a, _, dest)
+        | Instruction::ModuloImmediate(a, _, dest) => (vec![(a, Type::Int)], vec![Write::Typed(dest, Type::Int)]),
+
+        Instruction::SinSymbol(a, dest)
+        | Instruction::CosSymbol(a, dest)
+        | Instruction::TanSymbol(a, dest)
+        | Instruction::ArcsinSymbol(a, dest)
+        | Instruction::ArccosSymbol(a, dest)
+        | Instruction::ArctanSymbol(a, dest)
+        | Instruction::SqrtSymbol(a, dest)
+        | Instruction::AbsSymbol(a, dest)
+        | Instruction::FloorSymbol(a, dest)
+        | Instruction::CeilSymbol(a, dest)
+        | Instruction::ExpSymbol(a, dest)
+        | Instruction::LnSymbol(a, dest) => (vec![(a, Type::Float)], vec![Write::Typed(dest, Type::Float)]),
+        Instruction::PowSymbols(a, b, dest)
+        | Instruction::FloatMinSymbols(a, b, dest)
+        | Instruction::FloatMaxSymbols(a, b, dest)
+        | Instruction::Arctan2Symbols(a, b, dest) => (vec![(a, Type::Float), (b, Type::Float)], vec![Write::Typed(dest, Type::Float)]),
+
+        Instruction::CompareEqual(a, b, dest)
+        | Instruction::CompareGreater(a, b, dest)
+        | Instruction::CompareLesser(a, b, dest) => (vec![(a, Type::Int), (b, Type::Int)], vec![Write::Typed(dest, Type::Bool)]),
+        Instruction::CompareEqualImmediate(a, _, dest)
+        | Instruction::CompareGreaterImmediate(a, _, dest)
+        | Instruction::CompareLesserImmediate(a, _, dest) => (vec![(a, Type::Int)], vec![Write::Typed(dest, Type::Bool)]),
+        Instruction::CompareStringsEqual(a, b, dest)
+        | Instruction::CompareStringsGreater(a, b, dest)
+        | Instruction::CompareStringsLesser(a, b, dest) => (vec![(a, Type::String(0)), (b, Type::String(0))], vec![Write::Typed(dest, Type::Bool)]),
+        Instruction::CompareBoolsEqual(a, b, dest) => (vec![(a, Type::Bool), (b, Type::Bool)], vec![Write::Typed(dest, Type::Bool)]),
+        Instruction::CompareFloatsEqual(a, b, dest)
+        | Instruction::CompareFloatsGreater(a, b, dest)
+        | Instruction::CompareFloatsLesser(a, b, dest) => (vec![(a, Type::Float), (b, Type::Float)], vec![Write::Typed(dest, Type::Bool)]),
+        Instruction::CompareBytesEqual(_, a_len, _, b_len, dest)
+        | Instruction::CompareBytesGreater(_, a_len, _, b_len, dest)
+        | Instruction::CompareBytesLesser(_, a_len, _, b_len, dest) => (vec![(a_len, Type::Int), (b_len, Type::Int)], vec![Write::Typed(dest, Type::Bool)]),
+
+        Instruction::Jump(_) | Instruction::Return(_, _) | Instruction::ReturnFromInterrupt() => (vec![], vec![]),
+        Instruction::JumpIfTrue(_, condition) | Instruction::JumpIfFalse(_, condition) => (vec![(condition, Type::Bool)], vec![]),
+        Instruction::Switch(value, _, _) => (vec![(value, Type::Int)], vec![]),
+        // The body may write anything before the loop carries on.
+        Instruction::ForEach(list, _, _)
+        | Instruction::MapList(list, _, _, _)
+        | Instruction::FilterList(list, _, _, _)
+        | Instruction::ReduceList(list, _, _, _) => (vec![(list, Type::Int)], vec![Write::Everything]),
+        Instruction::SortList(list, _, _) => (vec![(list, Type::Int)], vec![Write::From(list + 8)]),
+        Instruction::BinarySearch(list, _, _, dest) => (vec![(list, Type::Int)], vec![Write::Typed(dest, Type::Int)]),
+        Instruction::CreateCoroutine(_, _, _, write_fut_id) => (vec![], vec![Write::Typed(write_fut_id, Type::Int)]),
+        Instruction::Await(fut_id_location, dest) => (vec![(fut_id_location, Type::Int)], vec![Write::From(dest)]),
+        Instruction::AwaitEvent(name, dest) => (vec![(name, Type::String(0))], vec![Write::From(dest)]),
+        Instruction::Assert(condition, message) => (vec![(condition, Type::Bool), (message, Type::String(0))], vec![]),
+        Instruction::AssertType(symbol, ref type_name) => {
+            let ty = match type_name.as_str() {
+                "int" => Some(Type::Int),
+                "float" => Some(Type::Float),
+                "bool" => Some(Type::Bool),
+                "string" => Some(Type::String(0)),
+                _ => None,
+            };
+            (ty.map(|ty| (symbol, ty)).into_iter().collect(), vec![])
+        },
+
+        Instruction::ReadStream(_, _, dest)
+        | Instruction::ReadLine(_, dest)
+        | Instruction::ReadFileToSymbol(_, dest)
+        | Instruction::GetEnv(_, dest)
+        | Instruction::GetArgs(dest)
+        | Instruction::GetCallerSymbol(dest)
+        | Instruction::ListCodeBlocks(dest)
+        | Instruction::Import(_, dest) => (vec![], vec![Write::From(dest)]),
+        Instruction::LoadCode(_, n, dest) => (vec![(n, Type::Int)], vec![Write::From(dest)]),
+        Instruction::PrintSymbol(symbol) => (vec![(symbol, Type::String(0))], vec![]),
+        Instruction::BlockLength(name, dest) => (vec![(name, Type::String(0))], vec![Write::Typed(dest, Type::Int)]),
+        Instruction::InvokeHostValue(value, _, _, dest) => (vec![(value, Type::Int)], vec![Write::From(dest)]),
+        Instruction::CreateSharedRegion(_, _)
+        | Instruction::Lock(_)
+        | Instruction::Unlock(_)
+        | Instruction::BeginScope()
+        | Instruction::EndScope(_)
+        | Instruction::UnloadDomain(_) => (vec![], vec![]),
+        Instruction::AtomicAdd(_, _, value, dest) => (vec![(value, Type::Int)], vec![Write::Typed(dest, Type::Int)]),
+        Instruction::CompareAndSwap(_, _, expected, new, dest) => (vec![(expected, Type::Int), (new, Type::Int)], vec![Write::Typed(dest, Type::Bool)]),
+        Instruction::ReadShared(_, _, n, dest) => (vec![], vec![Write::Untyped(dest, n)]),
+        Instruction::WriteShared(_, _, _, _) => (vec![], vec![]),
+        Instruction::SpawnActor(_, _, write_actor_id) => (vec![], vec![Write::Typed(write_actor_id, Type::Int)]),
+        Instruction::MutexCreate(dest) => (vec![], vec![Write::Typed(dest, Type::Int)]),
+        Instruction::SemaphoreCreate(permits, dest) => (vec![(permits, Type::Int)], vec![Write::Typed(dest, Type::Int)]),
+        Instruction::MutexLock(id)
+        | Instruction::MutexUnlock(id)
+        | Instruction::SemaphoreAcquire(id)
+        | Instruction::SemaphoreRelease(id) => (vec![(id, Type::Int)], vec![]),
+        Instruction::SendMessage(actor_location, _) => (vec![(actor_location, Type::Int)], vec![]),
+        Instruction::OpenStream(_, _, _)
+        | Instruction::CloseStream(_)
+        | Instruction::FlushStream(_)
+        | Instruction::WriteStream(_, _, _)
+        | Instruction::WriteSymbolToFile(_, _, _)
+        | Instruction::RenameFile(_, _)
+        | Instruction::DeleteFile(_)
+        | Instruction::CopyFile(_, _)
+        | Instruction::SetEnv(_, _) => (vec![], vec![]),
+        _ => (vec![], vec![Write::Everything]),
+    }
+}
+
+fn apply(state: &mut State, write: &Write) {
+    match *write {
+        Write::Typed(address, ty) => {
+            forget(state, address, ty.size());
+            state.insert(address, ty);
+        },
+        Write::Untyped(address, n) => forget(state, address, n),
+        Write::From(address) => state.retain(|symbol, ty| symbol + ty.size() <= address),
+        Write::Everything => state.clear(),
+    }
+}
+
+// Forget every symbol overlapping the given bytes.
+fn forget(state: &mut State, address: usize, n: usize) {
+    state.retain(|symbol, ty| *symbol >= address + n || symbol + ty.size() <= address);
+}
+
+// Keep only the types both states agree on.
+fn join(into: &mut State, other: &State) -> bool {
+    let before = into.len();
+    into.retain(|symbol, ty| other.get(symbol) == Some(ty));
+    return into.len() != before;
+}
+
+// Merge a state into the one on entry to an instruction, queueing it if anything changed.
+fn reach(states: &mut [Option<State>], to_visit: &mut Vec<usize>, index: usize, state: &State) {
+    if index >= states.len() {
+        return;
+    }
+    let changed = match &mut states[index] {
+        Some(existing) => join(existing, state),
+        slot @ None => {
+            *slot = Some(state.clone());
+            true
+        },
+    };
+    if changed {
+        to_visit.push(index);
+    }
+}
+
+/// Check the types of symbols read throughout a program entered at `entrypoint`, describing
+/// every read of a symbol that is certain to hold another type.
+pub(crate) fn verify(instructions: &[Instruction], entrypoint: usize) -> Vec<String> {
+    let mut states: Vec<Option<State>> = vec![None; instructions.len()];
+    let mut to_visit = Vec::new();
+    reach(&mut states, &mut to_visit, entrypoint, &State::new());
+
+    while let Some(index) = to_visit.pop() {
+        let mut state = states[index].clone().unwrap();
+        let instruction = &instructions[index];
+        let (_, writes) = effects(instruction, &state);
+        for write in &writes {
+            apply(&mut state, write);
+        }
+        let (targets, falls_through) = successors(instruction);
+        for target in targets {
+            // New coroutines start with fresh memory holding only their arguments.
+            if let Instruction::CreateCoroutine(..) | Instruction::SpawnActor(..) = instruction {
+                reach(&mut states, &mut to_visit, target, &State::new());
+            } else {
+                reach(&mut states, &mut to_visit, target, &state);
+            }
+        }
+        if falls_through {
+            reach(&mut states, &mut to_visit, index + 1, &state);
+        }
+    }
+
+    let mut problems = Vec::new();
+    for (index, (instruction, state)) in instructions.iter().zip(&states).enumerate() {
+        let Some(state) = state else { continue };
+        let (reads, _) = effects(instruction, state);
+        for (symbol, expected) in reads {
+            match state.get(&symbol) {
+                Some(found) if std::mem::discriminant(found) != std::mem::discriminant(&expected) => problems.push(format!(
+                    "Instruction {} ({:?}) reads symbol {} as {}, but it holds {}",
+                    index, instruction, symbol, expected.describe(), found.describe()
+                )),
+                _ => {},
+            }
+        }
+    }
+    problems.sort();
+    problems.dedup();
+    return problems;
+}